@@ -0,0 +1,103 @@
+//! `#[derive(FangTask)]` for fang jobs, enabled with fang's `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitStr};
+
+/// Implements `fang::Runnable` (with `#[typetag::serde]`) on top of `fang::Perform`, and adds
+/// a `TASK_TYPE` constant and an `enqueue(&self, &Queue)` method to the type. The task type
+/// is set with `#[fang(task_type = "...")]` and defaults to `common`:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, FangTask)]
+/// #[fang(task_type = "emails")]
+/// struct SendEmail {
+///     pub to: String,
+/// }
+///
+/// impl Perform for SendEmail {
+///     fn perform(&self, connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+///         ...
+///     }
+/// }
+///
+/// SendEmail { to }.enqueue(&queue)?;
+/// ```
+#[proc_macro_derive(FangTask, attributes(fang))]
+pub fn derive_fang_task(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "FangTask can't be derived for generic types, typetag doesn't support them",
+        ));
+    }
+
+    let name = &input.ident;
+    let task_type = task_type(&input)?;
+
+    Ok(quote! {
+        impl #name {
+            pub const TASK_TYPE: &'static str = #task_type;
+
+            pub fn enqueue<Conn>(
+                &self,
+                queue: &fang::Queue<Conn>,
+            ) -> std::result::Result<fang::Task, fang::FangError>
+            where
+                Conn: std::borrow::Borrow<fang::PgConnection>,
+            {
+                queue.push_task(self)
+            }
+        }
+
+        const _: () = {
+            // typetag's expansion refers to `typetag::`
+            use fang::typetag;
+
+            #[typetag::serde]
+            impl fang::Runnable for #name {
+                fn run(
+                    &self,
+                    connection: &fang::PgConnection,
+                    context: &fang::TaskContext,
+                ) -> std::result::Result<(), fang::Error> {
+                    fang::Perform::perform(self, connection, context)
+                }
+
+                fn task_type(&self) -> std::string::String {
+                    Self::TASK_TYPE.to_string()
+                }
+            }
+        };
+    })
+}
+
+fn task_type(input: &DeriveInput) -> syn::Result<LitStr> {
+    let mut task_type = None;
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("fang"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("task_type") {
+                task_type = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported fang attribute, expected `task_type`"))
+            }
+        })?;
+    }
+
+    Ok(task_type.unwrap_or_else(|| LitStr::new("common", proc_macro2::Span::call_site())))
+}
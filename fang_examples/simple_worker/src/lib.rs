@@ -3,6 +3,7 @@ use fang::Error;
 use fang::PgConnection;
 use fang::Queue;
 use fang::Runnable;
+use fang::TaskContext;
 use serde::Deserialize;
 use serde::Serialize;
 use std::thread;
@@ -28,7 +29,11 @@ impl MyJob {
 
 #[typetag::serde]
 impl Runnable for MyJob {
-    fn run(&self, connection: &PgConnection) -> Result<(), Error> {
+    fn run(
+        &self,
+        connection: &PgConnection,
+        _context: &TaskContext,
+    ) -> Result<(), Error> {
         thread::sleep(Duration::from_secs(3));
 
         let new_job = MyJob::new(self.number + 1);
@@ -4,7 +4,6 @@ use fang::RetentionMode;
 use fang::WorkerParams;
 use fang::WorkerPool;
 use simple_worker::MyJob;
-use signal_hook::{consts::signal::*, iterator::Signals};
 
 fn main() {
     dotenv().ok();
@@ -22,20 +21,6 @@ fn main() {
     queue.push_task(&MyJob::new(1)).unwrap();
     queue.push_task(&MyJob::new(1000)).unwrap();
 
-    let mut signals = Signals::new(&[SIGINT, SIGTERM]).unwrap();
-    for sig in signals.forever() {
-        match sig {
-            SIGINT => {
-                println!("Received SIGINT");
-                worker_pool.shutdown().unwrap();
-                break;
-            },
-            SIGTERM => {
-                println!("Received SIGTERM");
-                worker_pool.shutdown().unwrap();
-                break;
-            },
-            _ => unreachable!(format!("Received unexpected signal: {:?}", sig)),
-        }
-    }
+    worker_pool.install_signal_handler().unwrap();
+    worker_pool.join().unwrap();
 }
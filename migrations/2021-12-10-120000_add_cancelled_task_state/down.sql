@@ -0,0 +1,10 @@
+UPDATE fang_tasks SET state = 'failed' WHERE state = 'cancelled';
+
+ALTER TYPE fang_task_state RENAME TO fang_task_state_old;
+CREATE TYPE fang_task_state AS ENUM ('new', 'in_progress', 'failed', 'finished');
+
+ALTER TABLE fang_tasks ALTER COLUMN state DROP DEFAULT;
+ALTER TABLE fang_tasks ALTER COLUMN state TYPE fang_task_state USING state::text::fang_task_state;
+ALTER TABLE fang_tasks ALTER COLUMN state SET DEFAULT 'new';
+
+DROP TYPE fang_task_state_old;
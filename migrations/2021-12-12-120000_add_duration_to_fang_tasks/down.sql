@@ -0,0 +1 @@
+ALTER TABLE fang_tasks DROP COLUMN duration_in_milliseconds;
@@ -0,0 +1 @@
+ALTER TABLE fang_tasks ADD COLUMN duration_in_milliseconds BIGINT;
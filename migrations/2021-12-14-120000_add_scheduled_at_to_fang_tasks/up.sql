@@ -0,0 +1 @@
+ALTER TABLE fang_tasks ADD COLUMN scheduled_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1 @@
+DROP TABLE fang_maintenance_windows;
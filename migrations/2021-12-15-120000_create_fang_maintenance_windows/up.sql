@@ -0,0 +1,10 @@
+CREATE TABLE fang_maintenance_windows (
+     id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+     task_type VARCHAR NOT NULL,
+     weekday SMALLINT NOT NULL CHECK (weekday BETWEEN 1 AND 7),
+     starts_at TIME NOT NULL,
+     ends_at TIME NOT NULL CHECK (ends_at > starts_at),
+     created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX fang_maintenance_windows_task_type_index ON fang_maintenance_windows(task_type);
@@ -0,0 +1 @@
+ALTER TABLE fang_tasks DROP COLUMN origin;
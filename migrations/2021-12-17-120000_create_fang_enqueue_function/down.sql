@@ -0,0 +1 @@
+DROP FUNCTION fang_enqueue(VARCHAR, JSONB, VARCHAR);
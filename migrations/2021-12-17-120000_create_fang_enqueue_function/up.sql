@@ -0,0 +1,27 @@
+-- Enqueue a task from SQL, e.g. in a trigger. `metadata` is the serialized job including its
+-- `type`, exactly what `Queue::push_task` stores. Like `push_task`, it returns the id of an
+-- identical task that is still new or in progress instead of inserting a duplicate.
+-- Listeners on the `fang_tasks` channel are notified with the task type.
+CREATE FUNCTION fang_enqueue(task_type VARCHAR, metadata JSONB, origin VARCHAR DEFAULT NULL)
+RETURNS UUID AS $$
+DECLARE
+    task_id UUID;
+BEGIN
+    SELECT id INTO task_id FROM fang_tasks
+    WHERE fang_tasks.metadata = fang_enqueue.metadata
+      AND fang_tasks.state IN ('new', 'in_progress')
+    LIMIT 1;
+
+    IF task_id IS NOT NULL THEN
+        RETURN task_id;
+    END IF;
+
+    INSERT INTO fang_tasks (metadata, task_type, origin)
+    VALUES (fang_enqueue.metadata, fang_enqueue.task_type, fang_enqueue.origin)
+    RETURNING id INTO task_id;
+
+    PERFORM pg_notify('fang_tasks', fang_enqueue.task_type);
+
+    RETURN task_id;
+END;
+$$ LANGUAGE plpgsql;
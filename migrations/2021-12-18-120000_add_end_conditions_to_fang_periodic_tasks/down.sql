@@ -0,0 +1,3 @@
+ALTER TABLE fang_periodic_tasks DROP COLUMN ends_at;
+ALTER TABLE fang_periodic_tasks DROP COLUMN runs;
+ALTER TABLE fang_periodic_tasks DROP COLUMN max_runs;
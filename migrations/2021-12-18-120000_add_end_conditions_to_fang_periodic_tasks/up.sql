@@ -0,0 +1,3 @@
+ALTER TABLE fang_periodic_tasks ADD COLUMN max_runs INTEGER;
+ALTER TABLE fang_periodic_tasks ADD COLUMN runs INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE fang_periodic_tasks ADD COLUMN ends_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,3 @@
+ALTER TABLE fang_periodic_tasks DROP COLUMN overlap_policy;
+
+DROP TYPE fang_overlap_policy;
@@ -0,0 +1,3 @@
+CREATE TYPE fang_overlap_policy AS ENUM ('skip', 'enqueue', 'replace');
+
+ALTER TABLE fang_periodic_tasks ADD COLUMN overlap_policy fang_overlap_policy DEFAULT 'skip' NOT NULL;
@@ -0,0 +1 @@
+ALTER TABLE fang_periodic_tasks DROP COLUMN task_type;
@@ -0,0 +1,3 @@
+ALTER TABLE fang_periodic_tasks ADD COLUMN task_type VARCHAR DEFAULT 'common' NOT NULL;
+
+CREATE INDEX fang_periodic_tasks_type_index ON fang_periodic_tasks(task_type);
@@ -0,0 +1,7 @@
+ALTER TABLE fang_tasks ADD COLUMN errors JSONB DEFAULT '[]' NOT NULL;
+
+UPDATE fang_tasks
+SET errors = jsonb_build_array(
+  jsonb_build_object('message', error_message, 'at', updated_at, 'attempt', 1)
+)
+WHERE error_message IS NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE fang_tasks DROP COLUMN finished_at;
+ALTER TABLE fang_tasks DROP COLUMN started_at;
+ALTER TABLE fang_tasks DROP COLUMN attempts;
@@ -0,0 +1,3 @@
+ALTER TABLE fang_tasks ADD COLUMN attempts INTEGER DEFAULT 0 NOT NULL;
+ALTER TABLE fang_tasks ADD COLUMN started_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE fang_tasks ADD COLUMN finished_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,21 @@
+CREATE TABLE fang_archived_tasks (
+  id uuid PRIMARY KEY,
+  metadata jsonb NOT NULL,
+  error_message TEXT,
+  state fang_task_state NOT NULL,
+  task_type VARCHAR NOT NULL,
+  created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  updated_at TIMESTAMP WITH TIME ZONE NOT NULL,
+  duration_in_milliseconds BIGINT,
+  signature VARCHAR,
+  scheduled_at TIMESTAMP WITH TIME ZONE,
+  origin VARCHAR,
+  errors JSONB NOT NULL,
+  attempts INTEGER NOT NULL,
+  started_at TIMESTAMP WITH TIME ZONE,
+  finished_at TIMESTAMP WITH TIME ZONE,
+  archived_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX fang_archived_tasks_type_index ON fang_archived_tasks(task_type);
+CREATE INDEX fang_archived_tasks_archived_at_index ON fang_archived_tasks(archived_at);
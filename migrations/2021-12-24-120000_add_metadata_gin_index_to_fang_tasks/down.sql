@@ -0,0 +1 @@
+DROP INDEX fang_tasks_metadata_gin_index;
@@ -0,0 +1 @@
+CREATE INDEX fang_tasks_metadata_gin_index ON fang_tasks USING GIN (metadata jsonb_path_ops);
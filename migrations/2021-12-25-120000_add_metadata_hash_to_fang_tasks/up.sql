@@ -0,0 +1,37 @@
+-- SHA-256 of the metadata as jsonb prints it, with sorted keys and normalized whitespace.
+-- `convert_to` is only stable because of the source encoding, which doesn't change for a
+-- database, so the function is declared immutable to be usable in a generated column.
+CREATE FUNCTION fang_metadata_hash(metadata JSONB) RETURNS VARCHAR AS $$
+    SELECT encode(sha256(convert_to(metadata::text, 'UTF8')), 'hex');
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Generated columns need PostgreSQL 12 or later
+ALTER TABLE fang_tasks ADD COLUMN metadata_hash VARCHAR
+  GENERATED ALWAYS AS (fang_metadata_hash(metadata)) STORED;
+
+CREATE INDEX fang_tasks_metadata_hash_index ON fang_tasks(metadata_hash);
+DROP INDEX fang_tasks_metadata_index;
+
+CREATE OR REPLACE FUNCTION fang_enqueue(task_type VARCHAR, metadata JSONB, origin VARCHAR DEFAULT NULL)
+RETURNS UUID AS $$
+DECLARE
+    task_id UUID;
+BEGIN
+    SELECT id INTO task_id FROM fang_tasks
+    WHERE fang_tasks.metadata_hash = fang_metadata_hash(fang_enqueue.metadata)
+      AND fang_tasks.state IN ('new', 'in_progress')
+    LIMIT 1;
+
+    IF task_id IS NOT NULL THEN
+        RETURN task_id;
+    END IF;
+
+    INSERT INTO fang_tasks (metadata, task_type, origin)
+    VALUES (fang_enqueue.metadata, fang_enqueue.task_type, fang_enqueue.origin)
+    RETURNING id INTO task_id;
+
+    PERFORM pg_notify('fang_tasks', fang_enqueue.task_type);
+
+    RETURN task_id;
+END;
+$$ LANGUAGE plpgsql;
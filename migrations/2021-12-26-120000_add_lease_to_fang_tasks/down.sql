@@ -0,0 +1,4 @@
+DROP INDEX fang_tasks_locked_until_index;
+
+ALTER TABLE fang_tasks DROP COLUMN locked_until;
+ALTER TABLE fang_tasks DROP COLUMN locked_by;
@@ -0,0 +1,4 @@
+ALTER TABLE fang_tasks ADD COLUMN locked_by VARCHAR;
+ALTER TABLE fang_tasks ADD COLUMN locked_until TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX fang_tasks_locked_until_index ON fang_tasks(locked_until) WHERE state = 'in_progress';
@@ -0,0 +1,5 @@
+ALTER TABLE fang_archived_tasks DROP COLUMN payload_format;
+ALTER TABLE fang_archived_tasks DROP COLUMN payload;
+
+ALTER TABLE fang_tasks DROP COLUMN payload_format;
+ALTER TABLE fang_tasks DROP COLUMN payload;
@@ -0,0 +1,5 @@
+ALTER TABLE fang_tasks ADD COLUMN payload BYTEA;
+ALTER TABLE fang_tasks ADD COLUMN payload_format VARCHAR;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN payload BYTEA;
+ALTER TABLE fang_archived_tasks ADD COLUMN payload_format VARCHAR;
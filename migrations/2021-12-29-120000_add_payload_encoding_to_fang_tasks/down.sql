@@ -0,0 +1,3 @@
+ALTER TABLE fang_archived_tasks DROP COLUMN payload_encoding;
+
+ALTER TABLE fang_tasks DROP COLUMN payload_encoding;
@@ -0,0 +1,3 @@
+ALTER TABLE fang_tasks ADD COLUMN payload_encoding VARCHAR;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN payload_encoding VARCHAR;
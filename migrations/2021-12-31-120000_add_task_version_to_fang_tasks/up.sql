@@ -0,0 +1,3 @@
+ALTER TABLE fang_tasks ADD COLUMN task_version INT4 NOT NULL DEFAULT 1;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN task_version INT4 NOT NULL DEFAULT 1;
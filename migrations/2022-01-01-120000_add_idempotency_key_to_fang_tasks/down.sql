@@ -0,0 +1,4 @@
+ALTER TABLE fang_archived_tasks DROP COLUMN idempotency_key;
+
+DROP INDEX fang_tasks_idempotency_key_index;
+ALTER TABLE fang_tasks DROP COLUMN idempotency_key;
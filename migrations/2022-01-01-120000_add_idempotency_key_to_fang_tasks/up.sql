@@ -0,0 +1,4 @@
+ALTER TABLE fang_tasks ADD COLUMN idempotency_key VARCHAR;
+CREATE UNIQUE INDEX fang_tasks_idempotency_key_index ON fang_tasks(idempotency_key);
+
+ALTER TABLE fang_archived_tasks ADD COLUMN idempotency_key VARCHAR;
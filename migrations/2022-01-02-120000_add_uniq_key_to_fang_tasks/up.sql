@@ -0,0 +1,4 @@
+ALTER TABLE fang_tasks ADD COLUMN uniq_key VARCHAR;
+CREATE INDEX fang_tasks_uniq_key_index ON fang_tasks(task_type, uniq_key) WHERE uniq_key IS NOT NULL;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN uniq_key VARCHAR;
@@ -0,0 +1,21 @@
+ALTER TABLE fang_archived_tasks DROP COLUMN next_task_id;
+
+ALTER TABLE fang_tasks DROP COLUMN next_task_id;
+
+UPDATE fang_tasks SET state = 'cancelled' WHERE state = 'waiting';
+UPDATE fang_archived_tasks SET state = 'cancelled' WHERE state = 'waiting';
+
+DROP INDEX fang_tasks_locked_until_index;
+
+ALTER TYPE fang_task_state RENAME TO fang_task_state_old;
+CREATE TYPE fang_task_state AS ENUM ('new', 'in_progress', 'failed', 'finished', 'cancelled', 'held');
+
+ALTER TABLE fang_tasks ALTER COLUMN state DROP DEFAULT;
+ALTER TABLE fang_tasks ALTER COLUMN state TYPE fang_task_state USING state::text::fang_task_state;
+ALTER TABLE fang_tasks ALTER COLUMN state SET DEFAULT 'new';
+
+ALTER TABLE fang_archived_tasks ALTER COLUMN state TYPE fang_task_state USING state::text::fang_task_state;
+
+DROP TYPE fang_task_state_old;
+
+CREATE INDEX fang_tasks_locked_until_index ON fang_tasks(locked_until) WHERE state = 'in_progress';
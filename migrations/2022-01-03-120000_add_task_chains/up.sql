@@ -0,0 +1,5 @@
+ALTER TYPE fang_task_state ADD VALUE IF NOT EXISTS 'waiting';
+
+ALTER TABLE fang_tasks ADD COLUMN next_task_id uuid;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN next_task_id uuid;
@@ -0,0 +1 @@
+DROP TABLE fang_task_dependencies;
@@ -0,0 +1,5 @@
+CREATE TABLE fang_task_dependencies (
+     task_id uuid NOT NULL REFERENCES fang_tasks(id) ON DELETE CASCADE,
+     depends_on_id uuid NOT NULL,
+     PRIMARY KEY (task_id, depends_on_id)
+);
@@ -0,0 +1,6 @@
+ALTER TABLE fang_archived_tasks DROP COLUMN batch_id;
+
+DROP INDEX fang_tasks_batch_id_index;
+ALTER TABLE fang_tasks DROP COLUMN batch_id;
+
+DROP TABLE fang_batches;
@@ -0,0 +1,18 @@
+CREATE TABLE fang_batches (
+     id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+     name VARCHAR NOT NULL,
+     size INT4 NOT NULL,
+     finished_count INT4 NOT NULL DEFAULT 0,
+     failed_count INT4 NOT NULL DEFAULT 0,
+     callback_metadata jsonb NOT NULL,
+     callback_task_type VARCHAR NOT NULL,
+     callback_signature VARCHAR,
+     callback_task_id uuid,
+     created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE fang_tasks ADD COLUMN batch_id uuid;
+CREATE INDEX fang_tasks_batch_id_index ON fang_tasks(batch_id) WHERE batch_id IS NOT NULL;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN batch_id uuid;
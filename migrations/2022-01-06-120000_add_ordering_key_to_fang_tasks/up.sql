@@ -0,0 +1,4 @@
+ALTER TABLE fang_tasks ADD COLUMN ordering_key VARCHAR;
+CREATE INDEX fang_tasks_ordering_key_index ON fang_tasks(ordering_key, created_at) WHERE ordering_key IS NOT NULL;
+
+ALTER TABLE fang_archived_tasks ADD COLUMN ordering_key VARCHAR;
@@ -0,0 +1 @@
+DROP INDEX fang_tasks_fetch_index;
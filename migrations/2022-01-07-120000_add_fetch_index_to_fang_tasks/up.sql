@@ -0,0 +1,3 @@
+-- Fetching tasks of a type is an index range scan in created_at order, however many tasks
+-- of other types or states there are
+CREATE INDEX fang_tasks_fetch_index ON fang_tasks(task_type, state, created_at) WHERE state = 'new';
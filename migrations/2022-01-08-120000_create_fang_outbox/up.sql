@@ -0,0 +1,10 @@
+CREATE TABLE fang_outbox (
+     id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+     metadata jsonb NOT NULL,
+     task_type VARCHAR NOT NULL,
+     task_version INT4 NOT NULL DEFAULT 1,
+     signature VARCHAR,
+     created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX fang_outbox_created_at_index ON fang_outbox(created_at);
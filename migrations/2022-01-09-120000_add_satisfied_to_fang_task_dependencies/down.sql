@@ -0,0 +1,3 @@
+DROP INDEX fang_task_dependencies_depends_on_index;
+
+ALTER TABLE fang_task_dependencies DROP COLUMN satisfied;
@@ -0,0 +1,14 @@
+-- Set by workers when the dependency finishes successfully, so dependencies removed by the
+-- retention mode after failing don't count as finished
+ALTER TABLE fang_task_dependencies ADD COLUMN satisfied BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Dependencies that finished or were removed before this migration count as satisfied, like
+-- they did before
+UPDATE fang_task_dependencies SET satisfied = TRUE
+WHERE NOT EXISTS (SELECT 1 FROM fang_tasks
+                  WHERE fang_tasks.id = fang_task_dependencies.depends_on_id
+                  AND fang_tasks.state <> 'finished');
+
+-- Workers look up the dependencies on every task they finish
+CREATE INDEX fang_task_dependencies_depends_on_index ON fang_task_dependencies(depends_on_id)
+WHERE NOT satisfied;
@@ -0,0 +1,15 @@
+-- The last task enqueued with each uniq key, kept when the retention mode removes the task, so
+-- dedup windows outlast it
+CREATE TABLE fang_uniq_keys (
+     task_type VARCHAR NOT NULL,
+     uniq_key VARCHAR NOT NULL,
+     task_id uuid NOT NULL,
+     enqueued_at TIMESTAMP WITH TIME ZONE NOT NULL,
+     PRIMARY KEY (task_type, uniq_key)
+);
+
+INSERT INTO fang_uniq_keys (task_type, uniq_key, task_id, enqueued_at)
+SELECT DISTINCT ON (task_type, uniq_key) task_type, uniq_key, id, created_at
+FROM fang_tasks
+WHERE uniq_key IS NOT NULL
+ORDER BY task_type, uniq_key, created_at DESC;
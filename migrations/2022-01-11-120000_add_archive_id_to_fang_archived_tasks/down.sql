@@ -0,0 +1,10 @@
+DROP INDEX fang_archived_tasks_id_index;
+
+-- Keeps the last archived row of every task
+DELETE FROM fang_archived_tasks earlier USING fang_archived_tasks later
+WHERE earlier.id = later.id
+  AND (earlier.archived_at, earlier.updated_at, earlier.archive_id)
+    < (later.archived_at, later.updated_at, later.archive_id);
+
+ALTER TABLE fang_archived_tasks DROP COLUMN archive_id;
+ALTER TABLE fang_archived_tasks ADD PRIMARY KEY (id);
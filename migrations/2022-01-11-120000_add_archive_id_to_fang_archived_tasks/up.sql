@@ -0,0 +1,6 @@
+-- A task can be archived more than once, e.g. a task with a deterministic id whose idempotency
+-- key was reused, so every archiving gets a row of its own
+ALTER TABLE fang_archived_tasks DROP CONSTRAINT fang_archived_tasks_pkey;
+ALTER TABLE fang_archived_tasks ADD COLUMN archive_id uuid PRIMARY KEY DEFAULT uuid_generate_v4();
+
+CREATE INDEX fang_archived_tasks_id_index ON fang_archived_tasks(id);
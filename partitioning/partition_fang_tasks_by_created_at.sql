@@ -0,0 +1,70 @@
+-- Turns fang_tasks into a table partitioned by created_at, so old tasks can be removed by
+-- dropping partitions (`Queue::drop_tasks_partitions_before`) instead of bulk DELETEs.
+--
+-- Run it once, after all migrations and in a single transaction:
+--
+--   psql --single-transaction -f partitioning/partition_fang_tasks_by_created_at.sql
+--
+-- The existing table isn't copied, it becomes the partition fang_tasks_legacy for every
+-- task created before tomorrow. Later tasks go to the partitions created with
+-- `Queue::create_tasks_partition`, or to fang_tasks_default if there is none for their
+-- creation time.
+--
+-- Unique constraints of a partitioned table have to include created_at, so:
+--   * the primary key becomes (id, created_at)
+--   * fang_task_dependencies.task_id no longer references fang_tasks, dependencies of
+--     removed tasks stay behind and are ignored
+--   * idempotency keys are no longer enforced by an index, only by
+--     `Queue::push_task_with_idempotency_key`, which also keeps ids derived from the keys
+--     (`Queue::set_deterministic_ids`) unique
+
+LOCK TABLE fang_tasks IN ACCESS EXCLUSIVE MODE;
+
+ALTER TABLE fang_task_dependencies DROP CONSTRAINT fang_task_dependencies_task_id_fkey;
+
+ALTER TABLE fang_tasks RENAME TO fang_tasks_legacy;
+ALTER TABLE fang_tasks_legacy DROP CONSTRAINT fang_tasks_pkey;
+DROP INDEX fang_tasks_idempotency_key_index;
+
+-- Frees the index names for fang_tasks, the indexes are reused by its partitioned indexes
+DO $$
+DECLARE
+    index_name TEXT;
+BEGIN
+    FOR index_name IN SELECT indexname FROM pg_indexes WHERE tablename = 'fang_tasks_legacy' LOOP
+        EXECUTE format(
+            'ALTER INDEX %I RENAME TO %I',
+            index_name,
+            replace(index_name, 'fang_tasks_', 'fang_tasks_legacy_')
+        );
+    END LOOP;
+END;
+$$;
+
+CREATE TABLE fang_tasks (LIKE fang_tasks_legacy INCLUDING DEFAULTS INCLUDING GENERATED)
+PARTITION BY RANGE (created_at);
+
+DO $$
+BEGIN
+    EXECUTE format(
+        'ALTER TABLE fang_tasks ATTACH PARTITION fang_tasks_legacy FOR VALUES FROM (MINVALUE) TO (%L)',
+        date_trunc('day', NOW()) + INTERVAL '1 day'
+    );
+END;
+$$;
+
+CREATE TABLE fang_tasks_default PARTITION OF fang_tasks DEFAULT;
+
+ALTER TABLE fang_tasks ADD PRIMARY KEY (id, created_at);
+
+CREATE INDEX fang_tasks_state_index ON fang_tasks(state);
+CREATE INDEX fang_tasks_type_index ON fang_tasks(task_type);
+CREATE INDEX fang_tasks_created_at_index ON fang_tasks(created_at);
+CREATE INDEX fang_tasks_metadata_gin_index ON fang_tasks USING GIN (metadata jsonb_path_ops);
+CREATE INDEX fang_tasks_metadata_hash_index ON fang_tasks(metadata_hash);
+CREATE INDEX fang_tasks_locked_until_index ON fang_tasks(locked_until) WHERE state = 'in_progress';
+CREATE INDEX fang_tasks_idempotency_key_index ON fang_tasks(idempotency_key);
+CREATE INDEX fang_tasks_uniq_key_index ON fang_tasks(task_type, uniq_key) WHERE uniq_key IS NOT NULL;
+CREATE INDEX fang_tasks_batch_id_index ON fang_tasks(batch_id) WHERE batch_id IS NOT NULL;
+CREATE INDEX fang_tasks_ordering_key_index ON fang_tasks(ordering_key, created_at) WHERE ordering_key IS NOT NULL;
+CREATE INDEX fang_tasks_fetch_index ON fang_tasks(task_type, state, created_at) WHERE state = 'new';
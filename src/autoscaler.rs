@@ -0,0 +1,169 @@
+use crate::claim::ClaimParams;
+use crate::error::FangError;
+use crate::log_level::{fang_log, LogModule};
+use crate::queue::Queue;
+use crate::worker_pool::WorkerPool;
+use crate::worker_pool::WorkerState;
+use log::Level;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct AutoscaleParams {
+    pub min_workers: u32,
+    pub max_workers: u32,
+    /// Pending tasks one worker is expected to keep up with
+    pub tasks_per_worker: u32,
+    pub check_period: Duration,
+    /// Consecutive checks that must ask for fewer workers before the pool shrinks. The pool
+    /// grows on the first check that asks for more.
+    pub scale_down_checks: u32,
+}
+
+impl Default for AutoscaleParams {
+    fn default() -> Self {
+        Self {
+            min_workers: 1,
+            max_workers: 10,
+            tasks_per_worker: 10,
+            check_period: Duration::from_secs(10),
+            scale_down_checks: 6,
+        }
+    }
+}
+
+/// Resizes a worker pool between `min_workers` and `max_workers` based on the number of
+/// pending tasks its workers could claim. Counts come from the pool's maintenance connection.
+/// Pools with per-task-type workers can't be autoscaled, `scale_to` returns an error for them.
+pub struct Autoscaler<D: Clone + Send + 'static> {
+    pub worker_pool: WorkerPool<D>,
+    pub params: AutoscaleParams,
+    below_target_checks: u32,
+}
+
+impl<D: Clone + Send + 'static> Autoscaler<D> {
+    pub fn new(worker_pool: WorkerPool<D>, params: AutoscaleParams) -> Self {
+        Self {
+            worker_pool,
+            params,
+            below_target_checks: 0,
+        }
+    }
+
+    /// Run `autoscale_loop` in a separate thread. It exits once the pool is shut down.
+    pub fn start(self) -> Result<thread::JoinHandle<()>, FangError> {
+        let builder = thread::Builder::new().name("autoscaler".to_string());
+
+        Ok(builder.spawn(move || {
+            let mut autoscaler = self;
+            autoscaler.autoscale_loop();
+        })?)
+    }
+
+    pub fn autoscale_loop(&mut self) {
+        loop {
+            match self.worker_pool.state() {
+                Ok(WorkerState::Shutdown) | Err(_) => return,
+                Ok(_) => {}
+            }
+
+            if let Err(error) = self.autoscale() {
+                fang_log!(
+                    LogModule::WorkerPool,
+                    Level::Error,
+                    "Failed to autoscale the worker pool: {:?}",
+                    error
+                );
+            }
+
+            thread::sleep(self.params.check_period);
+        }
+    }
+
+    /// Check the number of pending tasks once and resize the pool if needed.
+    pub fn autoscale(&mut self) -> Result<(), FangError> {
+        let pending = {
+            let connection = self.worker_pool.maintenance_connection()?;
+            let params = ClaimParams {
+                task_type: &self.worker_pool.worker_params.task_type,
+                excluded_task_types: &[],
+            };
+
+            Queue::count_claimable_tasks_query(&*connection, &params)?
+        };
+
+        let current = self.worker_pool.number_of_workers;
+        let target = self.target_workers(pending, current);
+
+        if target != current {
+            fang_log!(
+                LogModule::WorkerPool,
+                Level::Info,
+                "scaling the worker pool from {} to {} workers, {} pending tasks",
+                current,
+                target,
+                pending
+            );
+
+            self.worker_pool.scale_to(target)?;
+        }
+
+        Ok(())
+    }
+
+    fn target_workers(&mut self, pending: i64, current: u32) -> u32 {
+        let tasks_per_worker = i64::from(self.params.tasks_per_worker.max(1));
+        let wanted = (pending + tasks_per_worker - 1) / tasks_per_worker;
+        let wanted = wanted.clamp(
+            i64::from(self.params.min_workers),
+            i64::from(self.params.max_workers),
+        ) as u32;
+
+        if wanted >= current {
+            self.below_target_checks = 0;
+
+            return wanted;
+        }
+
+        self.below_target_checks += 1;
+
+        if self.below_target_checks < self.params.scale_down_checks {
+            return current;
+        }
+
+        self.below_target_checks = 0;
+
+        wanted
+    }
+}
+
+#[cfg(test)]
+mod autoscaler_tests {
+    use super::AutoscaleParams;
+    use super::Autoscaler;
+    use crate::worker_pool::WorkerPool;
+
+    #[test]
+    fn grows_right_away_and_shrinks_after_consecutive_checks() {
+        let params = AutoscaleParams {
+            min_workers: 1,
+            max_workers: 5,
+            tasks_per_worker: 10,
+            scale_down_checks: 3,
+            ..AutoscaleParams::default()
+        };
+        let mut autoscaler = Autoscaler::new(WorkerPool::<()>::new(1), params);
+
+        assert_eq!(1, autoscaler.target_workers(0, 1));
+        assert_eq!(3, autoscaler.target_workers(21, 1));
+        assert_eq!(5, autoscaler.target_workers(1000, 3));
+
+        assert_eq!(5, autoscaler.target_workers(5, 5));
+        assert_eq!(5, autoscaler.target_workers(5, 5));
+        // A busy check resets the count
+        assert_eq!(5, autoscaler.target_workers(100, 5));
+        assert_eq!(5, autoscaler.target_workers(5, 5));
+        assert_eq!(5, autoscaler.target_workers(5, 5));
+        assert_eq!(1, autoscaler.target_workers(5, 5));
+    }
+}
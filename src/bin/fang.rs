@@ -0,0 +1,75 @@
+use fang::Queue;
+use fang::ScheduleDiff;
+use fang::Schedules;
+use std::env;
+use std::process;
+
+const USAGE: &str = "Usage: fang schedules <apply|diff> <schedules.toml|schedules.yml>
+
+Makes fang_periodic_tasks match the schedule file. `diff` only prints the changes.
+Every schedule needs a task_type, the jobs aren't known here.
+The database is read from FANG_DATABASE_URL or DATABASE_URL.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let (apply, path) = match args.as_slice() {
+        ["schedules", "apply", path] => (true, *path),
+        ["schedules", "diff", path] => (false, *path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    let schedules = match Schedules::from_path(path) {
+        Ok(schedules) => schedules,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    let queue = Queue::new();
+    let result = if apply {
+        queue.apply_schedules(&schedules.schedules)
+    } else {
+        queue
+            .diff_schedules(&schedules.schedules)
+            .map_err(Into::into)
+    };
+
+    match result {
+        Ok(diff) => print_diff(&diff),
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_diff(diff: &ScheduleDiff) {
+    if diff.is_empty() {
+        println!("Periodic tasks are up to date");
+        return;
+    }
+
+    for schedule in &diff.added {
+        println!(
+            "+ {} every {}s",
+            schedule.metadata, schedule.period_in_seconds
+        );
+    }
+
+    for schedule in &diff.updated {
+        println!(
+            "~ {} every {}s",
+            schedule.metadata, schedule.period_in_seconds
+        );
+    }
+
+    for task in &diff.removed {
+        println!("- {} every {}s", task.metadata, task.period_in_seconds);
+    }
+}
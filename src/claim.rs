@@ -0,0 +1,265 @@
+use crate::queue::Queue;
+use crate::queue::Task;
+use chrono::{DateTime, TimeZone, Utc};
+use diesel::pg::PgConnection;
+use diesel::result::Error;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a worker asks for when claiming tasks.
+#[derive(Debug, Clone, Copy)]
+pub struct ClaimParams<'a> {
+    /// Only claim tasks of this type
+    pub task_type: &'a Option<String>,
+    /// Never claim tasks of these types. The executor fills it with the task types its rate
+    /// limiter throttles and the ones paused by a maintenance window.
+    pub excluded_task_types: &'a [String],
+}
+
+/// Decides which tasks a worker claims next.
+///
+/// Implementations must move every returned task into the `InProgress` state (usually with
+/// `UPDATE ... WHERE id IN (SELECT ... FOR UPDATE SKIP LOCKED) RETURNING *`) and return them
+/// in the order they should be executed.
+pub trait ClaimStrategy: Send + Sync {
+    fn claim(
+        &self,
+        connection: &PgConnection,
+        params: &ClaimParams,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error>;
+}
+
+/// Oldest `New` task first, optionally filtered by task type, see `Queue::claim_tasks`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultClaimStrategy;
+
+impl ClaimStrategy for DefaultClaimStrategy {
+    fn claim(
+        &self,
+        connection: &PgConnection,
+        params: &ClaimParams,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error> {
+        Queue::claim_tasks_query(connection, params, limit)
+    }
+}
+
+/// Claims tasks with a lease instead of relying on the `InProgress` state alone: claimed tasks
+/// get `locked_by` set to `worker_id` and `locked_until` to `lease_duration` from now, and
+/// tasks whose lease expired are claimed again. Nothing stays locked while a task runs, so
+/// this works with PgBouncer in transaction pooling mode, and tasks of crashed workers are
+/// picked up without `WorkerPool::shutdown_with_timeout` or a manual reset.
+///
+/// The lease has to outlive the task, otherwise another worker runs it as well. Jobs that
+/// take longer can set their own with `Runnable::lease_duration` or extend it with
+/// `Queue::renew_lease`.
+#[derive(Debug, Clone)]
+pub struct LeaseClaimStrategy {
+    /// Identifies the process, e.g. the host name and pid
+    pub worker_id: String,
+    pub lease_duration: chrono::Duration,
+}
+
+impl LeaseClaimStrategy {
+    pub fn new(worker_id: String, lease_duration: chrono::Duration) -> Self {
+        Self {
+            worker_id,
+            lease_duration,
+        }
+    }
+}
+
+impl ClaimStrategy for LeaseClaimStrategy {
+    fn claim(
+        &self,
+        connection: &PgConnection,
+        params: &ClaimParams,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error> {
+        Queue::claim_tasks_with_lease_query(
+            connection,
+            params,
+            limit,
+            &self.worker_id,
+            self.lease_duration,
+        )
+    }
+}
+
+/// Spreads claims across task types with a smooth weighted round-robin, so a task type that
+/// floods the queue doesn't starve the others for workers without a `task_type`. Every type
+/// has a weight of 1 unless set otherwise. Share one instance between the workers of a pool
+/// (`WorkerParams::set_claim_strategy`) to be fair across the pool.
+#[derive(Debug, Default)]
+pub struct FairClaimStrategy {
+    weights: HashMap<String, u32>,
+    current_weights: Mutex<HashMap<String, i64>>,
+}
+
+impl FairClaimStrategy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `weight` tasks of `task_type` for every task of a type with weight 1, e.g. 3 for
+    /// a type that should get three times the throughput. At least 1.
+    pub fn set_weight(&mut self, task_type: String, weight: u32) {
+        self.weights.insert(task_type, weight.max(1));
+    }
+
+    /// How many of the next `limit` tasks to claim of each type, in the order of the
+    /// first pick.
+    fn plan(&self, task_types: &[String], limit: i64) -> Vec<(String, i64)> {
+        let mut current_weights = self.current_weights.lock().unwrap();
+        current_weights.retain(|task_type, _| task_types.contains(task_type));
+
+        let weight = |task_type: &String| i64::from(*self.weights.get(task_type).unwrap_or(&1));
+        let total_weight: i64 = task_types.iter().map(weight).sum();
+
+        let mut plan: Vec<(String, i64)> = Vec::new();
+
+        for _ in 0..limit {
+            for task_type in task_types {
+                *current_weights.entry(task_type.clone()).or_insert(0) += weight(task_type);
+            }
+
+            let picked = task_types
+                .iter()
+                .max_by_key(|task_type| current_weights[*task_type])
+                .unwrap();
+
+            *current_weights.get_mut(picked).unwrap() -= total_weight;
+
+            match plan.iter_mut().find(|(task_type, _)| task_type == picked) {
+                Some((_, count)) => *count += 1,
+                None => plan.push((picked.clone(), 1)),
+            }
+        }
+
+        plan
+    }
+}
+
+impl ClaimStrategy for FairClaimStrategy {
+    fn claim(
+        &self,
+        connection: &PgConnection,
+        params: &ClaimParams,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error> {
+        if params.task_type.is_some() {
+            return Queue::claim_tasks_query(connection, params, limit);
+        }
+
+        let task_types = Queue::claimable_task_types_query(connection, params)?;
+
+        if task_types.len() < 2 {
+            return Queue::claim_tasks_query(connection, params, limit);
+        }
+
+        let mut tasks = Vec::new();
+
+        for (task_type, count) in self.plan(&task_types, limit) {
+            let task_type = Some(task_type);
+            let params = ClaimParams {
+                task_type: &task_type,
+                excluded_task_types: params.excluded_task_types,
+            };
+
+            tasks.extend(Queue::claim_tasks_query(connection, &params, count)?);
+        }
+
+        Ok(tasks)
+    }
+}
+
+/// Claim counters of one worker, shared with the pool through `WorkerPool::claim_stats`.
+#[derive(Debug, Default)]
+pub struct ClaimMetrics {
+    attempts: AtomicU64,
+    empty_due_to_locks: AtomicU64,
+    latency_in_microseconds: AtomicU64,
+    /// Milliseconds since the epoch, 0 before the first claim
+    last_claim_at: AtomicI64,
+}
+
+/// A snapshot of `ClaimMetrics`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ClaimStats {
+    pub attempts: u64,
+    /// Claims that returned nothing although claimable tasks existed, because other workers
+    /// held their row locks (`SKIP LOCKED`). Tasks inserted right after the claim are counted
+    /// too, so treat it as an estimate.
+    pub empty_due_to_locks: u64,
+    pub average_latency: Duration,
+    pub last_claim_at: Option<DateTime<Utc>>,
+}
+
+impl ClaimMetrics {
+    pub fn record(&self, latency: Duration, empty_due_to_locks: bool) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        self.last_claim_at
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        self.latency_in_microseconds
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+
+        if empty_due_to_locks {
+            self.empty_due_to_locks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> ClaimStats {
+        let attempts = self.attempts.load(Ordering::Relaxed);
+        let latency = self.latency_in_microseconds.load(Ordering::Relaxed);
+
+        ClaimStats {
+            attempts,
+            empty_due_to_locks: self.empty_due_to_locks.load(Ordering::Relaxed),
+            average_latency: Duration::from_micros(latency.checked_div(attempts).unwrap_or(0)),
+            last_claim_at: match self.last_claim_at.load(Ordering::Relaxed) {
+                0 => None,
+                millis => Utc.timestamp_millis_opt(millis).single(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod claim_tests {
+    use super::FairClaimStrategy;
+
+    #[test]
+    fn fair_claim_strategy_spreads_claims_by_weight() {
+        let mut strategy = FairClaimStrategy::new();
+        strategy.set_weight("reports".to_string(), 3);
+
+        let task_types = vec!["emails".to_string(), "reports".to_string()];
+
+        assert_eq!(
+            vec![("reports".to_string(), 3), ("emails".to_string(), 1)],
+            strategy.plan(&task_types, 4)
+        );
+
+        let picks: Vec<String> = (0..8)
+            .map(|_| strategy.plan(&task_types, 1).remove(0).0)
+            .collect();
+
+        assert_eq!(
+            2,
+            picks
+                .iter()
+                .filter(|task_type| *task_type == "emails")
+                .count()
+        );
+        assert_eq!(
+            6,
+            picks
+                .iter()
+                .filter(|task_type| *task_type == "reports")
+                .count()
+        );
+    }
+}
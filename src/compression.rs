@@ -0,0 +1,101 @@
+use crate::runnable::Error;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// Stored in `payload_encoding`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    #[cfg(feature = "runtime")]
+    fn from_encoding(encoding: &str) -> Result<Self, Error> {
+        match encoding {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(Error {
+                description: format!("Unknown payload encoding {}", encoding),
+            }),
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+
+                Ok(encoder.finish()?)
+            }
+            Self::Zstd => Ok(zstd::encode_all(bytes, 0)?),
+        }
+    }
+
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            Self::Gzip => {
+                let mut decompressed = Vec::new();
+                GzDecoder::new(bytes).read_to_end(&mut decompressed)?;
+
+                Ok(decompressed)
+            }
+            Self::Zstd => Ok(zstd::decode_all(bytes)?),
+        }
+    }
+}
+
+/// Compresses jobs whose encoding is at least `threshold_in_bytes` long, see
+/// `Queue::set_compression`. Workers decompress them by the task's `payload_encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    pub threshold_in_bytes: usize,
+}
+
+impl Compression {
+    pub fn new(algorithm: CompressionAlgorithm, threshold_in_bytes: usize) -> Self {
+        Self {
+            algorithm,
+            threshold_in_bytes,
+        }
+    }
+}
+
+/// Decompress a payload stored with `payload_encoding`.
+#[cfg(feature = "runtime")]
+pub(crate) fn decompress(encoding: &str, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    CompressionAlgorithm::from_encoding(encoding)?.decompress(bytes)
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod compression_tests {
+    use super::decompress;
+    use super::CompressionAlgorithm;
+
+    #[test]
+    fn algorithms_round_trip_bytes() {
+        let bytes = "fang ".repeat(1000).into_bytes();
+
+        for algorithm in [CompressionAlgorithm::Gzip, CompressionAlgorithm::Zstd] {
+            let compressed = algorithm.compress(&bytes).unwrap();
+
+            assert!(compressed.len() < bytes.len());
+            assert_eq!(
+                bytes,
+                decompress(algorithm.encoding(), &compressed).unwrap()
+            );
+        }
+
+        assert!(decompress("brotli", &bytes).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// At most `max_in_flight` tasks execute at once across all workers sharing the limit,
+/// whatever the number of worker threads. Useful when tasks start threads or heavy external
+/// processes of their own. Clones share the permits, limits aren't coordinated between
+/// processes.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimit {
+    max_in_flight: u32,
+    /// Number of permits handed out, notified whenever one is returned
+    in_flight: Arc<(Mutex<u32>, Condvar)>,
+}
+
+/// Held while a worker claims and runs tasks, returned to its `ConcurrencyLimit` on drop.
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    in_flight: Arc<(Mutex<u32>, Condvar)>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_in_flight: u32) -> Self {
+        Self {
+            max_in_flight,
+            in_flight: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    pub fn max_in_flight(&self) -> u32 {
+        self.max_in_flight
+    }
+
+    /// Number of permits currently held.
+    pub fn in_flight(&self) -> u32 {
+        *self
+            .in_flight
+            .0
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Wait up to `timeout` for a permit. Returns `None` if none was returned in time.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<ConcurrencyPermit> {
+        let (in_flight, condvar) = &*self.in_flight;
+        let in_flight = in_flight.lock().unwrap_or_else(|error| error.into_inner());
+
+        let (mut in_flight, _) = condvar
+            .wait_timeout_while(in_flight, timeout, |in_flight| {
+                *in_flight >= self.max_in_flight
+            })
+            .unwrap_or_else(|error| error.into_inner());
+
+        if *in_flight >= self.max_in_flight {
+            return None;
+        }
+
+        *in_flight += 1;
+
+        Some(ConcurrencyPermit {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let (in_flight, condvar) = &*self.in_flight;
+        *in_flight.lock().unwrap_or_else(|error| error.into_inner()) -= 1;
+        condvar.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod concurrency_limit_tests {
+    use super::ConcurrencyLimit;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn hands_out_at_most_max_in_flight_permits() {
+        let limit = ConcurrencyLimit::new(2);
+        let shared = limit.clone();
+
+        let permit1 = limit.acquire_timeout(Duration::ZERO).unwrap();
+        let _permit2 = shared.acquire_timeout(Duration::ZERO).unwrap();
+
+        assert_eq!(2, limit.in_flight());
+        assert!(limit.acquire_timeout(Duration::from_millis(50)).is_none());
+
+        let waiting = thread::spawn(move || {
+            let started_at = Instant::now();
+            let permit = shared.acquire_timeout(Duration::from_secs(5));
+
+            (permit.is_some(), started_at.elapsed())
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        drop(permit1);
+
+        let (acquired, waited) = waiting.join().unwrap();
+
+        assert!(acquired);
+        assert!(waited < Duration::from_secs(5));
+        assert_eq!(1, limit.in_flight());
+    }
+}
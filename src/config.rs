@@ -0,0 +1,547 @@
+use crate::diesel::r2d2;
+use crate::diesel::Connection;
+use crate::diesel::PgConnection;
+use crate::error::FangError;
+use crate::runnable::RetentionMode;
+#[cfg(feature = "runtime")]
+use crate::worker_pool::WorkerParams;
+use diesel::connection::SimpleConnection;
+use dotenv::dotenv;
+use std::convert::TryFrom;
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Where the database is and how workers poll it, built in code or read from `FANG_*`
+/// environment variables with `from_env`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FangConfig {
+    pub database_url: String,
+    /// Connections of pools built with `connection_pool`. Worker pools size their pools for
+    /// their workers.
+    pub pool_size: u32,
+    /// Postgres schema of fang's tables, put first on the `search_path` of every connection.
+    /// `public` is searched next, e.g. for `uuid_generate_v4`.
+    pub schema: Option<String>,
+    pub sleep_params: SleepParams,
+    pub retention_mode: RetentionMode,
+}
+
+impl FangConfig {
+    pub fn new(database_url: String) -> Self {
+        Self {
+            database_url,
+            pool_size: 10,
+            schema: None,
+            sleep_params: SleepParams::default(),
+            retention_mode: RetentionMode::RemoveFinished,
+        }
+    }
+
+    /// Reads `.env` and the environment:
+    ///
+    /// - `FANG_DATABASE_URL`, or `DATABASE_URL` if it isn't set
+    /// - `FANG_POOL_SIZE`
+    /// - `FANG_SCHEMA`
+    /// - `FANG_MIN_SLEEP_PERIOD`, `FANG_MAX_SLEEP_PERIOD`, `FANG_SLEEP_STEP` and
+    ///   `FANG_SLEEP_JITTER` in seconds, fractions like `0.5` allowed
+    /// - `FANG_RETENTION_MODE`: `keep_all`, `remove_all`, `remove_finished`, `remove_failed`
+    ///   or `archive_finished`
+    ///
+    /// Unset variables keep the defaults of `new`.
+    pub fn from_env() -> Result<Self, FangError> {
+        dotenv().ok();
+
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, FangError> {
+        let database_url = var("FANG_DATABASE_URL")
+            .or_else(|| var("DATABASE_URL"))
+            .ok_or(FangError::MissingDatabaseUrl)?;
+
+        let mut config = Self::new(database_url);
+
+        if let Some(pool_size) = parse_var(&var, "FANG_POOL_SIZE")? {
+            config.pool_size = pool_size;
+        }
+
+        config.schema = var("FANG_SCHEMA");
+
+        let mut sleep_params = SleepParams::builder();
+
+        if let Some(min_sleep_period) = parse_seconds(&var, "FANG_MIN_SLEEP_PERIOD")? {
+            sleep_params = sleep_params.min_sleep_period(min_sleep_period);
+        }
+
+        if let Some(max_sleep_period) = parse_seconds(&var, "FANG_MAX_SLEEP_PERIOD")? {
+            sleep_params = sleep_params.max_sleep_period(max_sleep_period);
+        }
+
+        if let Some(sleep_step) = parse_seconds(&var, "FANG_SLEEP_STEP")? {
+            sleep_params = sleep_params.sleep_step(sleep_step);
+        }
+
+        if let Some(jitter) = parse_seconds(&var, "FANG_SLEEP_JITTER")? {
+            sleep_params = sleep_params.jitter(jitter);
+        }
+
+        config.sleep_params = sleep_params.build()?;
+
+        if let Some(retention_mode) = var("FANG_RETENTION_MODE") {
+            config.retention_mode = parse_retention_mode(&retention_mode)?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn set_pool_size(&mut self, pool_size: u32) {
+        self.pool_size = pool_size;
+    }
+
+    pub fn set_schema(&mut self, schema: String) {
+        self.schema = Some(schema);
+    }
+
+    pub fn set_sleep_params(&mut self, sleep_params: SleepParams) {
+        self.sleep_params = sleep_params;
+    }
+
+    pub fn set_retention_mode(&mut self, retention_mode: RetentionMode) {
+        self.retention_mode = retention_mode;
+    }
+
+    pub fn establish(&self) -> Result<PgConnection, FangError> {
+        let connection = PgConnection::establish(&self.database_url)?;
+
+        if let Some(ref schema) = self.schema {
+            connection.batch_execute(&search_path(schema))?;
+        }
+
+        Ok(connection)
+    }
+
+    pub fn connection_pool(
+        &self,
+    ) -> Result<r2d2::Pool<r2d2::ConnectionManager<PgConnection>>, FangError> {
+        let pool = self.pool_builder().build(self.connection_manager())?;
+
+        Ok(pool)
+    }
+
+    /// Like `connection_pool`, but connections are only opened when they're needed and closed
+    /// again after being idle for `idle_timeout`, so the pool shrinks when its users go away.
+    /// Returns an error right away if the database can't be reached.
+    pub fn on_demand_connection_pool(
+        &self,
+        idle_timeout: Duration,
+    ) -> Result<r2d2::Pool<r2d2::ConnectionManager<PgConnection>>, FangError> {
+        // Without idle connections to wait for, `build` doesn't connect
+        self.establish()?;
+
+        let pool = self
+            .pool_builder()
+            .min_idle(Some(0))
+            .idle_timeout(Some(idle_timeout))
+            .build(self.connection_manager())?;
+
+        Ok(pool)
+    }
+
+    fn pool_builder(&self) -> r2d2::Builder<r2d2::ConnectionManager<PgConnection>> {
+        r2d2::Pool::builder()
+            .max_size(self.pool_size)
+            .connection_customizer(Box::new(SchemaCustomizer {
+                schema: self.schema.clone(),
+            }))
+    }
+
+    fn connection_manager(&self) -> r2d2::ConnectionManager<PgConnection> {
+        r2d2::ConnectionManager::new(self.database_url.clone())
+    }
+
+    /// `worker_params` with the sleep params and retention mode of the config, unless they
+    /// are set already.
+    #[cfg(feature = "runtime")]
+    pub fn worker_params(&self, mut worker_params: WorkerParams) -> WorkerParams {
+        worker_params
+            .sleep_params
+            .get_or_insert_with(|| self.sleep_params.clone());
+        worker_params
+            .retention_mode
+            .get_or_insert(self.retention_mode);
+
+        worker_params
+    }
+}
+
+/// How long idle workers sleep between polls: `sleep_period` grows by `sleep_step` after
+/// every poll that found nothing, until it reaches `max_sleep_period`, and goes back to
+/// `min_sleep_period` once a task is found. Built with `SleepParams::builder`:
+///
+/// ```ignore
+/// let sleep_params = SleepParams::builder()
+///     .min_sleep_period(Duration::from_millis(500))
+///     .max_sleep_period(Duration::from_secs(10))
+///     .jitter(Duration::from_secs(1))
+///     .build()?;
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SleepParams {
+    sleep_period: Duration,
+    max_sleep_period: Duration,
+    min_sleep_period: Duration,
+    sleep_step: Duration,
+    jitter: Duration,
+}
+
+impl SleepParams {
+    pub fn builder() -> SleepParamsBuilder {
+        SleepParamsBuilder::default()
+    }
+
+    /// The current sleep period, without jitter
+    pub fn sleep_period(&self) -> Duration {
+        self.sleep_period
+    }
+
+    pub fn max_sleep_period(&self) -> Duration {
+        self.max_sleep_period
+    }
+
+    pub fn min_sleep_period(&self) -> Duration {
+        self.min_sleep_period
+    }
+
+    pub fn sleep_step(&self) -> Duration {
+        self.sleep_step
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// The current sleep period plus a random part of the jitter, different for every call.
+    pub fn jittered_sleep_period(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.sleep_period;
+        }
+
+        let jitter = u64::try_from(self.jitter.as_nanos()).unwrap_or(u64::MAX);
+
+        self.sleep_period
+            .saturating_add(Duration::from_nanos(fastrand::u64(0..=jitter)))
+    }
+
+    pub fn maybe_reset_sleep_period(&mut self) {
+        if self.sleep_period != self.min_sleep_period {
+            self.sleep_period = self.min_sleep_period;
+        }
+    }
+
+    pub fn maybe_increase_sleep_period(&mut self) {
+        if self.sleep_period < self.max_sleep_period {
+            self.sleep_period = (self.sleep_period + self.sleep_step).min(self.max_sleep_period);
+        }
+    }
+}
+
+impl Default for SleepParams {
+    fn default() -> Self {
+        SleepParamsBuilder::default().build_unchecked()
+    }
+}
+
+/// Builds `SleepParams`, see `SleepParams::builder`. Defaults to sleeping 5 seconds at first,
+/// up to 15 seconds in steps of 5 seconds, without jitter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SleepParamsBuilder {
+    min_sleep_period: Duration,
+    max_sleep_period: Duration,
+    sleep_step: Duration,
+    jitter: Duration,
+}
+
+impl Default for SleepParamsBuilder {
+    fn default() -> Self {
+        Self {
+            min_sleep_period: Duration::from_secs(5),
+            max_sleep_period: Duration::from_secs(15),
+            sleep_step: Duration::from_secs(5),
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+impl SleepParamsBuilder {
+    /// The first sleep after finding no tasks, and the one after finding tasks again.
+    pub fn min_sleep_period(mut self, min_sleep_period: Duration) -> Self {
+        self.min_sleep_period = min_sleep_period;
+        self
+    }
+
+    pub fn max_sleep_period(mut self, max_sleep_period: Duration) -> Self {
+        self.max_sleep_period = max_sleep_period;
+        self
+    }
+
+    pub fn sleep_step(mut self, sleep_step: Duration) -> Self {
+        self.sleep_step = sleep_step;
+        self
+    }
+
+    /// Add a random duration of up to `jitter` to every sleep, so idle workers started
+    /// together don't all poll the database at the same moments.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Fails with `FangError::InvalidConfig` if `min_sleep_period` is longer than
+    /// `max_sleep_period`.
+    pub fn build(self) -> Result<SleepParams, FangError> {
+        if self.min_sleep_period > self.max_sleep_period {
+            return Err(FangError::InvalidConfig(format!(
+                "min_sleep_period ({:?}) is longer than max_sleep_period ({:?})",
+                self.min_sleep_period, self.max_sleep_period
+            )));
+        }
+
+        Ok(self.build_unchecked())
+    }
+
+    fn build_unchecked(self) -> SleepParams {
+        SleepParams {
+            sleep_period: self.min_sleep_period,
+            max_sleep_period: self.max_sleep_period,
+            min_sleep_period: self.min_sleep_period,
+            sleep_step: self.sleep_step,
+            jitter: self.jitter,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SchemaCustomizer {
+    schema: Option<String>,
+}
+
+impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for SchemaCustomizer {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), r2d2::Error> {
+        match self.schema {
+            Some(ref schema) => connection
+                .batch_execute(&search_path(schema))
+                .map_err(r2d2::Error::QueryError),
+            None => Ok(()),
+        }
+    }
+}
+
+fn search_path(schema: &str) -> String {
+    format!(
+        "SET search_path TO \"{}\", public",
+        schema.replace('"', "\"\"")
+    )
+}
+
+fn parse_var<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>, FangError> {
+    match var(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| FangError::InvalidConfig(format!("{} is not a number: {}", name, value))),
+        None => Ok(None),
+    }
+}
+
+fn parse_seconds(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<Duration>, FangError> {
+    match parse_var::<f64>(var, name)? {
+        Some(seconds) => Duration::try_from_secs_f64(seconds).map(Some).map_err(|_| {
+            FangError::InvalidConfig(format!("{} is not a valid duration: {}", name, seconds))
+        }),
+        None => Ok(None),
+    }
+}
+
+fn parse_retention_mode(value: &str) -> Result<RetentionMode, FangError> {
+    match value {
+        "keep_all" => Ok(RetentionMode::KeepAll),
+        "remove_all" => Ok(RetentionMode::RemoveAll),
+        "remove_finished" => Ok(RetentionMode::RemoveFinished),
+        "remove_failed" => Ok(RetentionMode::RemoveFailed),
+        "archive_finished" => Ok(RetentionMode::ArchiveFinished),
+        _ => Err(FangError::InvalidConfig(format!(
+            "Unknown FANG_RETENTION_MODE: {}",
+            value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::FangConfig;
+    use super::SleepParams;
+    use crate::error::FangError;
+    use crate::runnable::RetentionMode;
+    use crate::schema::fang_tasks;
+    use assert_matches::assert_matches;
+    use diesel::prelude::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<FangConfig, FangError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        FangConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn reads_fang_variables() {
+        let config = from_vars(&[
+            ("DATABASE_URL", "postgres://localhost/app"),
+            ("FANG_DATABASE_URL", "postgres://localhost/fang"),
+            ("FANG_POOL_SIZE", "4"),
+            ("FANG_SCHEMA", "jobs"),
+            ("FANG_MIN_SLEEP_PERIOD", "1"),
+            ("FANG_MAX_SLEEP_PERIOD", "20"),
+            ("FANG_RETENTION_MODE", "keep_all"),
+        ])
+        .unwrap();
+
+        assert_eq!("postgres://localhost/fang", config.database_url);
+        assert_eq!(4, config.pool_size);
+        assert_eq!(Some("jobs".to_string()), config.schema);
+        assert_eq!(Duration::from_secs(1), config.sleep_params.sleep_period());
+        assert_eq!(
+            Duration::from_secs(20),
+            config.sleep_params.max_sleep_period()
+        );
+        assert_eq!(Duration::from_secs(5), config.sleep_params.sleep_step());
+        assert_eq!(RetentionMode::KeepAll, config.retention_mode);
+    }
+
+    #[test]
+    fn rejects_missing_and_invalid_variables() {
+        assert_matches!(from_vars(&[]), Err(FangError::MissingDatabaseUrl));
+
+        assert_eq!(
+            FangConfig::new("postgres://localhost/app".to_string()),
+            from_vars(&[("DATABASE_URL", "postgres://localhost/app")]).unwrap()
+        );
+
+        assert_matches!(
+            from_vars(&[("DATABASE_URL", "postgres://"), ("FANG_POOL_SIZE", "ten")]),
+            Err(FangError::InvalidConfig(_))
+        );
+        assert_matches!(
+            from_vars(&[
+                ("DATABASE_URL", "postgres://"),
+                ("FANG_RETENTION_MODE", "all")
+            ]),
+            Err(FangError::InvalidConfig(_))
+        );
+        assert_matches!(
+            from_vars(&[("DATABASE_URL", "postgres://"), ("FANG_SLEEP_STEP", "-1")]),
+            Err(FangError::InvalidConfig(_))
+        );
+
+        assert_eq!(
+            Duration::from_millis(500),
+            from_vars(&[("DATABASE_URL", "postgres://"), ("FANG_SLEEP_STEP", "0.5")])
+                .unwrap()
+                .sleep_params
+                .sleep_step()
+        );
+    }
+
+    #[test]
+    fn sleep_params_are_validated_and_jittered() {
+        assert_matches!(
+            SleepParams::builder()
+                .min_sleep_period(Duration::from_secs(10))
+                .max_sleep_period(Duration::from_secs(5))
+                .build(),
+            Err(FangError::InvalidConfig(_))
+        );
+        assert_matches!(
+            from_vars(&[
+                ("DATABASE_URL", "postgres://"),
+                ("FANG_MIN_SLEEP_PERIOD", "30")
+            ]),
+            Err(FangError::InvalidConfig(_))
+        );
+
+        let mut sleep_params = SleepParams::builder()
+            .min_sleep_period(Duration::from_millis(100))
+            .max_sleep_period(Duration::from_millis(250))
+            .sleep_step(Duration::from_millis(100))
+            .jitter(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        sleep_params.maybe_increase_sleep_period();
+        sleep_params.maybe_increase_sleep_period();
+        assert_eq!(Duration::from_millis(250), sleep_params.sleep_period());
+
+        for _ in 0..100 {
+            let sleep_period = sleep_params.jittered_sleep_period();
+
+            assert!(sleep_period >= Duration::from_millis(250));
+            assert!(sleep_period <= Duration::from_millis(300));
+        }
+
+        sleep_params.maybe_reset_sleep_period();
+        assert_eq!(Duration::from_millis(100), sleep_params.sleep_period());
+    }
+
+    #[test]
+    fn connections_use_the_schema() {
+        let mut config = FangConfig::from_env().unwrap();
+        config.set_pool_size(1);
+        config.set_schema("fang_config_tests".to_string());
+
+        let connection = config.connection_pool().unwrap().get().unwrap();
+
+        let search_path = diesel::select(diesel::dsl::sql::<diesel::sql_types::Text>(
+            "current_setting('search_path')",
+        ))
+        .get_result::<String>(&*connection)
+        .unwrap();
+
+        assert_eq!("fang_config_tests, public", search_path);
+
+        // The tables are still found in `public`
+        fang_tasks::table
+            .count()
+            .get_result::<i64>(&*connection)
+            .unwrap();
+    }
+
+    #[test]
+    fn on_demand_pools_connect_when_needed() {
+        let mut config = FangConfig::from_env().unwrap();
+        config.set_pool_size(2);
+
+        let pool = config
+            .on_demand_connection_pool(Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(0, pool.state().connections);
+
+        drop(pool.get().unwrap());
+        assert_eq!(1, pool.state().connections);
+
+        let unreachable = FangConfig::new("postgres://fang@127.0.0.1:1/fang".to_string());
+        assert!(unreachable
+            .on_demand_connection_pool(Duration::from_secs(60))
+            .is_err());
+    }
+}
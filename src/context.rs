@@ -0,0 +1,159 @@
+use crate::error::FangError;
+use crate::queue::Queue;
+use crate::runnable::RetentionMode;
+use crate::schema::FangTaskState;
+use diesel::pg::PgConnection;
+use std::any::Any;
+use std::cell::Cell;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum WorkerState {
+    NotStarted,
+    Running,
+    /// Workers are alive but don't claim tasks, see `WorkerPool::pause`
+    Paused,
+    /// Workers finish the tasks they claimed, then don't claim new ones, see `WorkerPool::drain`
+    Draining,
+    Shutdown,
+}
+
+impl WorkerState {
+    /// Returns true if the worker threads are alive: the pool is running, paused or draining.
+    pub fn is_started(&self) -> bool {
+        matches!(
+            self,
+            WorkerState::Running | WorkerState::Paused | WorkerState::Draining
+        )
+    }
+}
+
+pub type SharedState = Arc<RwLock<WorkerState>>;
+
+/// Why a running task was asked to stop.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Cancellation {
+    /// The task was cancelled with `Queue::cancel_task`
+    TaskCancelled,
+    /// The worker pool is shutting down
+    Shutdown,
+}
+
+/// Passed into `Runnable::run`. Lets a task check whether it should stop early and tell the
+/// executor how to treat a failure.
+pub struct TaskContext<'a> {
+    task_id: Uuid,
+    shared_state: Option<&'a SharedState>,
+    cancellation: Cell<Option<Cancellation>>,
+    held: Cell<bool>,
+    snoozed_for: Cell<Option<Duration>>,
+    #[cfg_attr(not(feature = "runtime"), allow(dead_code))]
+    retention_mode: Cell<Option<RetentionMode>>,
+    worker_data: Option<&'a (dyn Any + Send)>,
+}
+
+impl<'a> TaskContext<'a> {
+    pub fn new(task_id: Uuid, shared_state: Option<&'a SharedState>) -> Self {
+        Self {
+            task_id,
+            shared_state,
+            cancellation: Cell::new(None),
+            held: Cell::new(false),
+            snoozed_for: Cell::new(None),
+            retention_mode: Cell::new(None),
+            worker_data: None,
+        }
+    }
+
+    pub fn with_worker_data(mut self, worker_data: &'a (dyn Any + Send)) -> Self {
+        self.worker_data = Some(worker_data);
+        self
+    }
+
+    /// The `worker_data` the worker pool was created with, e.g. API clients or configuration.
+    /// `None` if there is none or it isn't a `D`.
+    pub fn worker_data<D: 'static>(&self) -> Option<&'a D> {
+        self.worker_data
+            .and_then(|worker_data| worker_data.downcast_ref::<D>())
+    }
+
+    pub fn task_id(&self) -> Uuid {
+        self.task_id
+    }
+
+    /// Returns true if the worker pool is shutting down. Doesn't touch the database.
+    pub fn is_shutting_down(&self) -> bool {
+        let shutting_down = match self.shared_state {
+            Some(shared_state) => match shared_state.read() {
+                Ok(state) => *state == WorkerState::Shutdown,
+                Err(_) => true,
+            },
+            None => false,
+        };
+
+        if shutting_down {
+            self.cancellation.set(Some(Cancellation::Shutdown));
+        }
+
+        shutting_down
+    }
+
+    /// Returns true if the worker pool is shutting down or the task was cancelled or removed.
+    /// The latter is read from the task row, so don't call it in a tight loop. Database errors
+    /// are returned, they don't count as a cancellation.
+    pub fn is_cancelled(&self, connection: &PgConnection) -> Result<bool, FangError> {
+        if self.is_shutting_down() {
+            return Ok(true);
+        }
+
+        let cancelled = match Queue::task_state_query(connection, self.task_id)? {
+            Some(state) => state == FangTaskState::Cancelled,
+            None => true,
+        };
+
+        if cancelled {
+            self.cancellation.set(Some(Cancellation::TaskCancelled));
+        }
+
+        Ok(cancelled)
+    }
+
+    /// If the task then returns an error, put it into the `Held` state instead of failing it.
+    /// Held tasks aren't fetched until an operator calls `Queue::release_task` or
+    /// `Queue::cancel_task`.
+    pub fn hold(&self) {
+        self.held.set(true);
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.get()
+    }
+
+    /// If the task then returns an error, put it back into the queue to be fetched again after
+    /// `duration` instead of failing it. Used for failed `Runnable::preflight` checks.
+    pub fn snooze(&self, duration: Duration) {
+        self.snoozed_for.set(Some(duration));
+    }
+
+    pub fn snoozed_for(&self) -> Option<Duration> {
+        self.snoozed_for.get()
+    }
+
+    /// Set from `Runnable::retention`, overrides the retention mode of the worker.
+    #[cfg(feature = "runtime")]
+    pub(crate) fn set_retention_mode(&self, retention_mode: Option<RetentionMode>) {
+        self.retention_mode.set(retention_mode);
+    }
+
+    #[cfg(feature = "runtime")]
+    pub(crate) fn retention_mode(&self) -> Option<RetentionMode> {
+        self.retention_mode.get()
+    }
+
+    /// The cancellation the task observed, if any.
+    pub fn cancellation(&self) -> Option<Cancellation> {
+        self.cancellation.get()
+    }
+}
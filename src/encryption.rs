@@ -0,0 +1,368 @@
+use crate::runnable::Error;
+use crate::runnable::Runnable;
+use crate::serializer::TaskSerializer;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ENVELOPE_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+/// Derives the digest keys of `StaticKeyProvider` from its keys
+const DIGEST_KEY_LABEL: &[u8] = b"fang job digest";
+
+/// Wraps and unwraps the data keys of `EncryptedSerializer`, e.g. with a KMS. Every payload
+/// is encrypted with its own random data key, only the wrapped data key is stored.
+pub trait KeyProvider: Send + Sync {
+    /// Wrap `data_key` with the current key encryption key. Returns the id of that key,
+    /// which is passed back to `unwrap_key`, so keys can be rotated.
+    fn wrap_key(&self, data_key: &[u8]) -> Result<(String, Vec<u8>), Error>;
+
+    fn unwrap_key(&self, key_id: &str, wrapped_key: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// The HMAC key for the digests of jobs whose data key was wrapped with `key_id`, see
+    /// `TaskSerializer::serialize_with_digest`. A keyed digest doesn't let anyone with access
+    /// to the database guess the plaintext of a job. Has to stay the same for a key id.
+    fn digest_key(&self, key_id: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Wraps data keys with AES-256-GCM keys held in memory. Payloads encrypted with an older
+/// key can still be decrypted as long as it's added.
+#[derive(Clone)]
+pub struct StaticKeyProvider {
+    current_key_id: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl StaticKeyProvider {
+    pub fn new(key_id: String, key: [u8; 32]) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key_id.clone(), key);
+
+        Self {
+            current_key_id: key_id,
+            keys,
+        }
+    }
+
+    /// Keep decrypting payloads encrypted with `key` after a rotation.
+    pub fn add_key(&mut self, key_id: String, key: [u8; 32]) {
+        self.keys.insert(key_id, key);
+    }
+
+    fn key(&self, key_id: &str) -> Result<&[u8; 32], Error> {
+        self.keys.get(key_id).ok_or_else(|| Error {
+            description: format!("Unknown key {}", key_id),
+        })
+    }
+}
+
+impl KeyProvider for StaticKeyProvider {
+    fn wrap_key(&self, data_key: &[u8]) -> Result<(String, Vec<u8>), Error> {
+        let key = self.key(&self.current_key_id)?;
+
+        Ok((self.current_key_id.clone(), encrypt(key, data_key)?))
+    }
+
+    fn unwrap_key(&self, key_id: &str, wrapped_key: &[u8]) -> Result<Vec<u8>, Error> {
+        decrypt(self.key(key_id)?, wrapped_key)
+    }
+
+    fn digest_key(&self, key_id: &str) -> Result<Vec<u8>, Error> {
+        Ok(hmac(self.key(key_id)?, DIGEST_KEY_LABEL)?.to_vec())
+    }
+}
+
+/// Encrypts the payloads of another serializer with AES-256-GCM (envelope encryption), so
+/// personal data in jobs isn't stored in plaintext. Workers registering the same
+/// serializer with `WorkerParams::add_serializer` decrypt them transparently.
+///
+/// Only the payload is encrypted, the job's type and task type stay readable. The `metadata`
+/// holds an HMAC of the unencrypted payload instead of the SHA-256 of the encrypted one,
+/// keyed by `KeyProvider::digest_key`, so identical jobs are deduplicated as long as their
+/// data keys are wrapped with the same key.
+pub struct EncryptedSerializer {
+    inner: Arc<dyn TaskSerializer>,
+    key_provider: Arc<dyn KeyProvider>,
+}
+
+impl EncryptedSerializer {
+    pub fn new(inner: Arc<dyn TaskSerializer>, key_provider: Arc<dyn KeyProvider>) -> Self {
+        Self {
+            inner,
+            key_provider,
+        }
+    }
+}
+
+impl TaskSerializer for EncryptedSerializer {
+    fn format(&self) -> &'static str {
+        "encrypted"
+    }
+
+    fn serialize(&self, job: &dyn Runnable) -> Result<Vec<u8>, Error> {
+        let (envelope, _) = self.seal(&self.inner.serialize(job)?)?;
+
+        Ok(envelope)
+    }
+
+    fn deserialize(&self, envelope: &[u8]) -> Result<Box<dyn Runnable>, Error> {
+        let (_, payload) = self.open(envelope)?;
+
+        self.inner.deserialize(&payload)
+    }
+
+    fn serialize_with_digest(
+        &self,
+        job: &dyn Runnable,
+    ) -> Result<(Vec<u8>, Option<String>), Error> {
+        let payload = self.inner.serialize(job)?;
+        let (envelope, key_id) = self.seal(&payload)?;
+
+        Ok((envelope, Some(self.digest(&key_id, &payload)?)))
+    }
+
+    fn deserialize_with_digest(
+        &self,
+        envelope: &[u8],
+    ) -> Result<(Box<dyn Runnable>, Option<String>), Error> {
+        let (key_id, payload) = self.open(envelope)?;
+        let digest = self.digest(&key_id, &payload)?;
+
+        Ok((self.inner.deserialize(&payload)?, Some(digest)))
+    }
+}
+
+impl EncryptedSerializer {
+    /// Encrypts `payload` with a new data key. Returns the envelope and the id of the key the
+    /// data key was wrapped with.
+    fn seal(&self, payload: &[u8]) -> Result<(Vec<u8>, String), Error> {
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let (key_id, wrapped_key) = self.key_provider.wrap_key(&data_key)?;
+
+        // The version, the key id and the wrapped data key prefixed with their lengths (u16,
+        // big endian), and the encrypted payload
+        let mut envelope = vec![ENVELOPE_VERSION];
+        push_field(&mut envelope, key_id.as_bytes())?;
+        push_field(&mut envelope, &wrapped_key)?;
+        envelope.extend(encrypt(data_key.as_ref(), payload)?);
+
+        Ok((envelope, key_id))
+    }
+
+    /// Returns the key id and the decrypted payload.
+    fn open(&self, envelope: &[u8]) -> Result<(String, Vec<u8>), Error> {
+        let (version, rest) = envelope.split_first().ok_or_else(invalid_envelope)?;
+
+        if *version != ENVELOPE_VERSION {
+            return Err(Error {
+                description: format!("Unsupported envelope version {}", version),
+            });
+        }
+
+        let (key_id, rest) = take_field(rest)?;
+        let (wrapped_key, ciphertext) = take_field(rest)?;
+
+        let key_id = std::str::from_utf8(key_id)?;
+        let data_key = self.key_provider.unwrap_key(key_id, wrapped_key)?;
+        let data_key = <[u8; 32]>::try_from(data_key.as_slice()).map_err(|_| invalid_envelope())?;
+
+        Ok((key_id.to_string(), decrypt(&data_key, ciphertext)?))
+    }
+
+    fn digest(&self, key_id: &str, payload: &[u8]) -> Result<String, Error> {
+        let digest_key = self.key_provider.digest_key(key_id)?;
+
+        Ok(hex::encode(hmac(&digest_key, payload)?))
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Result<[u8; 32], Error> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).map_err(|_| Error {
+        description: "Invalid digest key".to_string(),
+    })?;
+    mac.update(message);
+
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// The random nonce followed by the ciphertext.
+fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher.encrypt(&nonce, plaintext).map_err(|_| Error {
+        description: "Failed to encrypt the payload".to_string(),
+    })?;
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(ciphertext);
+
+    Ok(encrypted)
+}
+
+fn decrypt(key: &[u8; 32], encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+    if encrypted.len() < NONCE_LENGTH {
+        return Err(invalid_envelope());
+    }
+
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error {
+            description: "Failed to decrypt the payload, wrong key or tampered payload".to_string(),
+        })
+}
+
+fn push_field(envelope: &mut Vec<u8>, field: &[u8]) -> Result<(), Error> {
+    let length = u16::try_from(field.len())?;
+
+    envelope.extend(length.to_be_bytes());
+    envelope.extend(field);
+
+    Ok(())
+}
+
+fn take_field(envelope: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if envelope.len() < 2 {
+        return Err(invalid_envelope());
+    }
+
+    let (length, rest) = envelope.split_at(2);
+    let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+
+    if rest.len() < length {
+        return Err(invalid_envelope());
+    }
+
+    Ok(rest.split_at(length))
+}
+
+fn invalid_envelope() -> Error {
+    Error {
+        description: "Invalid encrypted payload".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod encryption_tests {
+    use super::{EncryptedSerializer, StaticKeyProvider};
+    use crate::context::TaskContext;
+    use crate::queue::Queue;
+    use crate::runnable::{Error, Runnable};
+    use crate::serializer::{MessagePackSerializer, TaskSerializer};
+    use crate::typetag;
+    use diesel::connection::Connection;
+    use diesel::pg::PgConnection;
+    use diesel::result::Error as DieselError;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+
+    #[derive(Serialize, Deserialize)]
+    struct EncryptedJob {
+        pub email: String,
+    }
+
+    #[typetag::serde]
+    impl Runnable for EncryptedJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            self.email.clone()
+        }
+    }
+
+    fn serializer(key_provider: StaticKeyProvider) -> EncryptedSerializer {
+        EncryptedSerializer::new(Arc::new(MessagePackSerializer), Arc::new(key_provider))
+    }
+
+    #[test]
+    fn encrypts_payloads() {
+        let job = EncryptedJob {
+            email: "user@example.com".to_string(),
+        };
+
+        let serializer = serializer(StaticKeyProvider::new("2021".to_string(), [1; 32]));
+        let envelope = serializer.serialize(&job).unwrap();
+
+        assert!(!envelope
+            .windows(job.email.len())
+            .any(|window| window == job.email.as_bytes()));
+
+        let deserialized = serializer.deserialize(&envelope).unwrap();
+        assert_eq!("user@example.com", deserialized.task_type());
+
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(serializer.deserialize(&tampered).is_err());
+
+        let mut rotated = StaticKeyProvider::new("2022".to_string(), [2; 32]);
+        assert!(self::serializer(rotated.clone())
+            .deserialize(&envelope)
+            .is_err());
+
+        rotated.add_key("2021".to_string(), [1; 32]);
+        assert!(self::serializer(rotated).deserialize(&envelope).is_ok());
+    }
+
+    #[test]
+    fn identical_jobs_get_the_same_digest() {
+        let job = EncryptedJob {
+            email: "user@example.com".to_string(),
+        };
+
+        let serializer = serializer(StaticKeyProvider::new("2021".to_string(), [1; 32]));
+        let (first, first_digest) = serializer.serialize_with_digest(&job).unwrap();
+        let (second, second_digest) = serializer.serialize_with_digest(&job).unwrap();
+
+        assert_ne!(first, second);
+        assert!(first_digest.is_some());
+        assert_eq!(first_digest, second_digest);
+
+        let (_, digest) = serializer.deserialize_with_digest(&second).unwrap();
+        assert_eq!(first_digest, digest);
+
+        let rotated = self::serializer(StaticKeyProvider::new("2022".to_string(), [2; 32]));
+        let (_, rotated_digest) = rotated.serialize_with_digest(&job).unwrap();
+        assert_ne!(first_digest, rotated_digest);
+    }
+
+    #[test]
+    fn deduplicates_encrypted_jobs() {
+        let mut queue = Queue::new();
+        queue.set_serializer(Arc::new(serializer(StaticKeyProvider::new(
+            "2021".to_string(),
+            [1; 32],
+        ))));
+
+        queue.connection.test_transaction::<(), DieselError, _>(|| {
+            let job = EncryptedJob {
+                email: "dedup@example.com".to_string(),
+            };
+
+            let first = queue.push_task(&job).unwrap();
+            let second = queue.push_task(&job).unwrap();
+
+            assert_eq!(first.id, second.id);
+
+            let other = queue
+                .push_task(&EncryptedJob {
+                    email: "other@example.com".to_string(),
+                })
+                .unwrap();
+
+            assert_ne!(first.id, other.id);
+
+            Ok(())
+        });
+    }
+}
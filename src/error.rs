@@ -1,7 +1,7 @@
+use crate::runnable::TaskError;
 use std::io::Error as IoError;
 use std::sync::PoisonError;
 use thiserror::Error;
-use crate::executor::TaskError;
 
 #[derive(Error, Debug)]
 pub enum FangError {
@@ -11,8 +11,66 @@ pub enum FangError {
     #[error("Database error: {0:?}")]
     DbError(#[from] diesel::result::Error),
 
+    #[error("Connection pool error: {0:?}")]
+    PoolError(#[from] diesel::r2d2::PoolError),
+
+    #[error("FANG_DATABASE_URL or DATABASE_URL must be set")]
+    MissingDatabaseUrl,
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Error connecting to the database: {0}")]
+    ConnectionError(#[from] diesel::ConnectionError),
+
+    #[error("Failed to serialize the job: {0}")]
+    SerializationError(#[from] serde_json::Error),
+
+    #[error("Failed to serialize the job payload: {0}")]
+    PayloadSerializationError(String),
+
     #[error("Task execution error: {0:?}")]
-    TaskError(TaskError),
+    TaskError(Box<TaskError>),
+
+    #[error("The worker pool is already running")]
+    AlreadyRunning,
+
+    #[error("The worker pool is already shut down")]
+    AlreadyShutdown,
+
+    #[error("The worker pool isn't running")]
+    NotRunning,
+
+    #[error("The connection isn't in a transaction")]
+    NotInTransaction,
+
+    #[error("Timed out waiting for the task")]
+    WaitTimeout,
+
+    #[error("The worker pool has per-task-type workers, use scale_task_type_to")]
+    PerTaskTypeWorkers,
+
+    #[error("Invalid schedules: {0}")]
+    InvalidSchedules(String),
+
+    #[error("Failed to install the signal handler: {0}")]
+    SignalHandlerError(IoError),
+
+    #[error("Failed to restart worker '{name}': {source}")]
+    WorkerRestartFailed {
+        name: String,
+        #[source]
+        source: Box<FangError>,
+    },
+
+    #[error("Lost the database connection: {source}")]
+    ConnectionLost {
+        #[source]
+        source: Box<FangError>,
+    },
+
+    #[error("Worker '{name}' crashed after {restarts} restarts and won't be restarted")]
+    WorkerDead { name: String, restarts: u64 },
 
     #[error("Failed to create executor thread")]
     ExecutorThreadCreationFailed {
@@ -29,6 +87,6 @@ impl<T> From<PoisonError<T>> for FangError {
 
 impl From<TaskError> for FangError {
     fn from(x: TaskError) -> Self {
-        Self::TaskError(x)
+        Self::TaskError(Box::new(x))
     }
 }
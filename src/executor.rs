@@ -1,108 +1,246 @@
+use crate::claim::ClaimMetrics;
+use crate::claim::ClaimParams;
+use crate::claim::ClaimStrategy;
+use crate::claim::DefaultClaimStrategy;
+use crate::compression::decompress;
+use crate::concurrency_limit::ConcurrencyLimit;
+pub use crate::config::SleepParams;
+use crate::context::Cancellation;
+use crate::context::TaskContext;
+use crate::context::{SharedState, WorkerState};
 use crate::error::FangError;
+use crate::log_level::{fang_log, LogModule};
+use crate::maintenance;
+use crate::maintenance::MaintenanceWindow;
+use crate::metrics::WorkerMetrics;
+use crate::middleware::Next;
+use crate::middleware::TaskMiddleware;
+use crate::payload_store::PayloadStore;
 use crate::queue::Queue;
 use crate::queue::Task;
-use crate::worker_pool::{SharedState, WorkerState};
+use crate::rate_limit::RateLimiter;
+pub use crate::runnable::{Error, Perform, PreflightError, RetentionMode, Runnable, TaskError};
+use crate::schema::FangTaskState;
+use crate::serializer::default_serializers;
+use crate::serializer::job_digest_matches;
+use crate::serializer::payload_matches;
+use crate::serializer::JsonSerializer;
+use crate::serializer::TaskSerializer;
+use crate::signing::SigningKey;
+use crate::versioning::upgrade;
+use crate::versioning::TaskUpgrader;
+use crate::worker_pool::{emit, EventSenders, WorkerPoolEvent};
+use crate::worker_pool::{CurrentTask, InFlightTasks, RunningTask, SharedParams, StopSignal};
+use chrono::DateTime;
+use chrono::Utc;
 use diesel::pg::PgConnection;
-use log::error;
+use diesel::Connection;
+use log::Level;
+use serde::Deserialize;
+use std::any::Any;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ops::Deref;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use std::ops::Deref;
+use std::time::Instant;
+use uuid::Uuid;
 
 pub struct Executor<Conn>
 where
-    Conn: Deref<Target=PgConnection>,
+    Conn: Deref<Target = PgConnection>,
 {
     pub pooled_connection: Conn,
+    /// Jobs run with this connection if set, see `set_job_connection`
+    pub job_connection: Option<Conn>,
     pub task_type: Option<String>,
     pub sleep_params: SleepParams,
     pub retention_mode: RetentionMode,
+    pub fast_path_batch_size: Option<u32>,
+    pub deferred_finalization: Option<DeferredFinalization>,
+    pub claim_strategy: Arc<dyn ClaimStrategy>,
+    pub middlewares: Vec<Arc<dyn TaskMiddleware>>,
+    /// Decode tasks with a `payload`, see `Queue::set_serializer`
+    pub serializers: Vec<Arc<dyn TaskSerializer>>,
+    /// Fetch payloads kept outside the database, see `Queue::set_payload_store`
+    pub payload_store: Option<Arc<dyn PayloadStore>>,
+    pub upgraders: Vec<Arc<dyn TaskUpgrader>>,
+    pub signing_key: Option<SigningKey>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+    pub allowed_job_names: Option<Vec<String>>,
+    pub check_maintenance_windows: bool,
+    pub reconnect_policy: ReconnectPolicy,
+    /// Tasks running longer are logged as slow, see `set_warn_after`
+    pub warn_after: Option<Duration>,
+    pub slow_task_listeners: Vec<Arc<dyn SlowTaskListener>>,
+    connection_source: Option<ConnectionSource<Conn>>,
     shared_state: Option<SharedState>,
+    stop_signal: Option<StopSignal>,
+    in_flight_tasks: Option<InFlightTasks>,
+    current_task: Option<CurrentTask>,
+    claim_metrics: Option<Arc<ClaimMetrics>>,
+    shared_params: Option<SharedParams>,
+    /// Number of the last update of `shared_params` applied
+    params_version: Option<u64>,
+    worker_metrics: Option<Arc<WorkerMetrics>>,
+    event_senders: Option<EventSenders>,
+    worker_data: Option<Box<dyn Any + Send>>,
+    finished_buffer: RefCell<FinishedBuffer>,
+    maintenance_windows: RefCell<MaintenanceWindowsCache>,
 }
 
-#[derive(Clone)]
-pub enum RetentionMode {
-    KeepAll,
-    RemoveAll,
-    RemoveFinished,
+/// Told about every task that ran longer than `Executor::set_warn_after`, e.g. to count slow
+/// tasks in a metrics system.
+pub trait SlowTaskListener: Send + Sync {
+    /// `task` has its `duration_in_milliseconds` set. Called after the task returned, before
+    /// it's finalized.
+    fn on_slow_task(&self, task: &Task, duration: Duration);
+}
+
+/// Where the executor gets new connections from after losing one, see
+/// `Executor::set_connection_source`.
+pub type ConnectionSource<Conn> = Box<dyn Fn() -> Result<Conn, FangError>>;
+
+/// How often a paused or draining executor checks whether it may claim tasks again.
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the executor waits for a permit of its concurrency limit before checking whether
+/// it should stop.
+const PERMIT_WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long the executor keeps maintenance windows before reading them again.
+const MAINTENANCE_WINDOWS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct MaintenanceWindowsCache {
+    windows: Vec<MaintenanceWindow>,
+    loaded_at: Option<Instant>,
 }
 
+/// Buffer successfully finished tasks and finalize them with one statement once `max_tasks`
+/// are buffered or the oldest one has waited for `max_delay`. The statement depends on the
+/// retention mode: `KeepAll` marks them finished, the other modes remove them.
+///
+/// The buffer is also flushed when a task fails, when the worker is idle and on shutdown.
+/// Until then, buffered tasks stay `InProgress`.
 #[derive(Clone)]
-pub struct SleepParams {
-    pub sleep_period: u64,
-    pub max_sleep_period: u64,
-    pub min_sleep_period: u64,
-    pub sleep_step: u64,
+pub struct DeferredFinalization {
+    pub max_tasks: usize,
+    pub max_delay: Duration,
 }
 
-impl SleepParams {
-    pub fn maybe_reset_sleep_period(&mut self) {
-        if self.sleep_period != self.min_sleep_period {
-            self.sleep_period = self.min_sleep_period;
+#[derive(Default)]
+struct FinishedBuffer {
+    ids: Vec<Uuid>,
+    since: Option<Instant>,
+}
+
+impl FinishedBuffer {
+    fn extend(&mut self, ids: Vec<Uuid>) {
+        if self.since.is_none() && !ids.is_empty() {
+            self.since = Some(Instant::now());
         }
+
+        self.ids.extend(ids);
     }
 
-    pub fn maybe_increase_sleep_period(&mut self) {
-        if self.sleep_period < self.max_sleep_period {
-            self.sleep_period += self.sleep_step;
+    fn is_due(&self, params: &DeferredFinalization) -> bool {
+        match self.since {
+            Some(since) => {
+                self.ids.len() >= params.max_tasks || since.elapsed() >= params.max_delay
+            }
+            None => false,
         }
     }
-}
 
-impl Default for SleepParams {
-    fn default() -> Self {
-        SleepParams {
-            sleep_period: 5,
-            max_sleep_period: 15,
-            min_sleep_period: 5,
-            sleep_step: 5,
-        }
+    fn take(&mut self) -> Vec<Uuid> {
+        self.since = None;
+
+        std::mem::take(&mut self.ids)
     }
 }
 
-#[derive(Debug)]
-pub struct Error {
-    pub description: String,
+/// How a worker gets a new connection after losing its connection, e.g. because Postgres
+/// restarted. The delay before an attempt starts at `initial_delay` and doubles with every
+/// attempt up to `max_delay`. After `max_attempts` failed attempts `run_tasks` returns
+/// `FangError::ConnectionLost`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f,"{}", self.description)
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_attempts: 10,
+        }
     }
 }
 
-impl std::error::Error for Error { }
-
-#[derive(Debug)]
-pub struct TaskError(Task, Error);
-
-impl std::fmt::Display for TaskError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f,"{}; {:?}", self.1, self.0)
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
     }
 }
 
-impl std::error::Error for TaskError { }
-
-#[typetag::serde(tag = "type")]
-pub trait Runnable {
-    fn run(&self, connection: &PgConnection) -> Result<(), Error>;
-
-    fn task_type(&self) -> String {
-        "common".to_string()
+fn deserialization_error(error: impl std::fmt::Display) -> Error {
+    Error {
+        description: format!(
+            "Failed to deserialize the task, deserialization error: {}",
+            error
+        ),
     }
 }
 
 impl<Conn> Executor<Conn>
 where
-    Conn: Deref<Target=PgConnection>
+    Conn: Deref<Target = PgConnection>,
 {
     pub fn new(pooled_connection: Conn) -> Self {
         Self {
             pooled_connection,
+            job_connection: None,
             sleep_params: SleepParams::default(),
             retention_mode: RetentionMode::RemoveFinished,
             task_type: None,
+            fast_path_batch_size: None,
+            deferred_finalization: None,
+            claim_strategy: Arc::new(DefaultClaimStrategy),
+            middlewares: Vec::new(),
+            serializers: default_serializers(),
+            payload_store: None,
+            upgraders: Vec::new(),
+            signing_key: None,
+            rate_limiter: None,
+            concurrency_limit: None,
+            allowed_job_names: None,
+            check_maintenance_windows: false,
+            reconnect_policy: ReconnectPolicy::default(),
+            warn_after: None,
+            slow_task_listeners: Vec::new(),
+            connection_source: None,
             shared_state: None,
+            stop_signal: None,
+            in_flight_tasks: None,
+            current_task: None,
+            claim_metrics: None,
+            shared_params: None,
+            params_version: None,
+            worker_metrics: None,
+            event_senders: None,
+            worker_data: None,
+            finished_buffer: RefCell::new(FinishedBuffer::default()),
+            maintenance_windows: RefCell::new(MaintenanceWindowsCache::default()),
         }
     }
 
@@ -110,6 +248,58 @@ where
         self.shared_state = Some(shared_state);
     }
 
+    /// Exit `run_tasks` before claiming the next task once the signal is set.
+    pub fn set_stop_signal(&mut self, stop_signal: StopSignal) {
+        self.stop_signal = Some(stop_signal);
+    }
+
+    /// Keep the ids of the claimed tasks that are still running in `in_flight_tasks`.
+    pub fn set_in_flight_tasks(&mut self, in_flight_tasks: InFlightTasks) {
+        self.in_flight_tasks = Some(in_flight_tasks);
+    }
+
+    /// Keep the task that is executing in `current_task`, without the name of the worker.
+    pub fn set_current_task(&mut self, current_task: CurrentTask) {
+        self.current_task = Some(current_task);
+    }
+
+    /// Record claim attempts and latency. Every empty claim is followed by a query checking
+    /// whether it was caused by rows locked by other workers.
+    pub fn set_claim_metrics(&mut self, claim_metrics: Arc<ClaimMetrics>) {
+        self.claim_metrics = Some(claim_metrics);
+    }
+
+    /// Take the retention mode, sleep params and task type from `shared_params`, and
+    /// again whenever they are updated, see `WorkerPool::update_params`.
+    pub fn set_shared_params(&mut self, shared_params: SharedParams) {
+        self.shared_params = Some(shared_params);
+        self.params_version = None;
+    }
+
+    /// Count processed, failed and retried tasks and record when the last one finished.
+    pub fn set_worker_metrics(&mut self, worker_metrics: Arc<WorkerMetrics>) {
+        self.worker_metrics = Some(worker_metrics);
+    }
+
+    /// Send a `WorkerPoolEvent::TaskCompleted` to the subscribers of the pool for every task
+    /// that finishes successfully.
+    pub fn set_event_senders(&mut self, event_senders: EventSenders) {
+        self.event_senders = Some(event_senders);
+    }
+
+    /// Run jobs and their hooks with a connection of their own. `pooled_connection` then only
+    /// claims and finalizes tasks, so a job's transactions and locks (including ones it leaves
+    /// open after an error) never touch the connection the queue bookkeeping runs on. Claims
+    /// are committed before the job starts either way.
+    pub fn set_job_connection(&mut self, job_connection: Conn) {
+        self.job_connection = Some(job_connection);
+    }
+
+    /// Handed to tasks through `TaskContext::worker_data`.
+    pub fn set_worker_data(&mut self, worker_data: Box<dyn Any + Send>) {
+        self.worker_data = Some(worker_data);
+    }
+
     pub fn set_task_type(&mut self, task_type: String) {
         self.task_type = Some(task_type);
     }
@@ -122,271 +312,1857 @@ where
         self.retention_mode = retention_mode;
     }
 
-    pub fn run(&self, task: Task) -> Result<Task, TaskError> {
-        let result = self.execute_task(task);
-        self.finalize_task(&result);
-        result
+    /// Enable the fast path for tiny tasks: claim up to `batch_size` tasks with one query and
+    /// finalize all successful ones with one statement after the batch.
+    ///
+    /// The trade-off: successful tasks stay `InProgress` until the whole batch is done, so if
+    /// the worker dies mid-batch, tasks that already ran are indistinguishable from the ones
+    /// that didn't. Only use it for tasks that are safe to run twice.
+    pub fn set_fast_path(&mut self, batch_size: u32) {
+        self.fast_path_batch_size = Some(batch_size);
     }
 
-    pub fn run_tasks(&mut self) -> Result<(), FangError> {
-        loop {
-            if let Some(ref shared_state) = self.shared_state {
-                let shared_state = shared_state.read()?;
-                if let WorkerState::Shutdown = *shared_state {
-                    return Ok(());
-                }
-            }
+    pub fn set_deferred_finalization(&mut self, deferred_finalization: DeferredFinalization) {
+        self.deferred_finalization = Some(deferred_finalization);
+    }
 
-            match self.run_task() {
-                Ok(Some(_)) => {
-                    self.maybe_reset_sleep_period();
-                }
-                Ok(None) => {
-                    self.sleep();
-                }
-                Err(error) => {
-                    error!("Error while processing task: {:?}", error);
-                    self.sleep();
-                }
-            };
-        }
+    pub fn set_claim_strategy(&mut self, claim_strategy: Arc<dyn ClaimStrategy>) {
+        self.claim_strategy = claim_strategy;
     }
 
-    pub fn run_task(&mut self) -> Result<Option<Task>, FangError> {
-        let result = Queue::fetch_and_touch_query(&*self.pooled_connection, &self.task_type.clone());
-        if let Ok(Some(ref task)) = result {
-            self.run(task.clone())?;
-        }
-        result.map_err(FangError::DbError)
+    pub fn add_middleware(&mut self, middleware: Arc<dyn TaskMiddleware>) {
+        self.middlewares.push(middleware);
     }
 
-    pub fn maybe_reset_sleep_period(&mut self) {
-        self.sleep_params.maybe_reset_sleep_period();
+    /// Register a serializer besides `MessagePackSerializer` and `CborSerializer`.
+    pub fn add_serializer(&mut self, serializer: Arc<dyn TaskSerializer>) {
+        self.serializers.push(serializer);
     }
 
-    pub fn sleep(&mut self) {
-        self.sleep_params.maybe_increase_sleep_period();
+    pub fn set_payload_store(&mut self, payload_store: Arc<dyn PayloadStore>) {
+        self.payload_store = Some(payload_store);
+    }
 
-        thread::sleep(Duration::from_secs(self.sleep_params.sleep_period));
+    pub fn add_upgrader(&mut self, upgrader: Arc<dyn TaskUpgrader>) {
+        self.upgraders.push(upgrader);
     }
 
-    fn execute_task(&self, task: Task) -> Result<Task, TaskError> {
-        let actual_task: Box<dyn Runnable> = serde_json::from_value(task.metadata.clone()).unwrap();
-        let task_result = actual_task.run(&self.pooled_connection);
+    /// Put tasks without a valid signature on hold instead of running them, see `SigningKey`.
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = Some(signing_key);
+    }
 
-        match task_result {
-            Ok(()) => Ok(task),
-            Err(error) => Err(TaskError(task, error)),
-        }
+    /// Don't claim tasks of types that reached their rate limit. Tasks claimed anyway, for
+    /// example by another worker sharing the limiter, go back to the queue.
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.rate_limiter = Some(rate_limiter);
     }
 
-    fn finalize_task(&self, result: &Result<Task, TaskError>) {
-        match self.retention_mode {
-            RetentionMode::KeepAll => {
-                match result {
-                    Ok(task) => Queue::finish_task_query(&*self.pooled_connection, task).unwrap(),
-                    Err(TaskError(task, error)) => {
-                        Queue::fail_task_query(&*self.pooled_connection, task, error.description.to_owned()).unwrap()
-                    }
-                };
-            }
-            RetentionMode::RemoveAll => {
-                match result {
-                    Ok(task) => Queue::remove_task_query(&*self.pooled_connection, task.id).unwrap(),
-                    Err(TaskError(task, _)) => {
-                        Queue::remove_task_query(&*self.pooled_connection, task.id).unwrap()
-                    }
-                };
-            }
-            RetentionMode::RemoveFinished => match result {
-                Ok(task) => {
-                    Queue::remove_task_query(&*self.pooled_connection, task.id).unwrap();
-                }
-                Err(TaskError(task, error)) => {
-                    Queue::fail_task_query(&*self.pooled_connection, task, error.description.to_owned()).unwrap();
-                }
-            },
-        }
+    /// Wait for a permit of `concurrency_limit` before claiming tasks in `run_tasks`, so
+    /// workers sharing it execute at most `max_in_flight` tasks at once.
+    pub fn set_concurrency_limit(&mut self, concurrency_limit: ConcurrencyLimit) {
+        self.concurrency_limit = Some(concurrency_limit);
     }
-}
 
-#[cfg(test)]
-mod executor_tests {
-    use super::{Error, TaskError};
-    use super::Executor;
-    use super::RetentionMode;
-    use super::Runnable;
-    use crate::queue::NewTask;
-    use crate::queue::Queue;
-    use crate::schema::FangTaskState;
-    use crate::typetag;
-    use assert_matches::assert_matches;
-    use diesel::connection::Connection;
-    use diesel::pg::PgConnection;
-    use diesel::r2d2::{ConnectionManager, PooledConnection};
-    use serde::{Deserialize, Serialize};
+    /// Only run jobs with these typetag names (the struct name unless renamed with
+    /// `#[typetag::serde(name = "...")]`). Other tasks are put on hold before their metadata is
+    /// deserialized, whatever their `task_type` says.
+    pub fn set_allowed_job_names(&mut self, allowed_job_names: Vec<String>) {
+        self.allowed_job_names = Some(allowed_job_names);
+    }
 
-    #[derive(Serialize, Deserialize)]
-    struct ExecutorJobTest {
-        pub number: u16,
+    /// Don't claim tasks of types with an active maintenance window, see `MaintenanceWindow`.
+    /// Windows are read at most once a minute.
+    pub fn set_check_maintenance_windows(&mut self, check_maintenance_windows: bool) {
+        self.check_maintenance_windows = check_maintenance_windows;
     }
 
-    #[typetag::serde]
-    impl Runnable for ExecutorJobTest {
-        fn run(&self, _connection: &PgConnection) -> Result<(), Error> {
-            println!("the number is {}", self.number);
+    /// Replace lost connections with connections from `connection_source`, e.g. a pool.
+    /// Without one, `run_tasks` returns `FangError::ConnectionLost` as soon as the connection
+    /// is lost.
+    pub fn set_connection_source(&mut self, connection_source: ConnectionSource<Conn>) {
+        self.connection_source = Some(connection_source);
+    }
 
-            Ok(())
-        }
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: ReconnectPolicy) {
+        self.reconnect_policy = reconnect_policy;
     }
 
-    #[derive(Serialize, Deserialize)]
-    struct FailedJob {
-        pub number: u16,
+    /// Log a warning with the id, type and duration of every task that runs longer than
+    /// `warn_after`, and tell the slow task listeners. Tasks are checked once they return.
+    pub fn set_warn_after(&mut self, warn_after: Duration) {
+        self.warn_after = Some(warn_after);
     }
 
-    #[typetag::serde]
-    impl Runnable for FailedJob {
-        fn run(&self, _connection: &PgConnection) -> Result<(), Error> {
-            let message = format!("the number is {}", self.number);
+    pub fn add_slow_task_listener(&mut self, slow_task_listener: Arc<dyn SlowTaskListener>) {
+        self.slow_task_listeners.push(slow_task_listener);
+    }
 
-            Err(Error {
-                description: message,
-            })
+    /// Run the task and write its outcome. Failed tasks are returned as
+    /// `FangError::TaskError`, errors while writing the outcome as they are.
+    pub fn run(&self, task: Task) -> Result<Task, FangError> {
+        let context = self.task_context(task.id);
+        let started_at = Instant::now();
+        self.track_current_task(Some(&task));
+        let result = self.execute_task(task, &context);
+        self.track_current_task(None);
+
+        if let Ok(ref task) = result {
+            fang_log!(
+                LogModule::Executor,
+                Level::Debug,
+                "task {} of type {} finished in {:?}",
+                task.id,
+                task.task_type,
+                started_at.elapsed()
+            );
         }
+
+        self.handle_result(&context, &result)?;
+
+        Ok(result?)
     }
 
-    #[derive(Serialize, Deserialize)]
-    struct JobType1 {}
+    pub fn run_tasks(&mut self) -> Result<(), FangError> {
+        loop {
+            if self.is_stopping()? {
+                return self.flush_finished_tasks();
+            }
 
-    #[typetag::serde]
-    impl Runnable for JobType1 {
-        fn run(&self, _connection: &PgConnection) -> Result<(), Error> {
-            Ok(())
+            self.reload_params()?;
+
+            if self.is_paused()? {
+                self.flush_finished_tasks_or_log();
+                thread::sleep(PAUSED_POLL_INTERVAL);
+                continue;
+            }
+
+            // Waiting workers don't claim rows, so other processes can run the tasks
+            let permit = match self.concurrency_limit {
+                Some(ref concurrency_limit) => {
+                    match concurrency_limit.acquire_timeout(PERMIT_WAIT_TIMEOUT) {
+                        Some(permit) => Some(permit),
+                        None => continue,
+                    }
+                }
+                None => None,
+            };
+
+            let result = match self.fast_path_batch_size {
+                Some(batch_size) => self.run_task_batch(batch_size).map(|count| count > 0),
+                None => self.run_task().map(|task| task.is_some()),
+            };
+            drop(permit);
+
+            match result {
+                Ok(true) => {
+                    self.maybe_reset_sleep_period();
+                }
+                Ok(false) => {
+                    self.flush_finished_tasks_or_log();
+                    self.sleep();
+                }
+                Err(error) => {
+                    fang_log!(
+                        LogModule::Executor,
+                        Level::Error,
+                        "Error while processing task: {:?}",
+                        error
+                    );
+
+                    if let FangError::DbError(_) = error {
+                        if !Self::is_alive(&self.pooled_connection) {
+                            self.reconnect(error)?;
+                            continue;
+                        }
+                    }
+
+                    self.flush_finished_tasks_or_log();
+                    self.sleep();
+                }
+            };
         }
+    }
 
-        fn task_type(&self) -> String {
-            "type1".to_string()
+    fn is_stopping(&self) -> Result<bool, FangError> {
+        if let Some(ref shared_state) = self.shared_state {
+            if let WorkerState::Shutdown = *shared_state.read()? {
+                return Ok(true);
+            }
         }
+
+        Ok(self
+            .stop_signal
+            .as_ref()
+            .is_some_and(|stop_signal| stop_signal.load(Ordering::SeqCst)))
     }
 
-    #[derive(Serialize, Deserialize)]
-    struct JobType2 {}
+    /// Apply the parameters in `shared_params` if they changed since the last call.
+    pub fn reload_params(&mut self) -> Result<(), FangError> {
+        let shared_params = match self.shared_params {
+            Some(ref shared_params) => shared_params.clone(),
+            None => return Ok(()),
+        };
 
-    #[typetag::serde]
-    impl Runnable for JobType2 {
-        fn run(&self, _connection: &PgConnection) -> Result<(), Error> {
-            Ok(())
+        let shared_params = shared_params.read()?;
+        let (version, ref params) = *shared_params;
+
+        if self.params_version == Some(version) {
+            return Ok(());
         }
 
-        fn task_type(&self) -> String {
-            "type2".to_string()
+        self.params_version = Some(version);
+        self.retention_mode = params.retention_mode;
+        self.sleep_params = params.sleep_params.clone();
+        self.task_type = params.task_type.clone();
+
+        Ok(())
+    }
+
+    /// Returns true if the pool is paused or draining, so no tasks should be claimed.
+    fn is_paused(&self) -> Result<bool, FangError> {
+        Ok(self.state_is(WorkerState::Paused)? || self.state_is(WorkerState::Draining)?)
+    }
+
+    fn state_is(&self, state: WorkerState) -> Result<bool, FangError> {
+        match self.shared_state {
+            Some(ref shared_state) => Ok(*shared_state.read()? == state),
+            None => Ok(false),
         }
     }
 
-    pub fn serialize(job: &dyn Runnable) -> serde_json::Value {
-        serde_json::to_value(job).unwrap()
+    fn is_alive(connection: &PgConnection) -> bool {
+        connection.execute("SELECT 1").is_ok()
     }
 
-    #[test]
-    fn executes_and_finishes_task() {
-        let job = ExecutorJobTest { number: 10 };
+    /// Replace the lost connection, and the job connection if it's lost too, backing off
+    /// between attempts as `reconnect_policy` says.
+    fn reconnect(&mut self, error: FangError) -> Result<(), FangError> {
+        if let Some(ref connection_source) = self.connection_source {
+            let live_connection = || {
+                let connection = connection_source()?;
+                connection.execute("SELECT 1")?;
 
-        let new_task = NewTask {
-            metadata: serialize(&job),
-            task_type: "common".to_string(),
-        };
+                Ok::<Conn, FangError>(connection)
+            };
 
-        let mut executor = Executor::new(pooled_connection());
-        executor.set_retention_mode(RetentionMode::KeepAll);
+            for attempt in 0..self.reconnect_policy.max_attempts {
+                if self.is_stopping()? {
+                    break;
+                }
 
-        executor
-            .pooled_connection
-            .test_transaction::<(), Error, _>(|| {
-                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+                thread::sleep(self.reconnect_policy.delay(attempt));
 
-                assert_eq!(FangTaskState::New, task.state);
+                let job_connection_lost = self
+                    .job_connection
+                    .as_ref()
+                    .is_some_and(|job_connection| !Self::is_alive(job_connection));
 
-                executor.run(task.clone()).unwrap();
+                let connections = live_connection().and_then(|connection| {
+                    let job_connection = if job_connection_lost {
+                        Some(live_connection()?)
+                    } else {
+                        None
+                    };
 
-                let found_task =
-                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id).unwrap();
+                    Ok((connection, job_connection))
+                });
 
-                assert_eq!(FangTaskState::Finished, found_task.state);
+                match connections {
+                    Ok((connection, job_connection)) => {
+                        self.pooled_connection = connection;
 
-                Ok(())
-            });
+                        if job_connection.is_some() {
+                            self.job_connection = job_connection;
+                        }
+
+                        fang_log!(
+                            LogModule::Executor,
+                            Level::Info,
+                            "reconnected to the database after {} attempts",
+                            attempt + 1
+                        );
+
+                        return Ok(());
+                    }
+                    Err(error) => {
+                        fang_log!(
+                            LogModule::Executor,
+                            Level::Warn,
+                            "Failed to reconnect to the database: {:?}",
+                            error
+                        );
+                    }
+                }
+            }
+        }
+
+        Err(FangError::ConnectionLost {
+            source: Box::new(error),
+        })
     }
 
-    #[test]
-    #[ignore]
-    fn executes_task_only_of_specific_type() {
-        let job1 = JobType1 {};
-        let job2 = JobType2 {};
+    pub fn run_task(&self) -> Result<Option<Task>, FangError> {
+        match self.claim(1)?.pop() {
+            Some(task) => {
+                self.track_in_flight_tasks(&[task.id]);
+                let result = self.run(task);
+                self.track_in_flight_tasks(&[]);
 
-        let new_task1 = NewTask {
-            metadata: serialize(&job1),
-            task_type: "type1".to_string(),
-        };
+                Ok(Some(result?))
+            }
+            None => Ok(None),
+        }
+    }
 
-        let new_task2 = NewTask {
-            metadata: serialize(&job2),
-            task_type: "type2".to_string(),
-        };
+    /// Claim and run up to `batch_size` tasks, see `set_fast_path`. Returns the number of
+    /// claimed tasks. Failed tasks are finalized right away, successful ones after the batch.
+    pub fn run_task_batch(&self, batch_size: u32) -> Result<usize, FangError> {
+        let tasks = self.claim(batch_size.into())?;
+        let claimed = tasks.len();
+        let ids: Vec<Uuid> = tasks.iter().map(|task| task.id).collect();
+        self.track_in_flight_tasks(&ids);
+        let mut finished_ids = Vec::with_capacity(claimed);
+        let mut tasks = tasks.into_iter();
+
+        for task in &mut tasks {
+            let context = self.task_context(task.id);
+
+            // A draining pool finishes the batch, a paused one puts the rest back
+            if context.is_shutting_down() || self.state_is(WorkerState::Paused)? {
+                Queue::reset_task_query(&*self.pooled_connection, &task)?;
+                break;
+            }
 
-        let executor = Executor::new(pooled_connection());
+            self.track_current_task(Some(&task));
+            let result = self.execute_task(task, &context);
+            self.track_current_task(None);
 
-        let task1 = Queue::insert_query(&*executor.pooled_connection, &new_task1).unwrap();
-        let task2 = Queue::insert_query(&*executor.pooled_connection, &new_task2).unwrap();
+            match result {
+                Ok(task) if context.retention_mode().is_none() => {
+                    self.record_completed(task.id);
+                    finished_ids.push(task.id);
+                }
+                result => {
+                    self.handle_result(&context, &result)?;
+
+                    if let Err(error) = result {
+                        fang_log!(
+                            LogModule::Executor,
+                            Level::Error,
+                            "Error while processing task: {:?}",
+                            error
+                        );
+                    }
+                }
+            }
+        }
 
-        assert_eq!(FangTaskState::New, task1.state);
-        assert_eq!(FangTaskState::New, task2.state);
+        // Tasks left over after shutdown or pause go back to the queue
+        for task in tasks {
+            Queue::reset_task_query(&*self.pooled_connection, &task)?;
+        }
 
-        std::thread::spawn(move || {
-            let mut executor = Executor::new(pooled_connection());
-            executor.set_retention_mode(RetentionMode::KeepAll);
-            executor.set_task_type("type1".to_string());
+        let result = self.finish_tasks(finished_ids);
+        self.track_in_flight_tasks(&[]);
+        result?;
 
-            executor.run_tasks().unwrap();
-        });
+        Ok(claimed)
+    }
 
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+    /// Finalize all tasks buffered by deferred finalization.
+    pub fn flush_finished_tasks(&self) -> Result<(), FangError> {
+        let ids = self.finished_buffer.borrow_mut().take();
 
-        let found_task1 =
-            Queue::find_task_by_id_query(&*executor.pooled_connection, task1.id).unwrap();
-        assert_eq!(FangTaskState::Finished, found_task1.state);
+        self.finalize_finished_tasks(&ids)
+    }
 
-        let found_task2 =
-            Queue::find_task_by_id_query(&*executor.pooled_connection, task2.id).unwrap();
-        assert_eq!(FangTaskState::New, found_task2.state);
+    pub fn maybe_reset_sleep_period(&mut self) {
+        self.sleep_params.maybe_reset_sleep_period();
     }
 
-    #[test]
-    fn saves_error_for_failed_task() {
-        let job = FailedJob { number: 10 };
+    pub fn sleep(&mut self) {
+        self.sleep_params.maybe_increase_sleep_period();
 
-        let new_task = NewTask {
-            metadata: serialize(&job),
-            task_type: "common".to_string(),
-        };
+        thread::sleep(self.sleep_params.jittered_sleep_period());
+    }
 
-        let executor = Executor::new(pooled_connection());
+    fn execute_task(&self, task: Task, context: &TaskContext) -> Result<Task, TaskError> {
+        if let Some(ref signing_key) = self.signing_key {
+            if !signing_key.verify(&task.task_type, &task.metadata, task.signature.as_deref()) {
+                context.hold();
 
-        executor
-            .pooled_connection
-            .test_transaction::<(), Error, _>(|| {
-                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+                let error = Error {
+                    description: "Task signature is missing or invalid".to_string(),
+                };
 
-                assert_eq!(FangTaskState::New, task.state);
+                return Err(TaskError(task, error));
+            }
+        }
 
-                let result = executor.run(task.clone());
-                assert_matches!(result, Err(TaskError(_, _)));
+        if let Some(ref allowed_job_names) = self.allowed_job_names {
+            let job_name = job_name(&task.metadata);
+            let allowed = job_name
+                .is_some_and(|name| allowed_job_names.iter().any(|allowed| allowed == name));
 
-                let found_task =
-                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id).unwrap();
+            if !allowed {
+                context.hold();
 
-                assert_eq!(FangTaskState::Failed, found_task.state);
-                assert_eq!(
+                let error = Error {
+                    description: format!(
+                        "Job {} is not allowed in this worker",
+                        job_name.unwrap_or("<unknown>")
+                    ),
+                };
+
+                return Err(TaskError(task, error));
+            }
+        }
+
+        // E.g. the job was renamed or removed. Fail the task instead of panicking the worker.
+        let actual_task = match self.deserialize_job(&task) {
+            Ok(actual_task) => actual_task,
+            Err(error) => return Err(TaskError(task, error)),
+        };
+        context.set_retention_mode(actual_task.retention());
+
+        // Only tasks claimed with a lease have one to override
+        if let (Some(lease_duration), Some(locked_by)) =
+            (actual_task.lease_duration(), task.locked_by.as_deref())
+        {
+            if let Err(error) = Queue::renew_lease_query(
+                &*self.pooled_connection,
+                task.id,
+                locked_by,
+                lease_duration,
+            ) {
+                return Err(TaskError(task, error.into()));
+            }
+        }
+
+        if let Err(error) = actual_task.preflight(context) {
+            context.snooze(error.retry_in);
+
+            let error = Error {
+                description: error.description,
+            };
+
+            return Err(TaskError(task, error));
+        }
+
+        let started_at = Instant::now();
+        let task_result = Next::new(
+            &*actual_task,
+            self.job_connection(),
+            context,
+            &self.middlewares,
+            &task,
+        )
+        .run();
+        let duration = started_at.elapsed();
+
+        let mut task = task;
+        task.duration_in_milliseconds =
+            Some(i64::try_from(duration.as_millis()).unwrap_or(i64::MAX));
+
+        self.check_slow_task(&task, duration);
+
+        match task_result {
+            Ok(()) => actual_task.on_success(self.job_connection()),
+            Err(ref error) if context.cancellation().is_none() && !context.is_held() => {
+                actual_task.on_failure(self.job_connection(), error)
+            }
+            Err(_) => {}
+        }
+
+        if let Err(ref error) = task_result {
+            if context.cancellation().is_none() && context.snoozed_for().is_some() {
+                task = self.replace_job_on_retry(task, &*actual_task, error);
+            }
+        }
+
+        match task_result {
+            Ok(()) => Ok(task),
+            Err(error) => Err(TaskError(task, error)),
+        }
+    }
+
+    /// Stores the job returned by `Runnable::on_retry`, if any, for the next attempt.
+    fn replace_job_on_retry(&self, task: Task, job: &dyn Runnable, error: &Error) -> Task {
+        let replacement = match job.on_retry(task.attempts, error) {
+            Some(replacement) => replacement,
+            None => return task,
+        };
+
+        match Queue::replace_job_query(
+            &*self.pooled_connection,
+            &task,
+            &*replacement,
+            self.signing_key.as_ref(),
+        ) {
+            Ok(replaced) => Task {
+                duration_in_milliseconds: task.duration_in_milliseconds,
+                ..replaced
+            },
+            Err(error) => {
+                fang_log!(
+                    LogModule::Executor,
+                    Level::Error,
+                    "Failed to replace the job of task {} for its retry: {:?}",
+                    task.id,
+                    error
+                );
+
+                task
+            }
+        }
+    }
+
+    fn check_slow_task(&self, task: &Task, duration: Duration) {
+        match self.warn_after {
+            Some(warn_after) if duration > warn_after => {}
+            _ => return,
+        }
+
+        fang_log!(
+            LogModule::Executor,
+            Level::Warn,
+            "task {} of type {} is slow, it ran for {} ms",
+            task.id,
+            task.task_type,
+            duration.as_millis()
+        );
+
+        for slow_task_listener in &self.slow_task_listeners {
+            slow_task_listener.on_slow_task(task, duration);
+        }
+    }
+
+    fn deserialize_job(&self, task: &Task) -> Result<Box<dyn Runnable>, Error> {
+        let format = match &task.payload_format {
+            Some(format) => format,
+            None => return self.deserialize_json(task, &task.metadata),
+        };
+
+        let serializer = self
+            .serializers
+            .iter()
+            .find(|serializer| serializer.format() == format)
+            .ok_or_else(|| Error {
+                description: format!("Unknown payload format {}", format),
+            })?;
+
+        let fetched;
+        let payload = match (&task.payload, &task.payload_reference) {
+            (Some(payload), _) => payload,
+            (None, Some(reference)) => {
+                fetched = self.fetch_payload(reference)?;
+                &fetched
+            }
+            (None, None) => {
+                return Err(Error {
+                    description: "The task has no payload".to_string(),
+                })
+            }
+        };
+
+        if !payload_matches(&task.metadata, payload) {
+            return Err(payload_mismatch());
+        }
+
+        let decompressed;
+        let payload = match &task.payload_encoding {
+            Some(encoding) => {
+                decompressed = decompress(encoding, payload)?;
+                &decompressed
+            }
+            None => payload,
+        };
+
+        if format == JsonSerializer.format() && !self.upgraders.is_empty() {
+            if !job_digest_matches(&task.metadata, None) {
+                return Err(payload_mismatch());
+            }
+
+            let json = serde_json::from_slice(payload).map_err(deserialization_error)?;
+
+            return self.deserialize_json(task, &json);
+        }
+
+        let (job, job_digest) = serializer
+            .deserialize_with_digest(payload)
+            .map_err(deserialization_error)?;
+
+        if !job_digest_matches(&task.metadata, job_digest.as_deref()) {
+            return Err(payload_mismatch());
+        }
+
+        Ok(job)
+    }
+
+    /// Runs the `TaskUpgrader` of the job first.
+    fn deserialize_json(
+        &self,
+        task: &Task,
+        json: &serde_json::Value,
+    ) -> Result<Box<dyn Runnable>, Error> {
+        let upgraded;
+        let json = if self.upgraders.is_empty() {
+            json
+        } else {
+            upgraded = upgrade(&self.upgraders, task.task_version, json.clone())?;
+            &upgraded
+        };
+
+        Box::<dyn Runnable>::deserialize(json).map_err(deserialization_error)
+    }
+
+    fn fetch_payload(&self, reference: &str) -> Result<Vec<u8>, Error> {
+        let payload_store = self.payload_store.as_ref().ok_or_else(|| Error {
+            description: "The payload is kept in a payload store, but none is set".to_string(),
+        })?;
+
+        payload_store.get(reference).map_err(|error| Error {
+            description: format!(
+                "Failed to fetch the payload {}, error: {}",
+                reference, error.description
+            ),
+        })
+    }
+
+    fn claim(&self, limit: i64) -> Result<Vec<Task>, FangError> {
+        // Doesn't allocate unless there are task types to exclude
+        let mut excluded_task_types = Vec::new();
+
+        if let Some(ref rate_limiter) = self.rate_limiter {
+            let throttled_task_types = rate_limiter.throttled_task_types();
+
+            if !throttled_task_types.is_empty() {
+                excluded_task_types.extend(throttled_task_types);
+            }
+        }
+
+        if self.check_maintenance_windows {
+            let paused_task_types = self.paused_task_types()?;
+
+            if !paused_task_types.is_empty() {
+                excluded_task_types.extend(paused_task_types);
+            }
+        }
+
+        let params = ClaimParams {
+            task_type: &self.task_type,
+            excluded_task_types: &excluded_task_types,
+        };
+
+        let started_at = Instant::now();
+        let tasks = self
+            .claim_strategy
+            .claim(&self.pooled_connection, &params, limit)?;
+
+        if !tasks.is_empty() {
+            fang_log!(
+                LogModule::Executor,
+                Level::Debug,
+                "claimed {} tasks in {:?}",
+                tasks.len(),
+                started_at.elapsed()
+            );
+        }
+
+        if let Some(ref claim_metrics) = self.claim_metrics {
+            let latency = started_at.elapsed();
+            let empty_due_to_locks = tasks.is_empty()
+                && Queue::has_claimable_tasks_query(&*self.pooled_connection, &params)?;
+
+            claim_metrics.record(latency, empty_due_to_locks);
+        }
+
+        let rate_limiter = match self.rate_limiter {
+            Some(ref rate_limiter) => rate_limiter,
+            None => return Ok(tasks),
+        };
+
+        let mut allowed = Vec::with_capacity(tasks.len());
+
+        for task in tasks {
+            if rate_limiter.try_acquire(&task.task_type) {
+                allowed.push(task);
+            } else {
+                Queue::reset_task_query(&*self.pooled_connection, &task)?;
+            }
+        }
+
+        Ok(allowed)
+    }
+
+    fn job_connection(&self) -> &PgConnection {
+        match self.job_connection {
+            Some(ref job_connection) => job_connection,
+            None => &self.pooled_connection,
+        }
+    }
+
+    fn task_context(&self, task_id: Uuid) -> TaskContext<'_> {
+        let context = TaskContext::new(task_id, self.shared_state.as_ref());
+
+        match self.worker_data {
+            Some(ref worker_data) => context.with_worker_data(&**worker_data),
+            None => context,
+        }
+    }
+
+    /// Reuses the allocation of the previous ids.
+    fn track_in_flight_tasks(&self, ids: &[Uuid]) {
+        if let Some(ref in_flight_tasks) = self.in_flight_tasks {
+            let mut in_flight_tasks = in_flight_tasks
+                .lock()
+                .unwrap_or_else(|error| error.into_inner());
+            in_flight_tasks.clear();
+            in_flight_tasks.extend_from_slice(ids);
+        }
+    }
+
+    /// `WorkerPool::running_tasks` fills in the worker name.
+    fn track_current_task(&self, task: Option<&Task>) {
+        if let Some(ref current_task) = self.current_task {
+            *current_task
+                .lock()
+                .unwrap_or_else(|error| error.into_inner()) = task.map(|task| RunningTask {
+                worker_name: String::new(),
+                task_id: task.id,
+                task_type: task.task_type.clone(),
+                started_at: Utc::now(),
+            });
+        }
+    }
+
+    fn paused_task_types(&self) -> Result<Vec<String>, FangError> {
+        let mut cache = self.maintenance_windows.borrow_mut();
+
+        let stale = match cache.loaded_at {
+            Some(loaded_at) => loaded_at.elapsed() >= MAINTENANCE_WINDOWS_REFRESH_INTERVAL,
+            None => true,
+        };
+
+        if stale {
+            cache.windows = Queue::maintenance_windows_query(&*self.pooled_connection)?;
+            cache.loaded_at = Some(Instant::now());
+        }
+
+        Ok(maintenance::paused_task_types(&cache.windows, Utc::now()))
+    }
+
+    fn handle_result(
+        &self,
+        context: &TaskContext,
+        result: &Result<Task, TaskError>,
+    ) -> Result<(), FangError> {
+        self.record_result(context, result);
+
+        // A task that stopped early because it was cancelled isn't a failure. Only if the row
+        // really is cancelled, otherwise the task is finalized like any other failure.
+        match (context.cancellation(), result) {
+            (Some(Cancellation::TaskCancelled), Err(TaskError(task, _)))
+                if Queue::task_state_query(&*self.pooled_connection, task.id)?
+                    == Some(FangTaskState::Cancelled) => {}
+            (Some(Cancellation::Shutdown), Err(TaskError(task, _))) => {
+                Queue::reset_task_query(&*self.pooled_connection, task)?;
+            }
+            (_, Ok(task))
+                if self.deferred_finalization.is_some() && context.retention_mode().is_none() =>
+            {
+                self.finish_tasks(vec![task.id])?;
+            }
+            (_, Err(TaskError(task, error))) if context.snoozed_for().is_some() => {
+                let snoozed_for = context.snoozed_for().unwrap_or_default();
+                let scheduled_at = chrono::Duration::from_std(snoozed_for)
+                    .ok()
+                    .and_then(|snoozed_for| Utc::now().checked_add_signed(snoozed_for))
+                    .unwrap_or(DateTime::<Utc>::MAX_UTC);
+
+                Queue::snooze_task_query(
+                    &*self.pooled_connection,
+                    task,
+                    scheduled_at,
+                    error.description.to_owned(),
+                )?;
+            }
+            (_, Err(TaskError(task, error))) if context.is_held() => {
+                self.flush_finished_tasks_or_log();
+                Queue::hold_task_query(
+                    &*self.pooled_connection,
+                    task,
+                    error.description.to_owned(),
+                )?;
+            }
+            (_, Err(_)) => {
+                self.flush_finished_tasks_or_log();
+                self.finalize_task(context, result)?;
+            }
+            _ => self.finalize_task(context, result)?,
+        }
+
+        Ok(())
+    }
+
+    fn record_result(&self, context: &TaskContext, result: &Result<Task, TaskError>) {
+        if let Ok(task) = result {
+            self.record_completed(task.id);
+            return;
+        }
+
+        let worker_metrics = match self.worker_metrics {
+            Some(ref worker_metrics) => worker_metrics,
+            None => return,
+        };
+
+        match (context.cancellation(), context.snoozed_for()) {
+            (Some(_), _) => worker_metrics.touch(),
+            (None, Some(_)) => worker_metrics.record_retried(),
+            (None, None) => worker_metrics.record_failed(),
+        }
+    }
+
+    fn record_completed(&self, id: Uuid) {
+        if let Some(ref worker_metrics) = self.worker_metrics {
+            worker_metrics.record_processed();
+        }
+
+        if let Some(ref event_senders) = self.event_senders {
+            emit(event_senders, WorkerPoolEvent::TaskCompleted { id });
+        }
+    }
+
+    fn finish_tasks(&self, ids: Vec<Uuid>) -> Result<(), FangError> {
+        match self.deferred_finalization {
+            None => self.finalize_finished_tasks(&ids),
+            Some(ref deferred_finalization) => {
+                let due = {
+                    let mut buffer = self.finished_buffer.borrow_mut();
+                    buffer.extend(ids);
+                    buffer.is_due(deferred_finalization)
+                };
+
+                if due {
+                    self.flush_finished_tasks()
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn flush_finished_tasks_or_log(&self) {
+        if let Err(error) = self.flush_finished_tasks() {
+            fang_log!(
+                LogModule::Executor,
+                Level::Error,
+                "Failed to finalize finished tasks: {:?}",
+                error
+            );
+        }
+    }
+
+    fn finalize_finished_tasks(&self, ids: &[Uuid]) -> Result<(), FangError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        Queue::enqueue_next_tasks_query(&*self.pooled_connection, ids)?;
+        Queue::satisfy_dependencies_query(&*self.pooled_connection, ids)?;
+        Queue::record_batch_results_query(&*self.pooled_connection, ids, true)?;
+
+        if self.retention_mode.removes_finished() {
+            Queue::remove_tasks_query(&*self.pooled_connection, ids)?;
+        } else {
+            Queue::finish_tasks_query(&*self.pooled_connection, ids)?;
+        }
+
+        if self.retention_mode.archives_finished() {
+            Queue::archive_tasks_query(&*self.pooled_connection, ids)?;
+        }
+
+        Ok(())
+    }
+
+    fn finalize_task(
+        &self,
+        context: &TaskContext,
+        result: &Result<Task, TaskError>,
+    ) -> Result<(), FangError> {
+        let retention_mode = context.retention_mode().unwrap_or(self.retention_mode);
+
+        // Chains and batches, before the task may be removed
+        let (task, succeeded) = match result {
+            Ok(task) => (task, true),
+            Err(TaskError(task, _)) => (task, false),
+        };
+
+        if succeeded && task.next_task_id.is_some() {
+            Queue::enqueue_next_tasks_query(&*self.pooled_connection, &[task.id])?;
+        }
+
+        if succeeded {
+            Queue::satisfy_dependencies_query(&*self.pooled_connection, &[task.id])?;
+        }
+
+        if task.batch_id.is_some() {
+            Queue::record_batch_results_query(&*self.pooled_connection, &[task.id], succeeded)?;
+        }
+
+        match result {
+            Ok(task) if retention_mode.removes_finished() => {
+                Queue::remove_task_query(&*self.pooled_connection, task.id)?;
+            }
+            Ok(task) => {
+                Queue::finish_task_query(&*self.pooled_connection, task)?;
+
+                if retention_mode.archives_finished() {
+                    Queue::archive_tasks_query(&*self.pooled_connection, &[task.id])?;
+                }
+            }
+            Err(TaskError(task, _)) if retention_mode.removes_failed() => {
+                Queue::remove_task_query(&*self.pooled_connection, task.id)?;
+            }
+            Err(TaskError(task, error)) => {
+                Queue::fail_task_query(
+                    &*self.pooled_connection,
+                    task,
+                    error.description.to_owned(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn payload_mismatch() -> Error {
+    Error {
+        description: "The payload doesn't match the metadata".to_string(),
+    }
+}
+
+/// The typetag name of a serialized job, stored in the `type` field of the metadata.
+fn job_name(metadata: &serde_json::Value) -> Option<&str> {
+    metadata.get("type").and_then(serde_json::Value::as_str)
+}
+
+#[cfg(test)]
+mod executor_tests {
+    use super::DeferredFinalization;
+    use super::Error;
+    use super::Executor;
+    use super::Perform;
+    use super::PreflightError;
+    use super::ReconnectPolicy;
+    use super::RetentionMode;
+    use super::Runnable;
+    use super::SlowTaskListener;
+    use crate::claim::{ClaimMetrics, ClaimParams, ClaimStrategy};
+    use crate::compression::{Compression, CompressionAlgorithm};
+    use crate::context::TaskContext;
+    use crate::context::WorkerState;
+    use crate::encryption::{EncryptedSerializer, StaticKeyProvider};
+    use crate::error::FangError;
+    use crate::maintenance::NewMaintenanceWindow;
+    use crate::metrics::WorkerMetrics;
+    use crate::middleware::{Next, TaskMiddleware};
+    use crate::payload_store::FilePayloadStore;
+    use crate::queue::NewTask;
+    use crate::queue::Queue;
+    use crate::queue::Task;
+    use crate::rate_limit::{RateLimit, RateLimiter};
+    use crate::schema::fang_tasks;
+    use crate::schema::FangTaskState;
+    use crate::serializer::{CborSerializer, MessagePackSerializer, TaskSerializer};
+    use crate::signing::SigningKey;
+    use crate::typetag;
+    use crate::versioning::TaskUpgrader;
+    use crate::worker_pool::{ReloadableParams, WorkerParams};
+    use assert_matches::assert_matches;
+    use chrono::{Datelike, NaiveTime, Utc};
+    use diesel::connection::Connection;
+    use diesel::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::r2d2::{ConnectionManager, PooledConnection};
+    use diesel::sql_types::{Bool, Integer};
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
+    use fang_derive::FangTask;
+    use serde::{Deserialize, Serialize};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[derive(Serialize, Deserialize)]
+    struct ExecutorJobTest {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for ExecutorJobTest {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            println!("the number is {}", self.number);
+
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct FailedJob {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for FailedJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            let message = format!("the number is {}", self.number);
+
+            Err(Error {
+                description: message,
+            })
+        }
+    }
+
+    #[derive(Serialize, Deserialize, FangTask)]
+    #[fang(task_type = "derived")]
+    struct DerivedJob {
+        pub number: u16,
+    }
+
+    impl Perform for DerivedJob {
+        fn perform(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            println!("the number is {}", self.number);
+
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ParseJob {
+        pub input: String,
+    }
+
+    #[typetag::serde]
+    impl Runnable for ParseJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            let number: u16 = self.input.parse()?;
+
+            println!("the number is {}", number);
+
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct DisconnectingJob {}
+
+    #[typetag::serde]
+    impl Runnable for DisconnectingJob {
+        fn run(&self, connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            terminate_backend(connection);
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "disconnecting".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct LongLeaseJob {}
+
+    #[typetag::serde]
+    impl Runnable for LongLeaseJob {
+        fn run(&self, connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+            let task = Queue::find_task_by_id_query(connection, context.task_id())?.unwrap();
+
+            match task.locked_until {
+                Some(locked_until) if locked_until > Utc::now() + chrono::Duration::hours(5) => {
+                    Ok(())
+                }
+                _ => Err(Error {
+                    description: "the lease wasn't extended".to_string(),
+                }),
+            }
+        }
+
+        fn task_type(&self) -> String {
+            "long_lease".to_string()
+        }
+
+        fn lease_duration(&self) -> Option<chrono::Duration> {
+            Some(chrono::Duration::hours(6))
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct CancellableJob {}
+
+    #[typetag::serde]
+    impl Runnable for CancellableJob {
+        fn run(&self, connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+            if context.is_cancelled(connection)? {
+                return Err(Error {
+                    description: "cancelled".to_string(),
+                });
+            }
+
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct WorkerDataJob {}
+
+    #[typetag::serde]
+    impl Runnable for WorkerDataJob {
+        fn run(&self, _connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+            match context.worker_data::<String>() {
+                Some(api_url) if api_url == "https://api.example.com" => Ok(()),
+                _ => Err(Error {
+                    description: "worker data is missing".to_string(),
+                }),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct BackendPidJob {}
+
+    #[typetag::serde]
+    impl Runnable for BackendPidJob {
+        fn run(&self, connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+            if context.worker_data::<i32>() == Some(&backend_pid(connection)) {
+                Ok(())
+            } else {
+                Err(Error {
+                    description: "ran with the wrong connection".to_string(),
+                })
+            }
+        }
+    }
+
+    fn backend_pid(connection: &PgConnection) -> i32 {
+        diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            "pg_backend_pid()",
+        ))
+        .get_result::<i32>(connection)
+        .unwrap()
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct HeldJob {}
+
+    #[typetag::serde]
+    impl Runnable for HeldJob {
+        fn run(&self, _connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+            context.hold();
+
+            Err(Error {
+                description: "needs attention".to_string(),
+            })
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct HookJob {
+        pub fail: bool,
+    }
+
+    #[typetag::serde]
+    impl Runnable for HookJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            if self.fail {
+                return Err(Error {
+                    description: "failed".to_string(),
+                });
+            }
+
+            Ok(())
+        }
+
+        fn on_success(&self, connection: &PgConnection) {
+            insert_hook_task(connection, "on_success");
+        }
+
+        fn on_failure(&self, connection: &PgConnection, error: &Error) {
+            insert_hook_task(connection, &error.description);
+        }
+    }
+
+    fn insert_hook_task(connection: &PgConnection, task_type: &str) {
+        let new_task = NewTask {
+            metadata: serde_json::json!(task_type),
+            task_type: task_type.to_string(),
+        };
+
+        Queue::insert_query(connection, &new_task).unwrap();
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JobType1 {}
+
+    #[typetag::serde]
+    impl Runnable for JobType1 {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "type1".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JobType2 {}
+
+    #[typetag::serde]
+    impl Runnable for JobType2 {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "type2".to_string()
+        }
+    }
+
+    pub fn serialize(job: &dyn Runnable) -> serde_json::Value {
+        serde_json::to_value(job).unwrap()
+    }
+
+    #[test]
+    fn executes_and_finishes_task() {
+        let job = ExecutorJobTest { number: 10 };
+
+        let new_task = NewTask {
+            metadata: serialize(&job),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_eq!(FangTaskState::New, task.state);
+
+                executor.run(task.clone()).unwrap();
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Finished, found_task.state);
+                assert!(found_task.duration_in_milliseconds.is_some());
+
+                Ok(())
+            });
+    }
+
+    #[derive(Default)]
+    struct SlowTasks(Mutex<Vec<Uuid>>);
+
+    impl SlowTaskListener for SlowTasks {
+        fn on_slow_task(&self, task: &Task, duration: Duration) {
+            assert!(duration > Duration::from_millis(0));
+            assert!(task.duration_in_milliseconds.is_some());
+
+            self.0.lock().unwrap().push(task.id);
+        }
+    }
+
+    #[test]
+    fn tells_listeners_about_slow_tasks() {
+        let slow_tasks = Arc::new(SlowTasks::default());
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_warn_after(Duration::from_nanos(1));
+        executor.add_slow_task_listener(slow_tasks.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let queue = Queue::new_with_connection(&*executor.pooled_connection);
+                let task = queue.push_task(&ExecutorJobTest { number: 1 }).unwrap();
+
+                executor.run(task.clone()).unwrap();
+
+                assert_eq!(vec![task.id], *slow_tasks.0.lock().unwrap());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn counts_processed_failed_and_retried_tasks() {
+        let worker_metrics = Arc::new(WorkerMetrics::default());
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_worker_metrics(worker_metrics.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let queue = Queue::new_with_connection(&*executor.pooled_connection);
+
+                assert_eq!(None, worker_metrics.stats().last_activity_at);
+
+                let task = queue.push_task(&ExecutorJobTest { number: 1 }).unwrap();
+                executor.run(task).unwrap();
+
+                let task = queue.push_task(&FailedJob { number: 2 }).unwrap();
+                executor.run(task).unwrap_err();
+
+                let task = queue.push_task(&MaintenanceWindowJob {}).unwrap();
+                executor.run(task).unwrap_err();
+
+                let stats = worker_metrics.stats();
+
+                assert_eq!(1, stats.processed);
+                assert_eq!(1, stats.failed);
+                assert_eq!(1, stats.retried);
+                assert!(stats.last_activity_at.is_some());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn runs_jobs_deriving_fang_task() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let queue = Queue::new_with_connection(&*executor.pooled_connection);
+                let task = DerivedJob { number: 10 }.enqueue(&queue).unwrap();
+
+                assert_eq!(DerivedJob::TASK_TYPE, task.task_type);
+                assert_eq!("derived", task.task_type);
+
+                executor.run(task.clone()).unwrap();
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn runs_tasks_with_serialized_payloads() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+                let serializers: Vec<Arc<dyn TaskSerializer>> =
+                    vec![Arc::new(MessagePackSerializer), Arc::new(CborSerializer)];
+
+                for serializer in serializers {
+                    let mut queue = Queue::new_with_connection(connection);
+                    queue.set_serializer(serializer.clone());
+
+                    let task = queue.push_task(&ExecutorJobTest { number: 10 }).unwrap();
+
+                    assert_eq!(Some(serializer.format().to_string()), task.payload_format);
+                    assert_eq!(Some("ExecutorJobTest"), task.metadata["type"].as_str());
+                    assert!(task.metadata.get("number").is_none());
+
+                    executor.run(task.clone()).unwrap();
+
+                    let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                    assert_eq!(FangTaskState::Finished, found_task.state);
+                }
+
+                let mut queue = Queue::new_with_connection(connection);
+                queue.set_serializer(Arc::new(MessagePackSerializer));
+
+                let task = queue.push_task(&ExecutorJobTest { number: 11 }).unwrap();
+                let other_payload = MessagePackSerializer
+                    .serialize(&ExecutorJobTest { number: 12 })
+                    .unwrap();
+
+                let tampered_task = diesel::update(&task)
+                    .set(fang_tasks::payload.eq(other_payload))
+                    .get_result::<Task>(connection)
+                    .unwrap();
+
+                assert_matches!(executor.run(tampered_task), Err(FangError::TaskError(_)));
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(FangTaskState::Failed, found_task.state);
+                assert_eq!(
+                    Some("The payload doesn't match the metadata".to_string()),
+                    found_task.error_message
+                );
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn jobs_override_the_lease_of_their_tasks() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+                let task = Queue::push_task_query(connection, &LongLeaseJob {}).unwrap();
+
+                let params = ClaimParams {
+                    task_type: &Some("long_lease".to_string()),
+                    excluded_task_types: &[],
+                };
+                let claimed = Queue::claim_tasks_with_lease_query(
+                    connection,
+                    &params,
+                    1,
+                    "host-1",
+                    chrono::Duration::minutes(1),
+                )
+                .unwrap();
+                assert_eq!(vec![task.id], vec![claimed[0].id]);
+
+                executor.run(claimed[0].clone()).unwrap();
+
+                let found_task = Queue::find_task_by_id_query(connection, task.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn runs_tasks_with_encrypted_payloads() {
+        let serializer = Arc::new(EncryptedSerializer::new(
+            Arc::new(MessagePackSerializer),
+            Arc::new(StaticKeyProvider::new("2021".to_string(), [1; 32])),
+        ));
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.add_serializer(serializer.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+                let mut queue = Queue::new_with_connection(connection);
+                queue.set_serializer(serializer.clone());
+
+                let task = queue.push_task(&ExecutorJobTest { number: 10 }).unwrap();
+                assert!(task.metadata.get("job_digest").is_some());
+
+                executor.run(task.clone()).unwrap();
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                let task = queue.push_task(&ExecutorJobTest { number: 11 }).unwrap();
+                let other_payload = serializer
+                    .serialize(&ExecutorJobTest { number: 12 })
+                    .unwrap();
+
+                let tampered_task = diesel::update(&task)
+                    .set(fang_tasks::payload.eq(other_payload))
+                    .get_result::<Task>(connection)
+                    .unwrap();
+
+                assert_matches!(executor.run(tampered_task), Err(FangError::TaskError(_)));
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(
+                    Some("The payload doesn't match the metadata".to_string()),
+                    found_task.error_message
+                );
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn runs_tasks_with_compressed_payloads() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                for algorithm in [CompressionAlgorithm::Gzip, CompressionAlgorithm::Zstd] {
+                    let mut queue = Queue::new_with_connection(connection);
+                    queue.set_compression(Compression::new(algorithm, 0));
+
+                    let task = queue.push_task(&ExecutorJobTest { number: 10 }).unwrap();
+
+                    assert_eq!(Some("json".to_string()), task.payload_format);
+                    assert_eq!(
+                        Some(algorithm.encoding().to_string()),
+                        task.payload_encoding
+                    );
+                    assert!(task.metadata.get("number").is_none());
+
+                    executor.run(task.clone()).unwrap();
+
+                    let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                    assert_eq!(FangTaskState::Finished, found_task.state);
+                }
+
+                let mut queue = Queue::new_with_connection(connection);
+                queue.set_compression(Compression::new(CompressionAlgorithm::Zstd, 1024));
+
+                let task = queue.push_task(&ExecutorJobTest { number: 11 }).unwrap();
+
+                assert_eq!(None, task.payload);
+                assert_eq!(Some(11), task.metadata["number"].as_i64());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn runs_tasks_with_offloaded_payloads() {
+        let directory = std::env::temp_dir().join("fang_executor_tests");
+        let payload_store = Arc::new(FilePayloadStore::new(&directory));
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_payload_store(payload_store.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let mut queue = Queue::new_with_connection(connection);
+                queue.set_payload_store(payload_store, 0);
+
+                let task = queue.push_task(&ExecutorJobTest { number: 10 }).unwrap();
+
+                assert_eq!(None, task.payload);
+                assert_eq!(Some("json".to_string()), task.payload_format);
+                assert!(task.payload_reference.is_some());
+
+                executor.run(task.clone()).unwrap();
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                let task = queue.push_task(&ExecutorJobTest { number: 11 }).unwrap();
+                let missing_task = diesel::update(&task)
+                    .set(fang_tasks::payload_reference.eq("00"))
+                    .get_result::<Task>(connection)
+                    .unwrap();
+
+                assert_matches!(executor.run(missing_task), Err(FangError::TaskError(_)));
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(FangTaskState::Failed, found_task.state);
+
+                Ok(())
+            });
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn enqueues_the_next_task_of_a_chain_on_success() {
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let tasks = Queue::push_chain_query(
+                    connection,
+                    &[
+                        &ExecutorJobTest { number: 1 },
+                        &ExecutorJobTest { number: 2 },
+                        &FailedJob { number: 3 },
+                        &ExecutorJobTest { number: 4 },
+                    ],
+                )
+                .unwrap();
+
+                let states: Vec<FangTaskState> =
+                    tasks.iter().map(|task| task.state.clone()).collect();
+                assert_eq!(
+                    vec![
+                        FangTaskState::New,
+                        FangTaskState::Waiting,
+                        FangTaskState::Waiting,
+                        FangTaskState::Waiting
+                    ],
+                    states
+                );
+                assert_eq!(Some(tasks[1].id), tasks[0].next_task_id);
+                assert_eq!(None, tasks[3].next_task_id);
+
+                executor.run(tasks[0].clone()).unwrap();
+
+                let second_task = Queue::find_task_by_id_query(connection, tasks[1].id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::New, second_task.state);
+
+                executor.run(second_task).unwrap();
+
+                let third_task = Queue::find_task_by_id_query(connection, tasks[2].id)
+                    .unwrap()
+                    .unwrap();
+                assert_matches!(executor.run(third_task), Err(FangError::TaskError(_)));
+
+                let last_task = Queue::find_task_by_id_query(connection, tasks[3].id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Waiting, last_task.state);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn removed_failed_dependencies_keep_blocking_their_dependents() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::RemoveAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let load =
+                    Queue::push_task_query(connection, &ExecutorJobTest { number: 1 }).unwrap();
+                let failing = Queue::push_task_query(connection, &FailedJob { number: 2 }).unwrap();
+                let report = Queue::push_task_with_dependencies_query(
+                    connection,
+                    &ExecutorJobTest { number: 3 },
+                    &[load.id],
+                )
+                .unwrap();
+                Queue::push_task_with_dependencies_query(
+                    connection,
+                    &ExecutorJobTest { number: 4 },
+                    &[load.id, failing.id],
+                )
+                .unwrap();
+
+                executor.run(load.clone()).unwrap();
+                assert_matches!(executor.run(failing.clone()), Err(FangError::TaskError(_)));
+                assert_eq!(
+                    None,
+                    Queue::find_task_by_id_query(connection, load.id).unwrap()
+                );
+                assert_eq!(
+                    None,
+                    Queue::find_task_by_id_query(connection, failing.id).unwrap()
+                );
+
+                let claimed = Queue::fetch_task_query(connection, &None);
+                assert_eq!(Some(report.id), claimed.map(|task| task.id));
+
+                Queue::remove_task_query(connection, report.id).unwrap();
+                assert_eq!(None, Queue::fetch_task_query(connection, &None));
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn enqueues_the_callback_of_a_batch_once_all_tasks_are_done() {
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let batch = Queue::push_batch_query(
+                    connection,
+                    "partitions",
+                    &[&ExecutorJobTest { number: 1 }, &FailedJob { number: 2 }],
+                    &ExecutorJobTest { number: 99 },
+                )
+                .unwrap();
+
+                let tasks = fang_tasks::table
+                    .filter(fang_tasks::batch_id.eq(batch.id))
+                    .load::<Task>(connection)
+                    .unwrap();
+                assert_eq!(2, tasks.len());
+
+                for task in tasks {
+                    let status = Queue::batch_status_query(connection, batch.id).unwrap();
+                    assert_eq!(None, status.callback_task_id);
+
+                    let _ = executor.run(task);
+                }
+
+                let status = Queue::batch_status_query(connection, batch.id).unwrap();
+                assert_eq!((1, 1, 0), (status.finished, status.failed, status.pending));
+
+                let callback_task =
+                    Queue::find_task_by_id_query(connection, status.callback_task_id.unwrap())
+                        .unwrap()
+                        .unwrap();
+                assert_eq!(Some(99), callback_task.metadata["number"].as_i64());
+                assert_eq!(FangTaskState::New, callback_task.state);
+
+                let empty_batch = Queue::push_batch_query(
+                    connection,
+                    "empty",
+                    &[],
+                    &ExecutorJobTest { number: 100 },
+                )
+                .unwrap();
+                assert!(empty_batch.callback_task_id.is_some());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn passes_worker_data_to_tasks() {
+        let new_task = NewTask {
+            metadata: serialize(&WorkerDataJob {}),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_worker_data(Box::new("https://api.example.com".to_string()));
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert!(executor.run(task).is_ok());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn runs_jobs_with_the_job_connection() {
+        let new_task = NewTask {
+            metadata: serialize(&BackendPidJob {}),
+            task_type: "common".to_string(),
+        };
+
+        let job_connection = pooled_connection();
+        let job_pid = backend_pid(&job_connection);
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_job_connection(job_connection);
+        executor.set_worker_data(Box::new(job_pid));
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert!(executor.run(task).is_ok());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    #[ignore]
+    fn executes_task_only_of_specific_type() {
+        let job1 = JobType1 {};
+        let job2 = JobType2 {};
+
+        let new_task1 = NewTask {
+            metadata: serialize(&job1),
+            task_type: "type1".to_string(),
+        };
+
+        let new_task2 = NewTask {
+            metadata: serialize(&job2),
+            task_type: "type2".to_string(),
+        };
+
+        let executor = Executor::new(pooled_connection());
+
+        let task1 = Queue::insert_query(&*executor.pooled_connection, &new_task1).unwrap();
+        let task2 = Queue::insert_query(&*executor.pooled_connection, &new_task2).unwrap();
+
+        assert_eq!(FangTaskState::New, task1.state);
+        assert_eq!(FangTaskState::New, task2.state);
+
+        std::thread::spawn(move || {
+            let mut executor = Executor::new(pooled_connection());
+            executor.set_retention_mode(RetentionMode::KeepAll);
+            executor.set_task_type("type1".to_string());
+
+            executor.run_tasks().unwrap();
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+
+        let found_task1 = Queue::find_task_by_id_query(&*executor.pooled_connection, task1.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(FangTaskState::Finished, found_task1.state);
+
+        let found_task2 = Queue::find_task_by_id_query(&*executor.pooled_connection, task2.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(FangTaskState::New, found_task2.state);
+    }
+
+    #[test]
+    fn saves_error_for_failed_task() {
+        let job = FailedJob { number: 10 };
+
+        let new_task = NewTask {
+            metadata: serialize(&job),
+            task_type: "common".to_string(),
+        };
+
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_eq!(FangTaskState::New, task.state);
+
+                let result = executor.run(task.clone());
+                assert_matches!(result, Err(FangError::TaskError(_)));
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Failed, found_task.state);
+                assert_eq!(
                     "the number is 10".to_string(),
                     found_task.error_message.unwrap()
                 );
@@ -395,7 +2171,1043 @@ mod executor_tests {
             });
     }
 
+    #[test]
+    fn converts_job_errors_with_the_question_mark_operator() {
+        let new_task = NewTask {
+            metadata: serialize(&ParseJob {
+                input: "ten".to_string(),
+            }),
+            task_type: "common".to_string(),
+        };
+
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_matches!(executor.run(task.clone()), Err(FangError::TaskError(_)));
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Failed, found_task.state);
+                assert_eq!(
+                    Some("invalid digit found in string".to_string()),
+                    found_task.error_message
+                );
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn remove_failed_keeps_finished_tasks() {
+        let finished_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let failed_task = NewTask {
+            metadata: serialize(&FailedJob { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::RemoveFailed);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let finished_task =
+                    Queue::insert_query(&*executor.pooled_connection, &finished_task).unwrap();
+                let failed_task =
+                    Queue::insert_query(&*executor.pooled_connection, &failed_task).unwrap();
+
+                executor.run(finished_task.clone()).unwrap();
+                assert_matches!(
+                    executor.run(failed_task.clone()),
+                    Err(FangError::TaskError(_))
+                );
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, finished_task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Finished, found_task.state);
+                assert_eq!(
+                    None,
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, failed_task.id)
+                        .unwrap()
+                );
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn archive_finished_moves_finished_tasks_to_the_archive() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::ArchiveFinished);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                executor.run(task.clone()).unwrap();
+
+                assert_eq!(
+                    None,
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id).unwrap()
+                );
+
+                let archived_task =
+                    Queue::find_archived_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Finished, archived_task.state);
+                assert!(archived_task.duration_in_milliseconds.is_some());
+                assert!(archived_task.finished_at.is_some());
+
+                Ok(())
+            });
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct AuditedJob {}
+
+    #[typetag::serde]
+    impl Runnable for AuditedJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn retention(&self) -> Option<RetentionMode> {
+            Some(RetentionMode::KeepAll)
+        }
+    }
+
+    #[test]
+    fn job_retention_overrides_the_retention_mode() {
+        let new_task = NewTask {
+            metadata: serialize(&AuditedJob {}),
+            task_type: "common".to_string(),
+        };
+
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                executor.run(task.clone()).unwrap();
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn reconnects_after_losing_the_connection() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_reconnect_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(10),
+            max_attempts: 3,
+        });
+
+        // Stops the executor once it reconnected
+        let stop_signal = Arc::new(AtomicBool::new(false));
+        executor.set_stop_signal(stop_signal.clone());
+        executor.set_connection_source(Box::new(move || {
+            stop_signal.store(true, Ordering::SeqCst);
+
+            Ok(pooled_connection())
+        }));
+
+        terminate_backend(&executor.pooled_connection);
+
+        executor.run_tasks().unwrap();
+
+        assert!(executor.pooled_connection.execute("SELECT 1").is_ok());
+    }
+
+    #[test]
+    fn returns_connection_lost_without_a_connection_source() {
+        let mut executor = Executor::new(pooled_connection());
+
+        terminate_backend(&executor.pooled_connection);
+
+        assert_matches!(executor.run_tasks(), Err(FangError::ConnectionLost { .. }));
+    }
+
+    #[test]
+    fn returns_errors_of_finalizing_tasks_after_losing_the_connection() {
+        let new_task = NewTask {
+            metadata: serialize(&DisconnectingJob {}),
+            task_type: "disconnecting".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_task_type("disconnecting".to_string());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_matches!(executor.run_task(), Err(FangError::DbError(_)));
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn run_task_batch_finalizes_all_claimed_tasks() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "batch".to_string(),
+        };
+
+        let failed_task = NewTask {
+            metadata: serialize(&FailedJob { number: 10 }),
+            task_type: "batch".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_task_type("batch".to_string());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task1 = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+                let task2 =
+                    Queue::insert_query(&*executor.pooled_connection, &failed_task).unwrap();
+
+                assert_eq!(2, executor.run_task_batch(10).unwrap());
+
+                let found_task1 =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task1.id)
+                        .unwrap()
+                        .unwrap();
+                let found_task2 =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task2.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Finished, found_task1.state);
+                assert_eq!(FangTaskState::Failed, found_task2.state);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn applies_each_update_of_the_shared_params() {
+        let mut worker_params = WorkerParams::new();
+        worker_params.set_task_type("reload".to_string());
+
+        let shared_params = Arc::new(RwLock::new((0, ReloadableParams::new(&worker_params))));
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_shared_params(shared_params.clone());
+
+        executor.reload_params().unwrap();
+        assert_eq!(Some("reload".to_string()), executor.task_type);
+        assert_eq!(RetentionMode::RemoveFinished, executor.retention_mode);
+
+        executor.set_task_type("local".to_string());
+        executor.reload_params().unwrap();
+        assert_eq!(Some("local".to_string()), executor.task_type);
+
+        {
+            let mut shared_params = shared_params.write().unwrap();
+            shared_params.0 += 1;
+            shared_params.1.task_type = None;
+            shared_params.1.retention_mode = RetentionMode::KeepAll;
+        }
+
+        executor.reload_params().unwrap();
+        assert_eq!(None, executor.task_type);
+        assert_eq!(RetentionMode::KeepAll, executor.retention_mode);
+    }
+
+    #[test]
+    fn paused_pools_put_claimed_batches_back() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "batch".to_string(),
+        };
+
+        let shared_state = Arc::new(RwLock::new(WorkerState::Paused));
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_task_type("batch".to_string());
+        executor.set_shared_state(shared_state.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task1 = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+                let task2 = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_eq!(2, executor.run_task_batch(10).unwrap());
+
+                for id in [task1.id, task2.id] {
+                    let task =
+                        Queue::find_task_by_id_query(&*executor.pooled_connection, id).unwrap();
+                    assert_eq!(FangTaskState::New, task.unwrap().state);
+                }
+
+                *shared_state.write().unwrap() = WorkerState::Draining;
+                assert_eq!(2, executor.run_task_batch(10).unwrap());
+
+                for id in [task1.id, task2.id] {
+                    let task =
+                        Queue::find_task_by_id_query(&*executor.pooled_connection, id).unwrap();
+                    assert_eq!(FangTaskState::Finished, task.unwrap().state);
+                }
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn deferred_finalization_buffers_finished_tasks() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_deferred_finalization(DeferredFinalization {
+            max_tasks: 2,
+            max_delay: Duration::from_secs(60),
+        });
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+                let task1 = Queue::insert_query(connection, &new_task).unwrap();
+                let task2 = Queue::insert_query(connection, &new_task).unwrap();
+                let task3 = Queue::insert_query(connection, &new_task).unwrap();
+
+                executor.run(task1.clone()).unwrap();
+
+                let found_task = Queue::find_task_by_id_query(connection, task1.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::New, found_task.state);
+
+                executor.run(task2.clone()).unwrap();
+
+                for task in &[&task1, &task2] {
+                    let found_task = Queue::find_task_by_id_query(connection, task.id)
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(FangTaskState::Finished, found_task.state);
+                }
+
+                executor.run(task3.clone()).unwrap();
+                executor.flush_finished_tasks().unwrap();
+
+                let found_task = Queue::find_task_by_id_query(connection, task3.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn holds_tasks_without_valid_signature() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let signing_key = SigningKey::new(b"secret");
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_signing_key(signing_key.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let unsigned_task = Queue::insert_query(connection, &new_task).unwrap();
+                let signed_task =
+                    Queue::insert_signed_query(connection, &new_task, Some(&signing_key)).unwrap();
+
+                assert_matches!(
+                    executor.run(unsigned_task.clone()),
+                    Err(FangError::TaskError(_))
+                );
+                executor.run(signed_task.clone()).unwrap();
+
+                let found_task = Queue::find_task_by_id_query(connection, unsigned_task.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Held, found_task.state);
+
+                let found_task = Queue::find_task_by_id_query(connection, signed_task.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                Ok(())
+            });
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct NarrowingJob {
+        pub days: i32,
+    }
+
+    #[typetag::serde]
+    impl Runnable for NarrowingJob {
+        fn run(&self, _connection: &PgConnection, context: &TaskContext) -> Result<(), Error> {
+            context.snooze(Duration::from_secs(3600));
+
+            Err(Error {
+                description: format!("{} days are too many", self.days),
+            })
+        }
+
+        fn on_retry(&self, attempt: i32, _error: &Error) -> Option<Box<dyn Runnable>> {
+            Some(Box::new(NarrowingJob {
+                days: self.days / (attempt + 1),
+            }))
+        }
+
+        fn task_type(&self) -> String {
+            "on_retry_test".to_string()
+        }
+    }
+
+    #[test]
+    fn stores_the_job_returned_by_on_retry() {
+        let signing_key = SigningKey::new(b"secret");
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_task_type("on_retry_test".to_string());
+        executor.set_signing_key(signing_key.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let mut queue = Queue::new_with_connection(&*executor.pooled_connection);
+                queue.set_signing_key(signing_key.clone());
+
+                let task = queue.push_task(&NarrowingJob { days: 8 }).unwrap();
+
+                assert_matches!(executor.run_task(), Err(FangError::TaskError(_)));
+
+                let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+
+                assert_eq!(FangTaskState::New, found_task.state);
+                assert_eq!(serialize(&NarrowingJob { days: 4 }), found_task.metadata);
+                assert!(signing_key.verify(
+                    &found_task.task_type,
+                    &found_task.metadata,
+                    found_task.signature.as_deref()
+                ));
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn calls_on_success_and_on_failure_hooks() {
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                for fail in &[false, true] {
+                    let new_task = NewTask {
+                        metadata: serialize(&HookJob { fail: *fail }),
+                        task_type: "common".to_string(),
+                    };
+
+                    let task = Queue::insert_query(connection, &new_task).unwrap();
+                    let _result = executor.run(task);
+                }
+
+                let success_task = Queue::fetch_task_query(connection, &Some("on_success".into()));
+                let failure_task = Queue::fetch_task_query(connection, &Some("failed".into()));
+
+                assert!(success_task.is_some());
+                assert!(failure_task.is_some());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn held_task_is_not_failed() {
+        let new_task = NewTask {
+            metadata: serialize(&HeldJob {}),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::RemoveAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_matches!(executor.run(task.clone()), Err(FangError::TaskError(_)));
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Held, found_task.state);
+                assert_eq!(
+                    "needs attention".to_string(),
+                    found_task.error_message.unwrap()
+                );
+
+                Ok(())
+            });
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct MaintenanceWindowJob {}
+
+    #[typetag::serde]
+    impl Runnable for MaintenanceWindowJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            panic!("run must not be called when the preflight check fails");
+        }
+
+        fn preflight(&self, _context: &TaskContext) -> Result<(), PreflightError> {
+            Err(PreflightError {
+                description: "maintenance window".to_string(),
+                retry_in: Duration::from_secs(600),
+            })
+        }
+
+        fn task_type(&self) -> String {
+            "preflight_test".to_string()
+        }
+    }
+
+    #[test]
+    fn failed_preflight_snoozes_task() {
+        let new_task = NewTask {
+            metadata: serialize(&MaintenanceWindowJob {}),
+            task_type: "preflight_test".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_task_type("preflight_test".to_string());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert_matches!(executor.run_task(), Err(FangError::TaskError(_)));
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::New, found_task.state);
+                assert_eq!(
+                    Some("maintenance window".to_string()),
+                    found_task.error_message
+                );
+                assert!(
+                    found_task.scheduled_at.unwrap() > Utc::now() + chrono::Duration::minutes(9)
+                );
+
+                // Not fetched again before `scheduled_at`
+                assert!(executor.run_task().unwrap().is_none());
+
+                Ok(())
+            });
+    }
+
+    struct NothingClaimStrategy;
+
+    impl ClaimStrategy for NothingClaimStrategy {
+        fn claim(
+            &self,
+            _connection: &PgConnection,
+            _params: &ClaimParams,
+            _limit: i64,
+        ) -> Result<Vec<Task>, diesel::result::Error> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn defers_tasks_of_throttled_types() {
+        let new_task = NewTask {
+            metadata: serialize(&JobType1 {}),
+            task_type: "type1".to_string(),
+        };
+
+        let mut rate_limiter = RateLimiter::new();
+        rate_limiter.set_limit("type1".to_string(), RateLimit::per_minute(1));
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_rate_limiter(rate_limiter);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let task1 = Queue::insert_query(connection, &new_task).unwrap();
+                let task2 = Queue::insert_query(connection, &new_task).unwrap();
+
+                // Both are claimed, the second one goes back to the queue
+                assert_eq!(1, executor.run_task_batch(2).unwrap());
+
+                let states: Vec<FangTaskState> = vec![task1.id, task2.id]
+                    .into_iter()
+                    .map(|id| {
+                        Queue::find_task_by_id_query(connection, id)
+                            .unwrap()
+                            .unwrap()
+                            .state
+                    })
+                    .collect();
+                assert!(states.contains(&FangTaskState::Finished));
+                assert!(states.contains(&FangTaskState::New));
+
+                // Now the type is throttled and isn't claimed at all
+                assert_eq!(0, executor.run_task_batch(2).unwrap());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn fails_tasks_that_cannot_be_deserialized() {
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let task = Queue::insert_query(
+                    connection,
+                    &NewTask {
+                        metadata: serde_json::json!({"type": "RemovedJob"}),
+                        task_type: "common".to_string(),
+                    },
+                )
+                .unwrap();
+
+                assert_matches!(executor.run(task.clone()), Err(FangError::TaskError(_)));
+
+                let found_task = Queue::find_task_by_id_query(connection, task.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Failed, found_task.state);
+                assert!(found_task
+                    .error_message
+                    .unwrap()
+                    .starts_with("Failed to deserialize the task"));
+
+                Ok(())
+            });
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct VersionedJob {
+        pub count: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for VersionedJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn version(&self) -> i32 {
+            2
+        }
+    }
+
+    struct VersionedJobUpgrader;
+
+    impl TaskUpgrader for VersionedJobUpgrader {
+        fn job_name(&self) -> &str {
+            "VersionedJob"
+        }
+
+        fn version(&self) -> i32 {
+            2
+        }
+
+        fn upgrade(
+            &self,
+            _old_version: i32,
+            mut metadata: serde_json::Value,
+        ) -> Result<serde_json::Value, Error> {
+            let amount = metadata["amount"].take();
+            metadata["count"] = amount;
+
+            Ok(metadata)
+        }
+    }
+
+    #[test]
+    fn upgrades_tasks_of_older_versions() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.add_upgrader(Arc::new(VersionedJobUpgrader));
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let old_task = Queue::insert_query(
+                    connection,
+                    &NewTask {
+                        metadata: serde_json::json!({"type": "VersionedJob", "amount": 3}),
+                        task_type: "common".to_string(),
+                    },
+                )
+                .unwrap();
+                let task = Queue::push_task_query(connection, &VersionedJob { count: 3 }).unwrap();
+
+                assert_eq!(1, old_task.task_version);
+                assert_eq!(2, task.task_version);
+
+                for task in [old_task, task] {
+                    executor.run(task.clone()).unwrap();
+
+                    let found_task = Queue::find_task_by_id_query(connection, task.id)
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(FangTaskState::Finished, found_task.state);
+                }
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn holds_jobs_that_are_not_allowed() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_allowed_job_names(vec!["JobType1".to_string()]);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+
+                let allowed_task = Queue::insert_query(
+                    connection,
+                    &NewTask {
+                        metadata: serialize(&JobType1 {}),
+                        task_type: "common".to_string(),
+                    },
+                )
+                .unwrap();
+                let other_task = Queue::insert_query(
+                    connection,
+                    &NewTask {
+                        metadata: serialize(&JobType2 {}),
+                        task_type: "common".to_string(),
+                    },
+                )
+                .unwrap();
+
+                executor.run(allowed_task.clone()).unwrap();
+                assert_matches!(
+                    executor.run(other_task.clone()),
+                    Err(FangError::TaskError(_))
+                );
+
+                let found_task = Queue::find_task_by_id_query(connection, allowed_task.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Finished, found_task.state);
+
+                let found_task = Queue::find_task_by_id_query(connection, other_task.id)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(FangTaskState::Held, found_task.state);
+                assert_eq!(
+                    Some("Job JobType2 is not allowed in this worker".to_string()),
+                    found_task.error_message
+                );
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn skips_task_types_in_maintenance_window() {
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_check_maintenance_windows(true);
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let connection = &*executor.pooled_connection;
+                let now = Utc::now();
+
+                Queue::add_maintenance_window_query(
+                    connection,
+                    &NewMaintenanceWindow {
+                        task_type: "type1".to_string(),
+                        weekday: now.weekday().number_from_monday() as i16,
+                        starts_at: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+                        ends_at: NaiveTime::from_hms_micro_opt(23, 59, 59, 999_999).unwrap(),
+                    },
+                )
+                .unwrap();
+
+                Queue::insert_query(
+                    connection,
+                    &NewTask {
+                        metadata: serialize(&JobType1 {}),
+                        task_type: "type1".to_string(),
+                    },
+                )
+                .unwrap();
+
+                assert!(executor.run_task().unwrap().is_none());
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn uses_custom_claim_strategy() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_claim_strategy(Arc::new(NothingClaimStrategy));
+
+        let connection = pooled_connection();
+
+        connection.test_transaction::<(), Error, _>(|| {
+            Queue::insert_query(&*connection, &new_task).unwrap();
+
+            assert!(executor.run_task().unwrap().is_none());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn counts_empty_claims_caused_by_locked_tasks() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "claim_metrics_test".to_string(),
+        };
+
+        let claim_metrics = Arc::new(ClaimMetrics::default());
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_task_type("claim_metrics_test".to_string());
+        executor.set_claim_strategy(Arc::new(NothingClaimStrategy));
+        executor.set_claim_metrics(claim_metrics.clone());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                assert!(executor.run_task().unwrap().is_none());
+                assert_eq!(0, claim_metrics.stats().empty_due_to_locks);
+
+                Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                assert!(executor.run_task().unwrap().is_none());
+
+                let stats = claim_metrics.stats();
+                assert!(stats.last_claim_at.is_some());
+                assert_eq!(2, stats.attempts);
+                assert_eq!(1, stats.empty_due_to_locks);
+
+                Ok(())
+            });
+    }
+
+    struct RecordingMiddleware {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TaskMiddleware for RecordingMiddleware {
+        fn call(&self, _task: &Task, next: Next) -> Result<(), Error> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} before", self.name));
+            let result = next.run();
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} after", self.name));
+
+            result
+        }
+    }
+
+    #[test]
+    fn middlewares_wrap_task_execution_in_order() {
+        let new_task = NewTask {
+            metadata: serialize(&ExecutorJobTest { number: 10 }),
+            task_type: "common".to_string(),
+        };
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+
+        let mut executor = Executor::new(pooled_connection());
+
+        for name in &["first", "second"] {
+            executor.add_middleware(Arc::new(RecordingMiddleware {
+                name,
+                calls: calls.clone(),
+            }));
+        }
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                executor.run(task).unwrap();
+
+                Ok(())
+            });
+
+        assert_eq!(
+            vec![
+                "first before",
+                "second before",
+                "second after",
+                "first after"
+            ],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn cancelled_task_keeps_its_state() {
+        let job = CancellableJob {};
+
+        let new_task = NewTask {
+            metadata: serialize(&job),
+            task_type: "common".to_string(),
+        };
+
+        let executor = Executor::new(pooled_connection());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                Queue::cancel_task_query(&*executor.pooled_connection, task.id).unwrap();
+
+                let result = executor.run(task.clone());
+                assert_matches!(result, Err(FangError::TaskError(_)));
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Cancelled, found_task.state);
+                assert_eq!(None, found_task.error_message);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn database_errors_while_checking_for_cancellation_fail_the_task() {
+        let new_task = NewTask {
+            metadata: serialize(&CancellableJob {}),
+            task_type: "common".to_string(),
+        };
+
+        let mut executor = Executor::new(pooled_connection());
+        executor.set_retention_mode(RetentionMode::KeepAll);
+        executor.set_job_connection(pooled_connection());
+        terminate_backend(executor.job_connection.as_ref().unwrap());
+
+        executor
+            .pooled_connection
+            .test_transaction::<(), Error, _>(|| {
+                let task = Queue::insert_query(&*executor.pooled_connection, &new_task).unwrap();
+
+                let result = executor.run(task.clone());
+                assert_matches!(result, Err(FangError::TaskError(_)));
+
+                let found_task =
+                    Queue::find_task_by_id_query(&*executor.pooled_connection, task.id)
+                        .unwrap()
+                        .unwrap();
+
+                assert_eq!(FangTaskState::Failed, found_task.state);
+
+                Ok(())
+            });
+    }
+
     fn pooled_connection() -> PooledConnection<ConnectionManager<PgConnection>> {
         Queue::connection_pool(5).get().unwrap()
     }
+
+    fn terminate_backend(connection: &PgConnection) {
+        let pid = diesel::select(sql::<Integer>("pg_backend_pid()"))
+            .get_result::<i32>(connection)
+            .unwrap();
+
+        diesel::select(sql::<Bool>(&format!("pg_terminate_backend({})", pid)))
+            .get_result::<bool>(&pooled_connection() as &PgConnection)
+            .unwrap();
+
+        // The backend exits asynchronously
+        std::thread::sleep(Duration::from_millis(100));
+    }
 }
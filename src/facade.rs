@@ -0,0 +1,304 @@
+use crate::config::FangConfig;
+use crate::diesel::r2d2;
+use crate::diesel::PgConnection;
+use crate::error::FangError;
+use crate::queue::Queue;
+use crate::runnable::Runnable;
+use crate::scheduler::Scheduler;
+use crate::scheduler::SchedulerHandle;
+use crate::schedules::ScheduleRegistry;
+use crate::worker_pool::WorkerParams;
+use crate::worker_pool::WorkerPool;
+use std::time::Duration;
+
+/// How often the scheduler of a `Fang` checks for due periodic tasks unless
+/// `FangBuilder::scheduler` says otherwise.
+pub const DEFAULT_CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+/// How far from a check periodic tasks of a `Fang` are enqueued by it unless
+/// `FangBuilder::scheduler` says otherwise.
+pub const DEFAULT_ERROR_MARGIN: Duration = Duration::from_secs(5);
+
+/// A worker pool and, if there are periodic tasks, a scheduler, started and stopped together:
+///
+/// ```ignore
+/// let mut fang = Fang::builder()
+///     .workers(4)
+///     .with_schedule(&SyncJob::default(), 120)
+///     .build()?
+///     .start()?;
+///
+/// // ...
+///
+/// fang.shutdown()?;
+/// ```
+///
+/// For worker data or several pools, wire `WorkerPool` and `Scheduler` up by hand.
+pub struct Fang {
+    worker_pool: WorkerPool<()>,
+    /// Taken by `start`
+    scheduler: Option<Scheduler<PgConnection>>,
+    scheduler_handle: Option<SchedulerHandle>,
+}
+
+pub struct FangBuilder {
+    config: Option<FangConfig>,
+    number_of_workers: u32,
+    worker_params: WorkerParams,
+    schedule_registry: ScheduleRegistry,
+    /// `check_period` and `error_margin`, set if the scheduler runs without schedules too
+    scheduler: Option<(Duration, Duration)>,
+    /// The first error of `with_schedule`, returned by `build`
+    error: Option<FangError>,
+}
+
+impl Fang {
+    pub fn builder() -> FangBuilder {
+        FangBuilder {
+            config: None,
+            number_of_workers: 1,
+            worker_params: WorkerParams::new(),
+            schedule_registry: ScheduleRegistry::new(),
+            scheduler: None,
+            error: None,
+        }
+    }
+
+    /// Start the workers, which makes `fang_periodic_tasks` match the schedules, then the
+    /// scheduler. A `Fang` that was shut down can't be started again.
+    pub fn start(mut self) -> Result<Self, FangError> {
+        self.worker_pool.start()?;
+
+        if let Some(scheduler) = self.scheduler.take() {
+            let scheduler_handle = scheduler.start();
+
+            // `WorkerPool::shutdown` stops the scheduler before the workers
+            self.worker_pool
+                .attach_scheduler(scheduler_handle.clone())?;
+            self.scheduler_handle = Some(scheduler_handle);
+        }
+
+        Ok(self)
+    }
+
+    /// Stop the scheduler, so no more periodic tasks are enqueued, then the workers once they
+    /// finished their tasks.
+    pub fn shutdown(&mut self) -> Result<(), FangError> {
+        self.worker_pool.shutdown()
+    }
+
+    /// Block until `shutdown` is called, e.g. by the handler of
+    /// `WorkerPool::install_signal_handler`, see `WorkerPool::join`.
+    pub fn join(&self) -> Result<(), FangError> {
+        self.worker_pool.join()
+    }
+
+    /// E.g. to scale, pause or inspect the workers.
+    pub fn worker_pool(&mut self) -> &mut WorkerPool<()> {
+        &mut self.worker_pool
+    }
+
+    /// `None` before `start` and without a scheduler.
+    pub fn scheduler(&self) -> Option<&SchedulerHandle> {
+        self.scheduler_handle.as_ref()
+    }
+
+    /// The pool the workers take their connections from.
+    pub fn connection_pool(&self) -> &r2d2::Pool<r2d2::ConnectionManager<PgConnection>> {
+        &self.worker_pool.connection_pool
+    }
+}
+
+impl FangBuilder {
+    /// Build the connection pools from `config` instead of `FangConfig::from_env`.
+    pub fn config(mut self, config: FangConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// 1 by default.
+    pub fn workers(mut self, number_of_workers: u32) -> Self {
+        self.number_of_workers = number_of_workers;
+        self
+    }
+
+    /// The schedule registry of `worker_params` is replaced with the schedules of
+    /// `with_schedule`, if there are any.
+    pub fn worker_params(mut self, worker_params: WorkerParams) -> Self {
+        self.worker_params = worker_params;
+        self
+    }
+
+    /// Run `job` every `period_in_seconds`, see `ScheduleRegistry::register`. Starts a
+    /// scheduler with `DEFAULT_CHECK_PERIOD` and `DEFAULT_ERROR_MARGIN` unless `scheduler`
+    /// is set. Invalid schedules make `build` fail.
+    pub fn with_schedule(mut self, job: &dyn Runnable, period_in_seconds: i32) -> Self {
+        if let Err(error) = self.schedule_registry.register(job, period_in_seconds) {
+            self.error.get_or_insert(error);
+        }
+
+        self
+    }
+
+    /// Run a scheduler with these periods, with or without `with_schedule`, e.g. for periodic
+    /// tasks pushed with `Queue::push_periodic_task`.
+    pub fn scheduler(mut self, check_period: Duration, error_margin: Duration) -> Self {
+        self.scheduler = Some((check_period, error_margin));
+        self
+    }
+
+    /// Fails if a schedule is invalid, the configuration can't be read from the environment
+    /// or the database can't be reached.
+    pub fn build(mut self) -> Result<Fang, FangError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let config = match self.config {
+            Some(config) => config,
+            None => FangConfig::from_env()?,
+        };
+
+        let has_schedules = !self.schedule_registry.schedules().is_empty();
+
+        if has_schedules {
+            self.worker_params
+                .set_schedule_registry(self.schedule_registry);
+        }
+
+        let scheduler_periods = match self.scheduler {
+            None if has_schedules => Some((DEFAULT_CHECK_PERIOD, DEFAULT_ERROR_MARGIN)),
+            scheduler_periods => scheduler_periods,
+        };
+
+        let scheduler = match scheduler_periods {
+            Some((check_period, error_margin)) => {
+                let mut scheduler = Scheduler::new(
+                    check_period,
+                    error_margin,
+                    Queue::try_new_with_config(&config)?,
+                );
+                scheduler.set_config(config.clone());
+
+                Some(scheduler)
+            }
+            None => None,
+        };
+
+        let worker_pool = WorkerPool::try_new_with_config(
+            self.number_of_workers,
+            self.worker_params,
+            None,
+            config,
+        )?;
+
+        Ok(Fang {
+            worker_pool,
+            scheduler,
+            scheduler_handle: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod facade_tests {
+    use super::Fang;
+    use crate::config::FangConfig;
+    use crate::context::TaskContext;
+    use crate::context::WorkerState;
+    use crate::error::FangError;
+    use crate::executor::SleepParams;
+    use crate::runnable::Error;
+    use crate::runnable::Runnable;
+    use crate::typetag;
+    use crate::worker_pool::WorkerParams;
+    use assert_matches::assert_matches;
+    use diesel::pg::PgConnection;
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize)]
+    struct FacadeJob {}
+
+    #[typetag::serde]
+    impl Runnable for FacadeJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "facade_test".to_string()
+        }
+    }
+
+    fn test_params() -> WorkerParams {
+        let mut worker_params = WorkerParams::new();
+        worker_params.set_task_type("facade_test".to_string());
+        worker_params.set_sleep_params(
+            SleepParams::builder()
+                .min_sleep_period(Duration::ZERO)
+                .max_sleep_period(Duration::ZERO)
+                .sleep_step(Duration::ZERO)
+                .build()
+                .unwrap(),
+        );
+
+        worker_params
+    }
+
+    #[test]
+    fn shutdown_stops_the_scheduler_and_the_workers() {
+        let mut fang = Fang::builder()
+            .workers(2)
+            .worker_params(test_params())
+            .scheduler(Duration::from_secs(60), Duration::from_secs(5))
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert_eq!(WorkerState::Running, fang.worker_pool().state().unwrap());
+        let scheduler = fang.scheduler().unwrap().clone();
+        assert!(!scheduler.is_stopped());
+
+        fang.shutdown().unwrap();
+
+        assert!(scheduler.is_stopped());
+        assert_eq!(WorkerState::Shutdown, fang.worker_pool().state().unwrap());
+    }
+
+    #[test]
+    fn runs_without_a_scheduler() {
+        let mut fang = Fang::builder()
+            .worker_params(test_params())
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+
+        assert!(fang.scheduler().is_none());
+
+        fang.shutdown().unwrap();
+    }
+
+    #[test]
+    fn invalid_schedules_fail_the_build() {
+        let result = Fang::builder()
+            .with_schedule(&FacadeJob {}, 60)
+            .with_schedule(&FacadeJob {}, 30)
+            .build();
+
+        assert_matches!(result.err(), Some(FangError::InvalidSchedules(_)));
+    }
+
+    #[test]
+    fn unreachable_databases_fail_the_build() {
+        let result = Fang::builder()
+            .config(FangConfig::new(
+                "postgres://fang@127.0.0.1:1/fang".to_string(),
+            ))
+            .build();
+
+        assert_matches!(result.err(), Some(FangError::ConnectionError(_)));
+    }
+}
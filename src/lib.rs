@@ -1,22 +1,96 @@
 #![allow(clippy::nonstandard_macro_braces)]
+#![allow(clippy::result_large_err)]
+#![allow(non_local_definitions)]
 
 #[macro_use]
 extern crate diesel;
 
+// Lets the code generated by `fang_derive` refer to `fang::` inside this crate
+extern crate self as fang;
+
+#[cfg(feature = "runtime")]
+pub mod autoscaler;
+pub mod claim;
+pub mod compression;
+#[cfg(feature = "runtime")]
+pub mod concurrency_limit;
+pub mod config;
+pub mod context;
+pub mod encryption;
 pub mod error;
+#[cfg(feature = "runtime")]
 pub mod executor;
+#[cfg(feature = "runtime")]
+pub mod facade;
+pub mod log_level;
+pub mod maintenance;
+#[cfg(feature = "runtime")]
+pub mod metrics;
+#[cfg(feature = "runtime")]
+pub mod middleware;
+pub mod outbox;
+pub mod payload_store;
+#[cfg(feature = "runtime")]
+pub mod prune;
 pub mod queue;
+#[cfg(feature = "runtime")]
+pub mod rate_limit;
+pub mod runnable;
+#[cfg(feature = "runtime")]
 pub mod scheduler;
+pub mod schedules;
 pub mod schema;
+pub mod serializer;
+pub mod signing;
+pub mod task_query;
+pub mod transactional;
+pub mod versioning;
+#[cfg(feature = "runtime")]
 pub mod worker_pool;
 
+#[cfg(feature = "runtime")]
+pub use autoscaler::*;
+pub use claim::*;
+pub use compression::*;
+#[cfg(feature = "runtime")]
+pub use concurrency_limit::*;
+pub use config::{FangConfig, SleepParams, SleepParamsBuilder};
+pub use context::*;
+pub use encryption::*;
 pub use error::FangError;
+#[cfg(feature = "runtime")]
 pub use executor::*;
+#[cfg(feature = "runtime")]
+pub use facade::*;
+pub use log_level::{log_level, set_log_level, LogModule};
+pub use maintenance::*;
+#[cfg(feature = "runtime")]
+pub use metrics::*;
+#[cfg(feature = "runtime")]
+pub use middleware::*;
+pub use outbox::*;
+pub use payload_store::*;
+#[cfg(feature = "runtime")]
+pub use prune::*;
 pub use queue::*;
+#[cfg(feature = "runtime")]
+pub use rate_limit::*;
+pub use runnable::*;
+#[cfg(feature = "runtime")]
 pub use scheduler::*;
+pub use schedules::*;
 pub use schema::*;
+pub use serializer::*;
+pub use signing::*;
+pub use task_query::*;
+pub use transactional::*;
+pub use versioning::*;
+#[cfg(feature = "runtime")]
 pub use worker_pool::*;
 
+#[cfg(feature = "derive")]
+pub use fang_derive::FangTask;
+
 #[doc(hidden)]
 pub use diesel::pg::PgConnection;
 #[doc(hidden)]
@@ -0,0 +1,80 @@
+use log::{Level, LevelFilter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Parts of fang whose log output can be adjusted while the application runs, see
+/// `set_log_level`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LogModule {
+    Executor,
+    Scheduler,
+    Queue,
+    /// Also covers the autoscaler and the signal handler
+    WorkerPool,
+}
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+static LEVELS: [AtomicUsize; 4] = [
+    AtomicUsize::new(DEFAULT_LEVEL as usize),
+    AtomicUsize::new(DEFAULT_LEVEL as usize),
+    AtomicUsize::new(DEFAULT_LEVEL as usize),
+    AtomicUsize::new(DEFAULT_LEVEL as usize),
+];
+
+/// Pass records of `module` up to `level` to the logger, `Info` by default. Takes effect
+/// immediately in every thread, so a production incident can be debugged without a redeploy.
+///
+/// The application's logger still applies its own filter: to be able to raise the level at
+/// runtime, let it accept fang's debug output (e.g. `RUST_LOG=info,fang=debug`).
+pub fn set_log_level(module: LogModule, level: LevelFilter) {
+    LEVELS[module as usize].store(level as usize, Ordering::Relaxed);
+}
+
+pub fn log_level(module: LogModule) -> LevelFilter {
+    match LEVELS[module as usize].load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+pub fn log_enabled(module: LogModule, level: Level) -> bool {
+    level <= log_level(module)
+}
+
+/// `log::log!` behind the level of a `LogModule`.
+macro_rules! fang_log {
+    ($module:expr, $level:expr, $($arg:tt)+) => {
+        if $crate::log_level::log_enabled($module, $level) {
+            ::log::log!($level, $($arg)+);
+        }
+    };
+}
+
+pub(crate) use fang_log;
+
+#[cfg(test)]
+mod log_level_tests {
+    use super::log_enabled;
+    use super::log_level;
+    use super::set_log_level;
+    use super::LogModule;
+    use log::{Level, LevelFilter};
+
+    #[test]
+    fn levels_are_set_per_module() {
+        set_log_level(LogModule::Queue, LevelFilter::Trace);
+
+        assert!(log_enabled(LogModule::Queue, Level::Debug));
+        assert!(!log_enabled(LogModule::Scheduler, Level::Debug));
+        assert_eq!(LevelFilter::Info, log_level(LogModule::Scheduler));
+
+        set_log_level(LogModule::Queue, LevelFilter::Off);
+        assert!(!log_enabled(LogModule::Queue, Level::Error));
+
+        set_log_level(LogModule::Queue, LevelFilter::Info);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::schema::fang_maintenance_windows;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::NaiveTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use uuid::Uuid;
+
+/// A weekly window during which tasks of `task_type` aren't fetched and periodic tasks of that
+/// type aren't enqueued. `weekday` is 1 (Monday) to 7 (Sunday), times are UTC. A window can't
+/// cross midnight, split it into two.
+#[derive(Queryable, Identifiable, Debug, Eq, PartialEq, Clone)]
+#[table_name = "fang_maintenance_windows"]
+pub struct MaintenanceWindow {
+    pub id: Uuid,
+    pub task_type: String,
+    pub weekday: i16,
+    pub starts_at: NaiveTime,
+    pub ends_at: NaiveTime,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[table_name = "fang_maintenance_windows"]
+pub struct NewMaintenanceWindow {
+    pub task_type: String,
+    pub weekday: i16,
+    pub starts_at: NaiveTime,
+    pub ends_at: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, at: DateTime<Utc>) -> bool {
+        let time = at.time();
+
+        i64::from(at.weekday().number_from_monday()) == i64::from(self.weekday)
+            && self.starts_at <= time
+            && time < self.ends_at
+    }
+
+    /// The end of the window on the day of `at`.
+    pub fn ends_after(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&at.naive_utc().date().and_time(self.ends_at))
+    }
+}
+
+/// Task types with a window active at `at`.
+pub fn paused_task_types(windows: &[MaintenanceWindow], at: DateTime<Utc>) -> Vec<String> {
+    let mut task_types: Vec<String> = windows
+        .iter()
+        .filter(|window| window.is_active(at))
+        .map(|window| window.task_type.clone())
+        .collect();
+
+    task_types.sort();
+    task_types.dedup();
+
+    task_types
+}
+
+/// When the last window of `task_type` active at `at` ends, if there is one.
+pub fn paused_until(
+    windows: &[MaintenanceWindow],
+    task_type: &str,
+    at: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    windows
+        .iter()
+        .filter(|window| window.task_type == task_type && window.is_active(at))
+        .map(|window| window.ends_after(at))
+        .max()
+}
+
+#[cfg(test)]
+mod maintenance_tests {
+    use super::paused_task_types;
+    use super::paused_until;
+    use super::MaintenanceWindow;
+    use chrono::NaiveTime;
+    use chrono::TimeZone;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn window(task_type: &str, weekday: i16, starts_at: u32, ends_at: u32) -> MaintenanceWindow {
+        MaintenanceWindow {
+            id: Uuid::new_v4(),
+            task_type: task_type.to_string(),
+            weekday,
+            starts_at: NaiveTime::from_hms_opt(starts_at, 0, 0).unwrap(),
+            ends_at: NaiveTime::from_hms_opt(ends_at, 0, 0).unwrap(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn windows_are_active_on_their_weekday_and_time() {
+        // Sunday 2021-12-12 and the Monday after
+        let sunday_2am = Utc.with_ymd_and_hms(2021, 12, 12, 2, 0, 0).unwrap();
+        let sunday_3am = Utc.with_ymd_and_hms(2021, 12, 12, 3, 0, 0).unwrap();
+        let monday_2am = Utc.with_ymd_and_hms(2021, 12, 13, 2, 0, 0).unwrap();
+
+        let windows = vec![window("report", 7, 1, 3), window("report", 7, 2, 4)];
+
+        assert_eq!(
+            vec!["report".to_string()],
+            paused_task_types(&windows, sunday_2am)
+        );
+        assert!(!windows[0].is_active(sunday_3am));
+        assert!(paused_task_types(&windows, monday_2am).is_empty());
+
+        assert_eq!(
+            Some(Utc.with_ymd_and_hms(2021, 12, 12, 4, 0, 0).unwrap()),
+            paused_until(&windows, "report", sunday_2am)
+        );
+        assert_eq!(None, paused_until(&windows, "email", sunday_2am));
+    }
+}
@@ -0,0 +1,60 @@
+use chrono::{DateTime, TimeZone, Utc};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// Task counters of one worker, shared with the pool through `WorkerPool::metrics`.
+#[derive(Debug, Default)]
+pub struct WorkerMetrics {
+    processed: AtomicU64,
+    failed: AtomicU64,
+    retried: AtomicU64,
+    /// Milliseconds since the epoch, 0 before the first task
+    last_activity_at: AtomicI64,
+}
+
+/// A snapshot of `WorkerMetrics`.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct WorkerStats {
+    /// Tasks that finished successfully
+    pub processed: u64,
+    /// Tasks that failed or were held
+    pub failed: u64,
+    /// Tasks that were snoozed to run again later
+    pub retried: u64,
+    /// When the worker last finished running a task, whatever the outcome
+    pub last_activity_at: Option<DateTime<Utc>>,
+}
+
+impl WorkerMetrics {
+    pub fn record_processed(&self) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Update the last activity time without counting a task, e.g. for cancelled tasks.
+    pub fn touch(&self) {
+        self.last_activity_at
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            processed: self.processed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            last_activity_at: match self.last_activity_at.load(Ordering::Relaxed) {
+                0 => None,
+                millis => Utc.timestamp_millis_opt(millis).single(),
+            },
+        }
+    }
+}
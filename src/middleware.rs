@@ -0,0 +1,66 @@
+use crate::context::TaskContext;
+use crate::executor::Error;
+use crate::executor::Runnable;
+use crate::queue::Task;
+use diesel::pg::PgConnection;
+use std::sync::Arc;
+
+/// Wraps every `Runnable::run` call, for cross-cutting concerns like timing, error reporting,
+/// transactions or tenant setup. Middlewares run in the order they were added, each one
+/// decides whether and when to call `next.run()`.
+pub trait TaskMiddleware: Send + Sync {
+    fn call(&self, task: &Task, next: Next) -> Result<(), Error>;
+}
+
+/// The rest of the middleware chain, ending with the task itself.
+pub struct Next<'a> {
+    runnable: &'a dyn Runnable,
+    connection: &'a PgConnection,
+    context: &'a TaskContext<'a>,
+    middlewares: &'a [Arc<dyn TaskMiddleware>],
+    task: &'a Task,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        runnable: &'a dyn Runnable,
+        connection: &'a PgConnection,
+        context: &'a TaskContext<'a>,
+        middlewares: &'a [Arc<dyn TaskMiddleware>],
+        task: &'a Task,
+    ) -> Self {
+        Self {
+            runnable,
+            connection,
+            context,
+            middlewares,
+            task,
+        }
+    }
+
+    /// The connection the task runs with
+    pub fn connection(&self) -> &'a PgConnection {
+        self.connection
+    }
+
+    pub fn context(&self) -> &'a TaskContext<'a> {
+        self.context
+    }
+
+    pub fn run(self) -> Result<(), Error> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                let task = self.task;
+
+                middleware.call(
+                    task,
+                    Next {
+                        middlewares,
+                        ..self
+                    },
+                )
+            }
+            None => self.runnable.run(self.connection, self.context),
+        }
+    }
+}
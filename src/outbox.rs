@@ -0,0 +1,51 @@
+use crate::context::TaskContext;
+use crate::log_level::{fang_log, LogModule};
+use crate::queue::Queue;
+use crate::runnable::Error;
+use crate::runnable::Runnable;
+use crate::schema::fang_outbox;
+use chrono::DateTime;
+use chrono::Utc;
+use diesel::pg::PgConnection;
+use log::Level;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A job staged with `Queue::stage_task`, moved into `fang_tasks` by `Queue::relay_outbox`.
+#[derive(Queryable, Identifiable, Debug, Eq, PartialEq, Clone)]
+#[table_name = "fang_outbox"]
+pub struct OutboxEntry {
+    pub id: Uuid,
+    pub metadata: serde_json::Value,
+    pub task_type: String,
+    pub task_version: i32,
+    /// Signature of the task enqueued for the entry, see `Queue::stage_task`
+    pub signature: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Built-in job that moves up to `batch_size` staged jobs from `fang_outbox` into
+/// `fang_tasks`, oldest first. Usually pushed as a periodic task, see `Queue::stage_task`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayOutbox {
+    pub batch_size: i64,
+}
+
+#[typetag::serde]
+impl Runnable for RelayOutbox {
+    fn run(&self, connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+        let tasks =
+            Queue::relay_outbox_query(connection, self.batch_size).map_err(|error| Error {
+                description: format!("Failed to relay the outbox: {:?}", error),
+            })?;
+
+        fang_log!(
+            LogModule::Queue,
+            Level::Debug,
+            "relayed {} tasks from the outbox",
+            tasks.len()
+        );
+
+        Ok(())
+    }
+}
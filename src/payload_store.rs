@@ -0,0 +1,76 @@
+use crate::runnable::Error;
+use crate::serializer::payload_digest;
+use std::fs;
+use std::path::PathBuf;
+
+/// Keeps payloads that are too large for the database, e.g. in S3 or on a shared disk, see
+/// `Queue::set_payload_store`. Only the reference returned by `put` is stored in the task's
+/// `payload_reference`.
+///
+/// Blobs aren't deleted by fang, use the store's own lifecycle rules to expire them.
+pub trait PayloadStore: Send + Sync {
+    fn put(&self, payload: &[u8]) -> Result<String, Error>;
+
+    fn get(&self, reference: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Stores payloads as files in a directory, named by their SHA-256, so pushing the same
+/// payload twice writes one file.
+#[derive(Debug, Clone)]
+pub struct FilePayloadStore {
+    directory: PathBuf,
+}
+
+impl FilePayloadStore {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    fn path(&self, reference: &str) -> Result<PathBuf, Error> {
+        // References are file names, never paths
+        if reference.is_empty() || !reference.chars().all(|char| char.is_ascii_hexdigit()) {
+            return Err(Error {
+                description: format!("Invalid payload reference {}", reference),
+            });
+        }
+
+        Ok(self.directory.join(reference))
+    }
+}
+
+impl PayloadStore for FilePayloadStore {
+    fn put(&self, payload: &[u8]) -> Result<String, Error> {
+        let reference = payload_digest(payload);
+
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.path(&reference)?, payload)?;
+
+        Ok(reference)
+    }
+
+    fn get(&self, reference: &str) -> Result<Vec<u8>, Error> {
+        Ok(fs::read(self.path(reference)?)?)
+    }
+}
+
+#[cfg(test)]
+mod payload_store_tests {
+    use super::FilePayloadStore;
+    use super::PayloadStore;
+
+    #[test]
+    fn file_store_round_trips_payloads() {
+        let directory = std::env::temp_dir().join("fang_payload_store_tests");
+        let store = FilePayloadStore::new(&directory);
+
+        let reference = store.put(b"large payload").unwrap();
+
+        assert_eq!(reference, store.put(b"large payload").unwrap());
+        assert_eq!(b"large payload".to_vec(), store.get(&reference).unwrap());
+        assert!(store.get("../etc/passwd").is_err());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
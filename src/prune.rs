@@ -0,0 +1,43 @@
+use crate::context::TaskContext;
+use crate::executor::Error;
+use crate::executor::Runnable;
+use crate::log_level::{fang_log, LogModule};
+use crate::queue::Queue;
+use chrono::Duration;
+use diesel::pg::PgConnection;
+use log::Level;
+use serde::{Deserialize, Serialize};
+
+/// How often the periodic task registered by `Scheduler::set_prune_tasks_older_than` runs.
+pub const PRUNE_TASKS_PERIOD_IN_SECONDS: i32 = 3600;
+
+/// Built-in job that removes finished and failed tasks that weren't updated for
+/// `older_than_seconds`, so `fang_tasks` doesn't grow unbounded with `RetentionMode::KeepAll`.
+/// Usually registered by the scheduler, see `Scheduler::set_prune_tasks_older_than`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PruneTasks {
+    pub older_than_seconds: i64,
+}
+
+#[typetag::serde]
+impl Runnable for PruneTasks {
+    fn run(&self, connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+        let removed = Queue::remove_tasks_older_than_query(
+            connection,
+            Duration::seconds(self.older_than_seconds),
+        )
+        .map_err(|error| Error {
+            description: format!("Failed to prune tasks: {:?}", error),
+        })?;
+
+        fang_log!(
+            LogModule::Queue,
+            Level::Info,
+            "pruned {} tasks older than {} seconds",
+            removed,
+            self.older_than_seconds
+        );
+
+        Ok(())
+    }
+}
@@ -1,18 +1,140 @@
-use crate::executor::Runnable;
+use crate::claim::ClaimParams;
+use crate::compression::Compression;
+use crate::config::FangConfig;
+use crate::error::FangError;
+use crate::log_level::{fang_log, LogModule};
+use crate::maintenance::MaintenanceWindow;
+use crate::maintenance::NewMaintenanceWindow;
+use crate::outbox::OutboxEntry;
+use crate::payload_store::PayloadStore;
+use crate::runnable::Runnable;
+use crate::schedules::ScheduleDefinition;
+use crate::schedules::ScheduleDiff;
+use crate::schema::fang_archived_tasks;
+use crate::schema::fang_batches;
+use crate::schema::fang_maintenance_windows;
+use crate::schema::fang_outbox;
 use crate::schema::fang_periodic_tasks;
+use crate::schema::fang_task_dependencies;
 use crate::schema::fang_tasks;
+use crate::schema::fang_uniq_keys;
 use crate::schema::FangTaskState;
+use crate::schema::OverlapPolicy;
+use crate::serializer::payload_metadata;
+use crate::serializer::JsonSerializer;
+use crate::serializer::TaskSerializer;
+use crate::signing::SigningKey;
+use crate::task_query::TaskQuery;
+use crate::task_query::TaskStream;
 use chrono::DateTime;
 use chrono::Duration;
 use chrono::Utc;
+use diesel::dsl::sql;
+use diesel::expression::{AppearsOnTable, Expression, NonAggregate};
+use diesel::pg::Pg;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
 use diesel::r2d2;
 use diesel::result::Error;
-use dotenv::dotenv;
-use std::env;
-use uuid::Uuid;
+use diesel::sql_types::Array;
+use diesel::sql_types::Bool;
+use diesel::sql_types::Jsonb;
+use diesel::sql_types::Text;
+use diesel::sql_types::Timestamptz;
+use log::Level;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// The module, file and line it's called from, for `Queue::push_task_with_origin`:
+///
+/// ```ignore
+/// queue.push_task_with_origin(&job, fang::origin!())?;
+/// ```
+#[macro_export]
+macro_rules! origin {
+    () => {
+        concat!(module_path!(), " (", file!(), ":", line!(), ")")
+    };
+}
+
+type TaskPredicate<'a> = Box<dyn BoxableExpression<fang_tasks::table, Pg, SqlType = Bool> + 'a>;
+
+/// `fang_tasks.id IN (<query>)`. Diesel's `eq_any` doesn't accept a subselect with a locking
+/// clause, which claiming needs to skip rows locked by other workers.
+#[derive(Debug, Clone, Copy)]
+struct IdIn<Q>(Q);
+
+impl<Q> Expression for IdIn<Q> {
+    type SqlType = Bool;
+}
+
+impl<Q> NonAggregate for IdIn<Q> {}
+
+impl<Q> AppearsOnTable<fang_tasks::table> for IdIn<Q> {}
+
+impl<Q> QueryId for IdIn<Q> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for IdIn<Q> {
+    fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+        out.push_sql("fang_tasks.id IN (");
+        self.0.walk_ast(out.reborrow())?;
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+/// The columns `fang_tasks` and `fang_archived_tasks` have in common.
+const ARCHIVED_COLUMNS: &str = "id, metadata, error_message, state, task_type, created_at, \
+    updated_at, duration_in_milliseconds, signature, scheduled_at, origin, errors, attempts, \
+    started_at, finished_at, payload, payload_format, payload_encoding, payload_reference, \
+    task_version, idempotency_key, uniq_key, next_task_id, batch_id, \
+    ordering_key";
+
+/// Inserts the rows of the `archived` CTE into `fang_archived_tasks`. Earlier archived tasks
+/// with the same id, e.g. of a reused idempotency key with deterministic ids, are kept.
+fn insert_archived_sql() -> String {
+    format!(
+        "INSERT INTO fang_archived_tasks ({columns}) SELECT {columns} FROM archived",
+        columns = ARCHIVED_COLUMNS
+    )
+}
+
+const WAIT_FOR_TASK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Namespace of the ids derived by `idempotency_task_id`.
+pub const IDEMPOTENCY_KEY_NAMESPACE: Uuid =
+    Uuid::from_u128(0xf755_df85_f8ac_431f_90b1_31ef_dabd_9b37);
+
+/// The id a queue with `Queue::set_deterministic_ids` gives the task it pushes with
+/// `idempotency_key`: a UUIDv5 of the key, so clients can poll the task without looking its
+/// id up first.
+pub fn idempotency_task_id(idempotency_key: &str) -> Uuid {
+    Uuid::new_v5(&IDEMPOTENCY_KEY_NAMESPACE, idempotency_key.as_bytes())
+}
+
+/// A UUIDv7: the current Unix time in milliseconds followed by random bits. Ids created in
+/// later milliseconds sort after earlier ones, ids created in the same millisecond in random
+/// order. With the `uuid-v7` feature, tasks inserted by `Queue` get these ids, tasks enqueued
+/// with the `fang_enqueue` SQL function don't.
+pub fn time_ordered_id() -> Uuid {
+    let millis = u128::try_from(Utc::now().timestamp_millis()).unwrap_or(0) & 0xffff_ffff_ffff;
+    let random = fastrand::u128(..);
+    let rand_a = (random >> 64) & 0xfff;
+    let rand_b = random & ((1 << 62) - 1);
+
+    Uuid::from_u128((millis << 80) | (0x7 << 76) | (rand_a << 64) | (0b10 << 62) | rand_b)
+}
 
 #[derive(Queryable, Identifiable, Debug, Eq, PartialEq, Clone)]
 #[table_name = "fang_tasks"]
@@ -24,6 +146,136 @@ pub struct Task {
     pub task_type: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// How long the last execution took, measured with a monotonic clock by the executor.
+    /// Not recorded for tasks finalized in batches (fast path, deferred finalization).
+    pub duration_in_milliseconds: Option<i64>,
+    /// HMAC of the metadata, see `SigningKey`
+    pub signature: Option<String>,
+    /// The task isn't fetched before this time, see `Queue::schedule_task` and
+    /// `Runnable::preflight`
+    pub scheduled_at: Option<DateTime<Utc>>,
+    /// The code path that enqueued the task, see `Queue::push_task_with_origin`
+    pub origin: Option<String>,
+    /// Every error the task failed or was held with, oldest first, see `Task::error_history`.
+    /// `error_message` is the last one.
+    pub errors: serde_json::Value,
+    /// How often the task was picked up
+    pub attempts: i32,
+    /// When the task was last picked up
+    pub started_at: Option<DateTime<Utc>>,
+    /// When the task was last finished, failed or held
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The worker holding the lease on the task, see `LeaseClaimStrategy`
+    pub locked_by: Option<String>,
+    /// When the lease expires and other workers may claim the task again
+    pub locked_until: Option<DateTime<Utc>>,
+    /// Name of the thread that last picked up the task, e.g. `worker_common0`
+    pub worker_name: Option<String>,
+    /// The job encoded by a `TaskSerializer`, `metadata` only holds its type then
+    pub payload: Option<Vec<u8>>,
+    /// `TaskSerializer::format` of the payload
+    pub payload_format: Option<String>,
+    /// How the payload is compressed, see `Queue::set_compression`
+    pub payload_encoding: Option<String>,
+    /// Where the payload is kept instead of `payload`, see `Queue::set_payload_store`
+    pub payload_reference: Option<String>,
+    /// `Runnable::version` of the job when it was enqueued
+    pub task_version: i32,
+    /// See `Queue::push_task_with_idempotency_key`
+    pub idempotency_key: Option<String>,
+    /// See `Queue::push_task_with_uniq_key`
+    pub uniq_key: Option<String>,
+    /// The task enqueued when this one finishes, see `Queue::push_chain`
+    pub next_task_id: Option<Uuid>,
+    /// See `Queue::push_batch`
+    pub batch_id: Option<Uuid>,
+    /// See `Queue::push_task_with_ordering_key`
+    pub ordering_key: Option<String>,
+}
+
+/// An entry of `Task::errors`.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct ErrorRecord {
+    pub message: String,
+    pub at: DateTime<Utc>,
+    /// 1 for the first error of the task, 2 for the second and so on
+    pub attempt: i32,
+}
+
+impl Task {
+    pub fn error_history(&self) -> Vec<ErrorRecord> {
+        serde_json::from_value(self.errors.clone()).unwrap_or_default()
+    }
+}
+
+/// A task moved into `fang_archived_tasks`, see `RetentionMode::ArchiveFinished` and
+/// `Queue::archive_tasks_older_than`. The fields are the ones of `Task`.
+#[derive(Queryable, Debug, Eq, PartialEq, Clone)]
+pub struct ArchivedTask {
+    pub id: Uuid,
+    pub metadata: serde_json::Value,
+    pub error_message: Option<String>,
+    pub state: FangTaskState,
+    pub task_type: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub duration_in_milliseconds: Option<i64>,
+    pub signature: Option<String>,
+    pub scheduled_at: Option<DateTime<Utc>>,
+    pub origin: Option<String>,
+    pub errors: serde_json::Value,
+    pub attempts: i32,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub archived_at: DateTime<Utc>,
+    pub payload: Option<Vec<u8>>,
+    pub payload_format: Option<String>,
+    pub payload_encoding: Option<String>,
+    pub payload_reference: Option<String>,
+    pub task_version: i32,
+    pub idempotency_key: Option<String>,
+    pub uniq_key: Option<String>,
+    pub next_task_id: Option<Uuid>,
+    pub batch_id: Option<Uuid>,
+    pub ordering_key: Option<String>,
+    /// Identifies the row, a task with a deterministic id can be archived more than once
+    pub archive_id: Uuid,
+}
+
+impl From<ArchivedTask> for Task {
+    /// The lease and worker aren't archived, they're `None`.
+    fn from(archived: ArchivedTask) -> Self {
+        Self {
+            id: archived.id,
+            metadata: archived.metadata,
+            error_message: archived.error_message,
+            state: archived.state,
+            task_type: archived.task_type,
+            created_at: archived.created_at,
+            updated_at: archived.updated_at,
+            duration_in_milliseconds: archived.duration_in_milliseconds,
+            signature: archived.signature,
+            scheduled_at: archived.scheduled_at,
+            origin: archived.origin,
+            errors: archived.errors,
+            attempts: archived.attempts,
+            started_at: archived.started_at,
+            finished_at: archived.finished_at,
+            locked_by: None,
+            locked_until: None,
+            worker_name: None,
+            payload: archived.payload,
+            payload_format: archived.payload_format,
+            payload_encoding: archived.payload_encoding,
+            payload_reference: archived.payload_reference,
+            task_version: archived.task_version,
+            idempotency_key: archived.idempotency_key,
+            uniq_key: archived.uniq_key,
+            next_task_id: archived.next_task_id,
+            batch_id: archived.batch_id,
+            ordering_key: archived.ordering_key,
+        }
+    }
 }
 
 #[derive(Queryable, Identifiable, Debug, Eq, PartialEq, Clone)]
@@ -35,6 +287,95 @@ pub struct PeriodicTask {
     pub scheduled_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// The task is removed after this many executions, see
+    /// `Queue::push_periodic_task_with_end`
+    pub max_runs: Option<i32>,
+    pub runs: i32,
+    /// The task isn't enqueued at or after this time and is removed
+    pub ends_at: Option<DateTime<Utc>>,
+    pub overlap_policy: OverlapPolicy,
+    /// `Runnable::task_type` of the job
+    pub task_type: String,
+}
+
+/// Tasks pushed together with `Queue::push_batch`, and the callback enqueued once all of them
+/// finished or failed.
+#[derive(Queryable, QueryableByName, Identifiable, Debug, Eq, PartialEq, Clone)]
+#[table_name = "fang_batches"]
+pub struct Batch {
+    pub id: Uuid,
+    pub name: String,
+    /// Number of tasks in the batch
+    pub size: i32,
+    pub finished_count: i32,
+    pub failed_count: i32,
+    pub callback_metadata: serde_json::Value,
+    pub callback_task_type: String,
+    pub callback_signature: Option<String>,
+    /// Set once the callback is enqueued
+    pub callback_task_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Progress of a batch, see `Queue::batch_status`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BatchStatus {
+    pub size: i32,
+    pub finished: i32,
+    pub failed: i32,
+    /// Tasks that neither finished nor failed yet
+    pub pending: i32,
+    pub callback_task_id: Option<Uuid>,
+}
+
+impl Batch {
+    /// Whether all tasks finished or failed.
+    pub fn is_complete(&self) -> bool {
+        self.finished_count + self.failed_count >= self.size
+    }
+
+    pub fn status(&self) -> BatchStatus {
+        BatchStatus {
+            size: self.size,
+            finished: self.finished_count,
+            failed: self.failed_count,
+            pending: (self.size - self.finished_count - self.failed_count).max(0),
+            callback_task_id: self.callback_task_id,
+        }
+    }
+}
+
+impl PeriodicTask {
+    /// Whether the task has no executions left at `time`.
+    pub fn is_exhausted(&self, time: DateTime<Utc>) -> bool {
+        self.max_runs.is_some_and(|max_runs| self.runs >= max_runs)
+            || self.ends_at.is_some_and(|ends_at| time >= ends_at)
+    }
+
+    /// When the next `n` executions are due as of `now`, fewer if the task ends before. A
+    /// task the scheduler hasn't seen yet is first due one period after it's picked up.
+    /// Maintenance windows and the overlap policy aren't taken into account.
+    pub fn next_runs(&self, n: usize, now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let period = Duration::seconds(self.period_in_seconds.into());
+        let mut run = self.scheduled_at.unwrap_or(now + period);
+        let mut runs = self.runs;
+        let mut next_runs = Vec::with_capacity(n);
+
+        while next_runs.len() < n {
+            if self.max_runs.is_some_and(|max_runs| runs >= max_runs)
+                || self.ends_at.is_some_and(|ends_at| run >= ends_at)
+            {
+                break;
+            }
+
+            next_runs.push(run);
+            run += period;
+            runs += 1;
+        }
+
+        next_runs
+    }
 }
 
 #[derive(Insertable)]
@@ -49,6 +390,7 @@ pub struct NewTask {
 pub struct NewPeriodicTask {
     pub metadata: serde_json::Value,
     pub period_in_seconds: i32,
+    pub task_type: String,
 }
 
 pub struct Queue<Conn>
@@ -56,6 +398,146 @@ where
     Conn: Borrow<PgConnection>,
 {
     pub connection: Conn,
+    pub signing_key: Option<SigningKey>,
+    /// Whether `push_task` and `push_task_with_origin` look for an identical pending task
+    /// before inserting. Enabled by default.
+    pub dedup: bool,
+    pub serializer: Option<Arc<dyn TaskSerializer>>,
+    pub compression: Option<Compression>,
+    pub payload_store: Option<Arc<dyn PayloadStore>>,
+    pub offload_threshold_in_bytes: usize,
+    /// How long `push_task_with_uniq_key` doesn't enqueue another task with the same key,
+    /// by task type
+    pub dedup_windows: HashMap<String, Duration>,
+    /// Whether `push_task_with_idempotency_key` derives task ids from the keys, see
+    /// `set_deterministic_ids`
+    pub deterministic_ids: bool,
+}
+
+/// Connects a new queue, see `Queue::factory`.
+pub type QueueFactory = Arc<dyn Fn() -> Result<Queue<PgConnection>, FangError> + Send + Sync>;
+
+/// A job encoded by a `TaskSerializer`.
+struct Payload {
+    bytes: Vec<u8>,
+    format: &'static str,
+    encoding: Option<&'static str>,
+    /// Set when `bytes` are kept in a `PayloadStore` instead of the database
+    reference: Option<String>,
+}
+
+/// How jobs are stored, see `Queue::set_serializer`, `Queue::set_compression` and
+/// `Queue::set_payload_store`.
+#[derive(Default)]
+struct Encoder<'a> {
+    serializer: Option<&'a dyn TaskSerializer>,
+    compression: Option<Compression>,
+    payload_store: Option<&'a dyn PayloadStore>,
+    offload_threshold_in_bytes: usize,
+}
+
+impl Encoder<'_> {
+    /// Without a serializer jobs are stored as JSON in `metadata`, unless they're compressed
+    /// or offloaded.
+    fn encode(&self, job: &dyn Runnable) -> Result<(NewTask, Option<Payload>), FangError> {
+        let serializer = match self.serializer {
+            Some(serializer) => serializer,
+            None if self.compression.is_some() || self.payload_store.is_some() => &JsonSerializer,
+            None => {
+                let new_task = NewTask {
+                    metadata: serde_json::to_value(job)?,
+                    task_type: job.task_type(),
+                };
+
+                return Ok((new_task, None));
+            }
+        };
+
+        let (bytes, job_digest) = serializer
+            .serialize_with_digest(job)
+            .map_err(|error| FangError::PayloadSerializationError(error.description))?;
+
+        let mut payload = Payload {
+            bytes,
+            format: serializer.format(),
+            encoding: None,
+            reference: None,
+        };
+
+        if let Some(compression) = self.compression {
+            if payload.bytes.len() >= compression.threshold_in_bytes {
+                payload.bytes = compression
+                    .algorithm
+                    .compress(&payload.bytes)
+                    .map_err(|error| FangError::PayloadSerializationError(error.description))?;
+                payload.encoding = Some(compression.algorithm.encoding());
+            }
+        }
+
+        if let Some(payload_store) = self.payload_store {
+            if payload.bytes.len() >= self.offload_threshold_in_bytes {
+                let reference = payload_store
+                    .put(&payload.bytes)
+                    .map_err(|error| FangError::PayloadSerializationError(error.description))?;
+                payload.reference = Some(reference);
+            }
+        }
+
+        // Small JSON jobs stay in `metadata`
+        if self.serializer.is_none() && payload.encoding.is_none() && payload.reference.is_none() {
+            let new_task = NewTask {
+                metadata: serde_json::from_slice(&payload.bytes)?,
+                task_type: job.task_type(),
+            };
+
+            return Ok((new_task, None));
+        }
+
+        let new_task = NewTask {
+            metadata: payload_metadata(job, &payload.bytes, job_digest),
+            task_type: job.task_type(),
+        };
+
+        Ok((new_task, Some(payload)))
+    }
+}
+
+/// The columns `insert_task_query` sets besides the ones of `NewTask`.
+struct InsertOptions<'a> {
+    /// Random if not set
+    id: Option<Uuid>,
+    payload: Option<&'a Payload>,
+    origin: Option<&'a str>,
+    scheduled_at: Option<DateTime<Utc>>,
+    task_version: i32,
+    idempotency_key: Option<&'a str>,
+    uniq_key: Option<&'a str>,
+    /// `new` if not set
+    state: Option<FangTaskState>,
+    next_task_id: Option<Uuid>,
+    batch_id: Option<Uuid>,
+    ordering_key: Option<&'a str>,
+    /// Used if there's no signing key, for tasks signed when they were created
+    signature: Option<&'a str>,
+}
+
+impl Default for InsertOptions<'_> {
+    fn default() -> Self {
+        Self {
+            id: None,
+            payload: None,
+            origin: None,
+            scheduled_at: None,
+            task_version: 1,
+            idempotency_key: None,
+            uniq_key: None,
+            state: None,
+            next_task_id: None,
+            batch_id: None,
+            ordering_key: None,
+            signature: None,
+        }
+    }
 }
 
 impl Default for Queue<PgConnection> {
@@ -65,29 +547,47 @@ impl Default for Queue<PgConnection> {
 }
 
 impl Queue<PgConnection> {
+    /// Connects to the database of `FangConfig::from_env`. Panics if it isn't set or the
+    /// connection fails, see `try_new`.
     pub fn new() -> Self {
-        let connection = Self::pg_connection(None);
+        Self::try_new().unwrap_or_else(|error| panic!("{}", error))
+    }
 
-        Self { connection }
+    pub fn try_new() -> Result<Self, FangError> {
+        Self::try_new_with_config(&FangConfig::from_env()?)
     }
 
     pub fn new_with_url(database_url: String) -> Self {
-        let connection = Self::pg_connection(Some(database_url));
+        Self::try_new_with_url(database_url).unwrap_or_else(|error| panic!("{}", error))
+    }
 
-        Self { connection }
+    pub fn try_new_with_url(database_url: String) -> Result<Self, FangError> {
+        Self::try_new_with_config(&FangConfig::new(database_url))
     }
 
-    pub fn connection_pool(pool_size: u32) -> r2d2::Pool<r2d2::ConnectionManager<PgConnection>> {
-        dotenv().ok();
+    pub fn new_with_config(config: &FangConfig) -> Self {
+        Self::try_new_with_config(config).unwrap_or_else(|error| panic!("{}", error))
+    }
 
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    pub fn try_new_with_config(config: &FangConfig) -> Result<Self, FangError> {
+        let connection = config.establish()?;
 
-        let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
+        Ok(Self::new_with_connection(connection))
+    }
 
-        r2d2::Pool::builder()
-            .max_size(pool_size)
-            .build(manager)
-            .unwrap()
+    /// A pool of `pool_size` connections to the database of `FangConfig::from_env`. Panics if
+    /// it isn't set or the pool can't connect, see `try_connection_pool`.
+    pub fn connection_pool(pool_size: u32) -> r2d2::Pool<r2d2::ConnectionManager<PgConnection>> {
+        Self::try_connection_pool(pool_size).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn try_connection_pool(
+        pool_size: u32,
+    ) -> Result<r2d2::Pool<r2d2::ConnectionManager<PgConnection>>, FangError> {
+        let mut config = FangConfig::from_env()?;
+        config.set_pool_size(pool_size);
+
+        config.connection_pool()
     }
 }
 
@@ -96,468 +596,3851 @@ where
     Conn: Borrow<PgConnection>,
 {
     pub fn new_with_connection(connection: Conn) -> Self {
-        Self { connection }
+        Self {
+            connection,
+            signing_key: None,
+            dedup: true,
+            serializer: None,
+            compression: None,
+            payload_store: None,
+            offload_threshold_in_bytes: 0,
+            dedup_windows: HashMap::new(),
+            deterministic_ids: false,
+        }
     }
 
-    pub fn push_task(&self, job: &dyn Runnable) -> Result<Task, Error> {
-        Self::push_task_query(&self.connection, job)
+    /// Sign every task inserted through this queue instance. The `_query` functions never
+    /// sign, whatever key the queue has, except `push_signed_task_query` and
+    /// `insert_signed_query`, which take the key as an argument.
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = Some(signing_key);
     }
 
-    pub fn push_task_query(connection: &Conn, job: &dyn Runnable) -> Result<Task, Error> {
-        let json_job = serde_json::to_value(job).unwrap();
+    /// Disable to make `push_task` and `push_task_with_origin` behave like
+    /// `push_task_unchecked`. The `_query` functions always deduplicate.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
 
-        match Self::find_task_by_metadata_query(connection, &json_job) {
-            Some(task) => Ok(task),
-            None => {
-                let new_task = NewTask {
-                    metadata: json_job.clone(),
-                    task_type: job.task_type(),
-                };
-                Self::insert_query(connection, &new_task)
-            }
-        }
+    /// Store jobs pushed through this queue instance with `serializer` in the `payload`
+    /// column instead of as JSON in `metadata`. Periodic tasks and the `_query` functions
+    /// always use JSON. Workers know `MessagePackSerializer` and `CborSerializer`, other
+    /// serializers have to be registered with `WorkerParams::add_serializer`.
+    pub fn set_serializer(&mut self, serializer: Arc<dyn TaskSerializer>) {
+        self.serializer = Some(serializer);
     }
 
-    pub fn push_periodic_task(
-        &self,
-        job: &dyn Runnable,
-        period: i32,
-    ) -> Result<PeriodicTask, Error> {
-        Self::push_periodic_task_query(&self.connection, job, period)
+    /// Compress jobs pushed through this queue instance whose encoding (JSON or the
+    /// serializer's) is at least `compression.threshold_in_bytes` long. Compressed jobs are
+    /// stored in the `payload` column, `payload_encoding` says how.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
     }
 
-    pub fn push_periodic_task_query(
-        connection: &Conn,
-        job: &dyn Runnable,
-        period: i32,
-    ) -> Result<PeriodicTask, Error> {
-        let json_job = serde_json::to_value(job).unwrap();
+    /// Keep payloads (after serialization and compression) of at least
+    /// `threshold_in_bytes` in `payload_store` instead of the database. Jobs pushed through
+    /// this queue instance are stored in the `payload` column like with `set_serializer`, as
+    /// JSON if no serializer is set. Workers need the same store, see
+    /// `WorkerParams::set_payload_store`.
+    pub fn set_payload_store(
+        &mut self,
+        payload_store: Arc<dyn PayloadStore>,
+        threshold_in_bytes: usize,
+    ) {
+        self.payload_store = Some(payload_store);
+        self.offload_threshold_in_bytes = threshold_in_bytes;
+    }
 
-        match Self::find_periodic_task_by_metadata_query(connection, &json_job) {
-            Some(task) => Ok(task),
-            None => {
-                let new_task = NewPeriodicTask {
-                    metadata: json_job,
-                    period_in_seconds: period,
-                };
+    /// Make `push_task_with_uniq_key` return the task enqueued with the same key within
+    /// `window` for tasks of `task_type`, whatever its state and even if it was removed, e.g.
+    /// to debounce jobs triggered by bursts of change notifications.
+    pub fn set_dedup_window(&mut self, task_type: String, window: Duration) {
+        self.dedup_windows.insert(task_type, window);
+    }
 
-                diesel::insert_into(fang_periodic_tasks::table)
-                    .values(new_task)
-                    .get_result::<PeriodicTask>(connection.borrow())
-            }
+    /// Give tasks pushed with `push_task_with_idempotency_key` through this queue instance
+    /// the id `idempotency_task_id` derives from their key instead of a random one. Tasks
+    /// pushed before keep their ids, the `_query` functions use random ids.
+    ///
+    /// Tasks pushed with `push_task_with_uniq_key` always get random ids: a uniq key only
+    /// dedups new and in-progress tasks (or the ones of its dedup window), so the next task
+    /// with the key is pushed while the finished one is still in `fang_tasks`, and both would
+    /// need the same id.
+    ///
+    /// On a partitioned `fang_tasks` the primary key is `(id, created_at)`, so only the lock
+    /// `push_task_with_idempotency_key` takes on the key keeps the ids unique. Tasks inserted
+    /// with the same id some other way aren't rejected.
+    pub fn set_deterministic_ids(&mut self, deterministic_ids: bool) {
+        self.deterministic_ids = deterministic_ids;
+    }
+
+    /// Connects queues with the settings of this one, e.g. its signing key and serializer, to
+    /// the database of `config`, or of `FangConfig::from_env` if it's `None`. Nothing is
+    /// connected before the factory is called.
+    pub fn factory(&self, config: Option<FangConfig>) -> QueueFactory {
+        let signing_key = self.signing_key.clone();
+        let dedup = self.dedup;
+        let serializer = self.serializer.clone();
+        let compression = self.compression;
+        let payload_store = self.payload_store.clone();
+        let offload_threshold_in_bytes = self.offload_threshold_in_bytes;
+        let dedup_windows = self.dedup_windows.clone();
+        let deterministic_ids = self.deterministic_ids;
+
+        Arc::new(move || {
+            let connection = match config {
+                Some(ref config) => config.establish()?,
+                None => FangConfig::from_env()?.establish()?,
+            };
+
+            Ok(Queue {
+                connection,
+                signing_key: signing_key.clone(),
+                dedup,
+                serializer: serializer.clone(),
+                compression,
+                payload_store: payload_store.clone(),
+                offload_threshold_in_bytes,
+                dedup_windows: dedup_windows.clone(),
+                deterministic_ids,
+            })
+        })
+    }
+
+    fn encoder(&self) -> Encoder<'_> {
+        Encoder {
+            serializer: self.serializer.as_deref(),
+            compression: self.compression,
+            payload_store: self.payload_store.as_deref(),
+            offload_threshold_in_bytes: self.offload_threshold_in_bytes,
         }
     }
 
-    pub fn enqueue_task(job: &dyn Runnable) -> Result<Task, Error> {
-        Queue::new().push_task(job)
+    pub fn push_task(&self, job: &dyn Runnable) -> Result<Task, FangError> {
+        Self::push_task_with_options_query(
+            &self.connection,
+            job,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+            None,
+            self.dedup,
+        )
     }
 
-    pub fn insert(&self, params: &NewTask) -> Result<Task, Error> {
-        Self::insert_query(&self.connection, params)
+    /// Never signs the task, see `push_signed_task_query`.
+    pub fn push_task_query(connection: &Conn, job: &dyn Runnable) -> Result<Task, FangError> {
+        Self::push_signed_task_query(connection, job, None)
     }
 
-    pub fn insert_query(connection: &Conn, params: &NewTask) -> Result<Task, Error> {
-        diesel::insert_into(fang_tasks::table)
-            .values(params)
-            .get_result::<Task>(connection.borrow())
+    pub fn push_signed_task_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_options_query(
+            connection,
+            job,
+            signing_key,
+            &Encoder::default(),
+            None,
+            true,
+        )
     }
 
-    pub fn fetch_task(&self, task_type: &Option<String>) -> Option<Task> {
-        Self::fetch_task_query(&self.connection, task_type)
+    /// Like `push_task`, but inserts without looking for an identical pending task first, so
+    /// the same job may be enqueued several times. Saves a query per task for producers that
+    /// don't need deduplication.
+    pub fn push_task_unchecked(&self, job: &dyn Runnable) -> Result<Task, FangError> {
+        Self::push_task_with_options_query(
+            &self.connection,
+            job,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+            None,
+            false,
+        )
     }
 
-    pub fn fetch_task_query(connection: &Conn, task_type: &Option<String>) -> Option<Task> {
-        match task_type {
-            None => Self::fetch_any_task_query(connection),
-            Some(task_type_str) => Self::fetch_task_of_type_query(connection, task_type_str),
-        }
+    pub fn push_task_unchecked_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_options_query(connection, job, None, &Encoder::default(), None, false)
     }
 
-    pub fn fetch_and_touch(&self, task_type: &Option<String>) -> Result<Option<Task>, Error> {
-        Self::fetch_and_touch_query(&self.connection, task_type)
+    /// Enqueue the job unless a task with the same `idempotency_key` exists, in which case
+    /// that task is returned, whatever its job. Unlike `push_task`, jobs aren't compared. Keys
+    /// are unique among the tasks in `fang_tasks`, they can be reused once their task is
+    /// removed.
+    pub fn push_task_with_idempotency_key(
+        &self,
+        job: &dyn Runnable,
+        idempotency_key: &str,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_idempotency_key_and_options_query(
+            &self.connection,
+            job,
+            idempotency_key,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+            self.deterministic_ids,
+        )
     }
 
-    pub fn fetch_and_touch_query(
+    pub fn push_task_with_idempotency_key_query(
         connection: &Conn,
-        task_type: &Option<String>,
-    ) -> Result<Option<Task>, Error> {
-        connection.borrow().transaction::<Option<Task>, Error, _>(|| {
-            let found_task = Self::fetch_task_query(connection, task_type);
+        job: &dyn Runnable,
+        idempotency_key: &str,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_idempotency_key_and_options_query(
+            connection,
+            job,
+            idempotency_key,
+            None,
+            &Encoder::default(),
+            false,
+        )
+    }
 
-            if found_task.is_none() {
-                return Ok(None);
+    fn push_task_with_idempotency_key_and_options_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        idempotency_key: &str,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+        deterministic_id: bool,
+    ) -> Result<Task, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        connection.borrow().transaction::<Task, FangError, _>(|| {
+            // Partitioned task tables can't have a unique index on the key, so pushes with the
+            // same key are serialized instead
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                .bind::<Text, _>(format!("fang:idempotency:{}", idempotency_key))
+                .execute(connection.borrow())?;
+
+            let task = fang_tasks::table
+                .filter(fang_tasks::idempotency_key.eq(idempotency_key))
+                .first::<Task>(connection.borrow())
+                .optional()?;
+
+            if let Some(task) = task {
+                return Ok(task);
             }
 
-            match Self::start_processing_task_query(connection, &found_task.unwrap()) {
-                Ok(updated_task) => Ok(Some(updated_task)),
-                Err(err) => Err(err),
-            }
+            let options = InsertOptions {
+                id: deterministic_id.then(|| idempotency_task_id(idempotency_key)),
+                payload: payload.as_ref(),
+                task_version: job.version(),
+                idempotency_key: Some(idempotency_key),
+                ..InsertOptions::default()
+            };
+
+            let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+            Ok(task)
         })
     }
 
-    pub fn find_task_by_id(&self, id: Uuid) -> Option<Task> {
-        Self::find_task_by_id_query(&self.connection, id)
+    /// Enqueue the job unless a task of the same task type was enqueued with `uniq_key`
+    /// within the type's dedup window (see `set_dedup_window`), in which case that task is
+    /// returned, or `None` if it was removed already. The time of the last enqueue of every
+    /// key is kept in `fang_uniq_keys`, which the retention mode doesn't touch. Without a
+    /// window only new and in-progress tasks count.
+    pub fn push_task_with_uniq_key(
+        &self,
+        job: &dyn Runnable,
+        uniq_key: &str,
+    ) -> Result<Option<Task>, FangError> {
+        let window = self.dedup_windows.get(&job.task_type()).copied();
+
+        Self::push_task_with_uniq_key_and_options_query(
+            &self.connection,
+            job,
+            uniq_key,
+            window,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
     }
 
-    pub fn find_task_by_id_query(connection: &Conn, id: Uuid) -> Option<Task> {
-        fang_tasks::table
-            .filter(fang_tasks::id.eq(id))
-            .first::<Task>(connection.borrow())
-            .ok()
+    pub fn push_task_with_uniq_key_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        uniq_key: &str,
+        window: Option<Duration>,
+    ) -> Result<Option<Task>, FangError> {
+        Self::push_task_with_uniq_key_and_options_query(
+            connection,
+            job,
+            uniq_key,
+            window,
+            None,
+            &Encoder::default(),
+        )
     }
 
-    pub fn find_periodic_task_by_id(&self, id: Uuid) -> Option<PeriodicTask> {
-        Self::find_periodic_task_by_id_query(&self.connection, id)
+    fn push_task_with_uniq_key_and_options_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        uniq_key: &str,
+        window: Option<Duration>,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Option<Task>, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        connection
+            .borrow()
+            .transaction::<Option<Task>, FangError, _>(|| {
+                // Serializes pushes with the same key, so they can't both miss each other
+                diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                    .bind::<Text, _>(format!("fang:{}:{}", new_task.task_type, uniq_key))
+                    .execute(connection.borrow())?;
+
+                match window {
+                    Some(window) => {
+                        let last_task_id = fang_uniq_keys::table
+                            .select(fang_uniq_keys::task_id)
+                            .filter(fang_uniq_keys::task_type.eq(&new_task.task_type))
+                            .filter(fang_uniq_keys::uniq_key.eq(uniq_key))
+                            .filter(fang_uniq_keys::enqueued_at.gt(Self::current_time() - window))
+                            .first::<Uuid>(connection.borrow())
+                            .optional()?;
+
+                        if let Some(last_task_id) = last_task_id {
+                            return Ok(Self::find_task_by_id_query(connection, last_task_id)?);
+                        }
+                    }
+                    None => {
+                        let pending_task = fang_tasks::table
+                            .filter(fang_tasks::task_type.eq(&new_task.task_type))
+                            .filter(fang_tasks::uniq_key.eq(uniq_key))
+                            .filter(
+                                fang_tasks::state
+                                    .eq(FangTaskState::New)
+                                    .or(fang_tasks::state.eq(FangTaskState::InProgress)),
+                            )
+                            .order(fang_tasks::created_at.desc())
+                            .first::<Task>(connection.borrow())
+                            .optional()?;
+
+                        if pending_task.is_some() {
+                            return Ok(pending_task);
+                        }
+                    }
+                }
+
+                let options = InsertOptions {
+                    payload: payload.as_ref(),
+                    task_version: job.version(),
+                    uniq_key: Some(uniq_key),
+                    ..InsertOptions::default()
+                };
+
+                let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+                diesel::insert_into(fang_uniq_keys::table)
+                    .values((
+                        fang_uniq_keys::task_type.eq(&task.task_type),
+                        fang_uniq_keys::uniq_key.eq(uniq_key),
+                        fang_uniq_keys::task_id.eq(task.id),
+                        fang_uniq_keys::enqueued_at.eq(task.created_at),
+                    ))
+                    .on_conflict((fang_uniq_keys::task_type, fang_uniq_keys::uniq_key))
+                    .do_update()
+                    .set((
+                        fang_uniq_keys::task_id.eq(task.id),
+                        fang_uniq_keys::enqueued_at.eq(task.created_at),
+                    ))
+                    .execute(connection.borrow())?;
+
+                Ok(Some(task))
+            })
     }
 
-    pub fn find_periodic_task_by_id_query(
-        connection: &Conn,
-        id: Uuid,
-    ) -> Option<PeriodicTask> {
-        fang_periodic_tasks::table
-            .filter(fang_periodic_tasks::id.eq(id))
-            .first::<PeriodicTask>(connection.borrow())
-            .ok()
+    /// Enqueue the jobs as a chain: only the first one runs right away, every other one is
+    /// `Waiting` until its predecessor finished successfully. Returns the tasks in the order
+    /// of `jobs`. Jobs aren't deduplicated.
+    pub fn push_chain(&self, jobs: &[&dyn Runnable]) -> Result<Vec<Task>, FangError> {
+        Self::push_chain_with_options_query(
+            &self.connection,
+            jobs,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
     }
 
-    pub fn fetch_periodic_tasks(&self, error_margin_seconds: i64) -> Option<Vec<PeriodicTask>> {
-        Self::fetch_periodic_tasks_query(&self.connection, error_margin_seconds)
+    pub fn push_chain_query(
+        connection: &Conn,
+        jobs: &[&dyn Runnable],
+    ) -> Result<Vec<Task>, FangError> {
+        Self::push_chain_with_options_query(connection, jobs, None, &Encoder::default())
     }
 
-    pub fn fetch_periodic_tasks_query(
+    fn push_chain_with_options_query(
         connection: &Conn,
-        error_margin_seconds: i64,
-    ) -> Option<Vec<PeriodicTask>> {
-        let current_time = Self::current_time();
+        jobs: &[&dyn Runnable],
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Vec<Task>, FangError> {
+        connection
+            .borrow()
+            .transaction::<Vec<Task>, FangError, _>(|| {
+                let mut tasks = Vec::with_capacity(jobs.len());
+                let mut next_task_id = None;
+
+                // Inserted backwards, so every task knows the id of the next one
+                for (index, job) in jobs.iter().enumerate().rev() {
+                    let (new_task, payload) = encoder.encode(*job)?;
+
+                    let options = InsertOptions {
+                        payload: payload.as_ref(),
+                        task_version: job.version(),
+                        state: if index == 0 {
+                            None
+                        } else {
+                            Some(FangTaskState::Waiting)
+                        },
+                        next_task_id,
+                        ..InsertOptions::default()
+                    };
+
+                    let task =
+                        Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+                    next_task_id = Some(task.id);
+                    tasks.push(task);
+                }
+
+                tasks.reverse();
+
+                Ok(tasks)
+            })
+    }
 
-        let low_limit = current_time - Duration::seconds(error_margin_seconds);
-        let high_limit = current_time + Duration::seconds(error_margin_seconds);
+    /// Enqueue the job to run once all tasks in `depends_on` are finished, e.g. to generate a
+    /// report after all partitions are loaded. Workers record that a dependency finished
+    /// before the retention mode removes it, see `satisfy_dependencies_query`. Dependencies
+    /// have to be pending, finished or archived as finished when the job is pushed: one that
+    /// was already removed is never satisfied. Jobs aren't deduplicated.
+    pub fn push_task_with_dependencies(
+        &self,
+        job: &dyn Runnable,
+        depends_on: &[Uuid],
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_dependencies_and_options_query(
+            &self.connection,
+            job,
+            depends_on,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
+    }
 
-        fang_periodic_tasks::table
-            .filter(
-                fang_periodic_tasks::scheduled_at
-                    .gt(low_limit)
-                    .and(fang_periodic_tasks::scheduled_at.lt(high_limit)),
-            )
-            .or_filter(fang_periodic_tasks::scheduled_at.is_null())
-            .load::<PeriodicTask>(connection.borrow())
-            .ok()
+    pub fn push_task_with_dependencies_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        depends_on: &[Uuid],
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_dependencies_and_options_query(
+            connection,
+            job,
+            depends_on,
+            None,
+            &Encoder::default(),
+        )
     }
 
-    pub fn schedule_next_task_execution(&self, task: &PeriodicTask) -> Result<PeriodicTask, Error> {
-        let current_time = Self::current_time();
-        let scheduled_at = current_time + Duration::seconds(task.period_in_seconds.into());
+    fn push_task_with_dependencies_and_options_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        depends_on: &[Uuid],
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Task, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        connection.borrow().transaction::<Task, FangError, _>(|| {
+            let options = InsertOptions {
+                payload: payload.as_ref(),
+                task_version: job.version(),
+                ..InsertOptions::default()
+            };
+
+            let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+            let dependencies: Vec<_> = depends_on
+                .iter()
+                .map(|depends_on_id| {
+                    (
+                        fang_task_dependencies::task_id.eq(task.id),
+                        fang_task_dependencies::depends_on_id.eq(depends_on_id),
+                    )
+                })
+                .collect();
+
+            if !dependencies.is_empty() {
+                diesel::insert_into(fang_task_dependencies::table)
+                    .values(&dependencies)
+                    .on_conflict_do_nothing()
+                    .execute(connection.borrow())?;
+
+                diesel::sql_query(
+                    "UPDATE fang_task_dependencies SET satisfied = TRUE WHERE task_id = $1 \
+                     AND (depends_on_id IN (SELECT id FROM fang_tasks WHERE state = 'finished') \
+                     OR depends_on_id IN \
+                     (SELECT id FROM fang_archived_tasks WHERE state = 'finished'))",
+                )
+                .bind::<diesel::sql_types::Uuid, _>(task.id)
+                .execute(connection.borrow())?;
+            }
 
-        diesel::update(task)
-            .set((
-                fang_periodic_tasks::scheduled_at.eq(scheduled_at),
-                fang_periodic_tasks::updated_at.eq(current_time),
-            ))
-            .get_result::<PeriodicTask>(self.connection.borrow())
+            Ok(task)
+        })
     }
 
-    pub fn remove_all_tasks(&self) -> Result<usize, Error> {
-        Self::remove_all_tasks_query(&self.connection)
+    /// The ids of the tasks `id` depends on, see `push_task_with_dependencies`.
+    pub fn find_dependencies(&self, id: Uuid) -> Result<Vec<Uuid>, Error> {
+        Self::find_dependencies_query(&self.connection, id)
     }
 
-    pub fn remove_all_tasks_query(connection: &Conn) -> Result<usize, Error> {
-        diesel::delete(fang_tasks::table).execute(connection.borrow())
+    pub fn find_dependencies_query(connection: &Conn, id: Uuid) -> Result<Vec<Uuid>, Error> {
+        fang_task_dependencies::table
+            .select(fang_task_dependencies::depends_on_id)
+            .filter(fang_task_dependencies::task_id.eq(id))
+            .load::<Uuid>(connection.borrow())
     }
 
-    pub fn remove_tasks_of_type(&self, task_type: &str) -> Result<usize, Error> {
-        Self::remove_tasks_of_type_query(&self.connection, task_type)
+    /// Enqueue `jobs` as a batch named `name`. Once every task of the batch finished or
+    /// failed, the worker that finalized the last one enqueues `callback`, which can look up
+    /// the outcome with `batch_status`. Jobs aren't deduplicated, the callback is always
+    /// stored as JSON.
+    pub fn push_batch(
+        &self,
+        name: &str,
+        jobs: &[&dyn Runnable],
+        callback: &dyn Runnable,
+    ) -> Result<Batch, FangError> {
+        Self::push_batch_with_options_query(
+            &self.connection,
+            name,
+            jobs,
+            callback,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
     }
 
-    pub fn remove_tasks_of_type_query(
+    pub fn push_batch_query(
         connection: &Conn,
-        task_type: &str,
-    ) -> Result<usize, Error> {
-        let query = fang_tasks::table.filter(fang_tasks::task_type.eq(task_type));
+        name: &str,
+        jobs: &[&dyn Runnable],
+        callback: &dyn Runnable,
+    ) -> Result<Batch, FangError> {
+        Self::push_batch_with_options_query(
+            connection,
+            name,
+            jobs,
+            callback,
+            None,
+            &Encoder::default(),
+        )
+    }
 
-        diesel::delete(query).execute(connection.borrow())
+    fn push_batch_with_options_query(
+        connection: &Conn,
+        name: &str,
+        jobs: &[&dyn Runnable],
+        callback: &dyn Runnable,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Batch, FangError> {
+        let callback_metadata = serde_json::to_value(callback)?;
+        let callback_signature =
+            signing_key.map(|key| key.sign(&callback.task_type(), &callback_metadata));
+
+        connection.borrow().transaction::<Batch, FangError, _>(|| {
+            let batch = diesel::insert_into(fang_batches::table)
+                .values((
+                    fang_batches::name.eq(name),
+                    fang_batches::size.eq(jobs.len() as i32),
+                    fang_batches::callback_metadata.eq(&callback_metadata),
+                    fang_batches::callback_task_type.eq(callback.task_type()),
+                    fang_batches::callback_signature.eq(&callback_signature),
+                ))
+                .get_result::<Batch>(connection.borrow())?;
+
+            for job in jobs {
+                let (new_task, payload) = encoder.encode(*job)?;
+
+                let options = InsertOptions {
+                    payload: payload.as_ref(),
+                    task_version: job.version(),
+                    batch_id: Some(batch.id),
+                    ..InsertOptions::default()
+                };
+
+                Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+            }
+
+            if jobs.is_empty() {
+                return Ok(Self::enqueue_batch_callback_query(connection, &batch)?);
+            }
+
+            Ok(batch)
+        })
     }
 
-    pub fn remove_all_periodic_tasks(&self) -> Result<usize, Error> {
-        Self::remove_all_periodic_tasks_query(&self.connection)
+    pub fn find_batch_by_id(&self, id: Uuid) -> Option<Batch> {
+        Self::find_batch_by_id_query(&self.connection, id)
     }
 
-    pub fn remove_all_periodic_tasks_query(connection: &Conn) -> Result<usize, Error> {
-        diesel::delete(fang_periodic_tasks::table).execute(connection.borrow())
+    pub fn find_batch_by_id_query(connection: &Conn, id: Uuid) -> Option<Batch> {
+        fang_batches::table
+            .filter(fang_batches::id.eq(id))
+            .first::<Batch>(connection.borrow())
+            .ok()
     }
 
-    pub fn remove_task(&self, id: Uuid) -> Result<usize, Error> {
-        Self::remove_task_query(&self.connection, id)
+    /// How many tasks of the batch finished, failed or are pending, `None` if there's no
+    /// such batch.
+    pub fn batch_status(&self, batch_id: Uuid) -> Option<BatchStatus> {
+        Self::batch_status_query(&self.connection, batch_id)
     }
 
-    pub fn remove_task_query(connection: &Conn, id: Uuid) -> Result<usize, Error> {
-        let query = fang_tasks::table.filter(fang_tasks::id.eq(id));
-
-        diesel::delete(query).execute(connection.borrow())
+    pub fn batch_status_query(connection: &Conn, batch_id: Uuid) -> Option<BatchStatus> {
+        Self::find_batch_by_id_query(connection, batch_id).map(|batch| batch.status())
     }
 
-    pub fn finish_task(&self, task: &Task) -> Result<Task, Error> {
-        Self::finish_task_query(&self.connection, task)
+    /// Count the tasks `ids` of batches as finished (or failed if `succeeded` is false) and
+    /// enqueue the callbacks of the batches that are complete. Called by the executor before
+    /// finalized tasks are removed.
+    pub fn record_batch_results_query(
+        connection: &Conn,
+        ids: &[Uuid],
+        succeeded: bool,
+    ) -> Result<Vec<Batch>, Error> {
+        let counter = if succeeded {
+            "finished_count"
+        } else {
+            "failed_count"
+        };
+
+        connection.borrow().transaction::<Vec<Batch>, Error, _>(|| {
+            // Locks the batches, so only one worker sees a batch become complete
+            let batches = diesel::sql_query(format!(
+                "UPDATE fang_batches SET {counter} = {counter} + members.count, updated_at = $2 \
+                 FROM (SELECT batch_id, COUNT(*)::INT4 AS count FROM fang_tasks \
+                 WHERE id = ANY($1) AND batch_id IS NOT NULL GROUP BY batch_id) AS members \
+                 WHERE fang_batches.id = members.batch_id RETURNING fang_batches.*",
+                counter = counter
+            ))
+            .bind::<Array<diesel::sql_types::Uuid>, _>(ids)
+            .bind::<Timestamptz, _>(Self::current_time())
+            .load::<Batch>(connection.borrow())?;
+
+            batches
+                .into_iter()
+                .map(|batch| {
+                    if batch.is_complete() && batch.callback_task_id.is_none() {
+                        Self::enqueue_batch_callback_query(connection, &batch)
+                    } else {
+                        Ok(batch)
+                    }
+                })
+                .collect()
+        })
     }
 
-    pub fn finish_task_query(connection: &Conn, task: &Task) -> Result<Task, Error> {
-        diesel::update(task)
+    fn enqueue_batch_callback_query(connection: &Conn, batch: &Batch) -> Result<Batch, Error> {
+        let new_task = NewTask {
+            metadata: batch.callback_metadata.clone(),
+            task_type: batch.callback_task_type.clone(),
+        };
+
+        let options = InsertOptions {
+            signature: batch.callback_signature.as_deref(),
+            ..InsertOptions::default()
+        };
+
+        let task = Self::insert_task_query(connection, &new_task, None, &options)?;
+
+        diesel::update(batch)
             .set((
-                fang_tasks::state.eq(FangTaskState::Finished),
-                fang_tasks::updated_at.eq(Self::current_time()),
+                fang_batches::callback_task_id.eq(task.id),
+                fang_batches::updated_at.eq(Self::current_time()),
             ))
-            .get_result::<Task>(connection.borrow())
+            .get_result::<Batch>(connection.borrow())
     }
 
-    pub fn start_processing_task(&self, task: &Task) -> Result<Task, Error> {
-        Self::start_processing_task_query(&self.connection, task)
+    /// Stage the job in `fang_outbox` instead of enqueuing it. Staging on the connection of an
+    /// application transaction makes the job part of that transaction: `relay_outbox`, usually
+    /// run by the `RelayOutbox` job, only sees it once the transaction commits. Jobs are
+    /// stored as JSON, the serializer, compression and payload store aren't used.
+    pub fn stage_task(&self, job: &dyn Runnable) -> Result<OutboxEntry, FangError> {
+        Self::stage_task_with_options_query(&self.connection, job, self.signing_key.as_ref())
     }
 
-    pub fn start_processing_task_query(
+    pub fn stage_task_query(
         connection: &Conn,
-        task: &Task,
-    ) -> Result<Task, Error> {
-        diesel::update(task)
-            .set((
-                fang_tasks::state.eq(FangTaskState::InProgress),
-                fang_tasks::updated_at.eq(Self::current_time()),
+        job: &dyn Runnable,
+    ) -> Result<OutboxEntry, FangError> {
+        Self::stage_task_with_options_query(connection, job, None)
+    }
+
+    fn stage_task_with_options_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<OutboxEntry, FangError> {
+        let metadata = serde_json::to_value(job)?;
+        let signature = signing_key.map(|key| key.sign(&job.task_type(), &metadata));
+
+        let entry = diesel::insert_into(fang_outbox::table)
+            .values((
+                fang_outbox::metadata.eq(&metadata),
+                fang_outbox::task_type.eq(job.task_type()),
+                fang_outbox::task_version.eq(job.version()),
+                fang_outbox::signature.eq(&signature),
             ))
-            .get_result::<Task>(connection.borrow())
+            .get_result::<OutboxEntry>(connection.borrow())?;
+
+        Ok(entry)
     }
 
-    pub fn fail_task(&self, task: &Task, error: String) -> Result<Task, Error> {
-        Self::fail_task_query(&self.connection, task, error)
+    /// Move up to `limit` staged jobs into `fang_tasks`, oldest first, and return the tasks.
+    /// Entries locked by a concurrent relay are skipped, each entry is enqueued once. The
+    /// tasks aren't deduplicated.
+    pub fn relay_outbox(&self, limit: i64) -> Result<Vec<Task>, FangError> {
+        Self::relay_outbox_query(&self.connection, limit)
+    }
+
+    pub fn relay_outbox_query(connection: &Conn, limit: i64) -> Result<Vec<Task>, FangError> {
+        connection
+            .borrow()
+            .transaction::<Vec<Task>, FangError, _>(|| {
+                let entries = fang_outbox::table
+                    .order(fang_outbox::created_at.asc())
+                    .limit(limit)
+                    .for_update()
+                    .skip_locked()
+                    .load::<OutboxEntry>(connection.borrow())?;
+
+                let mut tasks = Vec::with_capacity(entries.len());
+
+                for entry in &entries {
+                    let new_task = NewTask {
+                        metadata: entry.metadata.clone(),
+                        task_type: entry.task_type.clone(),
+                    };
+
+                    let options = InsertOptions {
+                        task_version: entry.task_version,
+                        signature: entry.signature.as_deref(),
+                        ..InsertOptions::default()
+                    };
+
+                    tasks.push(Self::insert_task_query(
+                        connection, &new_task, None, &options,
+                    )?);
+                }
+
+                let ids: Vec<Uuid> = entries.iter().map(|entry| entry.id).collect();
+
+                diesel::delete(fang_outbox::table.filter(fang_outbox::id.eq_any(ids)))
+                    .execute(connection.borrow())?;
+
+                Ok(tasks)
+            })
+    }
+
+    /// Enqueue the job so that tasks with the same `ordering_key` run one at a time, in the
+    /// order they were enqueued, e.g. to keep jobs of a customer from racing each other. A
+    /// task of the key that is scheduled for later holds up the ones after it. Jobs aren't
+    /// deduplicated.
+    pub fn push_task_with_ordering_key(
+        &self,
+        job: &dyn Runnable,
+        ordering_key: &str,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_ordering_key_and_options_query(
+            &self.connection,
+            job,
+            ordering_key,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
     }
 
-    pub fn fail_task_query(
+    pub fn push_task_with_ordering_key_query(
         connection: &Conn,
-        task: &Task,
-        error: String,
-    ) -> Result<Task, Error> {
-        diesel::update(task)
-            .set((
-                fang_tasks::state.eq(FangTaskState::Failed),
-                fang_tasks::error_message.eq(error),
-                fang_tasks::updated_at.eq(Self::current_time()),
-            ))
-            .get_result::<Task>(connection.borrow())
+        job: &dyn Runnable,
+        ordering_key: &str,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_ordering_key_and_options_query(
+            connection,
+            job,
+            ordering_key,
+            None,
+            &Encoder::default(),
+        )
     }
 
-    fn current_time() -> DateTime<Utc> {
-        Utc::now()
+    fn push_task_with_ordering_key_and_options_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        ordering_key: &str,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Task, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        let options = InsertOptions {
+            payload: payload.as_ref(),
+            task_version: job.version(),
+            ordering_key: Some(ordering_key),
+            ..InsertOptions::default()
+        };
+
+        let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+        Ok(task)
+    }
+
+    /// Like `push_task`, but records where the task comes from, so the producer of unexpected
+    /// tasks can be found from the database. Use `fang::origin!()` to pass the caller's module,
+    /// file and line. If the same task is already enqueued, its origin isn't changed.
+    pub fn push_task_with_origin(
+        &self,
+        job: &dyn Runnable,
+        origin: &str,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_options_query(
+            &self.connection,
+            job,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+            Some(origin),
+            self.dedup,
+        )
+    }
+
+    pub fn push_task_with_origin_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        origin: &str,
+    ) -> Result<Task, FangError> {
+        Self::push_task_with_options_query(
+            connection,
+            job,
+            None,
+            &Encoder::default(),
+            Some(origin),
+            true,
+        )
+    }
+
+    /// Enqueue the task only if no task of `task_type` is `New` or `InProgress`, e.g. to kick
+    /// a reconciler unless it's already scheduled. Returns `None` if one is pending. Calls for
+    /// the same `task_type` are serialized with a transaction-level advisory lock, tasks pushed
+    /// by other functions don't take it.
+    pub fn push_task_unless_pending(
+        &self,
+        job: &dyn Runnable,
+        task_type: &str,
+    ) -> Result<Option<Task>, FangError> {
+        Self::push_task_unless_pending_with_key_query(
+            &self.connection,
+            job,
+            task_type,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
+    }
+
+    pub fn push_task_unless_pending_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        task_type: &str,
+    ) -> Result<Option<Task>, FangError> {
+        Self::push_task_unless_pending_with_key_query(
+            connection,
+            job,
+            task_type,
+            None,
+            &Encoder::default(),
+        )
+    }
+
+    fn push_task_unless_pending_with_key_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        task_type: &str,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Option<Task>, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        connection
+            .borrow()
+            .transaction::<Option<Task>, FangError, _>(|| {
+                diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                    .bind::<Text, _>(format!("fang:{}", task_type))
+                    .execute(connection.borrow())?;
+
+                let pending = fang_tasks::table
+                    .select(fang_tasks::id)
+                    .filter(fang_tasks::task_type.eq(task_type))
+                    .filter(
+                        fang_tasks::state
+                            .eq(FangTaskState::New)
+                            .or(fang_tasks::state.eq(FangTaskState::InProgress)),
+                    )
+                    .first::<Uuid>(connection.borrow())
+                    .optional()?;
+
+                if pending.is_some() {
+                    return Ok(None);
+                }
+
+                let options = InsertOptions {
+                    payload: payload.as_ref(),
+                    task_version: job.version(),
+                    ..InsertOptions::default()
+                };
+
+                let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+                Ok(Some(task))
+            })
+    }
+
+    fn push_task_with_options_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+        origin: Option<&str>,
+        dedup: bool,
+    ) -> Result<Task, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        if dedup {
+            if let Some(task) = Self::find_task_by_metadata_query(connection, &new_task.metadata) {
+                return Ok(task);
+            }
+        }
+
+        let options = InsertOptions {
+            payload: payload.as_ref(),
+            origin,
+            task_version: job.version(),
+            ..InsertOptions::default()
+        };
+
+        let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+        Ok(task)
+    }
+
+    /// Enqueue the task to run once, not before `at`. Unlike `push_task` it always inserts a
+    /// new task, the same job can be scheduled for several times.
+    pub fn schedule_task(&self, job: &dyn Runnable, at: DateTime<Utc>) -> Result<Task, FangError> {
+        Self::schedule_signed_task_query(
+            &self.connection,
+            job,
+            at,
+            self.signing_key.as_ref(),
+            &self.encoder(),
+        )
+    }
+
+    pub fn schedule_task_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        at: DateTime<Utc>,
+    ) -> Result<Task, FangError> {
+        Self::schedule_signed_task_query(connection, job, at, None, &Encoder::default())
+    }
+
+    fn schedule_signed_task_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        at: DateTime<Utc>,
+        signing_key: Option<&SigningKey>,
+        encoder: &Encoder,
+    ) -> Result<Task, FangError> {
+        let (new_task, payload) = encoder.encode(job)?;
+
+        let options = InsertOptions {
+            payload: payload.as_ref(),
+            scheduled_at: Some(at),
+            task_version: job.version(),
+            ..InsertOptions::default()
+        };
+
+        let task = Self::insert_task_query(connection, &new_task, signing_key, &options)?;
+
+        Ok(task)
+    }
+
+    pub fn push_periodic_task(
+        &self,
+        job: &dyn Runnable,
+        period: i32,
+    ) -> Result<PeriodicTask, FangError> {
+        Self::push_periodic_task_query(&self.connection, job, period)
+    }
+
+    pub fn push_periodic_task_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        period: i32,
+    ) -> Result<PeriodicTask, FangError> {
+        Self::push_periodic_task_with_end_query(connection, job, period, None, None)
+    }
+
+    /// Like `push_periodic_task`, but the scheduler removes the task after `max_runs`
+    /// executions or once its next execution would be at or after `ends_at`. If the same task
+    /// is already scheduled, its end conditions aren't changed.
+    pub fn push_periodic_task_with_end(
+        &self,
+        job: &dyn Runnable,
+        period: i32,
+        max_runs: Option<i32>,
+        ends_at: Option<DateTime<Utc>>,
+    ) -> Result<PeriodicTask, FangError> {
+        Self::push_periodic_task_with_end_query(&self.connection, job, period, max_runs, ends_at)
     }
 
-    fn pg_connection(database_url: Option<String>) -> PgConnection {
-        dotenv().ok();
+    pub fn push_periodic_task_with_end_query(
+        connection: &Conn,
+        job: &dyn Runnable,
+        period: i32,
+        max_runs: Option<i32>,
+        ends_at: Option<DateTime<Utc>>,
+    ) -> Result<PeriodicTask, FangError> {
+        let json_job = serde_json::to_value(job)?;
+
+        if let Some(task) = Self::find_periodic_task_by_metadata_query(connection, &json_job) {
+            return Ok(task);
+        }
 
-        let url = match database_url {
-            Some(string_url) => string_url,
-            None => env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+        let new_task = NewPeriodicTask {
+            metadata: json_job,
+            period_in_seconds: period,
+            task_type: job.task_type(),
         };
 
-        PgConnection::establish(&url).unwrap_or_else(|_| panic!("Error connecting to {}", url))
+        let task = diesel::insert_into(fang_periodic_tasks::table)
+            .values((
+                new_task,
+                fang_periodic_tasks::max_runs.eq(max_runs),
+                fang_periodic_tasks::ends_at.eq(ends_at),
+            ))
+            .get_result::<PeriodicTask>(connection.borrow())?;
+
+        Ok(task)
+    }
+
+    pub fn enqueue_task(job: &dyn Runnable) -> Result<Task, FangError> {
+        Queue::new().push_task(job)
+    }
+
+    pub fn insert(&self, params: &NewTask) -> Result<Task, Error> {
+        Self::insert_signed_query(&self.connection, params, self.signing_key.as_ref())
+    }
+
+    /// Never signs the task, see `insert_signed_query`.
+    pub fn insert_query(connection: &Conn, params: &NewTask) -> Result<Task, Error> {
+        Self::insert_signed_query(connection, params, None)
+    }
+
+    pub fn insert_signed_query(
+        connection: &Conn,
+        params: &NewTask,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Task, Error> {
+        Self::insert_task_query(connection, params, signing_key, &InsertOptions::default())
+    }
+
+    fn insert_task_query(
+        connection: &Conn,
+        params: &NewTask,
+        signing_key: Option<&SigningKey>,
+        options: &InsertOptions,
+    ) -> Result<Task, Error> {
+        let signature = signing_key
+            .map(|key| key.sign(&params.task_type, &params.metadata))
+            .or_else(|| options.signature.map(ToString::to_string));
+        let payload = options.payload;
+        let stored_payload = payload.filter(|payload| payload.reference.is_none());
+
+        #[cfg(feature = "uuid-v7")]
+        let id = Some(options.id.unwrap_or_else(time_ordered_id));
+        #[cfg(not(feature = "uuid-v7"))]
+        let id = options.id;
+
+        let task = diesel::insert_into(fang_tasks::table)
+            .values((
+                params,
+                id.map(|id| fang_tasks::id.eq(id)),
+                fang_tasks::signature.eq(signature),
+                fang_tasks::payload.eq(stored_payload.map(|payload| &payload.bytes)),
+                fang_tasks::payload_format.eq(payload.map(|payload| payload.format)),
+                fang_tasks::payload_encoding.eq(payload.and_then(|payload| payload.encoding)),
+                fang_tasks::payload_reference
+                    .eq(payload.and_then(|payload| payload.reference.as_deref())),
+                fang_tasks::origin.eq(options.origin),
+                fang_tasks::scheduled_at.eq(options.scheduled_at),
+                fang_tasks::task_version.eq(options.task_version),
+                fang_tasks::idempotency_key.eq(options.idempotency_key),
+                fang_tasks::uniq_key.eq(options.uniq_key),
+                options
+                    .state
+                    .clone()
+                    .map(|state| fang_tasks::state.eq(state)),
+                fang_tasks::next_task_id.eq(options.next_task_id),
+                fang_tasks::batch_id.eq(options.batch_id),
+                fang_tasks::ordering_key.eq(options.ordering_key),
+            ))
+            .get_result::<Task>(connection.borrow())?;
+
+        fang_log!(
+            LogModule::Queue,
+            Level::Debug,
+            "inserted task {} of type {}",
+            task.id,
+            task.task_type
+        );
+
+        Ok(task)
+    }
+
+    pub fn fetch_task(&self, task_type: &Option<String>) -> Option<Task> {
+        Self::fetch_task_query(&self.connection, task_type)
     }
 
-    fn fetch_any_task_query(connection: &Conn) -> Option<Task> {
+    pub fn fetch_task_query(connection: &Conn, task_type: &Option<String>) -> Option<Task> {
+        let params = ClaimParams {
+            task_type,
+            excluded_task_types: &[],
+        };
+
         fang_tasks::table
+            .filter(Self::claimable_tasks(&params))
             .order(fang_tasks::created_at.asc())
             .limit(1)
-            .filter(fang_tasks::state.eq(FangTaskState::New))
             .for_update()
             .skip_locked()
             .get_result::<Task>(connection.borrow())
             .ok()
     }
 
-    fn fetch_task_of_type_query(connection: &Conn, task_type: &str) -> Option<Task> {
+    pub fn fetch_and_touch(&self, task_type: &Option<String>) -> Result<Option<Task>, Error> {
+        Self::fetch_and_touch_query(&self.connection, task_type)
+    }
+
+    pub fn fetch_and_touch_query(
+        connection: &Conn,
+        task_type: &Option<String>,
+    ) -> Result<Option<Task>, Error> {
+        Self::fetch_and_touch_batch_query(connection, task_type, 1).map(|mut tasks| tasks.pop())
+    }
+
+    /// Claim up to `limit` tasks at once, oldest first.
+    pub fn fetch_and_touch_batch(
+        &self,
+        task_type: &Option<String>,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error> {
+        Self::fetch_and_touch_batch_query(&self.connection, task_type, limit)
+    }
+
+    pub fn fetch_and_touch_batch_query(
+        connection: &Conn,
+        task_type: &Option<String>,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error> {
+        let params = ClaimParams {
+            task_type,
+            excluded_task_types: &[],
+        };
+
+        Self::claim_tasks_query(connection, &params, limit)
+    }
+
+    /// Move up to `limit` tasks matching `params` into the `InProgress` state and return
+    /// them, oldest first.
+    pub fn claim_tasks(&self, params: &ClaimParams, limit: i64) -> Result<Vec<Task>, Error> {
+        Self::claim_tasks_query(&self.connection, params, limit)
+    }
+
+    /// Number of `New` tasks a worker with these params could claim.
+    pub fn count_claimable_tasks(&self, params: &ClaimParams) -> Result<i64, Error> {
+        Self::count_claimable_tasks_query(&self.connection, params)
+    }
+
+    pub fn count_claimable_tasks_query(
+        connection: &Conn,
+        params: &ClaimParams,
+    ) -> Result<i64, Error> {
         fang_tasks::table
-            .order(fang_tasks::created_at.asc())
-            .limit(1)
-            .filter(fang_tasks::state.eq(FangTaskState::New))
-            .filter(fang_tasks::task_type.eq(task_type))
-            .for_update()
-            .skip_locked()
-            .get_result::<Task>(connection.borrow())
-            .ok()
+            .filter(Self::claimable_tasks(params))
+            .count()
+            .get_result::<i64>(connection.borrow())
     }
 
-    fn find_periodic_task_by_metadata_query(
+    /// The distinct task types of the tasks a worker with these params could claim.
+    pub fn claimable_task_types(&self, params: &ClaimParams) -> Result<Vec<String>, Error> {
+        Self::claimable_task_types_query(&self.connection, params)
+    }
+
+    pub fn claimable_task_types_query(
         connection: &Conn,
-        metadata: &serde_json::Value,
-    ) -> Option<PeriodicTask> {
-        fang_periodic_tasks::table
-            .filter(fang_periodic_tasks::metadata.eq(metadata))
-            .first::<PeriodicTask>(connection.borrow())
-            .ok()
+        params: &ClaimParams,
+    ) -> Result<Vec<String>, Error> {
+        fang_tasks::table
+            .select(fang_tasks::task_type)
+            .filter(Self::claimable_tasks(params))
+            .distinct()
+            .order(fang_tasks::task_type.asc())
+            .load::<String>(connection.borrow())
     }
 
-    fn find_task_by_metadata_query(
+    pub fn has_claimable_tasks(&self, params: &ClaimParams) -> Result<bool, Error> {
+        Self::has_claimable_tasks_query(&self.connection, params)
+    }
+
+    /// Unlike claiming, this also sees tasks locked by other workers.
+    pub fn has_claimable_tasks_query(
         connection: &Conn,
-        metadata: &serde_json::Value,
-    ) -> Option<Task> {
+        params: &ClaimParams,
+    ) -> Result<bool, Error> {
         fang_tasks::table
-            .filter(fang_tasks::metadata.eq(metadata))
-            .filter(
-                fang_tasks::state
-                    .eq(FangTaskState::New)
-                    .or(fang_tasks::state.eq(FangTaskState::InProgress)),
-            )
-            .first::<Task>(connection.borrow())
-            .ok()
+            .select(fang_tasks::id)
+            .filter(Self::claimable_tasks(params))
+            .first::<Uuid>(connection.borrow())
+            .optional()
+            .map(|id| id.is_some())
     }
-}
 
-#[cfg(test)]
-mod queue_tests {
-    use super::NewTask;
-    use super::PeriodicTask;
-    use super::Queue;
-    use super::Task;
-    use crate::executor::Error as ExecutorError;
-    use crate::executor::Runnable;
-    use crate::schema::fang_periodic_tasks;
-    use crate::schema::fang_tasks;
-    use crate::schema::FangTaskState;
-    use crate::typetag;
-    use chrono::prelude::*;
-    use chrono::{DateTime, Duration, Utc};
-    use diesel::connection::Connection;
-    use diesel::prelude::*;
-    use diesel::result::Error;
-    use serde::{Deserialize, Serialize};
+    pub fn claim_tasks_query(
+        connection: &Conn,
+        params: &ClaimParams,
+        limit: i64,
+    ) -> Result<Vec<Task>, Error> {
+        // A single `UPDATE ... WHERE id IN (SELECT ... FOR UPDATE SKIP LOCKED) RETURNING *`,
+        // so the rows are locked only for the duration of one statement.
+        let claimable_ids = Self::claimable_ids(params, limit);
+
+        let current_time = Self::current_time();
+        let mut tasks = diesel::update(fang_tasks::table.filter(IdIn(claimable_ids)))
+            .set((
+                fang_tasks::state.eq(FangTaskState::InProgress),
+                fang_tasks::attempts.eq(fang_tasks::attempts + 1),
+                fang_tasks::started_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+                fang_tasks::worker_name.eq(Self::current_worker_name()),
+            ))
+            .get_results::<Task>(connection.borrow())?;
+
+        tasks.sort_by_key(|task| task.created_at);
+
+        Ok(tasks)
+    }
+
+    /// Claim up to `limit` tasks matching `params` with a lease held by `locked_by` until
+    /// `lease` from now. Besides `New` tasks, `InProgress` tasks whose lease expired are
+    /// claimed again, for example because their worker crashed. See `LeaseClaimStrategy`.
+    pub fn claim_tasks_with_lease(
+        &self,
+        params: &ClaimParams,
+        limit: i64,
+        locked_by: &str,
+        lease: Duration,
+    ) -> Result<Vec<Task>, Error> {
+        Self::claim_tasks_with_lease_query(&self.connection, params, limit, locked_by, lease)
+    }
+
+    pub fn claim_tasks_with_lease_query(
+        connection: &Conn,
+        params: &ClaimParams,
+        limit: i64,
+        locked_by: &str,
+        lease: Duration,
+    ) -> Result<Vec<Task>, Error> {
+        let leasable_ids = fang_tasks::table
+            .select(fang_tasks::id)
+            .filter(Self::leasable_tasks(params))
+            .order(fang_tasks::created_at.asc())
+            .limit(limit)
+            .for_update()
+            .skip_locked();
+
+        let current_time = Self::current_time();
+        let mut tasks = diesel::update(fang_tasks::table.filter(IdIn(leasable_ids)))
+            .set((
+                fang_tasks::state.eq(FangTaskState::InProgress),
+                fang_tasks::attempts.eq(fang_tasks::attempts + 1),
+                fang_tasks::started_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+                fang_tasks::worker_name.eq(Self::current_worker_name()),
+                fang_tasks::locked_by.eq(locked_by),
+                fang_tasks::locked_until.eq(current_time + lease),
+            ))
+            .get_results::<Task>(connection.borrow())?;
+
+        tasks.sort_by_key(|task| task.created_at);
+
+        Ok(tasks)
+    }
+
+    /// Extend the lease on a task for jobs that run longer than the lease. Returns `false` if
+    /// `locked_by` doesn't hold the lease anymore, because it expired and another worker
+    /// claimed the task, or the task isn't `InProgress`.
+    pub fn renew_lease(&self, id: Uuid, locked_by: &str, lease: Duration) -> Result<bool, Error> {
+        Self::renew_lease_query(&self.connection, id, locked_by, lease)
+    }
+
+    pub fn renew_lease_query(
+        connection: &Conn,
+        id: Uuid,
+        locked_by: &str,
+        lease: Duration,
+    ) -> Result<bool, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(fang_tasks::state.eq(FangTaskState::InProgress))
+            .filter(fang_tasks::locked_by.eq(locked_by));
+
+        let current_time = Self::current_time();
+        let updated = diesel::update(query)
+            .set((
+                fang_tasks::locked_until.eq(current_time + lease),
+                fang_tasks::updated_at.eq(current_time),
+            ))
+            .execute(connection.borrow())?;
+
+        Ok(updated > 0)
+    }
+
+    /// The task, `None` if it doesn't exist (anymore). Tasks the retention mode archived are
+    /// found with `find_archived_task_by_id`.
+    pub fn find_task_by_id(&self, id: Uuid) -> Result<Option<Task>, Error> {
+        Self::find_task_by_id_query(&self.connection, id)
+    }
+
+    pub fn find_task_by_id_query(connection: &Conn, id: Uuid) -> Result<Option<Task>, Error> {
+        fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .first::<Task>(connection.borrow())
+            .optional()
+    }
+
+    /// The state of the task, `None` if it doesn't exist (anymore).
+    pub fn task_state_query(connection: &Conn, id: Uuid) -> Result<Option<FangTaskState>, Error> {
+        fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .select(fang_tasks::state)
+            .first::<FangTaskState>(connection.borrow())
+            .optional()
+    }
+
+    /// Block until the task is finished, failed or cancelled and return it. Tasks archived in
+    /// the meantime are returned from `fang_archived_tasks`. Returns `None` if the task doesn't
+    /// exist (anymore): a task the retention mode removed can't be told apart from an unknown
+    /// id. Returns `FangError::WaitTimeout` if the task isn't done after `timeout`.
+    pub fn wait_for_task(
+        &self,
+        id: Uuid,
+        timeout: std::time::Duration,
+    ) -> Result<Option<Task>, FangError> {
+        Self::wait_for_task_query(&self.connection, id, timeout)
+    }
+
+    pub fn wait_for_task_query(
+        connection: &Conn,
+        id: Uuid,
+        timeout: std::time::Duration,
+    ) -> Result<Option<Task>, FangError> {
+        let started_at = Instant::now();
+
+        loop {
+            let task = match Self::find_task_by_id_query(connection, id)? {
+                Some(task) => task,
+                None => {
+                    return Ok(Self::find_archived_task_by_id_query(connection, id)?.map(Task::from))
+                }
+            };
+
+            if task.state.is_terminal() {
+                return Ok(Some(task));
+            }
+
+            let elapsed = started_at.elapsed();
+
+            if elapsed >= timeout {
+                return Err(FangError::WaitTimeout);
+            }
+
+            thread::sleep(WAIT_FOR_TASK_POLL_INTERVAL.min(timeout - elapsed));
+        }
+    }
+
+    /// Tasks whose metadata contains `metadata` (the `@>` operator), e.g. all tasks for a
+    /// user with `json!({"user_id": 123})`. Uses the GIN index on `metadata`, oldest first.
+    pub fn find_tasks_by_metadata_contains(
+        &self,
+        metadata: &serde_json::Value,
+    ) -> Result<Vec<Task>, Error> {
+        Self::find_tasks_by_metadata_contains_query(&self.connection, metadata)
+    }
+
+    pub fn find_tasks_by_metadata_contains_query(
+        connection: &Conn,
+        metadata: &serde_json::Value,
+    ) -> Result<Vec<Task>, Error> {
+        fang_tasks::table
+            .filter(sql::<Bool>("fang_tasks.metadata @> ").bind::<Jsonb, _>(metadata))
+            .order(fang_tasks::created_at.asc())
+            .load::<Task>(connection.borrow())
+    }
+
+    /// The tasks matching `query`, see `TaskQuery`.
+    pub fn find_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        Self::find_tasks_query(&self.connection, query)
+    }
+
+    pub fn find_tasks_query(connection: &Conn, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        let mut tasks = query.filtered();
+
+        tasks = if query.newest_first {
+            tasks.order((fang_tasks::created_at.desc(), fang_tasks::id.desc()))
+        } else {
+            tasks.order((fang_tasks::created_at.asc(), fang_tasks::id.asc()))
+        };
+
+        if let Some(limit) = query.limit {
+            tasks = tasks.limit(limit);
+        }
+
+        if let Some(offset) = query.offset {
+            tasks = tasks.offset(offset);
+        }
+
+        tasks.load::<Task>(connection.borrow())
+    }
+
+    /// The tasks matching `query`, loaded `DEFAULT_STREAM_CHUNK_SIZE` at a time instead of all
+    /// at once, e.g. to export millions of tasks. See `TaskStream`.
+    pub fn stream_tasks(&self, query: &TaskQuery) -> TaskStream<'_, Conn> {
+        Self::stream_tasks_query(&self.connection, query)
+    }
+
+    pub fn stream_tasks_query<'a>(connection: &'a Conn, query: &TaskQuery) -> TaskStream<'a, Conn> {
+        TaskStream::new(connection, query)
+    }
+
+    /// How many tasks match `query`, its limit and offset aside.
+    pub fn count_tasks(&self, query: &TaskQuery) -> Result<i64, Error> {
+        Self::count_tasks_query(&self.connection, query)
+    }
+
+    pub fn count_tasks_query(connection: &Conn, query: &TaskQuery) -> Result<i64, Error> {
+        query
+            .filtered()
+            .count()
+            .get_result::<i64>(connection.borrow())
+    }
+
+    pub fn find_periodic_task_by_id(&self, id: Uuid) -> Option<PeriodicTask> {
+        Self::find_periodic_task_by_id_query(&self.connection, id)
+    }
+
+    pub fn find_periodic_task_by_id_query(connection: &Conn, id: Uuid) -> Option<PeriodicTask> {
+        fang_periodic_tasks::table
+            .filter(fang_periodic_tasks::id.eq(id))
+            .first::<PeriodicTask>(connection.borrow())
+            .ok()
+    }
+
+    /// All periodic tasks ordered by `scheduled_at`, tasks the scheduler hasn't picked up yet
+    /// come last.
+    pub fn list_periodic_tasks(&self) -> Result<Vec<PeriodicTask>, Error> {
+        Self::list_periodic_tasks_query(&self.connection)
+    }
+
+    pub fn list_periodic_tasks_query(connection: &Conn) -> Result<Vec<PeriodicTask>, Error> {
+        fang_periodic_tasks::table
+            .order((
+                fang_periodic_tasks::scheduled_at.asc(),
+                fang_periodic_tasks::created_at.asc(),
+            ))
+            .load::<PeriodicTask>(connection.borrow())
+    }
+
+    /// When the next `n` executions of the periodic task `id` are due, see
+    /// `PeriodicTask::next_runs`.
+    pub fn next_runs(&self, id: Uuid, n: usize) -> Result<Vec<DateTime<Utc>>, Error> {
+        Self::next_runs_query(&self.connection, id, n)
+    }
+
+    pub fn next_runs_query(
+        connection: &Conn,
+        id: Uuid,
+        n: usize,
+    ) -> Result<Vec<DateTime<Utc>>, Error> {
+        let task = fang_periodic_tasks::table
+            .filter(fang_periodic_tasks::id.eq(id))
+            .first::<PeriodicTask>(connection.borrow())?;
+
+        Ok(task.next_runs(n, Self::current_time()))
+    }
+
+    pub fn fetch_periodic_tasks(
+        &self,
+        error_margin_seconds: i64,
+    ) -> Result<Vec<PeriodicTask>, Error> {
+        Self::fetch_periodic_tasks_query(&self.connection, error_margin_seconds)
+    }
+
+    pub fn fetch_periodic_tasks_query(
+        connection: &Conn,
+        error_margin_seconds: i64,
+    ) -> Result<Vec<PeriodicTask>, Error> {
+        Self::fetch_due_periodic_tasks_query(
+            connection,
+            Duration::seconds(error_margin_seconds),
+            None,
+        )
+    }
+
+    /// Like `fetch_periodic_tasks`, but only periodic tasks of `task_type`.
+    pub fn fetch_periodic_tasks_of_type(
+        &self,
+        error_margin_seconds: i64,
+        task_type: &str,
+    ) -> Result<Vec<PeriodicTask>, Error> {
+        Self::fetch_periodic_tasks_of_type_query(&self.connection, error_margin_seconds, task_type)
+    }
+
+    pub fn fetch_periodic_tasks_of_type_query(
+        connection: &Conn,
+        error_margin_seconds: i64,
+        task_type: &str,
+    ) -> Result<Vec<PeriodicTask>, Error> {
+        Self::fetch_due_periodic_tasks_query(
+            connection,
+            Duration::seconds(error_margin_seconds),
+            Some(task_type),
+        )
+    }
+
+    /// Like `fetch_periodic_tasks`, with a margin that isn't limited to whole seconds.
+    #[cfg(feature = "runtime")]
+    pub(crate) fn fetch_due_periodic_tasks(
+        &self,
+        error_margin: Duration,
+        task_type: Option<&str>,
+    ) -> Result<Vec<PeriodicTask>, Error> {
+        Self::fetch_due_periodic_tasks_query(&self.connection, error_margin, task_type)
+    }
+
+    fn fetch_due_periodic_tasks_query(
+        connection: &Conn,
+        error_margin: Duration,
+        task_type: Option<&str>,
+    ) -> Result<Vec<PeriodicTask>, Error> {
+        let current_time = Self::current_time();
+
+        let low_limit = current_time - error_margin;
+        let high_limit = current_time + error_margin;
+
+        let mut query = fang_periodic_tasks::table
+            .filter(
+                fang_periodic_tasks::scheduled_at
+                    .gt(low_limit)
+                    .and(fang_periodic_tasks::scheduled_at.lt(high_limit))
+                    .or(fang_periodic_tasks::scheduled_at.is_null()),
+            )
+            .into_boxed();
+
+        if let Some(task_type) = task_type {
+            query = query.filter(fang_periodic_tasks::task_type.eq(task_type));
+        }
+
+        query.load::<PeriodicTask>(connection.borrow())
+    }
+
+    /// What `apply_schedules` would change, without changing anything.
+    pub fn diff_schedules(&self, schedules: &[ScheduleDefinition]) -> Result<ScheduleDiff, Error> {
+        Self::diff_schedules_query(&self.connection, schedules)
+    }
+
+    pub fn diff_schedules_query(
+        connection: &Conn,
+        schedules: &[ScheduleDefinition],
+    ) -> Result<ScheduleDiff, Error> {
+        let periodic_tasks =
+            fang_periodic_tasks::table.load::<PeriodicTask>(connection.borrow())?;
+
+        Ok(ScheduleDiff::new(schedules, periodic_tasks))
+    }
+
+    /// Make `fang_periodic_tasks` match `schedules`: add missing periodic tasks, update changed
+    /// periods and declared task types and remove periodic tasks that aren't declared. Updated
+    /// tasks keep their `scheduled_at`, the new period applies from their next execution.
+    /// Fails without changing anything if the task type of an added schedule isn't known, see
+    /// `ScheduleDefinition::task_type`.
+    pub fn apply_schedules(
+        &self,
+        schedules: &[ScheduleDefinition],
+    ) -> Result<ScheduleDiff, FangError> {
+        Self::apply_schedules_query(&self.connection, schedules)
+    }
+
+    pub fn apply_schedules_query(
+        connection: &Conn,
+        schedules: &[ScheduleDefinition],
+    ) -> Result<ScheduleDiff, FangError> {
+        connection
+            .borrow()
+            .transaction::<ScheduleDiff, FangError, _>(|| {
+                let diff = Self::diff_schedules_query(connection, schedules)?;
+
+                let new_tasks = diff
+                    .added
+                    .iter()
+                    .map(|schedule| {
+                        Ok(NewPeriodicTask {
+                            metadata: schedule.metadata.clone(),
+                            period_in_seconds: schedule.period_in_seconds,
+                            task_type: schedule.task_type()?,
+                        })
+                    })
+                    .collect::<Result<Vec<NewPeriodicTask>, FangError>>()?;
+
+                diesel::insert_into(fang_periodic_tasks::table)
+                    .values(&new_tasks)
+                    .execute(connection.borrow())?;
+
+                for schedule in &diff.updated {
+                    diesel::update(
+                        fang_periodic_tasks::table
+                            .filter(fang_periodic_tasks::metadata.eq(&schedule.metadata)),
+                    )
+                    .set((
+                        fang_periodic_tasks::period_in_seconds.eq(schedule.period_in_seconds),
+                        fang_periodic_tasks::updated_at.eq(Self::current_time()),
+                    ))
+                    .execute(connection.borrow())?;
+
+                    if let Some(ref task_type) = schedule.task_type {
+                        diesel::update(
+                            fang_periodic_tasks::table
+                                .filter(fang_periodic_tasks::metadata.eq(&schedule.metadata)),
+                        )
+                        .set(fang_periodic_tasks::task_type.eq(task_type))
+                        .execute(connection.borrow())?;
+                    }
+                }
+
+                let removed_ids: Vec<Uuid> = diff.removed.iter().map(|task| task.id).collect();
+
+                diesel::delete(
+                    fang_periodic_tasks::table.filter(fang_periodic_tasks::id.eq_any(removed_ids)),
+                )
+                .execute(connection.borrow())?;
+
+                Ok(diff)
+            })
+    }
+
+    pub fn schedule_next_task_execution(&self, task: &PeriodicTask) -> Result<PeriodicTask, Error> {
+        let current_time = Self::current_time();
+        let scheduled_at = current_time + Duration::seconds(task.period_in_seconds.into());
+
+        diesel::update(task)
+            .set((
+                fang_periodic_tasks::scheduled_at.eq(scheduled_at),
+                fang_periodic_tasks::updated_at.eq(current_time),
+            ))
+            .get_result::<PeriodicTask>(self.connection.borrow())
+    }
+
+    /// Replace the job and period of the periodic task `id` in place. Its `scheduled_at` is kept,
+    /// the new period applies from its next execution.
+    pub fn update_periodic_task(
+        &self,
+        id: Uuid,
+        job: &dyn Runnable,
+        period: i32,
+    ) -> Result<PeriodicTask, FangError> {
+        Self::update_periodic_task_query(&self.connection, id, job, period)
+    }
+
+    pub fn update_periodic_task_query(
+        connection: &Conn,
+        id: Uuid,
+        job: &dyn Runnable,
+        period: i32,
+    ) -> Result<PeriodicTask, FangError> {
+        let metadata = serde_json::to_value(job)?;
+
+        let task =
+            diesel::update(fang_periodic_tasks::table.filter(fang_periodic_tasks::id.eq(id)))
+                .set((
+                    fang_periodic_tasks::metadata.eq(metadata),
+                    fang_periodic_tasks::period_in_seconds.eq(period),
+                    fang_periodic_tasks::task_type.eq(job.task_type()),
+                    fang_periodic_tasks::updated_at.eq(Self::current_time()),
+                ))
+                .get_result::<PeriodicTask>(connection.borrow())?;
+
+        Ok(task)
+    }
+
+    /// Change what the scheduler does when `id` is due while its previous execution is pending.
+    pub fn set_overlap_policy(
+        &self,
+        id: Uuid,
+        overlap_policy: OverlapPolicy,
+    ) -> Result<PeriodicTask, Error> {
+        Self::set_overlap_policy_query(&self.connection, id, overlap_policy)
+    }
+
+    pub fn set_overlap_policy_query(
+        connection: &Conn,
+        id: Uuid,
+        overlap_policy: OverlapPolicy,
+    ) -> Result<PeriodicTask, Error> {
+        diesel::update(fang_periodic_tasks::table.filter(fang_periodic_tasks::id.eq(id)))
+            .set((
+                fang_periodic_tasks::overlap_policy.eq(overlap_policy),
+                fang_periodic_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .get_result::<PeriodicTask>(connection.borrow())
+    }
+
+    /// Enqueue an execution of the periodic `task`, whose job is `job`, according to its
+    /// `overlap_policy`. Returns `None` if the execution was skipped.
+    pub fn push_periodic_task_execution(
+        &self,
+        task: &PeriodicTask,
+        job: &dyn Runnable,
+        origin: &str,
+    ) -> Result<Option<Task>, Error> {
+        let connection = self.connection.borrow();
+
+        connection.transaction::<Option<Task>, Error, _>(|| {
+            let pending = fang_tasks::table
+                .select(fang_tasks::id)
+                .filter(Self::metadata_matches(&task.metadata))
+                .filter(
+                    fang_tasks::state
+                        .eq(FangTaskState::New)
+                        .or(fang_tasks::state.eq(FangTaskState::InProgress)),
+                )
+                .load::<Uuid>(connection)?;
+
+            match task.overlap_policy {
+                OverlapPolicy::Skip if !pending.is_empty() => return Ok(None),
+                OverlapPolicy::Replace => {
+                    for id in pending {
+                        Self::cancel_task_query(&self.connection, id)?;
+                    }
+                }
+                _ => {}
+            }
+
+            let new_task = NewTask {
+                metadata: task.metadata.clone(),
+                task_type: job.task_type(),
+            };
+
+            let options = InsertOptions {
+                origin: Some(origin),
+                task_version: job.version(),
+                ..InsertOptions::default()
+            };
+
+            Self::insert_task_query(
+                &self.connection,
+                &new_task,
+                self.signing_key.as_ref(),
+                &options,
+            )
+            .map(Some)
+        })
+    }
+
+    /// Count an execution of `task` and schedule the next one. Removes the task instead and
+    /// returns `None` if that was its last execution, see `PeriodicTask::is_exhausted`.
+    pub fn record_periodic_task_run(
+        &self,
+        task: &PeriodicTask,
+    ) -> Result<Option<PeriodicTask>, Error> {
+        let current_time = Self::current_time();
+        let scheduled_at = current_time + Duration::seconds(task.period_in_seconds.into());
+
+        let task = diesel::update(task)
+            .set((
+                fang_periodic_tasks::runs.eq(fang_periodic_tasks::runs + 1),
+                fang_periodic_tasks::scheduled_at.eq(scheduled_at),
+                fang_periodic_tasks::updated_at.eq(current_time),
+            ))
+            .get_result::<PeriodicTask>(self.connection.borrow())?;
+
+        if task.is_exhausted(scheduled_at) {
+            self.remove_periodic_task(task.id)?;
+            return Ok(None);
+        }
+
+        Ok(Some(task))
+    }
+
+    /// Push `task`'s next execution to `scheduled_at` without enqueuing it, e.g. until a
+    /// maintenance window ends.
+    pub fn defer_periodic_task(
+        &self,
+        task: &PeriodicTask,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<PeriodicTask, Error> {
+        diesel::update(task)
+            .set((
+                fang_periodic_tasks::scheduled_at.eq(scheduled_at),
+                fang_periodic_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .get_result::<PeriodicTask>(self.connection.borrow())
+    }
+
+    pub fn add_maintenance_window(
+        &self,
+        window: &NewMaintenanceWindow,
+    ) -> Result<MaintenanceWindow, Error> {
+        Self::add_maintenance_window_query(&self.connection, window)
+    }
+
+    pub fn add_maintenance_window_query(
+        connection: &Conn,
+        window: &NewMaintenanceWindow,
+    ) -> Result<MaintenanceWindow, Error> {
+        diesel::insert_into(fang_maintenance_windows::table)
+            .values(window)
+            .get_result::<MaintenanceWindow>(connection.borrow())
+    }
+
+    pub fn remove_maintenance_window(&self, id: Uuid) -> Result<usize, Error> {
+        Self::remove_maintenance_window_query(&self.connection, id)
+    }
+
+    pub fn remove_maintenance_window_query(connection: &Conn, id: Uuid) -> Result<usize, Error> {
+        let query = fang_maintenance_windows::table.filter(fang_maintenance_windows::id.eq(id));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    pub fn maintenance_windows(&self) -> Result<Vec<MaintenanceWindow>, Error> {
+        Self::maintenance_windows_query(&self.connection)
+    }
+
+    pub fn maintenance_windows_query(connection: &Conn) -> Result<Vec<MaintenanceWindow>, Error> {
+        fang_maintenance_windows::table
+            .order((
+                fang_maintenance_windows::weekday.asc(),
+                fang_maintenance_windows::starts_at.asc(),
+            ))
+            .load::<MaintenanceWindow>(connection.borrow())
+    }
+
+    pub fn remove_all_tasks(&self) -> Result<usize, Error> {
+        Self::remove_all_tasks_query(&self.connection)
+    }
+
+    pub fn remove_all_tasks_query(connection: &Conn) -> Result<usize, Error> {
+        diesel::delete(fang_tasks::table).execute(connection.borrow())
+    }
+
+    pub fn remove_tasks_of_type(&self, task_type: &str) -> Result<usize, Error> {
+        Self::remove_tasks_of_type_query(&self.connection, task_type)
+    }
+
+    pub fn remove_tasks_of_type_query(connection: &Conn, task_type: &str) -> Result<usize, Error> {
+        let query = fang_tasks::table.filter(fang_tasks::task_type.eq(task_type));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    pub fn remove_all_periodic_tasks(&self) -> Result<usize, Error> {
+        Self::remove_all_periodic_tasks_query(&self.connection)
+    }
+
+    pub fn remove_all_periodic_tasks_query(connection: &Conn) -> Result<usize, Error> {
+        diesel::delete(fang_periodic_tasks::table).execute(connection.borrow())
+    }
+
+    /// Remove finished and failed tasks that weren't updated for `age`, see `PruneTasks`.
+    pub fn remove_tasks_older_than(&self, age: Duration) -> Result<usize, Error> {
+        Self::remove_tasks_older_than_query(&self.connection, age)
+    }
+
+    pub fn remove_tasks_older_than_query(connection: &Conn, age: Duration) -> Result<usize, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::state.eq_any(vec![FangTaskState::Finished, FangTaskState::Failed]))
+            .filter(fang_tasks::updated_at.lt(Self::current_time() - age));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    /// Remove finished tasks that weren't updated for `age` in one statement, failed tasks
+    /// are kept. Returns the number of removed tasks.
+    pub fn remove_finished_tasks_older_than(&self, age: Duration) -> Result<usize, Error> {
+        Self::remove_finished_tasks_older_than_query(&self.connection, age)
+    }
+
+    pub fn remove_finished_tasks_older_than_query(
+        connection: &Conn,
+        age: Duration,
+    ) -> Result<usize, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::Finished))
+            .filter(fang_tasks::updated_at.lt(Self::current_time() - age));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    /// Create the partition of a partitioned `fang_tasks` (see
+    /// `partitioning/partition_fang_tasks_by_created_at.sql`) for the tasks created from
+    /// `from` until `to`, and return its name. Create partitions ahead of time: no partition
+    /// can be created for a range that already has tasks in `fang_tasks_default`.
+    pub fn create_tasks_partition(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<String, Error> {
+        Self::create_tasks_partition_query(&self.connection, from, to)
+    }
+
+    pub fn create_tasks_partition_query(
+        connection: &Conn,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<String, Error> {
+        let name = format!("fang_tasks_{}", from.format("%Y%m%d%H%M"));
+
+        diesel::sql_query(format!(
+            "CREATE TABLE IF NOT EXISTS {} PARTITION OF fang_tasks FOR VALUES FROM ('{}') TO ('{}')",
+            name,
+            Self::timestamp_literal(from),
+            Self::timestamp_literal(to)
+        ))
+        .execute(connection.borrow())?;
+
+        Ok(name)
+    }
+
+    /// Drop the partitions of a partitioned `fang_tasks` whose tasks were all created before
+    /// `before`, unless they still have new, in-progress, waiting or held tasks. Returns the
+    /// names of the dropped partitions. It replaces `remove_tasks_older_than` for partitioned
+    /// tables, dropping a partition doesn't leave dead rows behind.
+    pub fn drop_tasks_partitions_before(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<String>, Error> {
+        Self::drop_tasks_partitions_before_query(&self.connection, before)
+    }
+
+    pub fn drop_tasks_partitions_before_query(
+        connection: &Conn,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<String>, Error> {
+        // The upper bound is parsed back from the partition bound, the default partition
+        // has none
+        let partitions = diesel::select(sql::<Array<Text>>(&format!(
+            "ARRAY(SELECT partitions.oid::regclass::text FROM pg_inherits \
+             JOIN pg_class partitions ON partitions.oid = pg_inherits.inhrelid \
+             WHERE pg_inherits.inhparent = 'fang_tasks'::regclass \
+             AND substring(pg_get_expr(partitions.relpartbound, partitions.oid) \
+             FROM 'TO \\(''(.*)''\\)')::timestamptz <= '{}')",
+            Self::timestamp_literal(before)
+        )))
+        .get_result::<Vec<String>>(connection.borrow())?;
+
+        let mut dropped = Vec::new();
+
+        for partition in partitions {
+            let pending = diesel::select(sql::<Bool>(&format!(
+                "EXISTS (SELECT 1 FROM {} WHERE state NOT IN ('finished', 'failed', 'cancelled'))",
+                partition
+            )))
+            .get_result::<bool>(connection.borrow())?;
+
+            if !pending {
+                diesel::sql_query(format!("DROP TABLE {}", partition))
+                    .execute(connection.borrow())?;
+
+                dropped.push(partition);
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    // Truncated to microseconds like diesel binds timestamps, Postgres would round them
+    fn timestamp_literal(timestamp: DateTime<Utc>) -> String {
+        timestamp.format("%Y-%m-%d %H:%M:%S%.6f%:z").to_string()
+    }
+
+    pub fn remove_periodic_tasks_of_type(&self, task_type: &str) -> Result<usize, Error> {
+        Self::remove_periodic_tasks_of_type_query(&self.connection, task_type)
+    }
+
+    pub fn remove_periodic_tasks_of_type_query(
+        connection: &Conn,
+        task_type: &str,
+    ) -> Result<usize, Error> {
+        let query = fang_periodic_tasks::table.filter(fang_periodic_tasks::task_type.eq(task_type));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    pub fn remove_periodic_task(&self, id: Uuid) -> Result<usize, Error> {
+        Self::remove_periodic_task_query(&self.connection, id)
+    }
+
+    pub fn remove_periodic_task_query(connection: &Conn, id: Uuid) -> Result<usize, Error> {
+        let query = fang_periodic_tasks::table.filter(fang_periodic_tasks::id.eq(id));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    pub fn remove_task(&self, id: Uuid) -> Result<usize, Error> {
+        Self::remove_task_query(&self.connection, id)
+    }
+
+    pub fn remove_task_query(connection: &Conn, id: Uuid) -> Result<usize, Error> {
+        let query = fang_tasks::table.filter(fang_tasks::id.eq(id));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    /// Remove several tasks with one statement.
+    pub fn remove_tasks(&self, ids: &[Uuid]) -> Result<usize, Error> {
+        Self::remove_tasks_query(&self.connection, ids)
+    }
+
+    pub fn remove_tasks_query(connection: &Conn, ids: &[Uuid]) -> Result<usize, Error> {
+        let query = fang_tasks::table.filter(fang_tasks::id.eq_any(ids));
+
+        diesel::delete(query).execute(connection.borrow())
+    }
+
+    /// Mark several tasks as finished with one statement.
+    pub fn finish_tasks(&self, ids: &[Uuid]) -> Result<usize, Error> {
+        Self::finish_tasks_query(&self.connection, ids)
+    }
+
+    pub fn finish_tasks_query(connection: &Conn, ids: &[Uuid]) -> Result<usize, Error> {
+        let query = fang_tasks::table.filter(fang_tasks::id.eq_any(ids));
+
+        let current_time = Self::current_time();
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Finished),
+                fang_tasks::finished_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+            ))
+            .execute(connection.borrow())
+    }
+
+    /// Mark the dependencies on the finished tasks `ids` as satisfied, see
+    /// `push_task_with_dependencies`. Called by the executor before finished tasks are
+    /// removed, `finish_task` doesn't call it.
+    pub fn satisfy_dependencies_query(connection: &Conn, ids: &[Uuid]) -> Result<usize, Error> {
+        let query = fang_task_dependencies::table
+            .filter(fang_task_dependencies::depends_on_id.eq_any(ids))
+            .filter(fang_task_dependencies::satisfied.eq(false));
+
+        diesel::update(query)
+            .set(fang_task_dependencies::satisfied.eq(true))
+            .execute(connection.borrow())
+    }
+
+    /// Move the `Waiting` successors of the finished tasks `ids` to `New`, see `push_chain`.
+    /// Called by the executor before finished tasks are removed.
+    pub fn enqueue_next_tasks_query(connection: &Conn, ids: &[Uuid]) -> Result<usize, Error> {
+        diesel::sql_query(
+            "UPDATE fang_tasks SET state = 'new', updated_at = $2 \
+             WHERE state = 'waiting' \
+             AND id IN (SELECT next_task_id FROM fang_tasks WHERE id = ANY($1))",
+        )
+        .bind::<Array<diesel::sql_types::Uuid>, _>(ids)
+        .bind::<Timestamptz, _>(Self::current_time())
+        .execute(connection.borrow())
+    }
+
+    pub fn finish_task(&self, task: &Task) -> Result<Task, Error> {
+        Self::finish_task_query(&self.connection, task)
+    }
+
+    pub fn finish_task_query(connection: &Conn, task: &Task) -> Result<Task, Error> {
+        let current_time = Self::current_time();
+
+        diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Finished),
+                fang_tasks::finished_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+                fang_tasks::duration_in_milliseconds.eq(task.duration_in_milliseconds),
+            ))
+            .get_result::<Task>(connection.borrow())
+    }
+
+    /// Move tasks into `fang_archived_tasks`. Returns the number of archived tasks. Archived
+    /// tasks with the same id, which tasks with deterministic ids can have (see
+    /// `set_deterministic_ids`), are kept.
+    pub fn archive_tasks(&self, ids: &[Uuid]) -> Result<usize, Error> {
+        Self::archive_tasks_query(&self.connection, ids)
+    }
+
+    pub fn archive_tasks_query(connection: &Conn, ids: &[Uuid]) -> Result<usize, Error> {
+        diesel::sql_query(format!(
+            "WITH archived AS (DELETE FROM fang_tasks WHERE id = ANY($1) RETURNING *) {}",
+            insert_archived_sql()
+        ))
+        .bind::<Array<diesel::sql_types::Uuid>, _>(ids)
+        .execute(connection.borrow())
+    }
+
+    /// Move finished, failed and cancelled tasks that weren't updated for `age` into
+    /// `fang_archived_tasks`. Returns the number of archived tasks.
+    pub fn archive_tasks_older_than(&self, age: Duration) -> Result<usize, Error> {
+        Self::archive_tasks_older_than_query(&self.connection, age)
+    }
+
+    pub fn archive_tasks_older_than_query(
+        connection: &Conn,
+        age: Duration,
+    ) -> Result<usize, Error> {
+        diesel::sql_query(format!(
+            "WITH archived AS (DELETE FROM fang_tasks \
+             WHERE state IN ('finished', 'failed', 'cancelled') AND updated_at < $1 RETURNING *) \
+             {}",
+            insert_archived_sql()
+        ))
+        .bind::<Timestamptz, _>(Self::current_time() - age)
+        .execute(connection.borrow())
+    }
+
+    /// The task archived last with the id, see `find_archived_tasks_by_id` for all of them.
+    pub fn find_archived_task_by_id(&self, id: Uuid) -> Result<Option<ArchivedTask>, Error> {
+        Self::find_archived_task_by_id_query(&self.connection, id)
+    }
+
+    pub fn find_archived_task_by_id_query(
+        connection: &Conn,
+        id: Uuid,
+    ) -> Result<Option<ArchivedTask>, Error> {
+        fang_archived_tasks::table
+            .filter(fang_archived_tasks::id.eq(id))
+            .order((
+                fang_archived_tasks::archived_at.desc(),
+                fang_archived_tasks::updated_at.desc(),
+            ))
+            .first::<ArchivedTask>(connection.borrow())
+            .optional()
+    }
+
+    /// Every archived task with the id, oldest first. Tasks with deterministic ids (see
+    /// `set_deterministic_ids`) are archived once per reuse of their idempotency key.
+    pub fn find_archived_tasks_by_id(&self, id: Uuid) -> Result<Vec<ArchivedTask>, Error> {
+        Self::find_archived_tasks_by_id_query(&self.connection, id)
+    }
+
+    pub fn find_archived_tasks_by_id_query(
+        connection: &Conn,
+        id: Uuid,
+    ) -> Result<Vec<ArchivedTask>, Error> {
+        fang_archived_tasks::table
+            .filter(fang_archived_tasks::id.eq(id))
+            .order((
+                fang_archived_tasks::archived_at.asc(),
+                fang_archived_tasks::updated_at.asc(),
+            ))
+            .load::<ArchivedTask>(connection.borrow())
+    }
+
+    /// Mark a new, in-progress or held task as cancelled. Running tasks see it through
+    /// `TaskContext::is_cancelled`. Returns the number of cancelled tasks.
+    pub fn cancel_task(&self, id: Uuid) -> Result<usize, Error> {
+        Self::cancel_task_query(&self.connection, id)
+    }
+
+    pub fn cancel_task_query(connection: &Conn, id: Uuid) -> Result<usize, Error> {
+        let query =
+            fang_tasks::table
+                .filter(fang_tasks::id.eq(id))
+                .filter(fang_tasks::state.eq_any(vec![
+                    FangTaskState::New,
+                    FangTaskState::InProgress,
+                    FangTaskState::Held,
+                ]));
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Cancelled),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .execute(connection.borrow())
+    }
+
+    /// Cancel all new, in-progress and held tasks of `task_type` in one statement, like
+    /// `cancel_task`. Returns the number of cancelled tasks.
+    pub fn cancel_tasks_of_type(&self, task_type: &str) -> Result<usize, Error> {
+        Self::cancel_tasks_of_type_query(&self.connection, task_type)
+    }
+
+    pub fn cancel_tasks_of_type_query(connection: &Conn, task_type: &str) -> Result<usize, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::task_type.eq(task_type))
+            .filter(fang_tasks::state.eq_any(vec![
+                FangTaskState::New,
+                FangTaskState::InProgress,
+                FangTaskState::Held,
+            ]));
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Cancelled),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .execute(connection.borrow())
+    }
+
+    /// Run a new or failed task at `scheduled_at` instead, e.g. to put off a problematic job
+    /// during an incident. Failed tasks are moved back to `New`, their errors are kept.
+    /// Returns the number of rescheduled tasks.
+    pub fn reschedule_task(&self, id: Uuid, scheduled_at: DateTime<Utc>) -> Result<usize, Error> {
+        Self::reschedule_task_query(&self.connection, id, scheduled_at)
+    }
+
+    pub fn reschedule_task_query(
+        connection: &Conn,
+        id: Uuid,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(fang_tasks::state.eq_any(vec![FangTaskState::New, FangTaskState::Failed]));
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::scheduled_at.eq(scheduled_at),
+                fang_tasks::locked_by.eq(None::<String>),
+                fang_tasks::locked_until.eq(None::<DateTime<Utc>>),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .execute(connection.borrow())
+    }
+
+    /// Put a task into the `Held` state, it won't be fetched until it's released.
+    pub fn hold_task(&self, task: &Task, error: String) -> Result<Task, Error> {
+        Self::hold_task_query(&self.connection, task, error)
+    }
+
+    pub fn hold_task_query(connection: &Conn, task: &Task, error: String) -> Result<Task, Error> {
+        let current_time = Self::current_time();
+
+        diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Held),
+                fang_tasks::errors.eq(Self::appended_error(&error)),
+                fang_tasks::error_message.eq(error),
+                fang_tasks::finished_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+                fang_tasks::duration_in_milliseconds.eq(task.duration_in_milliseconds),
+            ))
+            .get_result::<Task>(connection.borrow())
+    }
+
+    /// Move a task back to `New`, to be fetched again after `scheduled_at`. `reason` is kept
+    /// in `error_message`.
+    pub fn snooze_task_query(
+        connection: &Conn,
+        task: &Task,
+        scheduled_at: DateTime<Utc>,
+        reason: String,
+    ) -> Result<Task, Error> {
+        diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::scheduled_at.eq(scheduled_at),
+                fang_tasks::error_message.eq(reason),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .get_result::<Task>(connection.borrow())
+    }
+
+    /// Store `job` as the job of `task`, e.g. the one returned by `Runnable::on_retry`. The job
+    /// is kept as JSON in `metadata` and signed with the signing key of the queue. The payload
+    /// columns are cleared, offloaded payloads stay in the payload store.
+    pub fn replace_job(&self, task: &Task, job: &dyn Runnable) -> Result<Task, FangError> {
+        Self::replace_job_query(&self.connection, task, job, self.signing_key.as_ref())
+    }
+
+    pub fn replace_job_query(
+        connection: &Conn,
+        task: &Task,
+        job: &dyn Runnable,
+        signing_key: Option<&SigningKey>,
+    ) -> Result<Task, FangError> {
+        let metadata = serde_json::to_value(job)?;
+        let signature = signing_key.map(|signing_key| signing_key.sign(&task.task_type, &metadata));
+
+        let task = diesel::update(task)
+            .set((
+                fang_tasks::metadata.eq(&metadata),
+                fang_tasks::signature.eq(signature),
+                fang_tasks::task_version.eq(job.version()),
+                fang_tasks::payload.eq(None::<Vec<u8>>),
+                fang_tasks::payload_format.eq(None::<String>),
+                fang_tasks::payload_encoding.eq(None::<String>),
+                fang_tasks::payload_reference.eq(None::<String>),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .get_result::<Task>(connection.borrow())?;
+
+        Ok(task)
+    }
+
+    /// Move a held task back to `New`. Returns the number of released tasks.
+    pub fn release_task(&self, id: Uuid) -> Result<usize, Error> {
+        Self::release_task_query(&self.connection, id)
+    }
+
+    pub fn release_task_query(connection: &Conn, id: Uuid) -> Result<usize, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(fang_tasks::state.eq(FangTaskState::Held));
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .execute(connection.borrow())
+    }
+
+    /// All tasks waiting for manual intervention, the ones held the longest first.
+    pub fn held_tasks(&self) -> Result<Vec<Task>, Error> {
+        Self::held_tasks_query(&self.connection)
+    }
+
+    pub fn held_tasks_query(connection: &Conn) -> Result<Vec<Task>, Error> {
+        fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::Held))
+            .order(fang_tasks::updated_at.asc())
+            .load::<Task>(connection.borrow())
+    }
+
+    /// Put a task back into the `New` state so it's fetched again.
+    pub fn reset_task(&self, task: &Task) -> Result<Task, Error> {
+        Self::reset_task_query(&self.connection, task)
+    }
+
+    pub fn reset_task_query(connection: &Conn, task: &Task) -> Result<Task, Error> {
+        diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::locked_by.eq(None::<String>),
+                fang_tasks::locked_until.eq(None::<DateTime<Utc>>),
+                fang_tasks::worker_name.eq(None::<String>),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .get_result::<Task>(connection.borrow())
+    }
+
+    /// Move `InProgress` tasks back to `New`. Returns the number of reset tasks.
+    pub fn reset_tasks_query(connection: &Conn, ids: &[Uuid]) -> Result<usize, Error> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq_any(ids))
+            .filter(fang_tasks::state.eq(FangTaskState::InProgress));
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::locked_by.eq(None::<String>),
+                fang_tasks::locked_until.eq(None::<DateTime<Utc>>),
+                fang_tasks::worker_name.eq(None::<String>),
+                fang_tasks::updated_at.eq(Self::current_time()),
+            ))
+            .execute(connection.borrow())
+    }
+
+    pub fn start_processing_task(&self, task: &Task) -> Result<Task, Error> {
+        Self::start_processing_task_query(&self.connection, task)
+    }
+
+    pub fn start_processing_task_query(connection: &Conn, task: &Task) -> Result<Task, Error> {
+        let current_time = Self::current_time();
+
+        diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::InProgress),
+                fang_tasks::attempts.eq(fang_tasks::attempts + 1),
+                fang_tasks::started_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+                fang_tasks::worker_name.eq(Self::current_worker_name()),
+            ))
+            .get_result::<Task>(connection.borrow())
+    }
+
+    pub fn fail_task(&self, task: &Task, error: String) -> Result<Task, Error> {
+        Self::fail_task_query(&self.connection, task, error)
+    }
+
+    pub fn fail_task_query(connection: &Conn, task: &Task, error: String) -> Result<Task, Error> {
+        let current_time = Self::current_time();
+
+        diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Failed),
+                fang_tasks::errors.eq(Self::appended_error(&error)),
+                fang_tasks::error_message.eq(error),
+                fang_tasks::finished_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+                fang_tasks::duration_in_milliseconds.eq(task.duration_in_milliseconds),
+            ))
+            .get_result::<Task>(connection.borrow())
+    }
+
+    /// Mark all new and held tasks of `task_type` as failed with `reason` in one statement.
+    /// Running tasks aren't touched. Returns the number of failed tasks.
+    pub fn fail_tasks_of_type(&self, task_type: &str, reason: &str) -> Result<usize, Error> {
+        Self::fail_tasks_of_type_query(&self.connection, task_type, reason)
+    }
+
+    pub fn fail_tasks_of_type_query(
+        connection: &Conn,
+        task_type: &str,
+        reason: &str,
+    ) -> Result<usize, Error> {
+        let current_time = Self::current_time();
+
+        let query = fang_tasks::table
+            .filter(fang_tasks::task_type.eq(task_type))
+            .filter(fang_tasks::state.eq_any(vec![FangTaskState::New, FangTaskState::Held]));
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Failed),
+                fang_tasks::errors.eq(Self::appended_error(reason)),
+                fang_tasks::error_message.eq(reason),
+                fang_tasks::finished_at.eq(current_time),
+                fang_tasks::updated_at.eq(current_time),
+            ))
+            .execute(connection.borrow())
+    }
+
+    /// `fang_tasks.errors` with `message` appended.
+    fn appended_error(
+        message: &str,
+    ) -> impl AppearsOnTable<fang_tasks::table, SqlType = Jsonb> + NonAggregate + QueryFragment<Pg>
+    {
+        sql::<Jsonb>("fang_tasks.errors || jsonb_build_array(jsonb_build_object('message', ")
+            .bind::<Text, _>(message.to_string())
+            .sql(", 'at', now(), 'attempt', jsonb_array_length(fang_tasks.errors) + 1))")
+    }
+
+    fn current_time() -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn current_worker_name() -> Option<String> {
+        thread::current().name().map(ToString::to_string)
+    }
+
+    /// The ids of the oldest `limit` claimable tasks, locked. With a `task_type` it's a range
+    /// scan of `fang_tasks_fetch_index`.
+    fn claimable_ids<'a>(params: &ClaimParams<'a>, limit: i64) -> impl QueryFragment<Pg> + 'a {
+        fang_tasks::table
+            .select(fang_tasks::id)
+            .filter(Self::claimable_tasks(params))
+            .order(fang_tasks::created_at.asc())
+            .limit(limit)
+            .for_update()
+            .skip_locked()
+    }
+
+    fn claimable_tasks<'a>(params: &ClaimParams<'a>) -> TaskPredicate<'a> {
+        Self::with_task_types(Self::due_tasks(), params)
+    }
+
+    /// Claimable tasks and `InProgress` tasks whose lease expired.
+    fn leasable_tasks<'a>(params: &ClaimParams<'a>) -> TaskPredicate<'a> {
+        let expired_lease = fang_tasks::state
+            .eq(FangTaskState::InProgress)
+            .and(fang_tasks::locked_until.le(Self::current_time()));
+
+        Self::with_task_types(Box::new(Self::due_tasks().or(expired_lease)), params)
+    }
+
+    /// The state is a literal, a bound parameter wouldn't match the predicate of
+    /// `fang_tasks_fetch_index` in generic plans of prepared statements.
+    fn due_tasks<'a>() -> TaskPredicate<'a> {
+        Box::new(
+            sql::<Bool>("fang_tasks.state = 'new'")
+                .and(
+                    fang_tasks::scheduled_at
+                        .is_null()
+                        .or(fang_tasks::scheduled_at.le(Self::current_time())),
+                )
+                .and(Self::dependencies_satisfied())
+                .and(Self::first_of_ordering_key()),
+        )
+    }
+
+    /// Tasks without an ordering key, or the oldest new task of their key if no task of the
+    /// key is in progress, see `push_task_with_ordering_key`. While a worker claims that task,
+    /// other workers still see it as new, so they can't claim the next task of the key.
+    fn first_of_ordering_key<'a>() -> TaskPredicate<'a> {
+        Box::new(sql::<Bool>(
+            "(fang_tasks.ordering_key IS NULL OR NOT EXISTS (SELECT 1 FROM fang_tasks AS others \
+             WHERE others.ordering_key = fang_tasks.ordering_key AND others.id <> fang_tasks.id \
+             AND (others.state = 'in_progress' OR (others.state = 'new' \
+             AND (others.created_at, others.id) < (fang_tasks.created_at, fang_tasks.id)))))",
+        ))
+    }
+
+    /// Tasks whose dependencies all finished, see `push_task_with_dependencies`.
+    fn dependencies_satisfied<'a>() -> TaskPredicate<'a> {
+        Box::new(sql::<Bool>(
+            "NOT EXISTS (SELECT 1 FROM fang_task_dependencies \
+             WHERE fang_task_dependencies.task_id = fang_tasks.id \
+             AND NOT fang_task_dependencies.satisfied)",
+        ))
+    }
+
+    fn with_task_types<'a>(
+        mut predicate: TaskPredicate<'a>,
+        params: &ClaimParams<'a>,
+    ) -> TaskPredicate<'a> {
+        if let Some(task_type) = params.task_type {
+            predicate = Box::new(predicate.and(fang_tasks::task_type.eq(task_type)));
+        }
+
+        if !params.excluded_task_types.is_empty() {
+            predicate =
+                Box::new(predicate.and(fang_tasks::task_type.ne_all(params.excluded_task_types)));
+        }
+
+        predicate
+    }
+
+    /// Compares the indexed `metadata_hash` column instead of the full metadata. The column
+    /// is generated by the database, it isn't part of the `fang_tasks` schema.
+    fn metadata_matches(metadata: &serde_json::Value) -> TaskPredicate<'_> {
+        Box::new(
+            sql::<Bool>("fang_tasks.metadata_hash = fang_metadata_hash(")
+                .bind::<Jsonb, _>(metadata)
+                .sql(")"),
+        )
+    }
+
+    fn find_periodic_task_by_metadata_query(
+        connection: &Conn,
+        metadata: &serde_json::Value,
+    ) -> Option<PeriodicTask> {
+        fang_periodic_tasks::table
+            .filter(fang_periodic_tasks::metadata.eq(metadata))
+            .first::<PeriodicTask>(connection.borrow())
+            .ok()
+    }
+
+    fn find_task_by_metadata_query(
+        connection: &Conn,
+        metadata: &serde_json::Value,
+    ) -> Option<Task> {
+        fang_tasks::table
+            .filter(Self::metadata_matches(metadata))
+            .filter(
+                fang_tasks::state
+                    .eq(FangTaskState::New)
+                    .or(fang_tasks::state.eq(FangTaskState::InProgress)),
+            )
+            .first::<Task>(connection.borrow())
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use super::idempotency_task_id;
+    use super::time_ordered_id;
+    use super::NewTask;
+    use super::PeriodicTask;
+    use super::Queue;
+    use super::Task;
+    use super::TaskQuery;
+    use crate::claim::ClaimParams;
+    use crate::context::TaskContext;
+    use crate::error::FangError;
+    use crate::maintenance::NewMaintenanceWindow;
+    use crate::runnable::Error as ExecutorError;
+    use crate::runnable::Runnable;
+    use crate::schedules::ScheduleDefinition;
+    use crate::schema::fang_archived_tasks;
+    use crate::schema::fang_outbox;
+    use crate::schema::fang_periodic_tasks;
+    use crate::schema::fang_tasks;
+    use crate::schema::fang_uniq_keys;
+    use crate::schema::FangTaskState;
+    use crate::schema::OverlapPolicy;
+    use crate::signing::SigningKey;
+    use crate::typetag;
+    use chrono::prelude::*;
+    use chrono::{DateTime, Duration, NaiveTime, Utc};
+    use diesel::connection::Connection;
+    use diesel::connection::SimpleConnection;
+    use diesel::dsl::sql;
+    use diesel::pg::Pg;
+    use diesel::prelude::*;
+    use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
+    use diesel::result::Error;
+    use diesel::sql_types::Json;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    #[test]
+    fn try_new_with_url_returns_connection_errors() {
+        let result = Queue::try_new_with_url("postgres://localhost:1/fang".to_string());
+
+        assert!(matches!(result, Err(FangError::ConnectionError(_))));
+    }
+
+    #[test]
+    fn insert_inserts_task() {
+        let queue = Queue::new();
+
+        let new_task = NewTask {
+            metadata: serde_json::json!(true),
+            task_type: "common".to_string(),
+        };
+
+        let result = queue
+            .connection
+            .test_transaction::<Task, Error, _>(|| queue.insert(&new_task));
+
+        assert_eq!(result.state, FangTaskState::New);
+        assert_eq!(result.error_message, None);
+    }
+
+    #[test]
+    fn fetch_task_fetches_the_oldest_task() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let timestamp1 = Utc::now() - Duration::hours(40);
+
+            let task1 = insert_job(serde_json::json!(true), timestamp1, &queue.connection);
+
+            let timestamp2 = Utc::now() - Duration::hours(20);
+
+            insert_job(serde_json::json!(false), timestamp2, &queue.connection);
+
+            let found_task = queue.fetch_task(&None).unwrap();
+
+            assert_eq!(found_task.id, task1.id);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn finish_task_updates_state_field() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+
+            let updated_task = queue.finish_task(&task).unwrap();
+
+            assert_eq!(FangTaskState::Finished, updated_task.state);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn errors_are_appended_to_the_error_history() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+            assert!(task.error_history().is_empty());
+
+            let task = queue.hold_task(&task, "first".to_string()).unwrap();
+            let task = queue.fail_task(&task, "second".to_string()).unwrap();
+
+            let history = task.error_history();
+            let messages: Vec<_> = history.iter().map(|error| error.message.as_str()).collect();
+            let attempts: Vec<_> = history.iter().map(|error| error.attempt).collect();
+
+            assert_eq!(vec!["first", "second"], messages);
+            assert_eq!(vec![1, 2], attempts);
+            assert_eq!(Some("second".to_string()), task.error_message);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn archive_tasks_older_than_moves_old_terminal_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let old_task = insert_new_job(&queue.connection);
+            let old_task = queue.fail_task(&old_task, "failed".to_string()).unwrap();
+            let new_task = insert_job(serde_json::json!(false), Utc::now(), &queue.connection);
+
+            diesel::update(&old_task)
+                .set(fang_tasks::updated_at.eq(Utc::now() - Duration::days(2)))
+                .execute(&queue.connection)
+                .unwrap();
+
+            assert_eq!(
+                1,
+                queue.archive_tasks_older_than(Duration::days(1)).unwrap()
+            );
+
+            assert_eq!(None, queue.find_task_by_id(old_task.id).unwrap());
+            assert!(queue.find_task_by_id(new_task.id).unwrap().is_some());
+
+            let archived_task = queue
+                .find_archived_task_by_id(old_task.id)
+                .unwrap()
+                .unwrap();
+            assert_eq!(FangTaskState::Failed, archived_task.state);
+            assert_eq!(old_task.error_message, archived_task.error_message);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn remove_tasks_older_than_removes_old_finished_and_failed_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let finished_task = insert_new_job(&queue.connection);
+            let finished_task = queue.finish_task(&finished_task).unwrap();
+            let new_task = insert_job(serde_json::json!(false), Utc::now(), &queue.connection);
+
+            diesel::update(fang_tasks::table)
+                .set(fang_tasks::updated_at.eq(Utc::now() - Duration::days(2)))
+                .execute(&queue.connection)
+                .unwrap();
+
+            assert_eq!(1, queue.remove_tasks_older_than(Duration::days(1)).unwrap());
+
+            assert_eq!(None, queue.find_task_by_id(finished_task.id).unwrap());
+            assert!(queue.find_task_by_id(new_task.id).unwrap().is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn remove_finished_tasks_older_than_keeps_failed_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let finished_task = insert_new_job(&queue.connection);
+            let finished_task = queue.finish_task(&finished_task).unwrap();
+            let failed_task = insert_job(serde_json::json!(false), Utc::now(), &queue.connection);
+            let failed_task = queue.fail_task(&failed_task, "error".to_string()).unwrap();
+
+            diesel::update(fang_tasks::table)
+                .set(fang_tasks::updated_at.eq(Utc::now() - Duration::days(2)))
+                .execute(&queue.connection)
+                .unwrap();
+
+            assert_eq!(
+                1,
+                queue
+                    .remove_finished_tasks_older_than(Duration::days(1))
+                    .unwrap()
+            );
+
+            assert_eq!(None, queue.find_task_by_id(finished_task.id).unwrap());
+            assert!(queue.find_task_by_id(failed_task.id).unwrap().is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn find_tasks_filters_by_task_query() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let now = Utc::now();
+            let old_task = insert_job(
+                serde_json::json!(1),
+                now - Duration::days(2),
+                &queue.connection,
+            );
+            let task1 = insert_job(
+                serde_json::json!(2),
+                now - Duration::hours(2),
+                &queue.connection,
+            );
+            let task2 = insert_job(
+                serde_json::json!(3),
+                now - Duration::hours(1),
+                &queue.connection,
+            );
+            queue.fail_task(&old_task, "error".to_string()).unwrap();
+            let failed_task = queue.fail_task(&task2, "error".to_string()).unwrap();
+            queue.push_task(&ReconcileJob { number: 1 }).unwrap();
+
+            let query = TaskQuery::new()
+                .state(FangTaskState::Failed)
+                .task_type("common")
+                .created_after(now - Duration::days(1));
+
+            assert_eq!(vec![failed_task], queue.find_tasks(&query).unwrap());
+            assert_eq!(1, queue.count_tasks(&query).unwrap());
+
+            let query = TaskQuery::new()
+                .state(FangTaskState::New)
+                .state(FangTaskState::Failed)
+                .task_type("common")
+                .newest_first()
+                .limit(2);
+
+            let ids: Vec<Uuid> = queue
+                .find_tasks(&query)
+                .unwrap()
+                .iter()
+                .map(|task| task.id)
+                .collect();
+
+            assert_eq!(vec![task2.id, task1.id], ids);
+            assert_eq!(3, queue.count_tasks(&query).unwrap());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn stream_tasks_loads_chunks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let now = Utc::now();
+            let ids: Vec<Uuid> = (0..5)
+                .map(|n| {
+                    insert_job(
+                        serde_json::json!(n),
+                        now + Duration::seconds(n),
+                        &queue.connection,
+                    )
+                    .id
+                })
+                .collect();
+
+            let streamed: Vec<Uuid> = queue
+                .stream_tasks(&TaskQuery::new())
+                .chunk_size(2)
+                .map(|task| task.unwrap().id)
+                .collect();
+
+            assert_eq!(ids, streamed);
+
+            let query = TaskQuery::new().newest_first().offset(1).limit(3);
+            let streamed: Vec<Uuid> = queue
+                .stream_tasks(&query)
+                .chunk_size(2)
+                .map(|task| task.unwrap().id)
+                .collect();
+
+            assert_eq!(vec![ids[3], ids[2], ids[1]], streamed);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn cancel_and_fail_tasks_of_type() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let new_task = queue.push_task(&ReconcileJob { number: 1 }).unwrap();
+            let held_task = queue.push_task(&ReconcileJob { number: 2 }).unwrap();
+            let held_task = queue.hold_task(&held_task, "held".to_string()).unwrap();
+            let in_progress_task = queue.push_task(&ReconcileJob { number: 3 }).unwrap();
+            queue.start_processing_task(&in_progress_task).unwrap();
+            let other_task = insert_new_job(&queue.connection);
+
+            assert_eq!(
+                2,
+                queue
+                    .fail_tasks_of_type("reconcile", "incident 42")
+                    .unwrap()
+            );
+
+            let failed_task = queue.find_task_by_id(held_task.id).unwrap().unwrap();
+            assert_eq!(FangTaskState::Failed, failed_task.state);
+            assert_eq!(Some("incident 42".to_string()), failed_task.error_message);
+            assert_eq!(2, failed_task.error_history().len());
+            assert_eq!(
+                FangTaskState::Failed,
+                queue.find_task_by_id(new_task.id).unwrap().unwrap().state
+            );
+
+            assert_eq!(1, queue.cancel_tasks_of_type("reconcile").unwrap());
+            assert_eq!(
+                FangTaskState::Cancelled,
+                queue
+                    .find_task_by_id(in_progress_task.id)
+                    .unwrap()
+                    .unwrap()
+                    .state
+            );
+            assert_eq!(
+                FangTaskState::New,
+                queue.find_task_by_id(other_task.id).unwrap().unwrap().state
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn drop_tasks_partitions_before_keeps_partitions_with_pending_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            queue.connection.batch_execute(include_str!(
+                "../partitioning/partition_fang_tasks_by_created_at.sql"
+            ))?;
+
+            let day1 = Utc::now() + Duration::days(10);
+            let day2 = day1 + Duration::days(1);
+            let day3 = day2 + Duration::days(1);
+
+            let partition1 = queue.create_tasks_partition(day1, day2)?;
+            let partition2 = queue.create_tasks_partition(day2, day3)?;
+
+            let finished_task = insert_job(serde_json::json!(true), day1, &queue.connection);
+            queue.finish_task(&finished_task).unwrap();
+            let new_task = insert_job(serde_json::json!(false), day2, &queue.connection);
+
+            let task = queue
+                .push_task_with_idempotency_key(&Job { number: 10 }, "order-1")
+                .unwrap();
+            assert_eq!(
+                task,
+                queue
+                    .push_task_with_idempotency_key(&Job { number: 11 }, "order-1")
+                    .unwrap()
+            );
+
+            let dropped = queue.drop_tasks_partitions_before(day3)?;
+
+            assert!(dropped.contains(&partition1));
+            assert!(!dropped.contains(&partition2));
+            assert_eq!(None, queue.find_task_by_id(finished_task.id).unwrap());
+            assert!(queue.find_task_by_id(new_task.id).unwrap().is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn find_tasks_by_metadata_contains_matches_subsets() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue.push_task(&Job { number: 10 }).unwrap();
+            queue.push_task(&Job { number: 11 }).unwrap();
+
+            let found = queue
+                .find_tasks_by_metadata_contains(&serde_json::json!({"number": 10}))
+                .unwrap();
+
+            assert_eq!(vec![task], found);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn fail_task_updates_state_field_and_sets_error_message() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+            let error = "Failed".to_string();
+
+            let updated_task = queue.fail_task(&task, error.clone()).unwrap();
+
+            assert_eq!(FangTaskState::Failed, updated_task.state);
+            assert_eq!(error, updated_task.error_message.unwrap());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn cancel_task_updates_state_field() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+
+            assert_eq!(1, queue.cancel_task(task.id).unwrap());
+
+            let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+            assert_eq!(FangTaskState::Cancelled, found_task.state);
+
+            // finished and failed tasks can't be cancelled
+            let failed_task = queue.fail_task(&found_task, "error".to_string()).unwrap();
+            assert_eq!(0, queue.cancel_task(failed_task.id).unwrap());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn reschedule_task_moves_new_and_failed_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+
+            let later = Utc::now() + Duration::hours(1);
+            assert_eq!(1, queue.reschedule_task(task.id, later).unwrap());
+            assert_eq!(None, queue.fetch_task(&None));
+
+            let found_task = queue.find_task_by_id(task.id).unwrap().unwrap();
+            assert_eq!(FangTaskState::New, found_task.state);
+            assert!(found_task.scheduled_at.unwrap() > Utc::now());
+
+            let failed_task = queue.fail_task(&found_task, "error".to_string()).unwrap();
+            let earlier = Utc::now() - Duration::minutes(1);
+            assert_eq!(1, queue.reschedule_task(failed_task.id, earlier).unwrap());
+
+            let found_task = queue.fetch_task(&None).unwrap();
+            assert_eq!(task.id, found_task.id);
+            assert_eq!(FangTaskState::New, found_task.state);
+            assert_eq!(Some("error".to_string()), found_task.error_message);
+
+            // finished tasks can't be rescheduled
+            let finished_task = queue.finish_task(&found_task).unwrap();
+            assert_eq!(0, queue.reschedule_task(finished_task.id, later).unwrap());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn held_task_is_not_fetched_until_released() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+            let held_task = queue.hold_task(&task, "error".to_string()).unwrap();
+
+            assert_eq!(FangTaskState::Held, held_task.state);
+            assert_eq!(vec![held_task], queue.held_tasks().unwrap());
+            assert_eq!(None, queue.fetch_and_touch(&None).unwrap());
+
+            assert_eq!(1, queue.release_task(task.id).unwrap());
+            assert!(queue.held_tasks().unwrap().is_empty());
+
+            let fetched_task = queue.fetch_and_touch(&None).unwrap().unwrap();
+            assert_eq!(task.id, fetched_task.id);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn wait_for_task_returns_finished_task() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+            let finished_task = queue.finish_task(&task).unwrap();
+
+            let found_task = queue
+                .wait_for_task(task.id, std::time::Duration::from_secs(1))
+                .unwrap();
+
+            assert_eq!(Some(finished_task), found_task);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn wait_for_task_returns_archived_task() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+            let finished_task = queue.finish_task(&task).unwrap();
+            queue.archive_tasks(&[task.id]).unwrap();
+
+            let found_task = queue
+                .wait_for_task(task.id, std::time::Duration::from_secs(1))
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(finished_task.id, found_task.id);
+            assert_eq!(FangTaskState::Finished, found_task.state);
+            assert_eq!(finished_task.finished_at, found_task.finished_at);
+
+            // Removed tasks look like unknown ones
+            diesel::delete(fang_archived_tasks::table.filter(fang_archived_tasks::id.eq(task.id)))
+                .execute(&queue.connection)
+                .unwrap();
+
+            assert_eq!(
+                None,
+                queue
+                    .wait_for_task(task.id, std::time::Duration::from_secs(1))
+                    .unwrap()
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn wait_for_task_times_out() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+
+            let result = queue.wait_for_task(task.id, std::time::Duration::from_millis(300));
+
+            assert!(matches!(result, Err(FangError::WaitTimeout)));
+            assert_eq!(
+                None,
+                queue
+                    .wait_for_task(Uuid::new_v4(), std::time::Duration::from_secs(1))
+                    .unwrap()
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn fetch_and_touch_updates_state() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let _task = insert_new_job(&queue.connection);
+
+            let updated_task = queue.fetch_and_touch(&None).unwrap().unwrap();
+
+            assert_eq!(FangTaskState::InProgress, updated_task.state);
+            assert_eq!(
+                std::thread::current().name().map(ToString::to_string),
+                updated_task.worker_name
+            );
+
+            let reset_task = queue.reset_task(&updated_task).unwrap();
+
+            assert_eq!(None, reset_task.worker_name);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn attempts_and_run_timestamps_are_recorded() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = insert_new_job(&queue.connection);
+            assert_eq!(0, task.attempts);
+            assert_eq!(None, task.started_at);
+
+            let task = queue.fetch_and_touch(&None).unwrap().unwrap();
+            assert_eq!(1, task.attempts);
+            assert!(task.started_at.is_some());
+            assert_eq!(None, task.finished_at);
+
+            let task = queue.finish_task(&task).unwrap();
+            assert!(task.finished_at.unwrap() >= task.started_at.unwrap());
+
+            let task = queue.start_processing_task(&task).unwrap();
+            assert_eq!(2, task.attempts);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn fetch_and_touch_returns_none() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue.fetch_and_touch(&None).unwrap();
+
+            assert_eq!(None, task);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn fetch_and_touch_batch_claims_several_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let timestamp = Utc::now() - Duration::hours(40);
+
+            let task1 = insert_job(serde_json::json!(1), timestamp, &queue.connection);
+            let task2 = insert_job(serde_json::json!(2), Utc::now(), &queue.connection);
+
+            let tasks = queue.fetch_and_touch_batch(&None, 10).unwrap();
+
+            assert_eq!(2, tasks.len());
+            assert_eq!(task1.id, tasks[0].id);
+            assert_eq!(task2.id, tasks[1].id);
+            assert!(tasks
+                .iter()
+                .all(|task| task.state == FangTaskState::InProgress));
+
+            assert!(queue.fetch_and_touch_batch(&None, 10).unwrap().is_empty());
+
+            assert_eq!(2, queue.finish_tasks(&[task1.id, task2.id]).unwrap());
+            assert_eq!(
+                FangTaskState::Finished,
+                queue.find_task_by_id(task2.id).unwrap().unwrap().state
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn claim_tasks_with_lease_reclaims_expired_leases() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let params = ClaimParams {
+                task_type: &None,
+                excluded_task_types: &[],
+            };
+
+            let task = insert_new_job(&queue.connection);
+
+            let claimed = queue
+                .claim_tasks_with_lease(&params, 10, "worker_1", Duration::minutes(5))
+                .unwrap();
+
+            assert_eq!(1, claimed.len());
+            assert_eq!(task.id, claimed[0].id);
+            assert_eq!(Some("worker_1".to_string()), claimed[0].locked_by);
+            assert!(claimed[0].locked_until.unwrap() > Utc::now());
+
+            assert!(queue
+                .claim_tasks_with_lease(&params, 10, "worker_2", Duration::minutes(5))
+                .unwrap()
+                .is_empty());
+
+            assert!(queue
+                .renew_lease(task.id, "worker_1", Duration::seconds(-1))
+                .unwrap());
+
+            let reclaimed = queue
+                .claim_tasks_with_lease(&params, 10, "worker_2", Duration::minutes(5))
+                .unwrap();
+
+            assert_eq!(1, reclaimed.len());
+            assert_eq!(Some("worker_2".to_string()), reclaimed[0].locked_by);
+            assert_eq!(2, reclaimed[0].attempts);
+
+            assert!(!queue
+                .renew_lease(task.id, "worker_1", Duration::minutes(5))
+                .unwrap());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_returns_serialization_errors() {
+        let queue = Queue::new();
+
+        let mut numbers = std::collections::HashMap::new();
+        numbers.insert((1, 2), 3);
+
+        let result = queue.push_task(&UnserializableJob { numbers });
+
+        assert!(matches!(result, Err(FangError::SerializationError(_))));
+    }
+
+    #[test]
+    fn push_task_serializes_and_inserts_task() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let job = Job { number: 10 };
+            let task = queue.push_task(&job).unwrap();
+
+            let mut m = serde_json::value::Map::new();
+            m.insert(
+                "number".to_string(),
+                serde_json::value::Value::Number(10.into()),
+            );
+            m.insert(
+                "type".to_string(),
+                serde_json::value::Value::String("Job".to_string()),
+            );
+
+            assert_eq!(task.metadata, serde_json::value::Value::Object(m));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_does_not_insert_the_same_task() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let job = Job { number: 10 };
+            let task2 = queue.push_task(&job).unwrap();
+
+            let task1 = queue.push_task(&job).unwrap();
+
+            assert_eq!(task1.id, task2.id);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_unchecked_inserts_the_same_task_again() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let job = Job { number: 10 };
+            let task1 = queue.push_task(&job).unwrap();
+            let task2 = queue.push_task_unchecked(&job).unwrap();
+
+            assert_ne!(task1.id, task2.id);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_with_idempotency_key_returns_the_task_of_the_key() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task1 = queue
+                .push_task_with_idempotency_key(&Job { number: 10 }, "order-1")
+                .unwrap();
+            let task2 = queue
+                .push_task_with_idempotency_key(&Job { number: 11 }, "order-1")
+                .unwrap();
+            let task3 = queue
+                .push_task_with_idempotency_key(&Job { number: 10 }, "order-2")
+                .unwrap();
+            let task4 = queue.push_task_unchecked(&Job { number: 10 }).unwrap();
+
+            assert_eq!(task1.id, task2.id);
+            assert_eq!(Some(10), task2.metadata["number"].as_i64());
+            assert_eq!(Some("order-1".to_string()), task1.idempotency_key);
+            assert_ne!(task1.id, task3.id);
+            assert_eq!(None, task4.idempotency_key);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn deterministic_ids_are_derived_from_idempotency_keys() {
+        let mut queue = Queue::new();
+        queue.set_deterministic_ids(true);
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task1 = queue
+                .push_task_with_idempotency_key(&Job { number: 10 }, "order-3")
+                .unwrap();
+            let task2 = queue
+                .push_task_with_idempotency_key(&Job { number: 11 }, "order-3")
+                .unwrap();
+
+            assert_eq!(idempotency_task_id("order-3"), task1.id);
+            assert_eq!(task1.id, task2.id);
+            assert_ne!(idempotency_task_id("order-4"), task1.id);
+
+            queue.remove_task(task1.id).unwrap();
+
+            let task3 = queue
+                .push_task_with_idempotency_key(&Job { number: 12 }, "order-3")
+                .unwrap();
+
+            assert_eq!(task1.id, task3.id);
+            assert_eq!(Some(12), task3.metadata["number"].as_i64());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn archiving_keeps_archived_tasks_with_the_same_deterministic_id() {
+        let mut queue = Queue::new();
+        queue.set_deterministic_ids(true);
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            for number in 10..13 {
+                let task = queue
+                    .push_task_with_idempotency_key(&Job { number }, "order-5")
+                    .unwrap();
+                assert_eq!(Some(i64::from(number)), task.metadata["number"].as_i64());
+
+                let task = queue.finish_task(&task).unwrap();
+                assert_eq!(1, queue.archive_tasks(&[task.id]).unwrap());
+                assert_eq!(None, queue.find_task_by_id(task.id).unwrap());
+
+                let archived_task = queue.find_archived_task_by_id(task.id).unwrap().unwrap();
+                assert_eq!(task.metadata, archived_task.metadata);
+            }
+
+            let numbers: Vec<Option<i64>> = queue
+                .find_archived_tasks_by_id(idempotency_task_id("order-5"))
+                .unwrap()
+                .iter()
+                .map(|archived_task| archived_task.metadata["number"].as_i64())
+                .collect();
+            assert_eq!(vec![Some(10), Some(11), Some(12)], numbers);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn time_ordered_ids_sort_by_creation_time() {
+        let id1 = time_ordered_id();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let id2 = time_ordered_id();
+
+        assert_eq!(7, id1.get_version_num());
+        assert_eq!(Some(uuid::Variant::RFC4122), id1.get_variant());
+        assert!(id1 < id2);
+    }
+
+    #[cfg(feature = "uuid-v7")]
+    #[test]
+    fn tasks_get_time_ordered_ids() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue.push_task(&Job { number: 10 }).unwrap();
+
+            assert_eq!(7, task.id.get_version_num());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_with_uniq_key_dedups_within_the_window() {
+        let mut queue = Queue::new();
+        queue.set_dedup_window("common".to_string(), Duration::minutes(10));
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task1 = queue
+                .push_task_with_uniq_key(&Job { number: 10 }, "repo-1")
+                .unwrap()
+                .unwrap();
+            queue.finish_task(&task1).unwrap();
+
+            let task2 = queue
+                .push_task_with_uniq_key(&Job { number: 11 }, "repo-1")
+                .unwrap()
+                .unwrap();
+            let task3 = queue
+                .push_task_with_uniq_key(&Job { number: 10 }, "repo-2")
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(task1.id, task2.id);
+            assert_eq!(Some("repo-1".to_string()), task2.uniq_key);
+            assert_ne!(task1.id, task3.id);
+
+            diesel::update(fang_uniq_keys::table.filter(fang_uniq_keys::task_id.eq(task1.id)))
+                .set(fang_uniq_keys::enqueued_at.eq(Utc::now() - Duration::minutes(11)))
+                .execute(&queue.connection)
+                .unwrap();
+
+            let task4 = queue
+                .push_task_with_uniq_key(&Job { number: 11 }, "repo-1")
+                .unwrap()
+                .unwrap();
+
+            assert_ne!(task1.id, task4.id);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_with_uniq_key_dedups_removed_tasks_within_the_window() {
+        let mut queue = Queue::new();
+        queue.set_dedup_window("common".to_string(), Duration::minutes(10));
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue
+                .push_task_with_uniq_key(&Job { number: 10 }, "repo-1")
+                .unwrap()
+                .unwrap();
+
+            // What workers do with finished tasks under the default `RemoveFinished`
+            queue.finish_task(&task).unwrap();
+            queue.remove_task(task.id).unwrap();
+
+            assert_eq!(
+                None,
+                queue
+                    .push_task_with_uniq_key(&Job { number: 11 }, "repo-1")
+                    .unwrap()
+            );
+            assert_eq!(
+                0,
+                fang_tasks::table
+                    .filter(fang_tasks::uniq_key.eq("repo-1"))
+                    .count()
+                    .get_result::<i64>(&queue.connection)?
+            );
+
+            Ok(())
+        });
+    }
 
     #[test]
-    fn insert_inserts_task() {
+    fn push_task_with_uniq_key_without_window_dedups_pending_tasks() {
         let queue = Queue::new();
 
-        let new_task = NewTask {
-            metadata: serde_json::json!(true),
-            task_type: "common".to_string(),
-        };
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task1 = queue
+                .push_task_with_uniq_key(&Job { number: 10 }, "repo-1")
+                .unwrap()
+                .unwrap();
+            let task2 = queue
+                .push_task_with_uniq_key(&Job { number: 11 }, "repo-1")
+                .unwrap()
+                .unwrap();
 
-        let result = queue
-            .connection
-            .test_transaction::<Task, Error, _>(|| queue.insert(&new_task));
+            assert_eq!(task1.id, task2.id);
 
-        assert_eq!(result.state, FangTaskState::New);
-        assert_eq!(result.error_message, None);
+            queue.finish_task(&task1).unwrap();
+
+            let task3 = queue
+                .push_task_with_uniq_key(&Job { number: 11 }, "repo-1")
+                .unwrap()
+                .unwrap();
+
+            assert_ne!(task1.id, task3.id);
+
+            Ok(())
+        });
     }
 
     #[test]
-    fn fetch_task_fetches_the_oldest_task() {
+    fn tasks_with_dependencies_are_claimable_once_their_dependencies_finished() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
-            let timestamp1 = Utc::now() - Duration::hours(40);
+            let load1 = queue.push_task(&Job { number: 1 }).unwrap();
+            let load2 = queue.push_task(&Job { number: 2 }).unwrap();
+            let load3 = queue.push_task(&Job { number: 3 }).unwrap();
+            let report = queue
+                .push_task_with_dependencies(&Job { number: 4 }, &[load1.id, load2.id, load3.id])
+                .unwrap();
+
+            let mut dependencies = queue.find_dependencies(report.id).unwrap();
+            dependencies.sort();
+            let mut expected = vec![load1.id, load2.id, load3.id];
+            expected.sort();
+            assert_eq!(expected, dependencies);
+
+            let params = ClaimParams {
+                task_type: &None,
+                excluded_task_types: &[],
+            };
+            assert_eq!(3, queue.count_claimable_tasks(&params).unwrap());
+
+            queue.finish_task(&load1).unwrap();
+            Queue::satisfy_dependencies_query(&queue.connection, &[load1.id]).unwrap();
+            queue.remove_task(load2.id).unwrap();
+            assert_eq!(1, queue.count_claimable_tasks(&params).unwrap());
+
+            queue.fail_task(&load3, "error".to_string()).unwrap();
+            assert_eq!(0, queue.count_claimable_tasks(&params).unwrap());
+
+            // Removed without finishing, `load2` never satisfies the dependency
+            queue.finish_task(&load3).unwrap();
+            Queue::satisfy_dependencies_query(&queue.connection, &[load3.id]).unwrap();
+            assert_eq!(None, queue.fetch_task(&None));
+
+            let finished = queue.push_task(&Job { number: 5 }).unwrap();
+            let finished = queue.finish_task(&finished).unwrap();
+            let late_report = queue
+                .push_task_with_dependencies(&Job { number: 6 }, &[load1.id, finished.id])
+                .unwrap();
+            assert_eq!(
+                Some(late_report.id),
+                queue.fetch_task(&None).map(|task| task.id)
+            );
 
-            let task1 = insert_job(serde_json::json!(true), timestamp1, &queue.connection);
+            Ok(())
+        });
+    }
 
-            let timestamp2 = Utc::now() - Duration::hours(20);
+    #[test]
+    fn factory_connects_queues_with_the_same_settings() {
+        let mut queue = Queue::new();
+        queue.set_signing_key(SigningKey::new(b"factory"));
+        queue.set_dedup(false);
+
+        let new_queue = queue.factory(None)().unwrap();
+        let metadata = serde_json::to_value(&Job { number: 1 } as &dyn Runnable).unwrap();
+
+        assert!(!new_queue.dedup);
+        assert_eq!(
+            queue.signing_key.unwrap().sign("common", &metadata),
+            new_queue.signing_key.unwrap().sign("common", &metadata)
+        );
+    }
 
-            insert_job(serde_json::json!(false), timestamp2, &queue.connection);
+    #[test]
+    fn relay_outbox_enqueues_committed_entries() {
+        let mut queue = Queue::new();
+        queue.set_signing_key(SigningKey::new(b"outbox"));
 
-            let found_task = queue.fetch_task(&None).unwrap();
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let rolled_back = queue.connection.transaction::<(), FangError, _>(|| {
+                queue.stage_task(&Job { number: 1 })?;
 
-            assert_eq!(found_task.id, task1.id);
+                Err(FangError::WaitTimeout)
+            });
+            assert!(rolled_back.is_err());
+
+            let entry = queue.stage_task(&Job { number: 2 }).unwrap();
+
+            let tasks = queue.relay_outbox(10).unwrap();
+
+            assert_eq!(1, tasks.len());
+            assert_eq!(entry.metadata, tasks[0].metadata);
+            assert_eq!(entry.signature, tasks[0].signature);
+            assert!(tasks[0].signature.is_some());
+
+            let staged = fang_outbox::table
+                .count()
+                .get_result::<i64>(&queue.connection)
+                .unwrap();
+            assert_eq!(0, staged);
+            assert!(queue.relay_outbox(10).unwrap().is_empty());
 
             Ok(())
         });
     }
 
     #[test]
-    fn finish_task_updates_state_field() {
+    fn tasks_with_the_same_ordering_key_run_one_at_a_time_in_order() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
-            let task = insert_new_job(&queue.connection);
+            let first = queue
+                .push_task_with_ordering_key(&Job { number: 1 }, "customer-1")
+                .unwrap();
+            let second = queue
+                .push_task_with_ordering_key(&Job { number: 2 }, "customer-1")
+                .unwrap();
+            let other_key = queue
+                .push_task_with_ordering_key(&Job { number: 3 }, "customer-2")
+                .unwrap();
+            let without_key = queue.push_task(&Job { number: 4 }).unwrap();
+
+            for (task, minutes) in [(&first, 3), (&second, 2)] {
+                diesel::update(task)
+                    .set(fang_tasks::created_at.eq(Utc::now() - Duration::minutes(minutes)))
+                    .execute(&queue.connection)
+                    .unwrap();
+            }
 
-            let updated_task = queue.finish_task(&task).unwrap();
+            let params = ClaimParams {
+                task_type: &None,
+                excluded_task_types: &[],
+            };
 
-            assert_eq!(FangTaskState::Finished, updated_task.state);
+            let mut claimed: Vec<Uuid> = queue
+                .claim_tasks(&params, 10)
+                .unwrap()
+                .iter()
+                .map(|task| task.id)
+                .collect();
+            claimed.sort();
+
+            let mut expected = vec![first.id, other_key.id, without_key.id];
+            expected.sort();
+            assert_eq!(expected, claimed);
+
+            assert!(queue.claim_tasks(&params, 10).unwrap().is_empty());
+
+            queue.finish_task(&first).unwrap();
+
+            let claimed = queue.claim_tasks(&params, 10).unwrap();
+            assert_eq!(
+                vec![second.id],
+                claimed.iter().map(|task| task.id).collect::<Vec<_>>()
+            );
 
             Ok(())
         });
     }
 
     #[test]
-    fn fail_task_updates_state_field_and_sets_error_message() {
+    fn push_task_without_dedup_inserts_the_same_task_again() {
+        let mut queue = Queue::new();
+        queue.set_dedup(false);
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let job = Job { number: 10 };
+            let task1 = queue.push_task(&job).unwrap();
+            let task2 = queue.push_task(&job).unwrap();
+
+            assert_ne!(task1.id, task2.id);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_task_with_origin_records_the_caller() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
-            let task = insert_new_job(&queue.connection);
-            let error = "Failed".to_string();
+            let task = queue
+                .push_task_with_origin(&Job { number: 10 }, crate::origin!())
+                .unwrap();
 
-            let updated_task = queue.fail_task(&task, error.clone()).unwrap();
+            let origin = task.origin.unwrap();
+            assert!(origin.starts_with("fang::queue::queue_tests (src/queue.rs:"));
 
-            assert_eq!(FangTaskState::Failed, updated_task.state);
-            assert_eq!(error, updated_task.error_message.unwrap());
+            assert_eq!(None, queue.push_task(&Job { number: 11 }).unwrap().origin);
 
             Ok(())
         });
     }
 
     #[test]
-    fn fetch_and_touch_updates_state() {
+    fn push_task_unless_pending_skips_pending_types() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
-            let _task = insert_new_job(&queue.connection);
+            let task = queue
+                .push_task_unless_pending(&ReconcileJob { number: 10 }, "reconcile")
+                .unwrap()
+                .unwrap();
 
-            let updated_task = queue.fetch_and_touch(&None).unwrap().unwrap();
+            let other = ReconcileJob { number: 11 };
+            assert_eq!(
+                None,
+                queue.push_task_unless_pending(&other, "reconcile").unwrap()
+            );
 
-            assert_eq!(FangTaskState::InProgress, updated_task.state);
+            queue.finish_task(&task).unwrap();
+            assert!(queue
+                .push_task_unless_pending(&other, "reconcile")
+                .unwrap()
+                .is_some());
 
             Ok(())
         });
     }
 
     #[test]
-    fn fetch_and_touch_returns_none() {
+    fn fang_enqueue_sql_function_inserts_tasks() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
-            let task = queue.fetch_and_touch(&None).unwrap();
-
-            assert_eq!(None, task);
+            let enqueue = || {
+                diesel::select(diesel::dsl::sql::<diesel::sql_types::Uuid>(
+                    "fang_enqueue('sql_test', '{\"type\": \"Job\", \"number\": 10}', 'trigger')",
+                ))
+                .get_result::<Uuid>(&queue.connection)
+                .unwrap()
+            };
+
+            let id = enqueue();
+            assert_eq!(id, enqueue());
+
+            let task = queue.find_task_by_id(id).unwrap().unwrap();
+            assert_eq!("sql_test", task.task_type);
+            assert_eq!(Some("trigger".to_string()), task.origin);
+            assert_eq!(FangTaskState::New, task.state);
 
             Ok(())
         });
     }
 
     #[test]
-    fn push_task_serializes_and_inserts_task() {
+    fn schedule_task_is_not_fetched_before_its_time() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
             let job = Job { number: 10 };
-            let task = queue.push_task(&job).unwrap();
+            let later = queue
+                .schedule_task(&job, Utc::now() + Duration::hours(1))
+                .unwrap();
 
-            let mut m = serde_json::value::Map::new();
-            m.insert(
-                "number".to_string(),
-                serde_json::value::Value::Number(10.into()),
-            );
-            m.insert(
-                "type".to_string(),
-                serde_json::value::Value::String("Job".to_string()),
-            );
+            assert!(queue.fetch_task(&None).is_none());
 
-            assert_eq!(task.metadata, serde_json::value::Value::Object(m));
+            let now = queue
+                .schedule_task(&job, Utc::now() - Duration::seconds(1))
+                .unwrap();
+
+            assert_ne!(later.id, now.id);
+            assert_eq!(Some(now.id), queue.fetch_task(&None).map(|task| task.id));
 
             Ok(())
         });
     }
 
     #[test]
-    fn push_task_does_not_insert_the_same_task() {
+    fn push_task_deduplicates_by_metadata_hash() {
         let queue = Queue::new();
 
         queue.connection.test_transaction::<(), Error, _>(|| {
-            let job = Job { number: 10 };
-            let task2 = queue.push_task(&job).unwrap();
+            let id = diesel::select(sql::<diesel::sql_types::Uuid>(
+                "fang_enqueue('common', '{ \"number\": 10,  \"type\": \"Job\" }')",
+            ))
+            .get_result::<Uuid>(&queue.connection)
+            .unwrap();
 
-            let task1 = queue.push_task(&job).unwrap();
+            let task = queue.push_task(&Job { number: 10 }).unwrap();
 
-            assert_eq!(task1.id, task2.id);
+            assert_eq!(id, task.id);
 
             Ok(())
         });
@@ -578,6 +4461,114 @@ mod queue_tests {
         });
     }
 
+    #[test]
+    fn apply_schedules_adds_updates_and_removes_periodic_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let kept = queue.push_periodic_task(&Job { number: 1 }, 60).unwrap();
+            let changed = queue.push_periodic_task(&Job { number: 2 }, 60).unwrap();
+            let removed = queue.push_periodic_task(&Job { number: 3 }, 60).unwrap();
+
+            let schedules = vec![
+                ScheduleDefinition {
+                    metadata: kept.metadata.clone(),
+                    period_in_seconds: 60,
+                    task_type: None,
+                },
+                ScheduleDefinition {
+                    metadata: changed.metadata.clone(),
+                    period_in_seconds: 120,
+                    task_type: None,
+                },
+                ScheduleDefinition {
+                    metadata: serde_json::to_value(&Job { number: 4 } as &dyn Runnable).unwrap(),
+                    period_in_seconds: 30,
+                    task_type: None,
+                },
+            ];
+
+            let diff = queue.diff_schedules(&schedules).unwrap();
+            assert_eq!(vec![schedules[2].clone()], diff.added);
+            assert_eq!(vec![schedules[1].clone()], diff.updated);
+            assert_eq!(
+                vec![removed.id],
+                diff.removed.iter().map(|task| task.id).collect::<Vec<_>>()
+            );
+            assert!(queue.find_periodic_task_by_id(removed.id).is_some());
+
+            assert_eq!(diff, queue.apply_schedules(&schedules).unwrap());
+            assert!(queue.diff_schedules(&schedules).unwrap().is_empty());
+
+            let changed = queue.find_periodic_task_by_id(changed.id).unwrap();
+            assert_eq!(120, changed.period_in_seconds);
+            assert!(queue.find_periodic_task_by_id(removed.id).is_none());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn apply_schedules_needs_the_task_type_of_unknown_jobs() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let mut schedule = ScheduleDefinition {
+                metadata: serde_json::json!({"type": "UnknownJob"}),
+                period_in_seconds: 60,
+                task_type: None,
+            };
+
+            let result = queue.apply_schedules(&[schedule.clone()]);
+            assert!(matches!(result, Err(FangError::InvalidSchedules(_))));
+
+            schedule.task_type = Some("reports".to_string());
+            let diff = queue.apply_schedules(&[schedule.clone()]).unwrap();
+            assert_eq!(vec![schedule.clone()], diff.added);
+
+            let task_types = |queue: &Queue<PgConnection>| {
+                queue
+                    .list_periodic_tasks()
+                    .unwrap()
+                    .into_iter()
+                    .filter(|task| task.metadata == schedule.metadata)
+                    .map(|task| task.task_type)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(vec!["reports".to_string()], task_types(&queue));
+
+            let mut retyped = schedule.clone();
+            retyped.task_type = Some("emails".to_string());
+            let diff = queue.apply_schedules(&[retyped.clone()]).unwrap();
+            assert_eq!(vec![retyped], diff.updated);
+            assert_eq!(vec!["emails".to_string()], task_types(&queue));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn adds_and_removes_maintenance_windows() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let window = queue
+                .add_maintenance_window(&NewMaintenanceWindow {
+                    task_type: "report".to_string(),
+                    weekday: 7,
+                    starts_at: NaiveTime::from_hms_opt(1, 0, 0).unwrap(),
+                    ends_at: NaiveTime::from_hms_opt(3, 0, 0).unwrap(),
+                })
+                .unwrap();
+
+            assert_eq!(vec![window.clone()], queue.maintenance_windows().unwrap());
+            assert_eq!(1, queue.remove_maintenance_window(window.id).unwrap());
+            assert!(queue.maintenance_windows().unwrap().is_empty());
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn push_periodic_task_returns_existing_job() {
         let queue = Queue::new();
@@ -643,6 +4634,180 @@ mod queue_tests {
         });
     }
 
+    #[test]
+    fn record_periodic_task_run_removes_exhausted_tasks() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let job = Job { number: 10 };
+            let task = queue
+                .push_periodic_task_with_end(&job, 60, Some(2), None)
+                .unwrap();
+
+            let task = queue.record_periodic_task_run(&task).unwrap().unwrap();
+            assert_eq!(1, task.runs);
+
+            assert_eq!(None, queue.record_periodic_task_run(&task).unwrap());
+            assert_eq!(None, queue.find_periodic_task_by_id(task.id));
+
+            let ends_soon = Utc::now() + Duration::seconds(30);
+            let task = queue
+                .push_periodic_task_with_end(&job, 60, None, Some(ends_soon))
+                .unwrap();
+
+            assert!(!task.is_exhausted(Utc::now()));
+            assert_eq!(None, queue.record_periodic_task_run(&task).unwrap());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn push_periodic_task_execution_follows_overlap_policy() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let job = Job { number: 10 };
+            let task = queue.push_periodic_task(&job, 60).unwrap();
+            assert_eq!(OverlapPolicy::Skip, task.overlap_policy);
+
+            let first = queue
+                .push_periodic_task_execution(&task, &job, "test")
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(
+                None,
+                queue
+                    .push_periodic_task_execution(&task, &job, "test")
+                    .unwrap()
+            );
+
+            let task = queue
+                .set_overlap_policy(task.id, OverlapPolicy::Enqueue)
+                .unwrap();
+            let second = queue
+                .push_periodic_task_execution(&task, &job, "test")
+                .unwrap()
+                .unwrap();
+
+            assert_ne!(first.id, second.id);
+
+            let task = queue
+                .set_overlap_policy(task.id, OverlapPolicy::Replace)
+                .unwrap();
+            let third = queue
+                .push_periodic_task_execution(&task, &job, "test")
+                .unwrap()
+                .unwrap();
+
+            for id in &[first.id, second.id] {
+                let replaced = queue.find_task_by_id(*id).unwrap().unwrap();
+                assert_eq!(FangTaskState::Cancelled, replaced.state);
+            }
+
+            assert_eq!(
+                FangTaskState::New,
+                queue.find_task_by_id(third.id).unwrap().unwrap().state
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn update_periodic_task_keeps_the_schedule() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue.push_periodic_task(&Job { number: 10 }, 60).unwrap();
+            let task = queue.schedule_next_task_execution(&task).unwrap();
+
+            let updated = queue
+                .update_periodic_task(task.id, &Job { number: 11 }, 120)
+                .unwrap();
+
+            assert_eq!(task.id, updated.id);
+            assert_eq!(task.scheduled_at, updated.scheduled_at);
+            assert_eq!(120, updated.period_in_seconds);
+            assert_eq!(Some(11), updated.metadata["number"].as_u64());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn periodic_tasks_can_be_fetched_and_removed_by_type() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue.push_periodic_task(&Job { number: 10 }, 60).unwrap();
+            let other = queue
+                .push_periodic_task(&ReconcileJob { number: 10 }, 60)
+                .unwrap();
+
+            assert_eq!("common", task.task_type);
+            assert_eq!("reconcile", other.task_type);
+
+            let tasks = queue
+                .fetch_periodic_tasks_of_type(100, "reconcile")
+                .unwrap();
+            assert_eq!(
+                vec![other.id],
+                tasks.iter().map(|task| task.id).collect::<Vec<_>>()
+            );
+
+            assert_eq!(1, queue.remove_periodic_tasks_of_type("reconcile").unwrap());
+            assert_eq!(None, queue.find_periodic_task_by_id(other.id));
+            assert!(queue.find_periodic_task_by_id(task.id).is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn next_runs_previews_periodic_task_executions() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let scheduled_at = Utc::now() + Duration::minutes(1);
+            let task = insert_periodic_job(
+                serde_json::json!({"type": "Job", "number": 10}),
+                scheduled_at,
+                60,
+                &queue.connection,
+            );
+
+            let runs = queue.next_runs(task.id, 3).unwrap();
+
+            assert_eq!(3, runs.len());
+            assert!((runs[0] - scheduled_at).num_milliseconds().abs() < 1);
+            assert_eq!(Duration::minutes(1), runs[1] - runs[0]);
+            assert_eq!(Duration::minutes(1), runs[2] - runs[1]);
+
+            let limited = PeriodicTask {
+                max_runs: Some(5),
+                runs: 4,
+                ..task.clone()
+            };
+            assert_eq!(1, limited.next_runs(3, Utc::now()).len());
+
+            let ending = PeriodicTask {
+                ends_at: Some(scheduled_at + Duration::seconds(90)),
+                ..task.clone()
+            };
+            assert_eq!(2, ending.next_runs(3, Utc::now()).len());
+
+            let listed = queue.list_periodic_tasks().unwrap();
+            assert_eq!(
+                vec![task.id],
+                listed.iter().map(|task| task.id).collect::<Vec<_>>()
+            );
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn remove_all_periodic_tasks() {
         let queue = Queue::new();
@@ -671,7 +4836,7 @@ mod queue_tests {
 
             assert_eq!(1, result);
 
-            assert_eq!(None, queue.find_task_by_id(task.id));
+            assert_eq!(None, queue.find_task_by_id(task.id).unwrap());
 
             Ok(())
         });
@@ -719,17 +4884,17 @@ mod queue_tests {
 
         queue.connection.test_transaction::<(), Error, _>(|| {
             let task1 = queue.insert(&new_task1).unwrap();
-            assert!(queue.find_task_by_id(task1.id).is_some());
+            assert!(queue.find_task_by_id(task1.id).unwrap().is_some());
 
             let task2 = queue.insert(&new_task2).unwrap();
-            assert!(queue.find_task_by_id(task2.id).is_some());
+            assert!(queue.find_task_by_id(task2.id).unwrap().is_some());
 
             queue.remove_task(task1.id).unwrap();
-            assert!(queue.find_task_by_id(task1.id).is_none());
-            assert!(queue.find_task_by_id(task2.id).is_some());
+            assert!(queue.find_task_by_id(task1.id).unwrap().is_none());
+            assert!(queue.find_task_by_id(task2.id).unwrap().is_some());
 
             queue.remove_task(task2.id).unwrap();
-            assert!(queue.find_task_by_id(task2.id).is_none());
+            assert!(queue.find_task_by_id(task2.id).unwrap().is_none());
 
             Ok(())
         });
@@ -751,14 +4916,49 @@ mod queue_tests {
 
         queue.connection.test_transaction::<(), Error, _>(|| {
             let task1 = queue.insert(&new_task1).unwrap();
-            assert!(queue.find_task_by_id(task1.id).is_some());
+            assert!(queue.find_task_by_id(task1.id).unwrap().is_some());
 
             let task2 = queue.insert(&new_task2).unwrap();
-            assert!(queue.find_task_by_id(task2.id).is_some());
+            assert!(queue.find_task_by_id(task2.id).unwrap().is_some());
 
             queue.remove_tasks_of_type("type1").unwrap();
-            assert!(queue.find_task_by_id(task1.id).is_none());
-            assert!(queue.find_task_by_id(task2.id).is_some());
+            assert!(queue.find_task_by_id(task1.id).unwrap().is_none());
+            assert!(queue.find_task_by_id(task2.id).unwrap().is_some());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn claiming_tasks_of_a_type_scans_the_fetch_index() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            queue.connection.batch_execute(
+                "INSERT INTO fang_tasks (metadata, task_type) \
+                 SELECT jsonb_build_object('number', number), 'other' \
+                 FROM generate_series(1, 5000) AS number; \
+                 INSERT INTO fang_tasks (metadata, task_type) \
+                 SELECT jsonb_build_object('number', number), 'mailer' \
+                 FROM generate_series(1, 10) AS number; \
+                 ANALYZE fang_tasks;",
+            )?;
+
+            let task_type = Some("mailer".to_string());
+            let params = ClaimParams {
+                task_type: &task_type,
+                excluded_task_types: &[],
+            };
+
+            let plan = Explain(Queue::<PgConnection>::claimable_ids(&params, 1))
+                .get_result::<serde_json::Value>(&queue.connection)?
+                .to_string();
+
+            assert!(
+                plan.contains(r#""Index Name":"fang_tasks_fetch_index""#),
+                "{}",
+                plan
+            );
 
             Ok(())
         });
@@ -823,13 +5023,76 @@ mod queue_tests {
 
     #[typetag::serde]
     impl Runnable for Job {
-        fn run(&self, _connection: &PgConnection) -> Result<(), ExecutorError> {
+        fn run(
+            &self,
+            _connection: &PgConnection,
+            _context: &TaskContext,
+        ) -> Result<(), ExecutorError> {
             println!("the number is {}", self.number);
 
             Ok(())
         }
     }
 
+    #[derive(Serialize, Deserialize)]
+    struct ReconcileJob {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for ReconcileJob {
+        fn run(
+            &self,
+            _connection: &PgConnection,
+            _context: &TaskContext,
+        ) -> Result<(), ExecutorError> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "reconcile".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct UnserializableJob {
+        // JSON object keys have to be strings
+        pub numbers: std::collections::HashMap<(u16, u16), u16>,
+    }
+
+    #[typetag::serde]
+    impl Runnable for UnserializableJob {
+        fn run(
+            &self,
+            _connection: &PgConnection,
+            _context: &TaskContext,
+        ) -> Result<(), ExecutorError> {
+            Ok(())
+        }
+    }
+
+    /// `EXPLAIN ANALYZE` of a query, as JSON.
+    struct Explain<Q>(Q);
+
+    impl<Q> QueryId for Explain<Q> {
+        type QueryId = ();
+
+        const HAS_STATIC_QUERY_ID: bool = false;
+    }
+
+    impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for Explain<Q> {
+        fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
+            out.push_sql("EXPLAIN (ANALYZE, FORMAT JSON) ");
+            self.0.walk_ast(out.reborrow())
+        }
+    }
+
+    impl<Q> Query for Explain<Q> {
+        type SqlType = Json;
+    }
+
+    impl<Q> RunQueryDsl<PgConnection> for Explain<Q> {}
+
     fn insert_job(
         metadata: serde_json::Value,
         timestamp: DateTime<Utc>,
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::Instant;
+
+/// At most `max_executions` tasks of a type are started within any `period`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RateLimit {
+    pub max_executions: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    pub fn per_minute(max_executions: u32) -> Self {
+        Self {
+            max_executions,
+            period: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Counts started tasks per task type. Clones share the counts, so every worker of a pool sees
+/// the same limits. Limits aren't coordinated between processes.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limits: HashMap<String, RateLimit>,
+    started: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_limit(&mut self, task_type: String, limit: RateLimit) {
+        self.limits.insert(task_type, limit);
+    }
+
+    /// Task types that can't be started right now. Workers don't claim tasks of these types.
+    pub fn throttled_task_types(&self) -> Vec<String> {
+        let mut started = self
+            .started
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let now = Instant::now();
+
+        self.limits
+            .iter()
+            .filter(|(task_type, limit)| {
+                let timestamps = started.entry(task_type.to_string()).or_default();
+                Self::prune(timestamps, limit, now);
+
+                timestamps.len() >= limit.max_executions as usize
+            })
+            .map(|(task_type, _)| task_type.clone())
+            .collect()
+    }
+
+    /// Records a start of a task of `task_type`. Returns false if the limit was already reached.
+    pub fn try_acquire(&self, task_type: &str) -> bool {
+        let limit = match self.limits.get(task_type) {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let mut started = self
+            .started
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let timestamps = started.entry(task_type.to_string()).or_default();
+        let now = Instant::now();
+
+        Self::prune(timestamps, limit, now);
+
+        if timestamps.len() >= limit.max_executions as usize {
+            return false;
+        }
+
+        timestamps.push_back(now);
+
+        true
+    }
+
+    fn prune(timestamps: &mut VecDeque<Instant>, limit: &RateLimit, now: Instant) {
+        while let Some(oldest) = timestamps.front() {
+            if now.duration_since(*oldest) < limit.period {
+                break;
+            }
+
+            timestamps.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_tests {
+    use super::RateLimit;
+    use super::RateLimiter;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn throttles_task_types_over_their_limit() {
+        let mut rate_limiter = RateLimiter::new();
+        rate_limiter.set_limit(
+            "api".to_string(),
+            RateLimit {
+                max_executions: 2,
+                period: Duration::from_millis(200),
+            },
+        );
+
+        let shared = rate_limiter.clone();
+
+        assert!(rate_limiter.try_acquire("api"));
+        assert!(shared.try_acquire("api"));
+        assert!(!rate_limiter.try_acquire("api"));
+        assert!(rate_limiter.try_acquire("common"));
+        assert_eq!(vec!["api".to_string()], shared.throttled_task_types());
+
+        thread::sleep(Duration::from_millis(250));
+
+        assert!(rate_limiter.throttled_task_types().is_empty());
+        assert!(shared.try_acquire("api"));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::context::TaskContext;
+use crate::queue::Task;
+use diesel::pg::PgConnection;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RetentionMode {
+    KeepAll,
+    RemoveAll,
+    RemoveFinished,
+    RemoveFailed,
+    /// Move finished tasks into `fang_archived_tasks`, keep failed tasks
+    ArchiveFinished,
+}
+
+impl RetentionMode {
+    pub fn removes_finished(&self) -> bool {
+        matches!(
+            self,
+            RetentionMode::RemoveAll | RetentionMode::RemoveFinished
+        )
+    }
+
+    pub fn removes_failed(&self) -> bool {
+        matches!(self, RetentionMode::RemoveAll | RetentionMode::RemoveFailed)
+    }
+
+    pub fn archives_finished(&self) -> bool {
+        matches!(self, RetentionMode::ArchiveFinished)
+    }
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub description: String,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+/// Lets jobs use `?` with any error type, the error's message becomes the description. Like
+/// `anyhow::Error`, `Error` itself doesn't implement `std::error::Error`, because the blanket
+/// impl would conflict with `From<T> for T`.
+impl<E: std::error::Error> From<E> for Error {
+    fn from(error: E) -> Self {
+        Self {
+            description: error.to_string(),
+        }
+    }
+}
+
+/// Returned by `Runnable::preflight` when the environment isn't ready for the task, e.g. a
+/// feature flag is off or a maintenance window is active.
+#[derive(Debug)]
+pub struct PreflightError {
+    pub description: String,
+    /// When to try again
+    pub retry_in: Duration,
+}
+
+impl std::fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.description)
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+#[derive(Debug)]
+pub struct TaskError(pub(crate) Task, pub(crate) Error);
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}; {:?}", self.1, self.0)
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+#[typetag::serde(tag = "type")]
+pub trait Runnable {
+    fn run(&self, connection: &PgConnection, context: &TaskContext) -> Result<(), Error>;
+
+    fn task_type(&self) -> String {
+        "common".to_string()
+    }
+
+    /// Checked before `run`. On failure the task goes back to the queue for
+    /// `PreflightError::retry_in` instead of failing, and no hooks are called.
+    fn preflight(&self, _context: &TaskContext) -> Result<(), PreflightError> {
+        Ok(())
+    }
+
+    /// Called after `run` succeeded, before the task is finalized.
+    fn on_success(&self, _connection: &PgConnection) {}
+
+    /// Called after `run` failed, before the task is finalized. Not called for tasks that
+    /// stopped because of a cancellation or that were put on hold.
+    fn on_failure(&self, _connection: &PgConnection, _error: &Error) {}
+
+    /// Called after `run` failed and snoozed the task with `TaskContext::snooze`, before the
+    /// task goes back to the queue. `attempt` is the attempt that failed, starting at 1. A
+    /// returned job replaces this one for the next attempts, e.g. with a narrower date range.
+    /// Not called for failed preflight checks.
+    fn on_retry(&self, _attempt: i32, _error: &Error) -> Option<Box<dyn Runnable>> {
+        None
+    }
+
+    /// Overrides `WorkerParams::retention_mode` for this job, e.g. to always keep payment
+    /// jobs for an audit trail.
+    fn retention(&self) -> Option<RetentionMode> {
+        None
+    }
+
+    /// Overrides `LeaseClaimStrategy::lease_duration` for this job, e.g. hours for a video
+    /// encode and seconds for an email. The executor sets it right after claiming the task,
+    /// tasks claimed without a lease ignore it.
+    fn lease_duration(&self) -> Option<chrono::Duration> {
+        None
+    }
+
+    /// Stored in `Task::task_version` when the job is enqueued. Bump it when renaming or
+    /// removing fields, together with a `TaskUpgrader` for the tasks already in the queue.
+    fn version(&self) -> i32 {
+        1
+    }
+}
+
+/// The body of a job deriving `FangTask` (with the `derive` feature), which implements
+/// `Runnable::run` by calling `perform`. Jobs that need the other `Runnable` hooks implement
+/// `Runnable` by hand.
+pub trait Perform {
+    fn perform(&self, connection: &PgConnection, context: &TaskContext) -> Result<(), Error>;
+}
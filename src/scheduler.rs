@@ -1,116 +1,607 @@
+use crate::config::FangConfig;
+use crate::error::FangError;
+use crate::executor::ReconnectPolicy;
 use crate::executor::Runnable;
+use crate::log_level::{fang_log, LogModule};
+use crate::maintenance;
+use crate::maintenance::MaintenanceWindow;
+use crate::prune::{PruneTasks, PRUNE_TASKS_PERIOD_IN_SECONDS};
 use crate::queue::PeriodicTask;
 use crate::queue::Queue;
+use crate::schedules::ScheduleDefinition;
+use crate::schedules::ScheduleRegistry;
+use crate::worker_pool::StopSignal;
+use chrono::Utc;
+use diesel::PgConnection;
+use log::Level;
+use std::borrow::Borrow;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use std::borrow::Borrow;
-use diesel::PgConnection;
+use std::time::Instant;
 
 pub struct Scheduler<Conn>
 where
-    Conn: Borrow<PgConnection> + Send + 'static
+    Conn: Borrow<PgConnection> + Send + 'static,
 {
-    pub check_period: u64,
-    pub error_margin_seconds: u64,
+    /// How often due periodic tasks are checked for
+    pub check_period: Duration,
+    /// Periodic tasks scheduled up to this long before or after a check are enqueued by it
+    pub error_margin: Duration,
     pub queue: Queue<Conn>,
+    /// Defer periodic tasks whose type has an active maintenance window until it ends
+    pub check_maintenance_windows: bool,
+    /// Only enqueue periodic tasks of this type, all of them if `None`
+    pub task_type: Option<String>,
+    /// Register a `PruneTasks` periodic task with this window on start
+    pub prune_tasks_older_than: Option<Duration>,
+    /// Reconcile `fang_periodic_tasks` with these schedules on start
+    pub schedule_registry: Option<ScheduleRegistry>,
+    /// Backoff between checks that failed, see `set_retry_policy`
+    pub retry_policy: ReconnectPolicy,
+    pub error_listeners: Vec<Arc<dyn SchedulerErrorListener>>,
+    control: SchedulerControl,
+    /// Set in the scheduler thread, only a scheduler that was running is restarted
+    running: bool,
+}
+
+/// Told when the scheduler keeps failing, e.g. because the database is unreachable, see
+/// `Scheduler::set_retry_policy`.
+pub trait SchedulerErrorListener: Send + Sync {
+    /// Called for every failed check once `failures` consecutive checks failed.
+    fn on_persistent_failure(&self, error: &FangError, failures: u32);
+}
+
+/// State shared by the scheduler thread and its handles.
+#[derive(Clone)]
+struct SchedulerControl {
+    stop_signal: StopSignal,
+    restart_on_panic: Arc<AtomicBool>,
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    /// Where a restarted scheduler connects to, see `Scheduler::set_config`
+    config: Option<FangConfig>,
+}
+
+impl Default for SchedulerControl {
+    fn default() -> Self {
+        Self {
+            stop_signal: StopSignal::default(),
+            restart_on_panic: Arc::new(AtomicBool::new(true)),
+            thread: Arc::new(Mutex::new(None)),
+            config: None,
+        }
+    }
+}
+
+/// Returned by `Scheduler::start`. Clones control the same scheduler.
+#[derive(Clone)]
+pub struct SchedulerHandle {
+    control: SchedulerControl,
+    /// Shared by the clones only, the scheduler thread doesn't keep it alive
+    drop_guard: Arc<DropGuard>,
+}
+
+struct DropGuard {
+    stop_signal: StopSignal,
+    stop_on_drop: AtomicBool,
+}
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        if self.stop_on_drop.load(Ordering::SeqCst) {
+            self.stop_signal.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// How often a sleeping scheduler checks whether it was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl SchedulerHandle {
+    fn new(control: SchedulerControl) -> Self {
+        let drop_guard = Arc::new(DropGuard {
+            stop_signal: control.stop_signal.clone(),
+            stop_on_drop: AtomicBool::new(false),
+        });
+
+        Self {
+            control,
+            drop_guard,
+        }
+    }
+
+    /// Exit the schedule loop after the current check and wait for the scheduler thread. The
+    /// scheduler isn't restarted afterwards.
+    pub fn stop(&self) -> Result<(), FangError> {
+        self.control.stop_signal.store(true, Ordering::SeqCst);
+
+        // A scheduler that crashed may have been replaced in the meantime, see `Drop`
+        loop {
+            let thread = self.control.thread.lock()?.take();
+
+            match thread {
+                Some(thread) => {
+                    if let Err(error) = thread.join() {
+                        fang_log!(
+                            LogModule::Scheduler,
+                            Level::Error,
+                            "Failed to exit the scheduler thread cleanly: {:?}",
+                            error
+                        );
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.control.stop_signal.load(Ordering::SeqCst)
+    }
+
+    /// Replace the scheduler thread with a new one if it panics, `true` by default.
+    pub fn set_restart_on_panic(&self, restart_on_panic: bool) {
+        self.control
+            .restart_on_panic
+            .store(restart_on_panic, Ordering::SeqCst);
+    }
+
+    /// Stop the scheduler once the handle and all its clones are dropped, `false` by default.
+    /// Unlike `stop` it doesn't wait for the scheduler thread.
+    pub fn set_stop_on_drop(&self, stop_on_drop: bool) {
+        self.drop_guard
+            .stop_on_drop
+            .store(stop_on_drop, Ordering::SeqCst);
+    }
 }
 
 impl<Conn> Drop for Scheduler<Conn>
 where
-    Conn: Borrow<PgConnection> + Send + 'static
+    Conn: Borrow<PgConnection> + Send + 'static,
 {
     fn drop(&mut self) {
-        Scheduler::start_new(self.check_period, self.error_margin_seconds)
+        if !self.running
+            || self.control.stop_signal.load(Ordering::SeqCst)
+            || !self.control.restart_on_panic.load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        match self.restart() {
+            Ok(thread) => {
+                *self
+                    .control
+                    .thread
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner()) = Some(thread);
+            }
+            Err(error) => {
+                fang_log!(
+                    LogModule::Scheduler,
+                    Level::Error,
+                    "Failed to restart the scheduler: {:?}",
+                    error
+                );
+            }
+        }
     }
 }
 
 impl Scheduler<PgConnection> {
-    pub fn start_new(check_period: u64, error_margin_seconds: u64) {
-        let builder = thread::Builder::new().name("scheduler".to_string());
-
-        builder
-            .spawn(move || {
-                let queue = Queue::new();
-                let scheduler = Self::new(check_period, error_margin_seconds, queue);
-
-                scheduler.schedule_loop();
-            })
-            .unwrap();
+    pub fn start_new(check_period: Duration, error_margin: Duration) -> SchedulerHandle {
+        Self::new(check_period, error_margin, Queue::new()).start()
     }
 }
 
 impl<Conn> Scheduler<Conn>
 where
-    Conn: Borrow<PgConnection> + Send + 'static
+    Conn: Borrow<PgConnection> + Send + 'static,
 {
-    pub fn new(check_period: u64, error_margin_seconds: u64, queue: Queue<Conn>) -> Self {
+    pub fn new(check_period: Duration, error_margin: Duration, queue: Queue<Conn>) -> Self {
         Self {
             check_period,
             queue,
-            error_margin_seconds,
+            error_margin,
+            check_maintenance_windows: false,
+            task_type: None,
+            prune_tasks_older_than: None,
+            schedule_registry: None,
+            retry_policy: ReconnectPolicy::default(),
+            error_listeners: Vec::new(),
+            control: SchedulerControl::default(),
+            running: false,
         }
     }
 
-    pub fn start(self) {
+    pub fn set_check_maintenance_windows(&mut self, check_maintenance_windows: bool) {
+        self.check_maintenance_windows = check_maintenance_windows;
+    }
+
+    pub fn set_task_type(&mut self, task_type: String) {
+        self.task_type = Some(task_type);
+    }
+
+    /// Remove finished and failed tasks that weren't updated for `older_than`, checked hourly.
+    /// The scheduler registers a `PruneTasks` periodic task on start, a worker running
+    /// `common` tasks executes it.
+    pub fn set_prune_tasks_older_than(&mut self, older_than: Duration) {
+        self.prune_tasks_older_than = Some(older_than);
+    }
+
+    /// On start, add the periodic tasks of `schedule_registry` that are missing, update changed
+    /// periods and remove the periodic tasks it doesn't have, except the prune task.
+    pub fn set_schedule_registry(&mut self, schedule_registry: ScheduleRegistry) {
+        self.schedule_registry = Some(schedule_registry);
+    }
+
+    /// Checks that fail, e.g. because of a database error, are retried after
+    /// `retry_policy.delay(failures)` instead of `check_period`. Once `max_attempts` checks in
+    /// a row failed, the error listeners are told about every further failure. The scheduler
+    /// keeps retrying until it's stopped.
+    pub fn set_retry_policy(&mut self, retry_policy: ReconnectPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    pub fn add_error_listener(&mut self, error_listener: Arc<dyn SchedulerErrorListener>) {
+        self.error_listeners.push(error_listener);
+    }
+
+    /// Connect the scheduler that replaces a crashed one with `config` instead of
+    /// `FangConfig::from_env`. Its queue has the settings of `queue`.
+    pub fn set_config(&mut self, config: FangConfig) {
+        self.control.config = Some(config);
+    }
+
+    pub fn start(self) -> SchedulerHandle {
+        let handle = SchedulerHandle::new(self.control.clone());
+        let thread = self.spawn().unwrap();
+
+        // Unless the scheduler already crashed and `Drop` stored its replacement
+        handle
+            .control
+            .thread
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .get_or_insert(thread);
+
+        handle
+    }
+
+    fn spawn(self) -> Result<thread::JoinHandle<()>, FangError> {
         let builder = thread::Builder::new().name("scheduler".to_string());
 
-        builder
-            .spawn(move || {
-                self.schedule_loop();
-            })
-            .unwrap();
+        Ok(builder.spawn(move || {
+            let mut scheduler = self;
+            scheduler.running = true;
+            scheduler.schedule_loop();
+        })?)
     }
 
+    /// Spawns a scheduler with the settings of this one. It connects its queue in the new
+    /// thread, since `Drop` runs while the crashed thread unwinds, and retries until it's
+    /// stopped if the database is unreachable.
+    fn restart(&self) -> Result<thread::JoinHandle<()>, FangError> {
+        let queue_factory = self.queue.factory(self.control.config.clone());
+        let check_period = self.check_period;
+        let error_margin = self.error_margin;
+        let check_maintenance_windows = self.check_maintenance_windows;
+        let task_type = self.task_type.clone();
+        let prune_tasks_older_than = self.prune_tasks_older_than;
+        let schedule_registry = self.schedule_registry.clone();
+        let retry_policy = self.retry_policy.clone();
+        let error_listeners = self.error_listeners.clone();
+        let control = self.control.clone();
+
+        let builder = thread::Builder::new().name("scheduler".to_string());
+
+        Ok(builder.spawn(move || {
+            let mut failures: u32 = 0;
+
+            let queue = loop {
+                if control.stop_signal.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                match queue_factory() {
+                    Ok(queue) => break queue,
+                    Err(error) => {
+                        failures = failures.saturating_add(1);
+
+                        fang_log!(
+                            LogModule::Scheduler,
+                            Level::Error,
+                            "Failed to connect the restarted scheduler, {} failures in a row: {:?}",
+                            failures,
+                            error
+                        );
+
+                        sleep_unless_stopped(
+                            &control.stop_signal,
+                            retry_policy.delay(failures - 1),
+                        );
+                    }
+                }
+            };
+
+            let mut scheduler = Scheduler::new(check_period, error_margin, queue);
+            scheduler.check_maintenance_windows = check_maintenance_windows;
+            scheduler.task_type = task_type;
+            scheduler.prune_tasks_older_than = prune_tasks_older_than;
+            scheduler.schedule_registry = schedule_registry;
+            scheduler.retry_policy = retry_policy;
+            scheduler.error_listeners = error_listeners;
+            scheduler.control = control;
+            scheduler.running = true;
+            scheduler.schedule_loop();
+        })?)
+    }
+
+    /// Runs until the scheduler is stopped through its `SchedulerHandle`.
     pub fn schedule_loop(&self) {
-        let sleep_duration = Duration::from_secs(self.check_period);
+        let mut set_up = false;
+        let mut failures: u32 = 0;
 
-        loop {
-            self.schedule();
+        while !self.is_stopped() {
+            let result = self.set_up(&mut set_up).and_then(|_| self.schedule());
+
+            let sleep_duration = match result {
+                Ok(()) => {
+                    failures = 0;
+                    self.check_period
+                }
+                Err(error) => {
+                    failures = failures.saturating_add(1);
+                    self.report_failure(&error, failures);
+                    self.retry_policy.delay(failures - 1)
+                }
+            };
+
+            sleep_unless_stopped(&self.control.stop_signal, sleep_duration);
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.control.stop_signal.load(Ordering::SeqCst)
+    }
+
+    /// Applies the schedule registry and registers the prune task until both succeeded once.
+    fn set_up(&self, set_up: &mut bool) -> Result<(), FangError> {
+        if !*set_up {
+            self.apply_schedule_registry()?;
+            self.register_prune_task()?;
+            *set_up = true;
+        }
+
+        Ok(())
+    }
+
+    fn report_failure(&self, error: &FangError, failures: u32) {
+        fang_log!(
+            LogModule::Scheduler,
+            Level::Error,
+            "Failed to schedule periodic tasks, {} failures in a row: {:?}",
+            failures,
+            error
+        );
+
+        if failures < self.retry_policy.max_attempts {
+            return;
+        }
+
+        for error_listener in &self.error_listeners {
+            error_listener.on_persistent_failure(error, failures);
+        }
+    }
+
+    fn apply_schedule_registry(&self) -> Result<(), FangError> {
+        let schedule_registry = match self.schedule_registry {
+            Some(ref schedule_registry) => schedule_registry,
+            None => return Ok(()),
+        };
+
+        let mut schedules = schedule_registry.schedules().to_vec();
+
+        if let Some(job) = self.prune_job() {
+            match serde_json::to_value(&job as &dyn Runnable) {
+                Ok(metadata)
+                    if !schedules
+                        .iter()
+                        .any(|schedule| schedule.metadata == metadata) =>
+                {
+                    schedules.push(ScheduleDefinition {
+                        metadata,
+                        period_in_seconds: PRUNE_TASKS_PERIOD_IN_SECONDS,
+                        task_type: Some(job.task_type()),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        let diff = self.queue.apply_schedules(&schedules)?;
+
+        fang_log!(
+            LogModule::Scheduler,
+            Level::Info,
+            "applied the schedule registry: {} added, {} updated, {} removed",
+            diff.added.len(),
+            diff.updated.len(),
+            diff.removed.len()
+        );
+
+        Ok(())
+    }
+
+    fn prune_job(&self) -> Option<PruneTasks> {
+        let older_than = self.prune_tasks_older_than?;
 
-            thread::sleep(sleep_duration);
+        Some(PruneTasks {
+            older_than_seconds: i64::try_from(older_than.as_secs()).unwrap_or(i64::MAX),
+        })
+    }
+
+    fn register_prune_task(&self) -> Result<(), FangError> {
+        if let Some(job) = self.prune_job() {
+            self.queue
+                .push_periodic_task(&job, PRUNE_TASKS_PERIOD_IN_SECONDS)?;
         }
+
+        Ok(())
     }
 
-    pub fn schedule(&self) {
-        if let Some(tasks) = self
+    /// Enqueue the periodic tasks that are due. Stops at the first database error, the tasks
+    /// left are enqueued by the next check. Other errors, e.g. a periodic task whose job can't
+    /// be deserialized, are logged and skip that task only.
+    pub fn schedule(&self) -> Result<(), FangError> {
+        let error_margin = chrono::Duration::from_std(self.error_margin).map_err(|_| {
+            FangError::InvalidConfig(format!("error margin too large: {:?}", self.error_margin))
+        })?;
+        let tasks = self
             .queue
-            .fetch_periodic_tasks(self.error_margin_seconds as i64)
-        {
-            for task in tasks {
-                self.process_task(task);
+            .fetch_due_periodic_tasks(error_margin, self.task_type.as_deref())?;
+
+        let windows = self.maintenance_windows();
+
+        for task in tasks {
+            match self.process_task(&task, &windows) {
+                Ok(()) => {}
+                Err(error @ FangError::DbError(_)) => return Err(error),
+                Err(error) => fang_log!(
+                    LogModule::Scheduler,
+                    Level::Error,
+                    "Failed to schedule periodic task {}: {:?}",
+                    task.id,
+                    error
+                ),
             }
-        };
+        }
+
+        Ok(())
+    }
+
+    fn maintenance_windows(&self) -> Vec<MaintenanceWindow> {
+        if !self.check_maintenance_windows {
+            return Vec::new();
+        }
+
+        self.queue.maintenance_windows().unwrap_or_else(|error| {
+            fang_log!(
+                LogModule::Scheduler,
+                Level::Error,
+                "Failed to load maintenance windows: {:?}",
+                error
+            );
+            Vec::new()
+        })
     }
 
-    fn process_task(&self, task: PeriodicTask) {
+    fn process_task(
+        &self,
+        task: &PeriodicTask,
+        windows: &[MaintenanceWindow],
+    ) -> Result<(), FangError> {
         match task.scheduled_at {
             None => {
-                self.queue.schedule_next_task_execution(&task).unwrap();
+                self.queue.schedule_next_task_execution(task)?;
             }
             Some(_) => {
-                let actual_task: Box<dyn Runnable> =
-                    serde_json::from_value(task.metadata.clone()).unwrap();
+                if task.is_exhausted(Utc::now()) {
+                    self.queue.remove_periodic_task(task.id)?;
+                    Self::log_retired(task);
+                    return Ok(());
+                }
+
+                let actual_task: Box<dyn Runnable> = serde_json::from_value(task.metadata.clone())?;
+
+                let paused_until =
+                    maintenance::paused_until(windows, &actual_task.task_type(), Utc::now());
+
+                if let Some(paused_until) = paused_until {
+                    self.queue.defer_periodic_task(task, paused_until)?;
+                    return Ok(());
+                }
+
+                fang_log!(
+                    LogModule::Scheduler,
+                    Level::Debug,
+                    "enqueuing periodic task {} of type {}",
+                    task.id,
+                    actual_task.task_type()
+                );
+
+                let origin = format!("fang::scheduler (periodic task {})", task.id);
+                let pushed =
+                    self.queue
+                        .push_periodic_task_execution(task, &(*actual_task), &origin)?;
 
-                self.queue.push_task(&(*actual_task)).unwrap();
+                if pushed.is_none() {
+                    fang_log!(
+                        LogModule::Scheduler,
+                        Level::Debug,
+                        "skipped periodic task {}, its previous execution is still pending",
+                        task.id
+                    );
 
-                self.queue.schedule_next_task_execution(&task).unwrap();
+                    self.queue.schedule_next_task_execution(task)?;
+                    return Ok(());
+                }
+
+                if self.queue.record_periodic_task_run(task)?.is_none() {
+                    Self::log_retired(task);
+                }
             }
         }
+
+        Ok(())
+    }
+
+    fn log_retired(task: &PeriodicTask) {
+        fang_log!(
+            LogModule::Scheduler,
+            Level::Info,
+            "periodic task {} reached its end and was removed",
+            task.id
+        );
+    }
+}
+
+/// Sleeps for `duration` or until `stop_signal` is set.
+fn sleep_unless_stopped(stop_signal: &StopSignal, duration: Duration) {
+    let deadline = Instant::now() + duration;
+
+    while !stop_signal.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(STOP_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
     }
 }
 
 #[cfg(test)]
 mod job_scheduler_tests {
     use super::Scheduler;
+    use super::SchedulerErrorListener;
+    use super::SchedulerHandle;
+    use crate::config::FangConfig;
+    use crate::context::TaskContext;
+    use crate::error::FangError;
     use crate::executor::Error;
+    use crate::executor::ReconnectPolicy;
     use crate::executor::Runnable;
+    use crate::prune::PRUNE_TASKS_PERIOD_IN_SECONDS;
+    use crate::queue::PeriodicTask;
     use crate::queue::Queue;
     use crate::queue::Task;
+    use crate::schedules::ScheduleRegistry;
+    use crate::schema::fang_periodic_tasks;
     use crate::schema::fang_tasks;
     use crate::typetag;
+    use diesel::dsl::sql;
     use diesel::pg::PgConnection;
     use diesel::prelude::*;
+    use diesel::sql_types::Text;
     use serde::{Deserialize, Serialize};
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
     use std::thread;
     use std::time::Duration;
 
@@ -119,7 +610,7 @@ mod job_scheduler_tests {
 
     #[typetag::serde]
     impl Runnable for ScheduledJob {
-        fn run(&self, _connection: &PgConnection) -> Result<(), Error> {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
             Ok(())
         }
 
@@ -128,13 +619,23 @@ mod job_scheduler_tests {
         }
     }
 
+    #[derive(Serialize, Deserialize)]
+    struct StaleJob {}
+
+    #[typetag::serde]
+    impl Runnable for StaleJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
     #[test]
     #[ignore]
     fn schedules_jobs() {
         let queue = Queue::new();
 
         queue.push_periodic_task(&ScheduledJob {}, 10).unwrap();
-        Scheduler::start_new(1, 2);
+        Scheduler::start_new(Duration::from_secs(1), Duration::from_secs(2));
 
         let sleep_duration = Duration::from_secs(15);
         thread::sleep(sleep_duration);
@@ -144,6 +645,193 @@ mod job_scheduler_tests {
         assert_eq!(1, tasks.len());
     }
 
+    #[test]
+    fn dropping_the_last_handle_stops_the_scheduler_if_asked_to() {
+        let handle = Scheduler::start_new(Duration::from_secs(60), Duration::from_secs(5));
+        handle.set_stop_on_drop(true);
+
+        let control = handle.control.clone();
+        let clone = handle.clone();
+
+        drop(handle);
+        assert!(!control.stop_signal.load(Ordering::SeqCst));
+
+        drop(clone);
+        assert!(control.stop_signal.load(Ordering::SeqCst));
+
+        let thread = control.thread.lock().unwrap().take().unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn crashed_scheduler_is_not_restarted_if_disabled() {
+        let mut scheduler = Scheduler::new(
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            Queue::new(),
+        );
+        let handle = SchedulerHandle::new(scheduler.control.clone());
+        handle.set_restart_on_panic(false);
+        scheduler.running = true;
+
+        drop(scheduler);
+
+        assert!(handle.control.thread.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn crashed_scheduler_connects_its_replacement_in_the_new_thread() {
+        let mut scheduler = Scheduler::new(
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            Queue::new(),
+        );
+        scheduler.set_config(FangConfig::new(
+            "postgres://fang@127.0.0.1:1/fang".to_string(),
+        ));
+        let handle = SchedulerHandle::new(scheduler.control.clone());
+        scheduler.running = true;
+
+        // Doesn't panic although the replacement can't connect
+        drop(scheduler);
+
+        assert!(handle.control.thread.lock().unwrap().is_some());
+        handle.stop().unwrap();
+    }
+
+    #[test]
+    fn registers_the_prune_task() {
+        let mut scheduler = Scheduler::new(
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            Queue::new(),
+        );
+        scheduler.set_prune_tasks_older_than(Duration::from_secs(86400));
+
+        scheduler
+            .queue
+            .connection
+            .test_transaction::<(), diesel::result::Error, _>(|| {
+                scheduler.register_prune_task().unwrap();
+
+                let tasks = fang_periodic_tasks::table
+                    .filter(fang_periodic_tasks::metadata.eq(serde_json::json!({
+                        "type": "PruneTasks",
+                        "older_than_seconds": 86400
+                    })))
+                    .get_results::<PeriodicTask>(&scheduler.queue.connection)
+                    .unwrap();
+
+                assert_eq!(1, tasks.len());
+                assert_eq!(PRUNE_TASKS_PERIOD_IN_SECONDS, tasks[0].period_in_seconds);
+
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn applies_the_schedule_registry_and_keeps_the_prune_task() {
+        let mut registry = ScheduleRegistry::new();
+        registry.register(&ScheduledJob {}, 30).unwrap();
+        assert!(matches!(
+            registry.register(&ScheduledJob {}, 60),
+            Err(FangError::InvalidSchedules(_))
+        ));
+
+        let mut scheduler = Scheduler::new(
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            Queue::new(),
+        );
+        scheduler.set_prune_tasks_older_than(Duration::from_secs(86400));
+        scheduler.set_schedule_registry(registry);
+
+        scheduler
+            .queue
+            .connection
+            .test_transaction::<(), diesel::result::Error, _>(|| {
+                diesel::delete(fang_periodic_tasks::table)
+                    .execute(&scheduler.queue.connection)
+                    .unwrap();
+
+                scheduler.register_prune_task().unwrap();
+                scheduler
+                    .queue
+                    .push_periodic_task(&StaleJob {}, 60)
+                    .unwrap();
+
+                scheduler.apply_schedule_registry().unwrap();
+
+                let mut types: Vec<(String, i32)> = fang_periodic_tasks::table
+                    .select((
+                        sql::<Text>("metadata->>'type'"),
+                        fang_periodic_tasks::period_in_seconds,
+                    ))
+                    .get_results(&scheduler.queue.connection)
+                    .unwrap();
+                types.sort();
+
+                assert_eq!(
+                    vec![
+                        ("PruneTasks".to_string(), PRUNE_TASKS_PERIOD_IN_SECONDS),
+                        ("ScheduledJob".to_string(), 30)
+                    ],
+                    types
+                );
+
+                Ok(())
+            });
+    }
+
+    #[derive(Default)]
+    struct PersistentFailures(Mutex<Vec<u32>>);
+
+    impl SchedulerErrorListener for PersistentFailures {
+        fn on_persistent_failure(&self, _error: &FangError, failures: u32) {
+            self.0.lock().unwrap().push(failures);
+        }
+    }
+
+    #[test]
+    fn returns_database_errors_and_reports_persistent_failures() {
+        let persistent_failures = Arc::new(PersistentFailures::default());
+
+        let mut scheduler = Scheduler::new(
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+            Queue::new(),
+        );
+        scheduler.set_retry_policy(ReconnectPolicy {
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            max_attempts: 2,
+        });
+        scheduler.add_error_listener(persistent_failures.clone());
+
+        scheduler
+            .queue
+            .connection
+            .test_transaction::<(), diesel::result::Error, _>(|| {
+                // Every following query of the transaction fails
+                scheduler
+                    .queue
+                    .connection
+                    .execute("SELECT * FROM fang_missing_table")
+                    .unwrap_err();
+
+                let error = scheduler.schedule().unwrap_err();
+                assert!(matches!(error, FangError::DbError(_)));
+
+                scheduler.report_failure(&error, 1);
+                scheduler.report_failure(&error, 2);
+                scheduler.report_failure(&error, 3);
+
+                assert_eq!(vec![2, 3], *persistent_failures.0.lock().unwrap());
+
+                Ok(())
+            });
+    }
+
     fn get_all_tasks(conn: &PgConnection) -> Vec<Task> {
         fang_tasks::table
             .filter(fang_tasks::task_type.eq("schedule"))
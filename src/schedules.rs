@@ -0,0 +1,263 @@
+use crate::error::FangError;
+use crate::queue::PeriodicTask;
+use crate::runnable::Runnable;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// A periodic task as declared in a schedule file. `metadata` is the serialized job, the same
+/// value `Queue::push_periodic_task` stores, so it's identified by it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleDefinition {
+    pub metadata: serde_json::Value,
+    pub period_in_seconds: i32,
+    /// `Runnable::task_type` of the job. Required for jobs the process applying the schedules
+    /// doesn't know, e.g. in the `fang` binary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_type: Option<String>,
+}
+
+/// All periodic tasks that should exist, usually loaded from a TOML or YAML file:
+///
+/// ```yaml
+/// schedules:
+///   - period_in_seconds: 3600
+///     task_type: reports
+///     metadata:
+///       type: SendReport
+///       recipient: ops@example.com
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Schedules {
+    pub schedules: Vec<ScheduleDefinition>,
+}
+
+/// Periodic tasks registered in code. Workers reconcile `fang_periodic_tasks` with the
+/// registry on start, see `WorkerParams::set_schedule_registry` and
+/// `Scheduler::set_schedule_registry`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScheduleRegistry {
+    schedules: Vec<ScheduleDefinition>,
+}
+
+/// What `Queue::apply_schedules` changes (or would change) to make `fang_periodic_tasks` match
+/// the declared schedules.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ScheduleDiff {
+    pub added: Vec<ScheduleDefinition>,
+    /// Declared schedules whose period or declared task type differs from the stored one
+    pub updated: Vec<ScheduleDefinition>,
+    pub removed: Vec<PeriodicTask>,
+}
+
+impl ScheduleDefinition {
+    /// The declared `task_type`, or else `Runnable::task_type` of the job. Fails if neither
+    /// is known, instead of guessing.
+    pub fn task_type(&self) -> Result<String, FangError> {
+        if let Some(ref task_type) = self.task_type {
+            return Ok(task_type.clone());
+        }
+
+        serde_json::from_value::<Box<dyn Runnable>>(self.metadata.clone())
+            .map(|job| job.task_type())
+            .map_err(|error| {
+                FangError::InvalidSchedules(format!(
+                    "task_type of {} isn't declared and the job is unknown: {}",
+                    self.metadata, error
+                ))
+            })
+    }
+}
+
+impl Schedules {
+    /// Reads a `.toml`, `.yml` or `.yaml` file.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, FangError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|error| {
+            FangError::InvalidSchedules(format!("{}: {}", path.display(), error))
+        })?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml_str(&content),
+            Some("yml") | Some("yaml") => Self::from_yaml_str(&content),
+            _ => Err(FangError::InvalidSchedules(format!(
+                "{}: expected a .toml, .yml or .yaml file",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self, FangError> {
+        let schedules: Self = toml::from_str(content)
+            .map_err(|error| FangError::InvalidSchedules(error.to_string()))?;
+
+        schedules.validate()?;
+
+        Ok(schedules)
+    }
+
+    pub fn from_yaml_str(content: &str) -> Result<Self, FangError> {
+        let schedules: Self = serde_yaml::from_str(content)
+            .map_err(|error| FangError::InvalidSchedules(error.to_string()))?;
+
+        schedules.validate()?;
+
+        Ok(schedules)
+    }
+
+    fn validate(&self) -> Result<(), FangError> {
+        validate(&self.schedules)
+    }
+}
+
+impl ScheduleRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `job` every `period_in_seconds`. Each job can be registered once, jobs with
+    /// different fields are different jobs.
+    pub fn register(
+        &mut self,
+        job: &dyn Runnable,
+        period_in_seconds: i32,
+    ) -> Result<(), FangError> {
+        self.schedules.push(ScheduleDefinition {
+            metadata: serde_json::to_value(job)?,
+            period_in_seconds,
+            task_type: Some(job.task_type()),
+        });
+
+        if let Err(error) = validate(&self.schedules) {
+            self.schedules.pop();
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    pub fn schedules(&self) -> &[ScheduleDefinition] {
+        &self.schedules
+    }
+}
+
+fn validate(schedules: &[ScheduleDefinition]) -> Result<(), FangError> {
+    let mut seen = HashSet::with_capacity(schedules.len());
+
+    for schedule in schedules {
+        if schedule.period_in_seconds <= 0 {
+            return Err(FangError::InvalidSchedules(format!(
+                "period_in_seconds of {} must be positive",
+                schedule.metadata
+            )));
+        }
+
+        if !seen.insert(schedule.metadata.to_string()) {
+            return Err(FangError::InvalidSchedules(format!(
+                "{} is declared more than once",
+                schedule.metadata
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl ScheduleDiff {
+    /// Compares the declared schedules with the stored periodic tasks.
+    pub fn new(schedules: &[ScheduleDefinition], periodic_tasks: Vec<PeriodicTask>) -> Self {
+        let mut diff = Self::default();
+        let mut declared = HashSet::with_capacity(schedules.len());
+
+        for schedule in schedules {
+            declared.insert(&schedule.metadata);
+
+            match periodic_tasks
+                .iter()
+                .find(|task| task.metadata == schedule.metadata)
+            {
+                None => diff.added.push(schedule.clone()),
+                Some(task)
+                    if task.period_in_seconds != schedule.period_in_seconds
+                        || schedule
+                            .task_type
+                            .as_ref()
+                            .is_some_and(|task_type| *task_type != task.task_type) =>
+                {
+                    diff.updated.push(schedule.clone())
+                }
+                Some(_) => {}
+            }
+        }
+
+        diff.removed = periodic_tasks
+            .into_iter()
+            .filter(|task| !declared.contains(&task.metadata))
+            .collect();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod schedules_tests {
+    use super::ScheduleDefinition;
+    use super::Schedules;
+    use crate::error::FangError;
+    use serde_json::json;
+
+    #[test]
+    fn parses_toml_and_yaml() {
+        let toml = r#"
+            [[schedules]]
+            period_in_seconds = 60
+            task_type = "reports"
+
+            [schedules.metadata]
+            type = "SendReport"
+            recipient = "ops@example.com"
+        "#;
+
+        let yaml = r#"
+            schedules:
+              - period_in_seconds: 60
+                task_type: reports
+                metadata:
+                  type: SendReport
+                  recipient: ops@example.com
+        "#;
+
+        let expected = Schedules {
+            schedules: vec![ScheduleDefinition {
+                metadata: json!({"type": "SendReport", "recipient": "ops@example.com"}),
+                period_in_seconds: 60,
+                task_type: Some("reports".to_string()),
+            }],
+        };
+
+        assert_eq!(expected, Schedules::from_toml_str(toml).unwrap());
+        assert_eq!(expected, Schedules::from_yaml_str(yaml).unwrap());
+    }
+
+    #[test]
+    fn rejects_duplicate_schedules() {
+        let yaml = r#"
+            schedules:
+              - period_in_seconds: 60
+                metadata: {type: SendReport}
+              - period_in_seconds: 120
+                metadata: {type: SendReport}
+        "#;
+
+        assert!(matches!(
+            Schedules::from_yaml_str(yaml),
+            Err(FangError::InvalidSchedules(_))
+        ));
+    }
+}
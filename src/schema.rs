@@ -6,10 +6,40 @@ pub enum FangTaskState {
     InProgress,
     Failed,
     Finished,
+    Cancelled,
+    Held,
+    /// A task of a chain whose predecessor hasn't finished yet, see `Queue::push_chain`
+    Waiting,
+}
+
+impl FangTaskState {
+    /// Finished, failed and cancelled tasks are never picked up again.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            FangTaskState::Finished | FangTaskState::Failed | FangTaskState::Cancelled
+        )
+    }
+}
+
+/// What the scheduler does when a periodic task is due while its previous execution is still
+/// `New` or `InProgress`.
+#[derive(DbEnum, Debug, Eq, PartialEq, Clone, Copy)]
+#[PgType = "fang_overlap_policy"]
+pub enum OverlapPolicy {
+    /// Don't enqueue the execution
+    Skip,
+    /// Enqueue the execution next to the previous one
+    Enqueue,
+    /// Cancel the previous execution and enqueue a new one
+    Replace,
 }
 
 table! {
     use super::FangTaskStateMapping;
+    use diesel::sql_types::Bytea;
+    use diesel::sql_types::Int4;
+    use diesel::sql_types::Int8;
     use diesel::sql_types::Jsonb;
     use diesel::sql_types::Nullable;
     use diesel::sql_types::Text;
@@ -26,10 +56,39 @@ table! {
         task_type -> Varchar,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        duration_in_milliseconds -> Nullable<Int8>,
+        signature -> Nullable<Varchar>,
+        scheduled_at -> Nullable<Timestamptz>,
+        origin -> Nullable<Varchar>,
+        errors -> Jsonb,
+        attempts -> Int4,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+        locked_by -> Nullable<Varchar>,
+        locked_until -> Nullable<Timestamptz>,
+        worker_name -> Nullable<Varchar>,
+        payload -> Nullable<Bytea>,
+        payload_format -> Nullable<Varchar>,
+        payload_encoding -> Nullable<Varchar>,
+        payload_reference -> Nullable<Varchar>,
+        task_version -> Int4,
+        idempotency_key -> Nullable<Varchar>,
+        uniq_key -> Nullable<Varchar>,
+        next_task_id -> Nullable<Uuid>,
+        batch_id -> Nullable<Uuid>,
+        ordering_key -> Nullable<Varchar>,
     }
 }
 
 table! {
+    use super::OverlapPolicyMapping;
+    use diesel::sql_types::Int4;
+    use diesel::sql_types::Jsonb;
+    use diesel::sql_types::Nullable;
+    use diesel::sql_types::Timestamptz;
+    use diesel::sql_types::Uuid;
+    use diesel::sql_types::Varchar;
+
     fang_periodic_tasks (id) {
         id -> Uuid,
         metadata -> Jsonb,
@@ -37,5 +96,108 @@ table! {
         scheduled_at -> Nullable<Timestamptz>,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        max_runs -> Nullable<Int4>,
+        runs -> Int4,
+        ends_at -> Nullable<Timestamptz>,
+        overlap_policy -> OverlapPolicyMapping,
+        task_type -> Varchar,
+    }
+}
+
+table! {
+    use super::FangTaskStateMapping;
+    use diesel::sql_types::Bytea;
+    use diesel::sql_types::Int4;
+    use diesel::sql_types::Int8;
+    use diesel::sql_types::Jsonb;
+    use diesel::sql_types::Nullable;
+    use diesel::sql_types::Text;
+    use diesel::sql_types::Timestamptz;
+    use diesel::sql_types::Uuid;
+    use diesel::sql_types::Varchar;
+
+    fang_archived_tasks (archive_id) {
+        id -> Uuid,
+        metadata -> Jsonb,
+        error_message -> Nullable<Text>,
+        state -> FangTaskStateMapping,
+        task_type -> Varchar,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+        duration_in_milliseconds -> Nullable<Int8>,
+        signature -> Nullable<Varchar>,
+        scheduled_at -> Nullable<Timestamptz>,
+        origin -> Nullable<Varchar>,
+        errors -> Jsonb,
+        attempts -> Int4,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+        archived_at -> Timestamptz,
+        payload -> Nullable<Bytea>,
+        payload_format -> Nullable<Varchar>,
+        payload_encoding -> Nullable<Varchar>,
+        payload_reference -> Nullable<Varchar>,
+        task_version -> Int4,
+        idempotency_key -> Nullable<Varchar>,
+        uniq_key -> Nullable<Varchar>,
+        next_task_id -> Nullable<Uuid>,
+        batch_id -> Nullable<Uuid>,
+        ordering_key -> Nullable<Varchar>,
+        archive_id -> Uuid,
+    }
+}
+
+table! {
+    fang_maintenance_windows (id) {
+        id -> Uuid,
+        task_type -> Varchar,
+        weekday -> Int2,
+        starts_at -> Time,
+        ends_at -> Time,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    fang_task_dependencies (task_id, depends_on_id) {
+        task_id -> Uuid,
+        depends_on_id -> Uuid,
+        satisfied -> Bool,
+    }
+}
+
+table! {
+    fang_batches (id) {
+        id -> Uuid,
+        name -> Varchar,
+        size -> Int4,
+        finished_count -> Int4,
+        failed_count -> Int4,
+        callback_metadata -> Jsonb,
+        callback_task_type -> Varchar,
+        callback_signature -> Nullable<Varchar>,
+        callback_task_id -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    fang_outbox (id) {
+        id -> Uuid,
+        metadata -> Jsonb,
+        task_type -> Varchar,
+        task_version -> Int4,
+        signature -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    fang_uniq_keys (task_type, uniq_key) {
+        task_type -> Varchar,
+        uniq_key -> Varchar,
+        task_id -> Uuid,
+        enqueued_at -> Timestamptz,
     }
 }
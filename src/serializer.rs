@@ -0,0 +1,197 @@
+use crate::runnable::Error;
+use crate::runnable::Runnable;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Encodes jobs into the `payload` column instead of the `metadata` JSON, for large payloads
+/// where size and encoding cost matter, see `Queue::set_serializer`.
+///
+/// The `metadata` of such tasks only contains the job's `type` and the SHA-256 of the
+/// payload, or the digest of `serialize_with_digest`, so deduplication, signing and
+/// `WorkerParams::set_allowed_job_names` keep working. Workers pick the serializer by the task's `payload_format`.
+pub trait TaskSerializer: Send + Sync {
+    /// Stored in `payload_format`, has to be unique
+    fn format(&self) -> &'static str;
+
+    fn serialize(&self, job: &dyn Runnable) -> Result<Vec<u8>, Error>;
+
+    fn deserialize(&self, payload: &[u8]) -> Result<Box<dyn Runnable>, Error>;
+
+    /// Like `serialize`, and returns the digest the `metadata` holds instead of the SHA-256 of
+    /// the payload. For serializers whose payloads differ each time the same job is
+    /// serialized, like `EncryptedSerializer`, so deduplication keeps working. `None` by
+    /// default.
+    fn serialize_with_digest(
+        &self,
+        job: &dyn Runnable,
+    ) -> Result<(Vec<u8>, Option<String>), Error> {
+        Ok((self.serialize(job)?, None))
+    }
+
+    /// Like `deserialize`, and returns the digest `serialize_with_digest` returned for the
+    /// payload. Workers compare it with the `metadata`.
+    fn deserialize_with_digest(
+        &self,
+        payload: &[u8],
+    ) -> Result<(Box<dyn Runnable>, Option<String>), Error> {
+        Ok((self.deserialize(payload)?, None))
+    }
+}
+
+/// The `metadata` JSON as a payload, used when `Queue::set_compression` is set without a
+/// serializer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonSerializer;
+
+impl TaskSerializer for JsonSerializer {
+    fn format(&self) -> &'static str {
+        "json"
+    }
+
+    fn serialize(&self, job: &dyn Runnable) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(job)?)
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> Result<Box<dyn Runnable>, Error> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+/// MessagePack with named fields, see `rmp_serde::to_vec_named`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackSerializer;
+
+impl TaskSerializer for MessagePackSerializer {
+    fn format(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn serialize(&self, job: &dyn Runnable) -> Result<Vec<u8>, Error> {
+        Ok(rmp_serde::to_vec_named(job)?)
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> Result<Box<dyn Runnable>, Error> {
+        Ok(rmp_serde::from_slice(payload)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborSerializer;
+
+impl TaskSerializer for CborSerializer {
+    fn format(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn serialize(&self, job: &dyn Runnable) -> Result<Vec<u8>, Error> {
+        let mut payload = Vec::new();
+        ciborium::ser::into_writer(job, &mut payload)?;
+
+        Ok(payload)
+    }
+
+    fn deserialize(&self, payload: &[u8]) -> Result<Box<dyn Runnable>, Error> {
+        Ok(ciborium::de::from_reader(payload)?)
+    }
+}
+
+/// The serializers workers know without `WorkerParams::add_serializer`.
+pub fn default_serializers() -> Vec<Arc<dyn TaskSerializer>> {
+    vec![
+        Arc::new(JsonSerializer),
+        Arc::new(MessagePackSerializer),
+        Arc::new(CborSerializer),
+    ]
+}
+
+/// The `metadata` of a task whose job is stored in `payload`, see
+/// `TaskSerializer::serialize_with_digest` for `job_digest`.
+pub(crate) fn payload_metadata(
+    job: &dyn Runnable,
+    payload: &[u8],
+    job_digest: Option<String>,
+) -> serde_json::Value {
+    match job_digest {
+        Some(job_digest) => serde_json::json!({
+            "type": job.typetag_name(),
+            "job_digest": job_digest,
+        }),
+        None => serde_json::json!({
+            "type": job.typetag_name(),
+            "payload_sha256": payload_digest(payload),
+        }),
+    }
+}
+
+/// Whether `payload` is the one `metadata` was created for. The metadata is what's signed,
+/// so this extends the signature to the payload. Payloads with a job digest are checked
+/// once they're deserialized, see `job_digest_matches`.
+#[cfg(feature = "runtime")]
+pub(crate) fn payload_matches(metadata: &serde_json::Value, payload: &[u8]) -> bool {
+    match metadata.get("payload_sha256") {
+        Some(digest) => digest.as_str() == Some(payload_digest(payload).as_str()),
+        None => metadata.get("job_digest").is_some(),
+    }
+}
+
+/// Whether the digest `TaskSerializer::deserialize_with_digest` returned is the one in
+/// `metadata`, if it has one.
+#[cfg(feature = "runtime")]
+pub(crate) fn job_digest_matches(metadata: &serde_json::Value, job_digest: Option<&str>) -> bool {
+    match metadata.get("job_digest") {
+        Some(digest) => digest.as_str().is_some() && digest.as_str() == job_digest,
+        None => true,
+    }
+}
+
+pub(crate) fn payload_digest(payload: &[u8]) -> String {
+    hex::encode(Sha256::digest(payload))
+}
+
+#[cfg(test)]
+mod serializer_tests {
+    use super::{CborSerializer, JsonSerializer, MessagePackSerializer, TaskSerializer};
+    use crate::context::TaskContext;
+    use crate::runnable::{Error, Runnable};
+    use crate::typetag;
+    use diesel::pg::PgConnection;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct SerializerJob {
+        pub numbers: Vec<u16>,
+        pub name: String,
+    }
+
+    #[typetag::serde]
+    impl Runnable for SerializerJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            format!("{}{}", self.name, self.numbers.len())
+        }
+    }
+
+    #[test]
+    fn serializers_round_trip_jobs() {
+        let job = SerializerJob {
+            numbers: vec![1, 2, 3],
+            name: "serializer".to_string(),
+        };
+
+        let serializers: Vec<Box<dyn TaskSerializer>> = vec![
+            Box::new(JsonSerializer),
+            Box::new(MessagePackSerializer),
+            Box::new(CborSerializer),
+        ];
+
+        for serializer in serializers {
+            let payload = serializer.serialize(&job).unwrap();
+            let deserialized = serializer.deserialize(&payload).unwrap();
+
+            assert_eq!("serializer3", deserialized.task_type());
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret used to sign the task type and metadata of tasks with HMAC-SHA256.
+///
+/// Producers sign tasks when they're inserted (see `Queue::set_signing_key`), workers with
+/// the same key (see `Executor::set_signing_key`) put tasks with a missing or invalid
+/// signature on hold instead of running them. This stops anyone who can only insert rows
+/// into `fang_tasks` from running arbitrary registered jobs.
+///
+/// Replays aren't prevented: a signed task type, metadata and signature can be copied into
+/// new rows to run a job that was signed before again.
+#[derive(Clone)]
+pub struct SigningKey {
+    key: Arc<[u8]>,
+}
+
+impl SigningKey {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Hex encoded signature of the task type and metadata, joined by a newline. Object keys
+    /// are sorted before signing, so the signature survives the round trip through `jsonb`.
+    pub fn sign(&self, task_type: &str, metadata: &serde_json::Value) -> String {
+        hex::encode(self.mac(task_type, metadata).finalize().into_bytes())
+    }
+
+    pub fn verify(
+        &self,
+        task_type: &str,
+        metadata: &serde_json::Value,
+        signature: Option<&str>,
+    ) -> bool {
+        let signature = match signature.map(hex::decode) {
+            Some(Ok(signature)) => signature,
+            _ => return false,
+        };
+
+        self.mac(task_type, metadata)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+
+    fn mac(&self, task_type: &str, metadata: &serde_json::Value) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(task_type.as_bytes());
+        mac.update(b"\n");
+        mac.update(metadata.to_string().as_bytes());
+
+        mac
+    }
+}
+
+#[cfg(test)]
+mod signing_tests {
+    use super::SigningKey;
+
+    #[test]
+    fn verifies_own_signature() {
+        let key = SigningKey::new(b"secret");
+        let metadata = serde_json::json!({"type": "Job", "number": 10});
+
+        let signature = key.sign("common", &metadata);
+
+        assert!(key.verify("common", &metadata, Some(&signature)));
+        assert!(!key.verify("common", &metadata, None));
+        assert!(!key.verify(
+            "common",
+            &serde_json::json!({"type": "Job", "number": 11}),
+            Some(&signature)
+        ));
+        assert!(!key.verify("other", &metadata, Some(&signature)));
+        assert!(!SigningKey::new(b"other").verify("common", &metadata, Some(&signature)));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::queue::Task;
+use crate::schema::fang_tasks;
+use crate::schema::FangTaskState;
+use chrono::DateTime;
+use chrono::Utc;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::sql_types::Bool;
+use diesel::sql_types::Timestamptz;
+use std::borrow::Borrow;
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// How many tasks `TaskStream` loads at once unless `TaskStream::chunk_size` is set.
+pub const DEFAULT_STREAM_CHUNK_SIZE: i64 = 1000;
+
+/// Which tasks `Queue::find_tasks` returns and `Queue::count_tasks` counts, e.g. for admin
+/// pages:
+///
+/// ```ignore
+/// let query = TaskQuery::new()
+///     .state(FangTaskState::Failed)
+///     .task_type("emails")
+///     .created_after(Utc::now() - Duration::days(1))
+///     .limit(100);
+///
+/// let tasks = queue.find_tasks(&query)?;
+/// ```
+///
+/// Without filters all tasks match. Tasks are returned oldest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TaskQuery {
+    /// Tasks in any of these states, all states if empty
+    pub states: Vec<FangTaskState>,
+    pub task_type: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub newest_first: bool,
+    /// Not applied when counting
+    pub limit: Option<i64>,
+    /// Not applied when counting
+    pub offset: Option<i64>,
+}
+
+impl TaskQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tasks in `state`. Called several times, tasks in any of the states match.
+    pub fn state(mut self, state: FangTaskState) -> Self {
+        self.states.push(state);
+        self
+    }
+
+    pub fn task_type(mut self, task_type: &str) -> Self {
+        self.task_type = Some(task_type.to_string());
+        self
+    }
+
+    /// Tasks created at or after `time`.
+    pub fn created_after(mut self, time: DateTime<Utc>) -> Self {
+        self.created_after = Some(time);
+        self
+    }
+
+    /// Tasks created before `time`.
+    pub fn created_before(mut self, time: DateTime<Utc>) -> Self {
+        self.created_before = Some(time);
+        self
+    }
+
+    pub fn newest_first(mut self) -> Self {
+        self.newest_first = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// `fang_tasks` filtered by the query, without order, limit and offset.
+    pub(crate) fn filtered(&self) -> fang_tasks::BoxedQuery<'_, Pg> {
+        let mut query = fang_tasks::table.into_boxed();
+
+        if !self.states.is_empty() {
+            query = query.filter(fang_tasks::state.eq_any(self.states.clone()));
+        }
+
+        if let Some(ref task_type) = self.task_type {
+            query = query.filter(fang_tasks::task_type.eq(task_type));
+        }
+
+        if let Some(created_after) = self.created_after {
+            query = query.filter(fang_tasks::created_at.ge(created_after));
+        }
+
+        if let Some(created_before) = self.created_before {
+            query = query.filter(fang_tasks::created_at.lt(created_before));
+        }
+
+        query
+    }
+}
+
+/// Iterates over the tasks of a `TaskQuery` a chunk at a time, see `Queue::stream_tasks`.
+///
+/// Chunks are loaded with separate queries, after the `created_at` and `id` of the last task
+/// of the previous chunk. Tasks enqueued or removed during the iteration may or may not be
+/// returned, but no task is returned twice. Iteration stops after the first error.
+pub struct TaskStream<'a, Conn>
+where
+    Conn: Borrow<PgConnection>,
+{
+    connection: &'a Conn,
+    query: TaskQuery,
+    chunk_size: i64,
+    chunk: VecDeque<Task>,
+    /// `created_at` and `id` of the last loaded task
+    last: Option<(DateTime<Utc>, Uuid)>,
+    /// Tasks left until the limit of the query
+    remaining: Option<i64>,
+    done: bool,
+}
+
+impl<'a, Conn> TaskStream<'a, Conn>
+where
+    Conn: Borrow<PgConnection>,
+{
+    pub fn new(connection: &'a Conn, query: &TaskQuery) -> Self {
+        Self {
+            connection,
+            query: query.clone(),
+            chunk_size: DEFAULT_STREAM_CHUNK_SIZE,
+            chunk: VecDeque::new(),
+            last: None,
+            remaining: query.limit,
+            done: false,
+        }
+    }
+
+    /// Load `chunk_size` tasks per query instead of `DEFAULT_STREAM_CHUNK_SIZE`.
+    pub fn chunk_size(mut self, chunk_size: i64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    fn load_chunk(&mut self) -> Result<(), Error> {
+        let mut chunk_size = self.chunk_size;
+
+        if let Some(remaining) = self.remaining {
+            chunk_size = chunk_size.min(remaining);
+        }
+
+        let mut tasks = self.query.filtered();
+
+        tasks = match self.last {
+            Some((created_at, id)) => {
+                let operator = if self.query.newest_first { "<" } else { ">" };
+
+                tasks.filter(
+                    sql::<Bool>("(fang_tasks.created_at, fang_tasks.id) ")
+                        .sql(operator)
+                        .sql(" (")
+                        .bind::<Timestamptz, _>(created_at)
+                        .sql(", ")
+                        .bind::<diesel::sql_types::Uuid, _>(id)
+                        .sql(")"),
+                )
+            }
+            // The offset applies to the first chunk only
+            None => tasks.offset(self.query.offset.unwrap_or(0)),
+        };
+
+        tasks = if self.query.newest_first {
+            tasks.order((fang_tasks::created_at.desc(), fang_tasks::id.desc()))
+        } else {
+            tasks.order((fang_tasks::created_at.asc(), fang_tasks::id.asc()))
+        };
+
+        let tasks = tasks
+            .limit(chunk_size)
+            .load::<Task>(self.connection.borrow())?;
+
+        if (tasks.len() as i64) < chunk_size {
+            self.done = true;
+        }
+
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= tasks.len() as i64;
+
+            if *remaining <= 0 {
+                self.done = true;
+            }
+        }
+
+        self.last = tasks.last().map(|task| (task.created_at, task.id));
+        self.chunk.extend(tasks);
+
+        Ok(())
+    }
+}
+
+impl<'a, Conn> Iterator for TaskStream<'a, Conn>
+where
+    Conn: Borrow<PgConnection>,
+{
+    type Item = Result<Task, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.chunk.is_empty() && !self.done {
+            if let Err(error) = self.load_chunk() {
+                self.done = true;
+
+                return Some(Err(error));
+            }
+        }
+
+        self.chunk.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::FangError;
+use crate::queue::Queue;
+use diesel::connection::TransactionManager;
+use diesel::pg::PgConnection;
+use diesel::Connection;
+use std::borrow::Borrow;
+use std::ops::Deref;
+
+/// A queue on a connection in the caller's transaction: tasks pushed through it are only
+/// visible to workers once that transaction commits, and are gone if it rolls back. Enqueue
+/// the tasks of a change in the transaction that makes the change, so that neither happens
+/// without the other.
+///
+/// ```ignore
+/// connection.transaction::<_, FangError, _>(|| {
+///     let order = create_order(&connection)?;
+///     let queue = TransactionalQueue::new(&connection)?;
+///     queue.push_task(&SendConfirmation { order_id: order.id })?;
+///     Ok(order)
+/// })?;
+/// ```
+///
+/// All push functions of `Queue` are available. Payloads kept in a `PayloadStore` are written
+/// right away and stay there if the transaction rolls back.
+pub struct TransactionalQueue<'a> {
+    queue: Queue<&'a PgConnection>,
+}
+
+impl<'a> TransactionalQueue<'a> {
+    /// Fails with `FangError::NotInTransaction` unless `connection` is in a transaction
+    /// started by diesel, e.g. with `Connection::transaction`.
+    pub fn new(connection: &'a PgConnection) -> Result<Self, FangError> {
+        Self::with_queue(Queue::new_with_connection(connection))
+    }
+
+    fn with_queue(queue: Queue<&'a PgConnection>) -> Result<Self, FangError> {
+        let transaction_manager = queue.connection.transaction_manager();
+
+        if TransactionManager::<PgConnection>::get_transaction_depth(transaction_manager) == 0 {
+            return Err(FangError::NotInTransaction);
+        }
+
+        Ok(Self { queue })
+    }
+}
+
+impl<'a> Deref for TransactionalQueue<'a> {
+    type Target = Queue<&'a PgConnection>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.queue
+    }
+}
+
+impl<Conn> Queue<Conn>
+where
+    Conn: Borrow<PgConnection>,
+{
+    /// A `TransactionalQueue` on `connection` with the signing key, serializer, compression,
+    /// payload store, dedup and id settings of this queue.
+    pub fn transactional<'a>(
+        &self,
+        connection: &'a PgConnection,
+    ) -> Result<TransactionalQueue<'a>, FangError> {
+        TransactionalQueue::with_queue(Queue {
+            connection,
+            signing_key: self.signing_key.clone(),
+            dedup: self.dedup,
+            serializer: self.serializer.clone(),
+            compression: self.compression,
+            payload_store: self.payload_store.clone(),
+            offload_threshold_in_bytes: self.offload_threshold_in_bytes,
+            dedup_windows: self.dedup_windows.clone(),
+            deterministic_ids: self.deterministic_ids,
+        })
+    }
+
+    /// Runs `f` in a transaction on the connection of this queue and commits it if `f`
+    /// succeeds. Tasks pushed through the `TransactionalQueue` are enqueued together with
+    /// whatever else `f` writes.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&TransactionalQueue) -> Result<T, E>,
+        E: From<FangError> + From<diesel::result::Error>,
+    {
+        let connection = self.connection.borrow();
+
+        connection.transaction(|| f(&self.transactional(connection)?))
+    }
+}
+
+#[cfg(test)]
+mod transactional_tests {
+    use super::TransactionalQueue;
+    use crate::context::TaskContext;
+    use crate::error::FangError;
+    use crate::queue::Queue;
+    use crate::runnable::Runnable;
+    use crate::schema::fang_tasks;
+    use crate::signing::SigningKey;
+    use crate::typetag;
+    use assert_matches::assert_matches;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use diesel::result::Error;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct ConfirmOrder {
+        pub order_id: i32,
+    }
+
+    #[typetag::serde]
+    impl Runnable for ConfirmOrder {
+        fn run(
+            &self,
+            _connection: &PgConnection,
+            _context: &TaskContext,
+        ) -> Result<(), crate::runnable::Error> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "transactional_test".to_string()
+        }
+    }
+
+    fn count_tasks(connection: &PgConnection) -> i64 {
+        fang_tasks::table
+            .filter(fang_tasks::task_type.eq("transactional_test"))
+            .count()
+            .get_result(connection)
+            .unwrap()
+    }
+
+    #[test]
+    fn tasks_are_rolled_back_with_the_transaction() {
+        let queue = Queue::new();
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let result = queue.connection.transaction::<(), FangError, _>(|| {
+                let transactional_queue = TransactionalQueue::new(&queue.connection)?;
+
+                transactional_queue.push_task(&ConfirmOrder { order_id: 1 })?;
+                assert_eq!(1, count_tasks(&queue.connection));
+
+                Err(FangError::WaitTimeout)
+            });
+
+            assert_matches!(result, Err(FangError::WaitTimeout));
+            assert_eq!(0, count_tasks(&queue.connection));
+
+            queue
+                .transaction::<(), FangError, _>(|transactional_queue| {
+                    transactional_queue.push_task(&ConfirmOrder { order_id: 2 })?;
+
+                    Ok(())
+                })
+                .unwrap();
+
+            assert_eq!(1, count_tasks(&queue.connection));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn requires_a_transaction() {
+        let queue = Queue::new();
+
+        assert_matches!(
+            TransactionalQueue::new(&queue.connection).err(),
+            Some(FangError::NotInTransaction)
+        );
+    }
+
+    #[test]
+    fn keeps_the_settings_of_the_queue() {
+        let mut queue = Queue::new();
+        queue.set_signing_key(SigningKey::new(b"transactional"));
+
+        queue.connection.test_transaction::<(), Error, _>(|| {
+            let task = queue
+                .transaction::<_, FangError, _>(|transactional_queue| {
+                    transactional_queue.push_task(&ConfirmOrder { order_id: 3 })
+                })
+                .unwrap();
+
+            assert!(task.signature.is_some());
+
+            Ok(())
+        });
+    }
+}
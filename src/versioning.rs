@@ -0,0 +1,50 @@
+use crate::runnable::Error;
+#[cfg(feature = "runtime")]
+use std::sync::Arc;
+
+/// Upgrades the JSON of tasks enqueued by an older version of a job, so they survive field
+/// renames during deploys. Register it with `WorkerParams::add_upgrader` and bump
+/// `Runnable::version` of the job to `version()`.
+///
+/// Upgrades apply to jobs stored as JSON, in `metadata` or as a `json` payload.
+pub trait TaskUpgrader: Send + Sync {
+    /// The typetag name of the job, its struct name by default
+    fn job_name(&self) -> &str;
+
+    /// The current `Runnable::version` of the job
+    fn version(&self) -> i32;
+
+    /// Turn the JSON of a task enqueued at `old_version` (including its `type`) into the
+    /// JSON of the current version.
+    fn upgrade(
+        &self,
+        old_version: i32,
+        metadata: serde_json::Value,
+    ) -> Result<serde_json::Value, Error>;
+}
+
+/// Runs the upgrader of `metadata`'s job if the task was enqueued at an older version.
+#[cfg(feature = "runtime")]
+pub(crate) fn upgrade(
+    upgraders: &[Arc<dyn TaskUpgrader>],
+    task_version: i32,
+    metadata: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let job_name = metadata.get("type").and_then(serde_json::Value::as_str);
+
+    let upgrader = upgraders
+        .iter()
+        .find(|upgrader| Some(upgrader.job_name()) == job_name);
+
+    match upgrader {
+        Some(upgrader) if task_version < upgrader.version() => upgrader
+            .upgrade(task_version, metadata)
+            .map_err(|error| Error {
+                description: format!(
+                    "Failed to upgrade the task from version {}, error: {}",
+                    task_version, error.description
+                ),
+            }),
+        _ => Ok(metadata),
+    }
+}
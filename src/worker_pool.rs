@@ -1,24 +1,66 @@
+use crate::claim::ClaimMetrics;
+use crate::claim::ClaimStats;
+use crate::claim::ClaimStrategy;
+use crate::concurrency_limit::ConcurrencyLimit;
+use crate::config::FangConfig;
+pub use crate::context::{SharedState, WorkerState};
 use crate::diesel::r2d2;
 use crate::diesel::PgConnection;
 use crate::error::FangError;
+use crate::executor::DeferredFinalization;
 use crate::executor::Executor;
+use crate::executor::ReconnectPolicy;
 use crate::executor::RetentionMode;
 use crate::executor::SleepParams;
+use crate::executor::SlowTaskListener;
+use crate::log_level::{fang_log, LogModule};
+use crate::metrics::WorkerMetrics;
+use crate::metrics::WorkerStats;
+use crate::middleware::TaskMiddleware;
+use crate::payload_store::PayloadStore;
 use crate::queue::Queue;
-use log::error;
-use log::info;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::scheduler::SchedulerHandle;
+use crate::schedules::ScheduleRegistry;
+use crate::serializer::TaskSerializer;
+use crate::signing::SigningKey;
+use crate::versioning::TaskUpgrader;
+use chrono::{DateTime, Utc};
+use log::Level;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct WorkerPool<D: Clone + Send + 'static> {
     pub number_of_workers: u32,
     pub worker_params: WorkerParams,
     pub connection_pool: r2d2::Pool<r2d2::ConnectionManager<PgConnection>>,
+    /// Separate pool for housekeeping so it never competes with workers for connections. The
+    /// same pool as `connection_pool` if `new_with_connection_pool` wasn't given one.
+    pub maintenance_pool: r2d2::Pool<r2d2::ConnectionManager<PgConnection>>,
     pub worker_data: Option<Box<D>>,
+    /// Passed to `new_with_connection_pool`, so it's not resized
+    external_connection_pool: bool,
+    /// Passed to `new_with_config`, pools are built from it
+    config: Option<FangConfig>,
     shared_state: SharedState,
     thread_join_handles: Arc<RwLock<HashMap<String, thread::JoinHandle<()>>>>,
+    stop_signals: Arc<RwLock<HashMap<String, StopSignal>>>,
+    in_flight_tasks: Arc<RwLock<HashMap<String, InFlightTasks>>>,
+    current_tasks: Arc<RwLock<HashMap<String, CurrentTask>>>,
+    shared_params: SharedParams,
+    claim_metrics: Arc<RwLock<HashMap<String, Arc<ClaimMetrics>>>>,
+    worker_metrics: Arc<RwLock<HashMap<String, Arc<WorkerMetrics>>>>,
+    restarts: Arc<RwLock<HashMap<String, u64>>>,
+    schedulers: Arc<RwLock<Vec<SchedulerHandle>>>,
+    event_senders: EventSenders,
+    join_signal: Arc<(Mutex<PoolExit>, Condvar)>,
 }
 
 pub struct WorkerThread<D: Clone + Send + 'static> {
@@ -26,13 +68,150 @@ pub struct WorkerThread<D: Clone + Send + 'static> {
     pub restarts: u64,
     pub worker_pool: WorkerPool<D>,
     graceful_shutdown: bool,
+    started_at: Instant,
 }
 
-pub type SharedState = Arc<RwLock<WorkerState>>;
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-pub enum WorkerState {
-    Running,
-    Shutdown,
+/// How long connections given back by exited workers stay open in the pools the worker pool
+/// builds, see `FangConfig::on_demand_connection_pool`.
+pub const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Set to ask a single worker to exit after its current task, see `WorkerPool::scale_to`.
+pub type StopSignal = Arc<AtomicBool>;
+
+/// What `WorkerPool::join` waits for
+#[derive(Default)]
+struct PoolExit {
+    shutdown_complete: bool,
+    fatal_error: Option<FangError>,
+}
+
+/// The state a worker shares with the pool, see `WorkerPool::register_worker`.
+struct Registration {
+    stop_signal: StopSignal,
+    in_flight_tasks: InFlightTasks,
+    current_task: CurrentTask,
+    claim_metrics: Arc<ClaimMetrics>,
+    worker_metrics: Arc<WorkerMetrics>,
+}
+
+/// Ids of the tasks a worker claimed and is still running.
+pub type InFlightTasks = Arc<Mutex<Vec<Uuid>>>;
+
+/// The task a worker is executing right now, if any. Its `worker_name` is left empty, the
+/// pool knows which worker the slot belongs to.
+pub type CurrentTask = Arc<Mutex<Option<RunningTask>>>;
+
+/// A task being executed, returned by `WorkerPool::running_tasks`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct RunningTask {
+    pub worker_name: String,
+    pub task_id: Uuid,
+    pub task_type: String,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Sent to the receivers returned by `WorkerPool::subscribe`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum WorkerPoolEvent {
+    WorkerStarted {
+        name: String,
+    },
+    /// A crashed worker was started again
+    WorkerRestarted {
+        name: String,
+        restarts: u64,
+    },
+    /// A task finished successfully. With deferred finalization it may still be `InProgress`.
+    TaskCompleted {
+        id: Uuid,
+    },
+    /// `shutdown` or `shutdown_with_timeout` returned, or is about to
+    ShutdownComplete,
+}
+
+/// Senders of the receivers returned by `WorkerPool::subscribe`.
+pub type EventSenders = Arc<Mutex<Vec<mpsc::Sender<WorkerPoolEvent>>>>;
+
+/// Send `event` to every subscriber, forgetting the ones whose receiver was dropped.
+pub(crate) fn emit(event_senders: &EventSenders, event: WorkerPoolEvent) {
+    event_senders
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .retain(|sender| sender.send(event.clone()).is_ok());
+}
+
+/// Returned by `WorkerPool::shutdown_with_timeout`.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct ShutdownReport {
+    /// Workers that didn't exit before the deadline. Their threads are detached.
+    pub stuck_workers: Vec<String>,
+    /// Tasks of stuck workers that were moved back to `New`
+    pub reset_tasks: Vec<Uuid>,
+}
+
+/// Returned by `WorkerPool::status`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub restarts: u64,
+    /// Tasks the worker claimed and hasn't finished, more than one with the fast path
+    pub current_task_ids: Vec<Uuid>,
+    /// When the worker last tried to claim tasks
+    pub last_poll_at: Option<DateTime<Utc>>,
+    pub activity: WorkerActivity,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum WorkerActivity {
+    Idle,
+    Busy,
+    /// The thread exited, e.g. because the worker was given up on after too many restarts
+    Stopped,
+}
+
+/// What `WorkerPool::start` and `WorkerPool::shutdown` do when the pool is already in the
+/// requested state.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum DuplicateCallBehavior {
+    /// Return `FangError::AlreadyRunning` / `FangError::AlreadyShutdown`
+    Error,
+    /// Do nothing and return `Ok(())`
+    Ignore,
+}
+
+/// How crashed workers are restarted. The delay starts at `initial_delay` and doubles with
+/// every consecutive restart up to `max_delay`. A worker that ran for at least `max_delay`
+/// before crashing starts over at `initial_delay`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RestartPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// After this many consecutive restarts the worker is given up on and `WorkerPool::join`
+    /// returns `FangError::WorkerDead`. Unlimited if `None`.
+    pub max_restarts: Option<u64>,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_restarts: None,
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn delay(&self, restarts: u64) -> Duration {
+        let factor = 2u32.saturating_pow(restarts.min(u64::from(u32::MAX)) as u32);
+
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
 }
 
 #[derive(Clone)]
@@ -40,6 +219,27 @@ pub struct WorkerParams {
     pub retention_mode: Option<RetentionMode>,
     pub sleep_params: Option<SleepParams>,
     pub task_type: Option<String>,
+    pub duplicate_call_behavior: DuplicateCallBehavior,
+    pub maintenance_pool_size: u32,
+    pub fast_path_batch_size: Option<u32>,
+    pub deferred_finalization: Option<DeferredFinalization>,
+    pub claim_strategy: Option<Arc<dyn ClaimStrategy>>,
+    pub middlewares: Vec<Arc<dyn TaskMiddleware>>,
+    pub serializers: Vec<Arc<dyn TaskSerializer>>,
+    pub payload_store: Option<Arc<dyn PayloadStore>>,
+    pub upgraders: Vec<Arc<dyn TaskUpgrader>>,
+    pub signing_key: Option<SigningKey>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+    pub allowed_job_names: Option<Vec<String>>,
+    pub task_type_workers: HashMap<String, u32>,
+    pub check_maintenance_windows: bool,
+    pub restart_policy: RestartPolicy,
+    pub reconnect_policy: ReconnectPolicy,
+    pub separate_job_connections: bool,
+    pub schedule_registry: Option<ScheduleRegistry>,
+    pub warn_after: Option<Duration>,
+    pub slow_task_listeners: Vec<Arc<dyn SlowTaskListener>>,
 }
 
 impl Default for WorkerParams {
@@ -54,6 +254,27 @@ impl WorkerParams {
             retention_mode: None,
             sleep_params: None,
             task_type: None,
+            duplicate_call_behavior: DuplicateCallBehavior::Error,
+            maintenance_pool_size: 1,
+            fast_path_batch_size: None,
+            deferred_finalization: None,
+            claim_strategy: None,
+            middlewares: Vec::new(),
+            serializers: Vec::new(),
+            payload_store: None,
+            upgraders: Vec::new(),
+            signing_key: None,
+            rate_limiter: None,
+            concurrency_limit: None,
+            allowed_job_names: None,
+            task_type_workers: HashMap::new(),
+            check_maintenance_windows: false,
+            restart_policy: RestartPolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            separate_job_connections: false,
+            schedule_registry: None,
+            warn_after: None,
+            slow_task_listeners: Vec::new(),
         }
     }
 
@@ -68,254 +289,2003 @@ impl WorkerParams {
     pub fn set_task_type(&mut self, task_type: String) {
         self.task_type = Some(task_type);
     }
-}
 
-impl<D: Clone + Send + 'static> WorkerPool<D> {
-    pub fn new(number_of_workers: u32) -> Self {
-        let worker_params = WorkerParams::new();
-        let connection_pool = Queue::connection_pool(number_of_workers);
+    pub fn set_duplicate_call_behavior(&mut self, duplicate_call_behavior: DuplicateCallBehavior) {
+        self.duplicate_call_behavior = duplicate_call_behavior;
+    }
 
-        Self {
-            number_of_workers,
-            worker_params,
-            connection_pool,
-            worker_data: None,
-            shared_state: Arc::new(RwLock::new(WorkerState::Running)),
-            thread_join_handles: Arc::new(RwLock::new(HashMap::with_capacity(
-                number_of_workers as usize,
-            ))),
-        }
+    pub fn set_maintenance_pool_size(&mut self, maintenance_pool_size: u32) {
+        self.maintenance_pool_size = maintenance_pool_size;
     }
 
-    pub fn new_with_params(number_of_workers: u32, worker_params: WorkerParams, worker_data: Option<D>) -> Self {
-        let connection_pool = Queue::connection_pool(number_of_workers);
+    /// See `Executor::set_fast_path`
+    pub fn set_fast_path(&mut self, batch_size: u32) {
+        self.fast_path_batch_size = Some(batch_size);
+    }
 
-        Self {
-            number_of_workers,
-            worker_params,
-            connection_pool,
-            worker_data: worker_data.map(Box::new),
-            shared_state: Arc::new(RwLock::new(WorkerState::Running)),
-            thread_join_handles: Arc::new(RwLock::new(HashMap::with_capacity(
-                number_of_workers as usize,
-            ))),
-        }
+    pub fn set_deferred_finalization(&mut self, deferred_finalization: DeferredFinalization) {
+        self.deferred_finalization = Some(deferred_finalization);
     }
 
-    pub fn start(&mut self) -> Result<(), FangError> {
-        for idx in 1..self.number_of_workers + 1 {
-            let worker_type = self
-                .worker_params
-                .task_type
-                .clone()
-                .unwrap_or_else(|| "".to_string());
-            let name = format!("worker_{}{}", worker_type, idx);
-            WorkerThread::spawn_in_pool(name.clone(), 0, self.clone())?;
-        }
-        Ok(())
+    pub fn set_claim_strategy(&mut self, claim_strategy: Arc<dyn ClaimStrategy>) {
+        self.claim_strategy = Some(claim_strategy);
     }
 
-    /// Attempt graceful shutdown of each job thread, blocks until all threads exit. Threads exit
-    /// when their current job finishes.
-    pub fn shutdown(&mut self) -> Result<(), FangError> {
-        *self.shared_state.write()? = WorkerState::Shutdown;
+    pub fn add_middleware(&mut self, middleware: Arc<dyn TaskMiddleware>) {
+        self.middlewares.push(middleware);
+    }
 
-        for (worker_name, thread) in self.thread_join_handles.write()?.drain() {
-            if let Err(err) = thread.join() {
-                error!(
-                    "Failed to exit executor thread '{}' cleanly: {:?}",
-                    worker_name, err
-                );
-            }
-        }
-        Ok(())
+    /// See `Executor::add_serializer`
+    pub fn add_serializer(&mut self, serializer: Arc<dyn TaskSerializer>) {
+        self.serializers.push(serializer);
     }
-}
 
-impl<D: Clone + Send + 'static> WorkerThread<D> {
-    pub fn new(name: String, restarts: u64, worker_pool: WorkerPool<D>) -> Self {
-        Self {
-            name,
-            restarts,
-            worker_pool,
-            graceful_shutdown: false,
-        }
+    /// See `Queue::set_payload_store`
+    pub fn set_payload_store(&mut self, payload_store: Arc<dyn PayloadStore>) {
+        self.payload_store = Some(payload_store);
     }
 
-    pub fn spawn_in_pool(
-        name: String,
-        restarts: u64,
-        worker_pool: WorkerPool<D>,
-    ) -> Result<(), FangError> {
-        info!(
-            "starting a worker thread {}, number of restarts {}",
-            name, restarts
-        );
+    /// See `TaskUpgrader`
+    pub fn add_upgrader(&mut self, upgrader: Arc<dyn TaskUpgrader>) {
+        self.upgraders.push(upgrader);
+    }
 
-        let job = WorkerThread::new(name.clone(), restarts, worker_pool.clone());
-        let join_handle = Self::spawn_thread(name.clone(), job)?;
-        worker_pool
-            .thread_join_handles
-            .write()?
-            .insert(name, join_handle);
-        Ok(())
+    /// See `Executor::set_signing_key`
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = Some(signing_key);
     }
 
-    fn spawn_thread(
-        name: String,
-        mut job: WorkerThread<D>,
-    ) -> Result<thread::JoinHandle<()>, FangError> {
-        let builder = thread::Builder::new().name(name.clone());
-        builder
-            .spawn(move || {
-                match job.worker_pool.connection_pool.get() {
-                    Ok(connection) => {
-                        let mut executor = Executor::new(connection);
-                        executor.set_shared_state(job.worker_pool.shared_state.clone());
+    /// Start at most `limit.max_executions` tasks of `task_type` per `limit.period` across
+    /// all workers of the pool, see `Executor::set_rate_limiter`
+    pub fn set_rate_limit(&mut self, task_type: String, limit: RateLimit) {
+        self.rate_limiter
+            .get_or_insert_with(RateLimiter::new)
+            .set_limit(task_type, limit);
+    }
 
-                        if let Some(ref task_type_str) = job.worker_pool.worker_params.task_type {
-                            executor.set_task_type(task_type_str.to_owned());
-                        }
+    /// Execute at most `max_in_flight` tasks at once across all workers of the pool, whatever
+    /// the number of workers, see `Executor::set_concurrency_limit`
+    pub fn set_max_in_flight_tasks(&mut self, max_in_flight: u32) {
+        self.concurrency_limit = Some(ConcurrencyLimit::new(max_in_flight));
+    }
 
-                        if let Some(ref retention_mode) =
-                            job.worker_pool.worker_params.retention_mode
-                        {
-                            executor.set_retention_mode(retention_mode.to_owned());
-                        }
+    /// Dedicate a number of workers to each task type, e.g. `{"emails": 4, "reports": 1}`.
+    /// Replaces `task_type`, and the pool's `number_of_workers` becomes the sum of the counts.
+    pub fn set_task_type_workers(&mut self, task_type_workers: HashMap<String, u32>) {
+        self.task_type_workers = task_type_workers;
+    }
 
-                        if let Some(ref sleep_params) = job.worker_pool.worker_params.sleep_params {
-                            executor.set_sleep_params(sleep_params.clone());
-                        }
+    /// See `Executor::set_check_maintenance_windows`
+    pub fn set_check_maintenance_windows(&mut self, check_maintenance_windows: bool) {
+        self.check_maintenance_windows = check_maintenance_windows;
+    }
 
-                        // Run executor
-                        match executor.run_tasks() {
-                            Ok(_) => {
-                                job.graceful_shutdown = true;
-                            }
-                            Err(error) => {
-                                error!("Error executing tasks in worker '{}': {:?}", name, error);
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        error!("Failed to get postgres connection: {:?}", error);
-                    }
-                }
-            })
-            .map_err(FangError::from)
+    /// Give every worker a second connection to run jobs with, see
+    /// `Executor::set_job_connection`. Pools built by fang are sized for it.
+    pub fn set_separate_job_connections(&mut self, separate_job_connections: bool) {
+        self.separate_job_connections = separate_job_connections;
     }
-}
 
-impl<D: Clone + Send + 'static> Drop for WorkerThread<D> {
-    fn drop(&mut self) {
-        if self.graceful_shutdown {
-            return;
+    pub fn set_restart_policy(&mut self, restart_policy: RestartPolicy) {
+        self.restart_policy = restart_policy;
+    }
+
+    /// See `ReconnectPolicy`
+    pub fn set_reconnect_policy(&mut self, reconnect_policy: ReconnectPolicy) {
+        self.reconnect_policy = reconnect_policy;
+    }
+
+    fn connections_per_worker(&self) -> u32 {
+        if self.separate_job_connections {
+            2
+        } else {
+            1
         }
+    }
 
-        WorkerThread::spawn_in_pool(
-            self.name.clone(),
-            self.restarts + 1,
-            self.worker_pool.clone(),
-        )
-        .unwrap();
+    /// See `Executor::set_allowed_job_names`
+    pub fn set_allowed_job_names(&mut self, allowed_job_names: Vec<String>) {
+        self.allowed_job_names = Some(allowed_job_names);
     }
-}
 
-#[cfg(test)]
-mod job_pool_tests {
-    use super::WorkerParams;
-    use super::WorkerPool;
-    use crate::executor::Error;
-    use crate::executor::RetentionMode;
-    use crate::executor::Runnable;
-    use crate::queue::Queue;
-    use crate::queue::Task;
-    use crate::schema::{fang_tasks, FangTaskState};
-    use crate::typetag;
-    use diesel::pg::PgConnection;
-    use diesel::prelude::*;
-    use serde::{Deserialize, Serialize};
-    use std::thread;
-    use std::time::Duration;
+    /// See `Executor::set_warn_after`
+    pub fn set_warn_after(&mut self, warn_after: Duration) {
+        self.warn_after = Some(warn_after);
+    }
 
-    #[derive(Serialize, Deserialize)]
-    struct MyJob {
-        pub number: u16,
-        pub current_thread_name: String,
+    pub fn add_slow_task_listener(&mut self, slow_task_listener: Arc<dyn SlowTaskListener>) {
+        self.slow_task_listeners.push(slow_task_listener);
     }
 
-    impl MyJob {
-        pub fn new(number: u16) -> Self {
-            let handle = thread::current();
-            let current_thread_name = handle.name().unwrap().to_string();
+    /// `WorkerPool::start` makes `fang_periodic_tasks` match `schedule_registry`: it adds
+    /// missing periodic tasks, updates changed periods and removes the periodic tasks that
+    /// aren't registered.
+    pub fn set_schedule_registry(&mut self, schedule_registry: ScheduleRegistry) {
+        self.schedule_registry = Some(schedule_registry);
+    }
+}
 
-            Self {
-                number,
-                current_thread_name,
-            }
+/// The worker parameters `WorkerPool::update_params` changes on running workers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReloadableParams {
+    pub retention_mode: RetentionMode,
+    pub sleep_params: SleepParams,
+    pub task_type: Option<String>,
+}
+
+impl ReloadableParams {
+    /// The values of `worker_params`, with the defaults of `Executor` for the unset ones.
+    pub fn new(worker_params: &WorkerParams) -> Self {
+        Self {
+            retention_mode: worker_params
+                .retention_mode
+                .unwrap_or(RetentionMode::RemoveFinished),
+            sleep_params: worker_params.sleep_params.clone().unwrap_or_default(),
+            task_type: worker_params.task_type.clone(),
         }
     }
+}
 
-    #[typetag::serde]
-    impl Runnable for MyJob {
-        fn run(&self, connection: &PgConnection) -> Result<(), Error> {
-            thread::sleep(Duration::from_secs(3));
+/// `ReloadableParams` shared by a pool and its workers, with the number of updates so far, so
+/// workers apply every update once.
+pub type SharedParams = Arc<RwLock<(u64, ReloadableParams)>>;
 
-            let new_job = MyJob::new(self.number + 1);
+impl<D: Clone + Send + 'static> WorkerPool<D> {
+    pub fn new(number_of_workers: u32) -> Self {
+        Self::new_with_params(number_of_workers, WorkerParams::new(), None)
+    }
 
-            Queue::push_task_query(connection, &new_job).unwrap();
+    /// Configured by `FangConfig::from_env`, see `new_with_config`. Panics if it's invalid
+    /// or the pools can't connect, see `try_new_with_params`.
+    pub fn new_with_params(
+        number_of_workers: u32,
+        worker_params: WorkerParams,
+        worker_data: Option<D>,
+    ) -> Self {
+        Self::try_new_with_params(number_of_workers, worker_params, worker_data)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
 
-            Ok(())
-        }
+    pub fn try_new_with_params(
+        number_of_workers: u32,
+        worker_params: WorkerParams,
+        worker_data: Option<D>,
+    ) -> Result<Self, FangError> {
+        let config = FangConfig::from_env()?;
 
-        fn task_type(&self) -> String {
-            "worker_pool_test".to_string()
-        }
+        Self::try_new_with_config(number_of_workers, worker_params, worker_data, config)
     }
 
-    #[derive(Serialize, Deserialize)]
-    struct ShutdownJob {
-        pub number: u16,
-        pub current_thread_name: String,
+    /// Build the connection pools from `config` instead of `FangConfig::from_env`. The sleep
+    /// params and retention mode of `config` apply unless `worker_params` sets them. Panics
+    /// if the pools can't connect, see `try_new_with_config`.
+    pub fn new_with_config(
+        number_of_workers: u32,
+        worker_params: WorkerParams,
+        worker_data: Option<D>,
+        config: FangConfig,
+    ) -> Self {
+        Self::try_new_with_config(number_of_workers, worker_params, worker_data, config)
+            .unwrap_or_else(|error| panic!("{}", error))
     }
 
-    impl ShutdownJob {
-        pub fn new(number: u16) -> Self {
-            let handle = thread::current();
-            let current_thread_name = handle.name().unwrap().to_string();
+    pub fn try_new_with_config(
+        number_of_workers: u32,
+        worker_params: WorkerParams,
+        worker_data: Option<D>,
+        config: FangConfig,
+    ) -> Result<Self, FangError> {
+        let worker_params = config.worker_params(worker_params);
+        let connection_pool = Self::build_connection_pool(
+            Some(&config),
+            number_of_workers * worker_params.connections_per_worker(),
+        )?;
+        let maintenance_pool =
+            Self::build_connection_pool(Some(&config), worker_params.maintenance_pool_size)?;
 
-            Self {
-                number,
-                current_thread_name,
-            }
-        }
+        let mut worker_pool = Self::new_with_pools(
+            number_of_workers,
+            worker_params,
+            worker_data,
+            connection_pool,
+            maintenance_pool,
+            false,
+        );
+        worker_pool.config = Some(config);
+
+        Ok(worker_pool)
     }
 
-    #[typetag::serde]
-    impl Runnable for ShutdownJob {
-        fn run(&self, connection: &PgConnection) -> Result<(), Error> {
-            thread::sleep(Duration::from_secs(3));
+    /// Take connections from an existing pool instead of building one from `DATABASE_URL`, for
+    /// example to share its TLS and timeout settings with the rest of the application. It's
+    /// never replaced, so size it for `number_of_workers` (including workers added by
+    /// `scale_to`) plus its other users.
+    ///
+    /// `maintenance_connection` takes connections from `maintenance_pool`, or from
+    /// `connection_pool` if it's `None`. A shared pool can run out: `shutdown_with_timeout`
+    /// then waits for the pool's connection timeout and fails to reset the tasks of stuck
+    /// workers, which hold their connections.
+    pub fn new_with_connection_pool(
+        number_of_workers: u32,
+        worker_params: WorkerParams,
+        worker_data: Option<D>,
+        connection_pool: r2d2::Pool<r2d2::ConnectionManager<PgConnection>>,
+        maintenance_pool: Option<r2d2::Pool<r2d2::ConnectionManager<PgConnection>>>,
+    ) -> Self {
+        let maintenance_pool = maintenance_pool.unwrap_or_else(|| connection_pool.clone());
 
-            let new_job = MyJob::new(self.number + 1);
+        Self::new_with_pools(
+            number_of_workers,
+            worker_params,
+            worker_data,
+            connection_pool,
+            maintenance_pool,
+            true,
+        )
+    }
 
-            Queue::push_task_query(connection, &new_job).unwrap();
+    fn new_with_pools(
+        number_of_workers: u32,
+        worker_params: WorkerParams,
+        worker_data: Option<D>,
+        connection_pool: r2d2::Pool<r2d2::ConnectionManager<PgConnection>>,
+        maintenance_pool: r2d2::Pool<r2d2::ConnectionManager<PgConnection>>,
+        external_connection_pool: bool,
+    ) -> Self {
+        let shared_params = Arc::new(RwLock::new((0, ReloadableParams::new(&worker_params))));
 
-            Ok(())
+        Self {
+            number_of_workers,
+            worker_params,
+            connection_pool,
+            maintenance_pool,
+            external_connection_pool,
+            config: None,
+            worker_data: worker_data.map(Box::new),
+            shared_state: Arc::new(RwLock::new(WorkerState::NotStarted)),
+            thread_join_handles: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            stop_signals: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            in_flight_tasks: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            current_tasks: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            shared_params,
+            claim_metrics: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            worker_metrics: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            restarts: Arc::new(RwLock::new(HashMap::with_capacity(
+                number_of_workers as usize,
+            ))),
+            schedulers: Arc::new(RwLock::new(Vec::new())),
+            event_senders: Arc::new(Mutex::new(Vec::new())),
+            join_signal: Arc::new((Mutex::new(PoolExit::default()), Condvar::new())),
         }
+    }
 
-        fn task_type(&self) -> String {
-            "shutdown_test".to_string()
+    fn build_connection_pool(
+        config: Option<&FangConfig>,
+        pool_size: u32,
+    ) -> Result<r2d2::Pool<r2d2::ConnectionManager<PgConnection>>, FangError> {
+        let config = match config {
+            Some(config) => config.clone(),
+            None => FangConfig::from_env()?,
+        };
+
+        FangConfig {
+            pool_size,
+            ..config
         }
+        .on_demand_connection_pool(IDLE_CONNECTION_TIMEOUT)
     }
 
-    fn get_all_tasks(conn: &PgConnection, job_type: &str) -> Vec<Task> {
-        fang_tasks::table
-            .filter(fang_tasks::task_type.eq(job_type))
-            .get_results::<Task>(conn)
+    /// Spawn the worker threads. A pool that was shut down can be started again.
+    pub fn start(&mut self) -> Result<(), FangError> {
+        {
+            let mut shared_state = self.shared_state.write()?;
+
+            if shared_state.is_started() {
+                return self.duplicate_call(FangError::AlreadyRunning);
+            }
+
+            self.apply_schedule_registry()?;
+
+            if !self.worker_params.task_type_workers.is_empty() {
+                self.number_of_workers = self.worker_params.task_type_workers.values().sum();
+            }
+
+            // The pool may have been sized for the workers added by `scale_to`
+            let connections_per_worker = self.worker_params.connections_per_worker();
+            if !self.external_connection_pool
+                && self.connection_pool.max_size()
+                    != self.number_of_workers * connections_per_worker
+            {
+                self.connection_pool = Self::build_connection_pool(
+                    self.config.as_ref(),
+                    self.number_of_workers * connections_per_worker,
+                )?;
+            }
+
+            *shared_state = WorkerState::Running;
+        }
+
+        self.join_signal.0.lock()?.shutdown_complete = false;
+
+        {
+            // Updates made with `update_params` last until the pool is shut down
+            let mut shared_params = self.shared_params.write()?;
+            *shared_params = (
+                shared_params.0 + 1,
+                ReloadableParams::new(&self.worker_params),
+            );
+        }
+
+        if self.worker_params.task_type_workers.is_empty() {
+            for idx in 1..self.number_of_workers + 1 {
+                WorkerThread::spawn_in_pool(self.worker_name(idx), 0, self.clone())?;
+            }
+
+            return Ok(());
+        }
+
+        let mut task_type_workers: Vec<(String, u32)> = self
+            .worker_params
+            .task_type_workers
+            .iter()
+            .map(|(task_type, count)| (task_type.clone(), *count))
+            .collect();
+        task_type_workers.sort();
+
+        for (task_type, count) in task_type_workers {
+            let typed = self.for_task_type(&task_type);
+
+            for idx in 1..count + 1 {
+                WorkerThread::spawn_in_pool(typed.worker_name(idx), 0, typed.clone())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Change the number of worker threads. New workers get a connection pool of their own
+    /// sized for them. Surplus workers exit after finishing their current task; this blocks
+    /// until they did. An exited worker's connection goes back to the pool it came from and
+    /// is closed after `IDLE_CONNECTION_TIMEOUT`, unless the pool was passed to
+    /// `new_with_connection_pool`. After a restart, `start` builds one pool sized for
+    /// `number_of_workers` again.
+    ///
+    /// If the pool isn't running, only the number of workers for the next `start` changes.
+    /// If the connection pool for new workers can't be built, the error is returned and the
+    /// number of workers stays the same.
+    ///
+    /// Pools with per-task-type workers return `FangError::PerTaskTypeWorkers`, use
+    /// `scale_task_type_to` instead.
+    pub fn scale_to(&mut self, number_of_workers: u32) -> Result<(), FangError> {
+        if !self.worker_params.task_type_workers.is_empty() {
+            return Err(FangError::PerTaskTypeWorkers);
+        }
+
+        if self.shared_state.read()?.is_started() {
+            self.resize(self.number_of_workers, number_of_workers)?;
+        }
+
+        self.number_of_workers = number_of_workers;
+
+        Ok(())
+    }
+
+    /// `scale_to` for the workers of one task type, see `WorkerParams::set_task_type_workers`.
+    /// Adds the task type if the pool doesn't have workers for it yet.
+    pub fn scale_task_type_to(
+        &mut self,
+        task_type: &str,
+        number_of_workers: u32,
+    ) -> Result<(), FangError> {
+        let current = self
+            .worker_params
+            .task_type_workers
+            .get(task_type)
+            .copied()
+            .unwrap_or(0);
+
+        if self.shared_state.read()?.is_started() {
+            let mut typed = self.for_task_type(task_type);
+            typed
+                .worker_params
+                .task_type_workers
+                .insert(task_type.to_string(), number_of_workers);
+
+            typed.resize(current, number_of_workers)?;
+        }
+
+        self.worker_params
+            .task_type_workers
+            .insert(task_type.to_string(), number_of_workers);
+        self.number_of_workers = self.worker_params.task_type_workers.values().sum();
+
+        Ok(())
+    }
+
+    fn resize(&self, current: u32, number_of_workers: u32) -> Result<(), FangError> {
+        if number_of_workers > current {
+            let mut added = self.clone();
+
+            if !self.external_connection_pool {
+                added.connection_pool = Self::build_connection_pool(
+                    self.config.as_ref(),
+                    (number_of_workers - current) * self.worker_params.connections_per_worker(),
+                )?;
+            }
+
+            for idx in current + 1..number_of_workers + 1 {
+                WorkerThread::spawn_in_pool(self.worker_name(idx), 0, added.clone())?;
+            }
+
+            return Ok(());
+        }
+
+        let names: Vec<String> = (number_of_workers + 1..current + 1)
+            .map(|idx| self.worker_name(idx))
+            .collect();
+
+        // Workers that were just spawned may not have picked up their signal yet
+        for name in &names {
+            self.stop_signal(name)?.store(true, Ordering::SeqCst);
+        }
+
+        for name in names {
+            let thread = self.thread_join_handles.write()?.remove(&name);
+
+            if let Some(thread) = thread {
+                if let Err(err) = thread.join() {
+                    fang_log!(
+                        LogModule::WorkerPool,
+                        Level::Error,
+                        "Failed to exit executor thread '{}' cleanly: {:?}",
+                        name,
+                        err
+                    );
+                }
+            }
+
+            self.stop_signals.write()?.remove(&name);
+            self.current_tasks.write()?.remove(&name);
+            self.claim_metrics.write()?.remove(&name);
+            self.worker_metrics.write()?.remove(&name);
+            self.restarts.write()?.remove(&name);
+        }
+
+        Ok(())
+    }
+
+    /// Attempt graceful shutdown of each job thread, blocks until all threads exit. Threads exit
+    /// when their current job finishes.
+    pub fn shutdown(&mut self) -> Result<(), FangError> {
+        if !self.begin_shutdown()? {
+            return self.duplicate_call(FangError::AlreadyShutdown);
+        }
+
+        self.stop_schedulers()?;
+
+        // Release the lock before joining, a panicked worker re-registers itself from `Drop`
+        let threads: Vec<_> = self.thread_join_handles.write()?.drain().collect();
+
+        for (worker_name, thread) in threads {
+            if let Err(err) = thread.join() {
+                fang_log!(
+                    LogModule::WorkerPool,
+                    Level::Error,
+                    "Failed to exit executor thread '{}' cleanly: {:?}",
+                    worker_name,
+                    err
+                );
+            }
+        }
+
+        self.stop_signals.write()?.clear();
+        self.in_flight_tasks.write()?.clear();
+        self.current_tasks.write()?.clear();
+        self.notify_join(|exit| exit.shutdown_complete = true)?;
+        emit(&self.event_senders, WorkerPoolEvent::ShutdownComplete);
+
+        Ok(())
+    }
+
+    /// Like `shutdown`, but stops waiting for workers after `timeout`. The tasks stuck workers
+    /// are running are moved back to `New` so other workers can pick them up, and the stuck
+    /// threads are left running. If one of them finishes later, it still finalizes its task.
+    pub fn shutdown_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<ShutdownReport, FangError> {
+        if !self.begin_shutdown()? {
+            return self
+                .duplicate_call(FangError::AlreadyShutdown)
+                .map(|_| ShutdownReport::default());
+        }
+
+        self.stop_schedulers()?;
+
+        let deadline = Instant::now() + timeout;
+        let threads: Vec<_> = self.thread_join_handles.write()?.drain().collect();
+        let mut report = ShutdownReport::default();
+
+        for (worker_name, thread) in threads {
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+
+            if !thread.is_finished() {
+                report.stuck_workers.push(worker_name);
+                continue;
+            }
+
+            if let Err(err) = thread.join() {
+                fang_log!(
+                    LogModule::WorkerPool,
+                    Level::Error,
+                    "Failed to exit executor thread '{}' cleanly: {:?}",
+                    worker_name,
+                    err
+                );
+            }
+        }
+
+        {
+            let in_flight_tasks = self.in_flight_tasks.read()?;
+
+            for worker_name in &report.stuck_workers {
+                if let Some(ids) = in_flight_tasks.get(worker_name) {
+                    report
+                        .reset_tasks
+                        .extend(ids.lock().unwrap_or_else(|error| error.into_inner()).iter());
+                }
+            }
+        }
+
+        if !report.reset_tasks.is_empty() {
+            let connection = self.maintenance_connection()?;
+            Queue::reset_tasks_query(&*connection, &report.reset_tasks)?;
+        }
+
+        self.stop_signals.write()?.clear();
+        self.in_flight_tasks.write()?.clear();
+        self.current_tasks.write()?.clear();
+        self.notify_join(|exit| exit.shutdown_complete = true)?;
+        emit(&self.event_senders, WorkerPoolEvent::ShutdownComplete);
+
+        Ok(report)
+    }
+
+    /// Block until the pool is shut down, for example by the handler of
+    /// `install_signal_handler`. Returns an error if a crashed worker couldn't be restarted or
+    /// exceeded `RestartPolicy::max_restarts`, the other workers keep running in that case.
+    /// Returns right away if the pool was never started.
+    pub fn join(&self) -> Result<(), FangError> {
+        if *self.shared_state.read()? == WorkerState::NotStarted {
+            return Ok(());
+        }
+
+        let (exit, condvar) = &*self.join_signal;
+        let mut exit = exit.lock()?;
+
+        loop {
+            if let Some(error) = exit.fatal_error.take() {
+                return Err(error);
+            }
+
+            if exit.shutdown_complete {
+                return Ok(());
+            }
+
+            exit = condvar.wait(exit)?;
+        }
+    }
+
+    fn notify_join(&self, update: impl FnOnce(&mut PoolExit)) -> Result<(), FangError> {
+        let (exit, condvar) = &*self.join_signal;
+        update(&mut *exit.lock()?);
+        condvar.notify_all();
+
+        Ok(())
+    }
+
+    /// Hand an error `join` should return to it.
+    fn report_fatal_error(&self, error: FangError) {
+        if let Err(error) = self.notify_join(|exit| exit.fatal_error = Some(error)) {
+            fang_log!(
+                LogModule::WorkerPool,
+                Level::Error,
+                "Failed to report a fatal worker error: {:?}",
+                error
+            );
+        }
+    }
+
+    /// Returns false as soon as the pool is shut down or `stop_signal` is set.
+    fn sleep_while_running(&self, duration: Duration, stop_signal: &AtomicBool) -> bool {
+        let deadline = Instant::now() + duration;
+
+        loop {
+            match self.state() {
+                Ok(state) if state.is_started() && !stop_signal.load(Ordering::SeqCst) => {}
+                _ => return false,
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return true;
+            }
+
+            thread::sleep(SHUTDOWN_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// Stop the scheduler when the pool is shut down, before the workers, so no periodic
+    /// tasks are enqueued while the workers finish.
+    pub fn attach_scheduler(&self, scheduler: SchedulerHandle) -> Result<(), FangError> {
+        self.schedulers.write()?.push(scheduler);
+
+        Ok(())
+    }
+
+    fn stop_schedulers(&self) -> Result<(), FangError> {
+        let schedulers: Vec<_> = self.schedulers.write()?.drain(..).collect();
+
+        for scheduler in schedulers {
+            scheduler.stop()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns false if the pool wasn't running.
+    fn begin_shutdown(&self) -> Result<bool, FangError> {
+        let mut shared_state = self.shared_state.write()?;
+
+        if shared_state.is_started() {
+            *shared_state = WorkerState::Shutdown;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Shut the pool down on SIGINT or SIGTERM. The returned thread exits after the shutdown
+    /// finished, so joining it keeps the process alive until in-flight tasks are done.
+    #[cfg(unix)]
+    pub fn install_signal_handler(&self) -> Result<thread::JoinHandle<()>, FangError> {
+        use signal_hook::consts::signal::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM]).map_err(FangError::SignalHandlerError)?;
+        let mut worker_pool = self.clone();
+
+        let builder = thread::Builder::new().name("signal_handler".to_string());
+
+        Ok(builder.spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                fang_log!(
+                    LogModule::WorkerPool,
+                    Level::Info,
+                    "received signal {}, shutting down the worker pool",
+                    signal
+                );
+
+                if let Err(error) = worker_pool.shutdown() {
+                    fang_log!(
+                        LogModule::WorkerPool,
+                        Level::Error,
+                        "Failed to shut down the worker pool: {:?}",
+                        error
+                    );
+                }
+            }
+        })?)
+    }
+
+    fn apply_schedule_registry(&self) -> Result<(), FangError> {
+        let schedule_registry = match self.worker_params.schedule_registry {
+            Some(ref schedule_registry) => schedule_registry,
+            None => return Ok(()),
+        };
+
+        let connection = self.maintenance_connection()?;
+        let diff = Queue::apply_schedules_query(&*connection, schedule_registry.schedules())?;
+
+        fang_log!(
+            LogModule::WorkerPool,
+            Level::Info,
+            "applied the schedule registry: {} added, {} updated, {} removed",
+            diff.added.len(),
+            diff.updated.len(),
+            diff.removed.len()
+        );
+
+        Ok(())
+    }
+
+    /// Connection for housekeeping (pruning, reaping, heartbeats), taken from the maintenance
+    /// pool rather than the one workers fetch tasks with.
+    pub fn maintenance_connection(
+        &self,
+    ) -> Result<r2d2::PooledConnection<r2d2::ConnectionManager<PgConnection>>, FangError> {
+        Ok(self.maintenance_pool.get()?)
+    }
+
+    pub fn state(&self) -> Result<WorkerState, FangError> {
+        Ok(self.shared_state.read()?.clone())
+    }
+
+    /// Change the retention mode, sleep params or task type of the running workers,
+    /// without restarting them. Each worker applies the change before it claims its next
+    /// tasks. The change lasts until the pool is shut down, `start` uses `worker_params`
+    /// again.
+    ///
+    /// ```ignore
+    /// worker_pool.update_params(|params| {
+    ///     params.task_type = Some("reports".to_string());
+    /// })?;
+    /// ```
+    ///
+    /// Returns `FangError::NotRunning` unless the pool was started. Pools with per-task-type
+    /// workers return `FangError::PerTaskTypeWorkers`.
+    pub fn update_params(
+        &self,
+        update: impl FnOnce(&mut ReloadableParams),
+    ) -> Result<(), FangError> {
+        if !self.worker_params.task_type_workers.is_empty() {
+            return Err(FangError::PerTaskTypeWorkers);
+        }
+
+        if !self.shared_state.read()?.is_started() {
+            return Err(FangError::NotRunning);
+        }
+
+        let mut shared_params = self.shared_params.write()?;
+        let mut params = shared_params.1.clone();
+        update(&mut params);
+
+        fang_log!(
+            LogModule::WorkerPool,
+            Level::Info,
+            "worker params updated to {:?}",
+            params
+        );
+
+        *shared_params = (shared_params.0 + 1, params);
+
+        Ok(())
+    }
+
+    /// Stop claiming tasks but keep the worker threads alive, e.g. during a migration. Running
+    /// tasks finish, the rest of a batch claimed in the fast path goes back to the queue.
+    /// Returns `FangError::NotRunning` unless the pool was started.
+    pub fn pause(&self) -> Result<(), FangError> {
+        self.set_started_state(WorkerState::Paused)
+    }
+
+    /// Like `pause`, but workers first finish all the tasks they claimed, including the rest
+    /// of a fast path batch, and finalize the tasks buffered by deferred finalization.
+    pub fn drain(&self) -> Result<(), FangError> {
+        self.set_started_state(WorkerState::Draining)
+    }
+
+    /// Claim tasks again after `pause` or `drain`.
+    pub fn resume(&self) -> Result<(), FangError> {
+        self.set_started_state(WorkerState::Running)
+    }
+
+    fn set_started_state(&self, state: WorkerState) -> Result<(), FangError> {
+        let mut shared_state = self.shared_state.write()?;
+
+        if !shared_state.is_started() {
+            return Err(FangError::NotRunning);
+        }
+
+        fang_log!(
+            LogModule::WorkerPool,
+            Level::Info,
+            "worker pool state changed from {:?} to {:?}",
+            *shared_state,
+            state
+        );
+
+        *shared_state = state;
+
+        Ok(())
+    }
+
+    /// Claim attempts, claims that came back empty because of locked rows and the average
+    /// claim latency of each worker, keyed by worker name. Counted since the worker was first
+    /// started, restarts keep the counts.
+    pub fn claim_stats(&self) -> Result<HashMap<String, ClaimStats>, FangError> {
+        Ok(self
+            .claim_metrics
+            .read()?
+            .iter()
+            .map(|(name, claim_metrics)| (name.clone(), claim_metrics.stats()))
+            .collect())
+    }
+
+    /// A channel of the pool's lifecycle events: workers starting and restarting, tasks
+    /// completing and shutdowns completing. Events are only sent to receivers subscribed
+    /// before they happen. Dropping the receiver unsubscribes it. Receivers that aren't read
+    /// keep every event in memory, so read them from a thread of their own.
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<WorkerPoolEvent>, FangError> {
+        let (sender, receiver) = mpsc::channel();
+        self.event_senders.lock()?.push(sender);
+
+        Ok(receiver)
+    }
+
+    /// Processed, failed and retried tasks and the time of the last finished task of each
+    /// worker, keyed by worker name. Counted since the worker was first started, restarts keep
+    /// the counts. A worker whose `last_activity_at` stops moving while tasks are waiting is
+    /// stuck or starved.
+    pub fn metrics(&self) -> Result<HashMap<String, WorkerStats>, FangError> {
+        Ok(self
+            .worker_metrics
+            .read()?
+            .iter()
+            .map(|(name, worker_metrics)| (name.clone(), worker_metrics.stats()))
+            .collect())
+    }
+
+    /// Name, restarts, current tasks, last poll time and activity of each worker, sorted by
+    /// name. Meant for health endpoints and finding stuck workers.
+    pub fn status(&self) -> Result<Vec<WorkerStatus>, FangError> {
+        let thread_join_handles = self.thread_join_handles.read()?;
+        let in_flight_tasks = self.in_flight_tasks.read()?;
+        let claim_metrics = self.claim_metrics.read()?;
+        let restarts = self.restarts.read()?;
+
+        let mut status: Vec<WorkerStatus> = thread_join_handles
+            .iter()
+            .map(|(name, thread)| {
+                let current_task_ids = in_flight_tasks
+                    .get(name)
+                    .map(|ids| {
+                        ids.lock()
+                            .unwrap_or_else(|error| error.into_inner())
+                            .clone()
+                    })
+                    .unwrap_or_default();
+
+                let activity = if thread.is_finished() {
+                    WorkerActivity::Stopped
+                } else if current_task_ids.is_empty() {
+                    WorkerActivity::Idle
+                } else {
+                    WorkerActivity::Busy
+                };
+
+                WorkerStatus {
+                    name: name.clone(),
+                    restarts: restarts.get(name).copied().unwrap_or(0),
+                    current_task_ids,
+                    last_poll_at: claim_metrics
+                        .get(name)
+                        .and_then(|claim_metrics| claim_metrics.stats().last_claim_at),
+                    activity,
+                }
+            })
+            .collect();
+
+        status.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(status)
+    }
+
+    /// The tasks the workers are executing right now, sorted by worker name. In the fast
+    /// path, only the task of a batch that is running is listed, not the ones waiting for it.
+    pub fn running_tasks(&self) -> Result<Vec<RunningTask>, FangError> {
+        let mut running_tasks: Vec<RunningTask> = self
+            .current_tasks
+            .read()?
+            .iter()
+            .filter_map(|(worker_name, current_task)| {
+                current_task
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner())
+                    .clone()
+                    .map(|running_task| RunningTask {
+                        worker_name: worker_name.clone(),
+                        ..running_task
+                    })
+            })
+            .collect();
+
+        running_tasks.sort_by(|a, b| a.worker_name.cmp(&b.worker_name));
+
+        Ok(running_tasks)
+    }
+
+    fn claim_metrics(&self, name: &str) -> Result<Arc<ClaimMetrics>, FangError> {
+        Ok(self
+            .claim_metrics
+            .write()?
+            .entry(name.to_string())
+            .or_default()
+            .clone())
+    }
+
+    fn worker_metrics(&self, name: &str) -> Result<Arc<WorkerMetrics>, FangError> {
+        Ok(self
+            .worker_metrics
+            .write()?
+            .entry(name.to_string())
+            .or_default()
+            .clone())
+    }
+
+    fn current_task(&self, name: &str) -> Result<CurrentTask, FangError> {
+        Ok(self
+            .current_tasks
+            .write()?
+            .entry(name.to_string())
+            .or_default()
+            .clone())
+    }
+
+    fn in_flight_tasks(&self, name: &str) -> Result<InFlightTasks, FangError> {
+        Ok(self
+            .in_flight_tasks
+            .write()?
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(Vec::new())))
+            .clone())
+    }
+
+    /// The state the pool tracks for the worker `name`, created unless a worker with the name
+    /// ran before.
+    fn register_worker(&self, name: &str) -> Result<Registration, FangError> {
+        Ok(Registration {
+            stop_signal: self.stop_signal(name)?,
+            in_flight_tasks: self.in_flight_tasks(name)?,
+            current_task: self.current_task(name)?,
+            claim_metrics: self.claim_metrics(name)?,
+            worker_metrics: self.worker_metrics(name)?,
+        })
+    }
+
+    /// Created by whichever comes first, the worker or `scale_to`. A restarted worker keeps
+    /// the signal of the thread it replaces.
+    fn stop_signal(&self, name: &str) -> Result<StopSignal, FangError> {
+        Ok(self
+            .stop_signals
+            .write()?
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(false)))
+            .clone())
+    }
+
+    /// A clone whose workers only fetch tasks of `task_type`. Shares threads and state with
+    /// this pool.
+    fn for_task_type(&self, task_type: &str) -> Self {
+        let mut typed = self.clone();
+        typed.worker_params.task_type = Some(task_type.to_string());
+
+        typed
+    }
+
+    fn worker_name(&self, idx: u32) -> String {
+        let worker_type = self.worker_params.task_type.clone().unwrap_or_default();
+
+        format!("worker_{}{}", worker_type, idx)
+    }
+
+    fn duplicate_call(&self, error: FangError) -> Result<(), FangError> {
+        match self.worker_params.duplicate_call_behavior {
+            DuplicateCallBehavior::Error => Err(error),
+            DuplicateCallBehavior::Ignore => Ok(()),
+        }
+    }
+}
+
+impl<D: Clone + Send + 'static> WorkerThread<D> {
+    pub fn new(name: String, restarts: u64, worker_pool: WorkerPool<D>) -> Self {
+        Self {
+            name,
+            restarts,
+            worker_pool,
+            graceful_shutdown: false,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn spawn_in_pool(
+        name: String,
+        restarts: u64,
+        worker_pool: WorkerPool<D>,
+    ) -> Result<(), FangError> {
+        fang_log!(
+            LogModule::WorkerPool,
+            Level::Info,
+            "starting a worker thread {}, number of restarts {}",
+            name,
+            restarts
+        );
+
+        worker_pool.restarts.write()?.insert(name.clone(), restarts);
+
+        let event = match restarts {
+            0 => WorkerPoolEvent::WorkerStarted { name: name.clone() },
+            restarts => WorkerPoolEvent::WorkerRestarted {
+                name: name.clone(),
+                restarts,
+            },
+        };
+        emit(&worker_pool.event_senders, event);
+
+        let job = WorkerThread::new(name.clone(), restarts, worker_pool.clone());
+        let join_handle = Self::spawn_thread(name.clone(), job)?;
+        worker_pool
+            .thread_join_handles
+            .write()?
+            .insert(name, join_handle);
+        Ok(())
+    }
+
+    // `graceful_shutdown` is read in `Drop`, which the liveness lint doesn't see
+    #[allow(unused_assignments)]
+    fn spawn_thread(
+        name: String,
+        mut job: WorkerThread<D>,
+    ) -> Result<thread::JoinHandle<()>, FangError> {
+        let builder = thread::Builder::new().name(name.clone());
+        builder
+            .spawn(move || {
+                match job.worker_pool.connection_pool.get() {
+                    Ok(connection) => {
+                        let mut executor = Executor::new(connection);
+                        executor.set_shared_state(job.worker_pool.shared_state.clone());
+
+                        let registration = match job.worker_pool.register_worker(&name) {
+                            Ok(registration) => registration,
+                            Err(error) => {
+                                fang_log!(
+                                    LogModule::WorkerPool,
+                                    Level::Error,
+                                    "Failed to register worker '{}': {:?}",
+                                    name,
+                                    error
+                                );
+                                return;
+                            }
+                        };
+
+                        executor.set_stop_signal(registration.stop_signal);
+                        executor.set_in_flight_tasks(registration.in_flight_tasks);
+                        executor.set_current_task(registration.current_task);
+                        executor.set_claim_metrics(registration.claim_metrics);
+                        executor.set_worker_metrics(registration.worker_metrics);
+                        executor.set_event_senders(job.worker_pool.event_senders.clone());
+
+                        if job.worker_pool.worker_params.separate_job_connections {
+                            match job.worker_pool.connection_pool.get() {
+                                Ok(job_connection) => executor.set_job_connection(job_connection),
+                                Err(error) => {
+                                    fang_log!(
+                                        LogModule::WorkerPool,
+                                        Level::Error,
+                                        "Failed to get postgres connection: {:?}",
+                                        error
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+
+                        if let Some(ref worker_data) = job.worker_pool.worker_data {
+                            executor.set_worker_data(Box::new((**worker_data).clone()));
+                        }
+
+                        if let Some(ref task_type_str) = job.worker_pool.worker_params.task_type {
+                            executor.set_task_type(task_type_str.to_owned());
+                        }
+
+                        if let Some(ref retention_mode) =
+                            job.worker_pool.worker_params.retention_mode
+                        {
+                            executor.set_retention_mode(retention_mode.to_owned());
+                        }
+
+                        if let Some(ref sleep_params) = job.worker_pool.worker_params.sleep_params {
+                            executor.set_sleep_params(sleep_params.clone());
+                        }
+
+                        // Per-task-type workers keep the task type of their group
+                        if job.worker_pool.worker_params.task_type_workers.is_empty() {
+                            executor.set_shared_params(job.worker_pool.shared_params.clone());
+                        }
+
+                        if let Some(batch_size) = job.worker_pool.worker_params.fast_path_batch_size
+                        {
+                            executor.set_fast_path(batch_size);
+                        }
+
+                        if let Some(ref deferred_finalization) =
+                            job.worker_pool.worker_params.deferred_finalization
+                        {
+                            executor.set_deferred_finalization(deferred_finalization.clone());
+                        }
+
+                        if let Some(ref claim_strategy) =
+                            job.worker_pool.worker_params.claim_strategy
+                        {
+                            executor.set_claim_strategy(claim_strategy.clone());
+                        }
+
+                        for middleware in &job.worker_pool.worker_params.middlewares {
+                            executor.add_middleware(middleware.clone());
+                        }
+
+                        for serializer in &job.worker_pool.worker_params.serializers {
+                            executor.add_serializer(serializer.clone());
+                        }
+
+                        if let Some(ref payload_store) = job.worker_pool.worker_params.payload_store
+                        {
+                            executor.set_payload_store(payload_store.clone());
+                        }
+
+                        for upgrader in &job.worker_pool.worker_params.upgraders {
+                            executor.add_upgrader(upgrader.clone());
+                        }
+
+                        if let Some(ref signing_key) = job.worker_pool.worker_params.signing_key {
+                            executor.set_signing_key(signing_key.clone());
+                        }
+
+                        if let Some(ref rate_limiter) = job.worker_pool.worker_params.rate_limiter {
+                            executor.set_rate_limiter(rate_limiter.clone());
+                        }
+
+                        if let Some(ref concurrency_limit) =
+                            job.worker_pool.worker_params.concurrency_limit
+                        {
+                            executor.set_concurrency_limit(concurrency_limit.clone());
+                        }
+
+                        if let Some(ref allowed_job_names) =
+                            job.worker_pool.worker_params.allowed_job_names
+                        {
+                            executor.set_allowed_job_names(allowed_job_names.clone());
+                        }
+
+                        executor.set_check_maintenance_windows(
+                            job.worker_pool.worker_params.check_maintenance_windows,
+                        );
+
+                        if let Some(warn_after) = job.worker_pool.worker_params.warn_after {
+                            executor.set_warn_after(warn_after);
+                        }
+
+                        for slow_task_listener in &job.worker_pool.worker_params.slow_task_listeners
+                        {
+                            executor.add_slow_task_listener(slow_task_listener.clone());
+                        }
+
+                        let connection_pool = job.worker_pool.connection_pool.clone();
+                        executor.set_connection_source(Box::new(move || {
+                            connection_pool.get().map_err(FangError::from)
+                        }));
+                        executor.set_reconnect_policy(
+                            job.worker_pool.worker_params.reconnect_policy.clone(),
+                        );
+
+                        // Run executor
+                        match executor.run_tasks() {
+                            Ok(_) => {
+                                job.graceful_shutdown = true;
+                            }
+                            Err(error) => {
+                                fang_log!(
+                                    LogModule::WorkerPool,
+                                    Level::Error,
+                                    "Error executing tasks in worker '{}': {:?}",
+                                    name,
+                                    error
+                                );
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        fang_log!(
+                            LogModule::WorkerPool,
+                            Level::Error,
+                            "Failed to get postgres connection: {:?}",
+                            error
+                        );
+                    }
+                }
+            })
+            .map_err(FangError::from)
+    }
+}
+
+impl<D: Clone + Send + 'static> Drop for WorkerThread<D> {
+    fn drop(&mut self) {
+        if self.graceful_shutdown {
+            return;
+        }
+
+        let restart_policy = self.worker_pool.worker_params.restart_policy.clone();
+
+        // A worker that ran for a while before crashing starts over with the shortest delay
+        let restarts = if self.started_at.elapsed() >= restart_policy.max_delay {
+            0
+        } else {
+            self.restarts
+        };
+
+        if restart_policy
+            .max_restarts
+            .is_some_and(|max_restarts| restarts >= max_restarts)
+        {
+            fang_log!(
+                LogModule::WorkerPool,
+                Level::Error,
+                "Worker '{}' crashed after {} restarts, giving up",
+                self.name,
+                restarts
+            );
+
+            self.worker_pool.report_fatal_error(FangError::WorkerDead {
+                name: self.name.clone(),
+                restarts,
+            });
+
+            return;
+        }
+
+        // Set by `scale_to` for workers it removes, it's waiting for this thread
+        let stop_signal = match self.worker_pool.stop_signal(&self.name) {
+            Ok(stop_signal) => stop_signal,
+            Err(error) => {
+                fang_log!(
+                    LogModule::WorkerPool,
+                    Level::Error,
+                    "Failed to restart worker '{}': {:?}",
+                    self.name,
+                    error
+                );
+
+                return;
+            }
+        };
+
+        if !self
+            .worker_pool
+            .sleep_while_running(restart_policy.delay(restarts), &stop_signal)
+        {
+            return;
+        }
+
+        // Holding the lock, `scale_to` can't stop the worker between the check and the restart.
+        // If it stops it afterwards, the replacement gets the same signal.
+        let stop_signals = self
+            .worker_pool
+            .stop_signals
+            .read()
+            .unwrap_or_else(|error| error.into_inner());
+
+        if stop_signal.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let result =
+            WorkerThread::spawn_in_pool(self.name.clone(), restarts + 1, self.worker_pool.clone());
+        drop(stop_signals);
+
+        if let Err(error) = result {
+            fang_log!(
+                LogModule::WorkerPool,
+                Level::Error,
+                "Failed to restart worker '{}': {:?}",
+                self.name,
+                error
+            );
+
+            self.worker_pool
+                .report_fatal_error(FangError::WorkerRestartFailed {
+                    name: self.name.clone(),
+                    source: Box::new(error),
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod job_pool_tests {
+    use super::DuplicateCallBehavior;
+    use super::RestartPolicy;
+    use super::WorkerActivity;
+    use super::WorkerParams;
+    use super::WorkerPool;
+    use super::WorkerPoolEvent;
+    use super::WorkerState;
+    use crate::config::FangConfig;
+    use crate::context::TaskContext;
+    use crate::error::FangError;
+    use crate::executor::Error;
+    use crate::executor::RetentionMode;
+    use crate::executor::Runnable;
+    use crate::executor::SleepParams;
+    use crate::queue::PeriodicTask;
+    use crate::queue::Queue;
+    use crate::queue::Task;
+    use crate::scheduler::Scheduler;
+    use crate::schedules::ScheduleRegistry;
+    use crate::schema::{fang_periodic_tasks, fang_tasks, FangTaskState};
+    use crate::typetag;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize)]
+    struct MyJob {
+        pub number: u16,
+        pub current_thread_name: String,
+    }
+
+    impl MyJob {
+        pub fn new(number: u16) -> Self {
+            let handle = thread::current();
+            let current_thread_name = handle.name().unwrap().to_string();
+
+            Self {
+                number,
+                current_thread_name,
+            }
+        }
+    }
+
+    #[typetag::serde]
+    impl Runnable for MyJob {
+        fn run(&self, connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            thread::sleep(Duration::from_secs(3));
+
+            let new_job = MyJob::new(self.number + 1);
+
+            Queue::push_task_query(connection, &new_job).unwrap();
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "worker_pool_test".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ShutdownJob {
+        pub number: u16,
+        pub current_thread_name: String,
+    }
+
+    impl ShutdownJob {
+        pub fn new(number: u16) -> Self {
+            let handle = thread::current();
+            let current_thread_name = handle.name().unwrap().to_string();
+
+            Self {
+                number,
+                current_thread_name,
+            }
+        }
+    }
+
+    #[typetag::serde]
+    impl Runnable for ShutdownJob {
+        fn run(&self, connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            thread::sleep(Duration::from_secs(3));
+
+            let new_job = MyJob::new(self.number + 1);
+
+            Queue::push_task_query(connection, &new_job).unwrap();
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "shutdown_test".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct SlowJob {}
+
+    #[typetag::serde]
+    impl Runnable for SlowJob {
+        fn run(&self, _connection: &PgConnection, _context: &TaskContext) -> Result<(), Error> {
+            thread::sleep(Duration::from_secs(3));
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "shutdown_timeout_test".to_string()
+        }
+    }
+
+    fn get_all_tasks(conn: &PgConnection, job_type: &str) -> Vec<Task> {
+        fang_tasks::table
+            .filter(fang_tasks::task_type.eq(job_type))
+            .get_results::<Task>(conn)
+            .unwrap()
+    }
+
+    fn lifecycle_test_params() -> WorkerParams {
+        let mut worker_params = WorkerParams::new();
+        worker_params.set_task_type("lifecycle_test".to_string());
+        worker_params.set_sleep_params(
+            SleepParams::builder()
+                .min_sleep_period(Duration::ZERO)
+                .max_sleep_period(Duration::ZERO)
+                .sleep_step(Duration::ZERO)
+                .build()
+                .unwrap(),
+        );
+
+        worker_params
+    }
+
+    #[test]
+    fn duplicate_start_and_shutdown_return_errors() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+
+        assert_eq!(WorkerState::NotStarted, job_pool.state().unwrap());
+
+        job_pool.start().unwrap();
+        assert!(matches!(job_pool.start(), Err(FangError::AlreadyRunning)));
+
+        job_pool.shutdown().unwrap();
+        assert!(matches!(
+            job_pool.shutdown(),
+            Err(FangError::AlreadyShutdown)
+        ));
+        assert_eq!(WorkerState::Shutdown, job_pool.state().unwrap());
+
+        job_pool.start().unwrap();
+        assert_eq!(WorkerState::Running, job_pool.state().unwrap());
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn pause_drain_and_resume_keep_workers_alive() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+
+        assert!(matches!(job_pool.pause(), Err(FangError::NotRunning)));
+
+        job_pool.start().unwrap();
+
+        job_pool.pause().unwrap();
+        assert_eq!(WorkerState::Paused, job_pool.state().unwrap());
+        assert!(matches!(job_pool.start(), Err(FangError::AlreadyRunning)));
+
+        job_pool.drain().unwrap();
+        assert_eq!(WorkerState::Draining, job_pool.state().unwrap());
+
+        thread::sleep(Duration::from_millis(300));
+
+        for worker in job_pool.status().unwrap() {
+            assert_eq!(WorkerActivity::Idle, worker.activity);
+        }
+
+        job_pool.resume().unwrap();
+        assert_eq!(WorkerState::Running, job_pool.state().unwrap());
+
+        job_pool.pause().unwrap();
+        job_pool.shutdown().unwrap();
+        assert_eq!(WorkerState::Shutdown, job_pool.state().unwrap());
+        assert!(matches!(job_pool.resume(), Err(FangError::NotRunning)));
+    }
+
+    #[test]
+    fn update_params_changes_the_shared_params() {
+        let job_pool = WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+
+        assert!(matches!(
+            job_pool.update_params(|params| params.retention_mode = RetentionMode::KeepAll),
+            Err(FangError::NotRunning)
+        ));
+
+        let mut job_pool = job_pool;
+        job_pool.start().unwrap();
+
+        job_pool
+            .update_params(|params| params.retention_mode = RetentionMode::KeepAll)
+            .unwrap();
+
+        {
+            let shared_params = job_pool.shared_params.read().unwrap();
+            assert_eq!(RetentionMode::KeepAll, shared_params.1.retention_mode);
+            assert_eq!(
+                Some("lifecycle_test".to_string()),
+                shared_params.1.task_type
+            );
+        }
+
+        job_pool.shutdown().unwrap();
+        job_pool.start().unwrap();
+
+        assert_eq!(
+            RetentionMode::RemoveFinished,
+            job_pool.shared_params.read().unwrap().1.retention_mode
+        );
+
+        job_pool.shutdown().unwrap();
+
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_task_type_workers(HashMap::from([("lifecycle_test".to_string(), 1)]));
+        let typed_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+
+        assert!(matches!(
+            typed_pool.update_params(|params| params.task_type = None),
+            Err(FangError::PerTaskTypeWorkers)
+        ));
+    }
+
+    #[test]
+    fn maintenance_connection_does_not_use_worker_pool() {
+        let job_pool = WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+
+        let _worker_connection = job_pool.connection_pool.get().unwrap();
+        let maintenance_connection = job_pool.maintenance_connection().unwrap();
+
+        let queue = Queue::new_with_connection(&*maintenance_connection);
+        assert_eq!(None, queue.find_task_by_id(uuid::Uuid::new_v4()).unwrap());
+    }
+
+    #[test]
+    fn maintenance_connection_uses_the_given_maintenance_pool() {
+        let connection_pool = Queue::connection_pool(1);
+        let job_pool = WorkerPool::new_with_connection_pool(
+            1,
+            lifecycle_test_params(),
+            Option::<()>::None,
+            connection_pool,
+            Some(Queue::connection_pool(1)),
+        );
+
+        // Taken by a stuck worker
+        let _worker_connection = job_pool.connection_pool.get().unwrap();
+        let maintenance_connection = job_pool.maintenance_connection().unwrap();
+
+        let queue = Queue::new_with_connection(&*maintenance_connection);
+        assert_eq!(None, queue.find_task_by_id(uuid::Uuid::new_v4()).unwrap());
+    }
+
+    #[test]
+    fn uses_the_given_connection_pool() {
+        let connection_pool = Queue::connection_pool(4);
+        let mut job_pool = WorkerPool::new_with_connection_pool(
+            2,
+            lifecycle_test_params(),
+            Option::<()>::None,
+            connection_pool,
+            None,
+        );
+
+        job_pool.start().unwrap();
+        job_pool.scale_to(3).unwrap();
+        assert_eq!(3, job_pool.thread_join_handles.read().unwrap().len());
+
+        let maintenance_connection = job_pool.maintenance_connection().unwrap();
+        assert_eq!(4, job_pool.maintenance_pool.max_size());
+        drop(maintenance_connection);
+
+        job_pool.shutdown().unwrap();
+
+        job_pool.start().unwrap();
+        assert_eq!(4, job_pool.connection_pool.max_size());
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn scale_to_adds_and_stops_workers() {
+        let mut job_pool =
+            WorkerPool::new_with_params(2, lifecycle_test_params(), Option::<()>::None);
+
+        job_pool.scale_to(1).unwrap();
+        job_pool.start().unwrap();
+        assert_eq!(1, job_pool.thread_join_handles.read().unwrap().len());
+
+        job_pool.scale_to(3).unwrap();
+        assert_eq!(3, job_pool.thread_join_handles.read().unwrap().len());
+
+        job_pool.scale_to(2).unwrap();
+        let names: Vec<String> = job_pool
+            .thread_join_handles
+            .read()
             .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(2, names.len());
+        assert!(!names.contains(&"worker_lifecycle_test3".to_string()));
+
+        job_pool.shutdown().unwrap();
+
+        job_pool.start().unwrap();
+        assert_eq!(2, job_pool.connection_pool.max_size());
+        assert_eq!(2, job_pool.thread_join_handles.read().unwrap().len());
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn scale_to_keeps_the_workers_if_the_database_is_unreachable() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+        job_pool.start().unwrap();
+
+        job_pool.config = Some(FangConfig::new(
+            "postgres://fang@127.0.0.1:1/fang".to_string(),
+        ));
+
+        assert!(job_pool.scale_to(2).is_err());
+        assert_eq!(1, job_pool.number_of_workers);
+        assert_eq!(1, job_pool.thread_join_handles.read().unwrap().len());
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn spawns_workers_per_task_type() {
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_task_type_workers(
+            vec![("type_a".to_string(), 2), ("type_b".to_string(), 1)]
+                .into_iter()
+                .collect(),
+        );
+
+        let mut job_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+        job_pool.start().unwrap();
+
+        let worker_names = |job_pool: &WorkerPool<()>| {
+            let mut names: Vec<String> = job_pool
+                .thread_join_handles
+                .read()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(3, job_pool.number_of_workers);
+        assert_eq!(
+            vec!["worker_type_a1", "worker_type_a2", "worker_type_b1"],
+            worker_names(&job_pool)
+        );
+
+        job_pool.scale_task_type_to("type_a", 1).unwrap();
+        job_pool.scale_task_type_to("type_b", 2).unwrap();
+        assert_eq!(3, job_pool.number_of_workers);
+        assert_eq!(
+            vec!["worker_type_a1", "worker_type_b1", "worker_type_b2"],
+            worker_names(&job_pool)
+        );
+
+        assert!(matches!(
+            job_pool.scale_to(5),
+            Err(FangError::PerTaskTypeWorkers)
+        ));
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_handler_shuts_down_the_pool() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+        job_pool.start().unwrap();
+
+        let handler = job_pool.install_signal_handler().unwrap();
+        signal_hook::low_level::raise(signal_hook::consts::signal::SIGTERM).unwrap();
+        handler.join().unwrap();
+
+        assert_eq!(WorkerState::Shutdown, job_pool.state().unwrap());
+        assert!(job_pool.thread_join_handles.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn join_returns_after_shutdown() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+        job_pool.join().unwrap();
+
+        job_pool.start().unwrap();
+
+        let mut pool = job_pool.clone();
+        let shutdown = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            pool.shutdown().unwrap();
+        });
+
+        job_pool.join().unwrap();
+        assert!(job_pool.thread_join_handles.read().unwrap().is_empty());
+        shutdown.join().unwrap();
+
+        job_pool.join().unwrap();
+    }
+
+    #[test]
+    fn join_returns_fatal_worker_errors() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+        job_pool.start().unwrap();
+
+        job_pool
+            .notify_join(|exit| {
+                exit.fatal_error = Some(FangError::WorkerRestartFailed {
+                    name: "worker_lifecycle_test1".to_string(),
+                    source: Box::new(FangError::PoisonedLock),
+                })
+            })
+            .unwrap();
+
+        assert!(matches!(
+            job_pool.join(),
+            Err(FangError::WorkerRestartFailed { .. })
+        ));
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn restart_delay_doubles_up_to_max_delay() {
+        let restart_policy = RestartPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            max_restarts: None,
+        };
+
+        assert_eq!(Duration::from_secs(1), restart_policy.delay(0));
+        assert_eq!(Duration::from_secs(4), restart_policy.delay(2));
+        assert_eq!(Duration::from_secs(10), restart_policy.delay(4));
+        assert_eq!(Duration::from_secs(10), restart_policy.delay(u64::MAX));
+    }
+
+    #[test]
+    fn gives_up_on_workers_after_max_restarts() {
+        // Workers crash because the only connection is taken
+        dotenv::dotenv().ok();
+        let manager = diesel::r2d2::ConnectionManager::<PgConnection>::new(
+            std::env::var("DATABASE_URL").unwrap(),
+        );
+        let connection_pool = diesel::r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(50))
+            .build(manager)
+            .unwrap();
+        let _connection = connection_pool.get().unwrap();
+
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_restart_policy(RestartPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(10),
+            max_restarts: Some(2),
+        });
+
+        let mut job_pool = WorkerPool::new_with_connection_pool(
+            1,
+            worker_params,
+            Option::<()>::None,
+            connection_pool,
+            None,
+        );
+        job_pool.start().unwrap();
+
+        assert!(matches!(
+            job_pool.join(),
+            Err(FangError::WorkerDead { restarts: 2, .. })
+        ));
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn restarts_workers_that_fail_to_register() {
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_restart_policy(RestartPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(10),
+            max_restarts: Some(1),
+        });
+
+        let mut job_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+
+        let worker_metrics = job_pool.worker_metrics.clone();
+        thread::spawn(move || {
+            let _lock = worker_metrics.write().unwrap();
+            panic!("poisons the lock");
+        })
+        .join()
+        .unwrap_err();
+
+        job_pool.start().unwrap();
+
+        assert!(matches!(
+            job_pool.join(),
+            Err(FangError::WorkerDead { restarts: 1, .. })
+        ));
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn scale_to_stops_workers_that_are_restarting() {
+        // The worker crashes because the only connection is taken
+        dotenv::dotenv().ok();
+        let manager = diesel::r2d2::ConnectionManager::<PgConnection>::new(
+            std::env::var("DATABASE_URL").unwrap(),
+        );
+        let connection_pool = diesel::r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(50))
+            .build(manager)
+            .unwrap();
+        let _connection = connection_pool.get().unwrap();
+
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_restart_policy(RestartPolicy {
+            initial_delay: Duration::from_millis(300),
+            max_delay: Duration::from_secs(10),
+            max_restarts: None,
+        });
+
+        let mut job_pool = WorkerPool::new_with_connection_pool(
+            1,
+            worker_params,
+            Option::<()>::None,
+            connection_pool,
+            None,
+        );
+        job_pool.start().unwrap();
+        thread::sleep(Duration::from_millis(150));
+
+        job_pool.scale_to(0).unwrap();
+        thread::sleep(Duration::from_millis(500));
+
+        assert!(job_pool.thread_join_handles.read().unwrap().is_empty());
+        assert!(job_pool.status().unwrap().is_empty());
+
+        job_pool.shutdown().unwrap();
+    }
+
+    #[test]
+    fn status_reports_each_worker() {
+        let mut job_pool =
+            WorkerPool::new_with_params(2, lifecycle_test_params(), Option::<()>::None);
+        job_pool.start().unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let status = job_pool.status().unwrap();
+        job_pool.shutdown().unwrap();
+
+        let names: Vec<&str> = status.iter().map(|worker| worker.name.as_str()).collect();
+        assert_eq!(
+            vec!["worker_lifecycle_test1", "worker_lifecycle_test2"],
+            names
+        );
+
+        for worker in status {
+            assert_eq!(0, worker.restarts);
+            assert_eq!(WorkerActivity::Idle, worker.activity);
+            assert!(worker.current_task_ids.is_empty());
+            assert!(worker.last_poll_at.is_some());
+        }
+
+        assert!(job_pool.status().unwrap().is_empty());
+        assert!(job_pool.running_tasks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn subscribers_receive_lifecycle_events() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+        let events = job_pool.subscribe().unwrap();
+
+        job_pool.start().unwrap();
+        job_pool.shutdown().unwrap();
+
+        assert_eq!(
+            vec![
+                WorkerPoolEvent::WorkerStarted {
+                    name: "worker_lifecycle_test1".to_string()
+                },
+                WorkerPoolEvent::ShutdownComplete
+            ],
+            events.try_iter().collect::<Vec<_>>()
+        );
+
+        drop(events);
+        job_pool.start().unwrap();
+        job_pool.shutdown().unwrap();
+
+        assert!(job_pool.event_senders.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_stops_attached_schedulers() {
+        let mut job_pool =
+            WorkerPool::new_with_params(1, lifecycle_test_params(), Option::<()>::None);
+        job_pool.start().unwrap();
+
+        let scheduler = Scheduler::start_new(Duration::from_secs(60), Duration::from_secs(5));
+        job_pool.attach_scheduler(scheduler.clone()).unwrap();
+
+        job_pool.shutdown().unwrap();
+
+        assert!(scheduler.is_stopped());
+    }
+
+    #[test]
+    fn duplicate_calls_can_be_ignored() {
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_duplicate_call_behavior(DuplicateCallBehavior::Ignore);
+
+        let mut job_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+
+        job_pool.start().unwrap();
+        job_pool.start().unwrap();
+
+        job_pool.shutdown().unwrap();
+        job_pool.shutdown().unwrap();
     }
 
     // Following tests ignored because they commit data to the db
+    #[test]
+    #[ignore]
+    fn start_applies_the_schedule_registry() {
+        let queue = Queue::new();
+        queue.push_periodic_task(&MyJob::new(1), 60).unwrap();
+
+        let mut registry = ScheduleRegistry::new();
+        registry.register(&MyJob::new(2), 30).unwrap();
+
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_schedule_registry(registry);
+
+        let mut job_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+        job_pool.start().unwrap();
+        job_pool.shutdown().unwrap();
+
+        let periodic_tasks = fang_periodic_tasks::table
+            .get_results::<PeriodicTask>(&queue.connection)
+            .unwrap();
+
+        assert_eq!(1, periodic_tasks.len());
+        assert_eq!(2, periodic_tasks[0].metadata["number"]);
+        assert_eq!(30, periodic_tasks[0].period_in_seconds);
+
+        diesel::delete(fang_periodic_tasks::table)
+            .execute(&queue.connection)
+            .unwrap();
+    }
+
     #[test]
     #[ignore]
     fn tasks_are_finished_on_shutdown() {
@@ -347,6 +2317,61 @@ mod job_pool_tests {
         assert_eq!(finished_tasks.count(), 2);
     }
 
+    #[test]
+    #[ignore]
+    fn shutdown_with_timeout_resets_tasks_of_stuck_workers() {
+        let queue = Queue::new();
+
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_task_type("shutdown_timeout_test".to_string());
+        worker_params.set_retention_mode(RetentionMode::KeepAll);
+        let mut job_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+
+        let task = queue.push_task(&SlowJob {}).unwrap();
+
+        job_pool.start().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let report = job_pool
+            .shutdown_with_timeout(Duration::from_millis(100))
+            .unwrap();
+
+        assert_eq!(vec!["worker_shutdown_timeout_test1"], report.stuck_workers);
+        assert_eq!(vec![task.id], report.reset_tasks);
+        assert_eq!(
+            FangTaskState::New,
+            queue.find_task_by_id(task.id).unwrap().unwrap().state
+        );
+
+        queue.remove_tasks_of_type("shutdown_timeout_test").unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn running_tasks_lists_the_tasks_being_executed() {
+        let queue = Queue::new();
+
+        let mut worker_params = lifecycle_test_params();
+        worker_params.set_task_type("shutdown_timeout_test".to_string());
+        let mut job_pool = WorkerPool::new_with_params(1, worker_params, Option::<()>::None);
+
+        let task = queue.push_task(&SlowJob {}).unwrap();
+
+        job_pool.start().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        let running_tasks = job_pool.running_tasks().unwrap();
+        job_pool.shutdown().unwrap();
+
+        assert_eq!(1, running_tasks.len());
+        assert_eq!(
+            "worker_shutdown_timeout_test1",
+            running_tasks[0].worker_name
+        );
+        assert_eq!(task.id, running_tasks[0].task_id);
+        assert_eq!("shutdown_timeout_test", running_tasks[0].task_type);
+        assert!(running_tasks[0].started_at > task.created_at);
+        assert!(job_pool.running_tasks().unwrap().is_empty());
+    }
+
     #[test]
     #[ignore]
     fn tasks_are_split_between_two_threads() {